@@ -0,0 +1,271 @@
+use std::io;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+
+use BindServer;
+use futures::{Future, Poll, Async};
+use futures::stream::Stream;
+use futures::sync::mpsc;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_service::{NewService, Service};
+use util::extensions::{Extensions, ConnectionId, PeerAddr};
+use util::session::Session;
+use util::sni::SniRoutes;
+use util::transport_info::TransportInfo;
+
+/// Future returned by `serve_incoming`, completing once every connection
+/// yielded by the stream has been bound
+pub struct ServeIncoming {
+    inner: Box<dyn Future<Item = (), Error = io::Error>>,
+}
+
+/// Pushes established sockets to the `Sockets` stream it was created with,
+/// see `socket_channel`
+///
+/// The sender can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct SocketSender {
+    tx: mpsc::UnboundedSender<(net::TcpStream, SocketAddr)>,
+}
+
+/// A stream of the sockets pushed by the `SocketSender`s of a channel, ready
+/// to be served with `serve_incoming`
+pub struct Sockets {
+    rx: mpsc::UnboundedReceiver<(net::TcpStream, SocketAddr)>,
+    handle: Handle,
+}
+
+/// Returns a channel over which sockets established elsewhere are handed to
+/// the event loop of `handle`.
+///
+/// This serves sockets that are not accepted from a listener owned by the
+/// server, e.g. handed over by a parent process, or accepted by a thread
+/// applying custom logic before passing them on. The `Sockets` end is meant
+/// to be given to `serve_incoming`, which binds each socket with the protocol
+/// as it arrives:
+///
+/// ```ignore
+/// let (sender, sockets) = socket_channel(&handle);
+///
+/// thread::spawn(move || {
+///     for socket in listener.incoming() {
+///         sender.send(socket.unwrap()).unwrap();
+///     }
+/// });
+///
+/// core.run(serve_incoming(MyProto, sockets, || Ok(MyService), &handle)).unwrap();
+/// ```
+///
+/// The stream ends once all the senders are dropped.
+pub fn socket_channel(handle: &Handle) -> (SocketSender, Sockets) {
+    let (tx, rx) = mpsc::unbounded();
+    let sockets = Sockets {
+        rx: rx,
+        handle: handle.clone(),
+    };
+
+    (SocketSender { tx: tx }, sockets)
+}
+
+/// Serves `proto` on every connection yielded by `incoming`, on the event
+/// loop of `handle`.
+///
+/// `incoming` can be any stream of I/O objects along with the address of
+/// their peer, e.g. a `TcpListener::incoming`, the output of a TLS acceptor,
+/// or connections received through a tunnel. Each one gets a new instance of
+/// the service. The returned future completes once the stream ends, the
+/// connections already bound being served until they close, and fails if
+/// the stream or creating a service instance does.
+///
+/// As with `TcpServer`, the extensions of every request are populated with
+/// the `ConnectionId` and `PeerAddr` of its connection, as well as a handle
+/// to the connection's `Session`.
+///
+/// ```ignore
+/// let listener = TcpListener::bind(&addr, &handle).unwrap();
+/// let incoming = listener.incoming().and_then(|(socket, addr)| {
+///     tls.accept(socket).map(move |socket| (socket, addr))
+/// });
+///
+/// core.run(serve_incoming(MyProto, incoming, || Ok(MyService), &handle)).unwrap();
+/// ```
+pub fn serve_incoming<Kind, P, I, T, S>(proto: P, incoming: I, new_service: S, handle: &Handle)
+                                        -> ServeIncoming
+    where Kind: 'static,
+          P: BindServer<Kind, T>,
+          I: Stream<Item = (T, SocketAddr), Error = io::Error> + 'static,
+          T: 'static,
+          S: NewService<Request = P::ServiceRequest,
+                        Response = P::ServiceResponse,
+                        Error = P::ServiceError> + 'static,
+          S::Instance: 'static,
+{
+    serve_with(proto, incoming, handle, move |_, _| Some(new_service.new_service()))
+}
+
+/// Serves `proto` on every connection yielded by `incoming`, each one with a
+/// new instance of the service routed to its TLS server name.
+///
+/// `incoming` is typically the output of a TLS acceptor, whose I/O objects
+/// report the server name requested by the client through
+/// `TransportInfo::tls`, see `util::sni` for an example. Connections matching
+/// no route are closed right away. Otherwise, this behaves like
+/// `serve_incoming`.
+pub fn serve_sni<Kind, P, I, T, S, C>(proto: P,
+                                      incoming: I,
+                                      routes: Arc<SniRoutes<S, C>>,
+                                      handle: &Handle)
+                                      -> ServeIncoming
+    where Kind: 'static,
+          P: BindServer<Kind, T>,
+          I: Stream<Item = (T, SocketAddr), Error = io::Error> + 'static,
+          T: TransportInfo + 'static,
+          S: NewService<Request = P::ServiceRequest,
+                        Response = P::ServiceResponse,
+                        Error = P::ServiceError> + 'static,
+          S::Instance: 'static,
+          C: 'static,
+{
+    serve_with(proto, incoming, handle, move |io, peer_addr| {
+        let server_name = io.tls().and_then(|tls| tls.server_name);
+
+        match routes.select(server_name.as_ref().map(|name| &name[..])) {
+            Some(new_service) => {
+                trace!("routing incoming connection; server-name={:?}; peer={}",
+                       server_name, peer_addr);
+                Some(new_service.new_service())
+            }
+            None => {
+                debug!("no route for incoming connection; server-name={:?}; peer={}",
+                       server_name, peer_addr);
+                None
+            }
+        }
+    })
+}
+
+// Binds every connection yielded by `incoming` with the service returned by
+// `new_service`, closing the connection if it returns `None`
+fn serve_with<Kind, P, I, T, N, F>(proto: P, incoming: I, handle: &Handle, mut new_service: F)
+                                   -> ServeIncoming
+    where Kind: 'static,
+          P: BindServer<Kind, T>,
+          I: Stream<Item = (T, SocketAddr), Error = io::Error> + 'static,
+          T: 'static,
+          N: Service<Request = P::ServiceRequest,
+                     Response = P::ServiceResponse,
+                     Error = P::ServiceError> + 'static,
+          F: FnMut(&T, SocketAddr) -> Option<io::Result<N>> + 'static,
+{
+    let handle = handle.clone();
+    let mut connections = 0;
+
+    let served = incoming.for_each(move |(io, peer_addr)| {
+        let service = match new_service(&io, peer_addr) {
+            Some(service) => try!(service),
+            None => return Ok(()),
+        };
+
+        let connection_id = ConnectionId(connections);
+        connections += 1;
+
+        trace!("binding incoming connection; id={:?}; peer={}", connection_id, peer_addr);
+
+        proto.bind_server(&handle, io, WrapService::new(service,
+                                                        P::request_extensions,
+                                                        connection_id,
+                                                        peer_addr));
+
+        Ok(())
+    });
+
+    ServeIncoming { inner: Box::new(served) }
+}
+
+impl Future for ServeIncoming {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll()
+    }
+}
+
+impl SocketSender {
+    /// Push `socket` to the event loop serving the channel.
+    ///
+    /// Fails if the socket is not connected, or if the `Sockets` stream was
+    /// dropped.
+    pub fn send(&self, socket: net::TcpStream) -> io::Result<()> {
+        let peer_addr = try!(socket.peer_addr());
+
+        self.tx.unbounded_send((socket, peer_addr)).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "socket channel closed")
+        })
+    }
+}
+
+impl Stream for Sockets {
+    type Item = (TcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Some((socket, peer_addr)))) => {
+                let socket = try!(TcpStream::from_stream(socket, &self.handle));
+                Ok(Async::Ready(Some((socket, peer_addr))))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => unreachable!(),
+        }
+    }
+}
+
+/// Inserts the connection metadata into the extensions of every request
+///
+/// This is shared with `TcpServer`, which wraps the services of the
+/// connections it accepts the same way.
+pub struct WrapService<S: Service> {
+    inner: S,
+    extensions: fn(&mut S::Request) -> Option<&mut Extensions>,
+    connection_id: ConnectionId,
+    peer_addr: SocketAddr,
+    session: Session,
+}
+
+impl<S: Service> WrapService<S> {
+    /// Wraps the service of a new connection, `extensions` being the
+    /// `request_extensions` hook of its protocol
+    pub fn new(inner: S,
+               extensions: fn(&mut S::Request) -> Option<&mut Extensions>,
+               connection_id: ConnectionId,
+               peer_addr: SocketAddr)
+               -> WrapService<S> {
+        WrapService {
+            inner: inner,
+            extensions: extensions,
+            connection_id: connection_id,
+            peer_addr: peer_addr,
+            session: Session::new(),
+        }
+    }
+}
+
+impl<S: Service> Service for WrapService<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn call(&self, mut req: S::Request) -> S::Future {
+        if let Some(extensions) = (self.extensions)(&mut req) {
+            extensions.insert(self.connection_id);
+            extensions.insert(PeerAddr(self.peer_addr));
+            extensions.insert(self.session.clone());
+        }
+
+        self.inner.call(req)
+    }
+}
@@ -189,24 +189,59 @@ extern crate futures;
 #[macro_use]
 extern crate log;
 
+#[cfg(unix)]
+extern crate libc;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+
+#[cfg(feature = "bincode")]
+extern crate bincode;
+
+#[cfg(feature = "hdrhistogram")]
+extern crate hdrhistogram;
+
 mod simple;
-pub use simple::{pipeline, multiplex};
+pub use simple::{pipeline, multiplex, pubsub};
 
 pub mod streaming;
 pub mod util;
 
 mod tcp_client;
-pub use tcp_client::{TcpClient, Connect};
+pub use tcp_client::{TcpClient, Connect, Endpoint, ConnectEndpoint, Oneshot, OneshotResponse,
+                     Pool, PoolResponse};
+
+mod proxy;
+pub use proxy::Proxy;
+
+mod incoming;
+pub use incoming::{serve_incoming, serve_sni, ServeIncoming, socket_channel, SocketSender, Sockets};
 
 mod tcp_server;
-pub use tcp_server::TcpServer;
+pub use tcp_server::{TcpServer, Serve, Drain, AcceptErrorPolicy, ConnectionExecutor, ReactorPool};
+
+#[cfg(feature = "serde")]
+pub mod serde_proto;
+
+#[cfg(feature = "rpc")]
+pub mod rpc;
+
+mod multi_proto;
+pub use multi_proto::{MultiProto, Sniff, Sniffed, Peeked};
 
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
+use util::extensions::Extensions;
 
 // TODO: move this into futures-rs
 mod buffer_one;
 
+#[cfg(test)]
+mod test_support;
+
 /// Binds a service to an I/O object.
 ///
 /// This trait is not intended to be implemented directly; instead, implement
@@ -241,6 +276,21 @@ pub trait BindServer<Kind, T: 'static>: 'static {
         where S: Service<Request = Self::ServiceRequest,
                          Response = Self::ServiceResponse,
                          Error = Self::ServiceError> + 'static;
+
+    /// Returns the extensions map carried by the given request, if any.
+    ///
+    /// The protocol traits forward this to their `request_extensions` hook.
+    fn request_extensions(_request: &mut Self::ServiceRequest) -> Option<&mut Extensions> {
+        None
+    }
+
+    /// Returns the response answering the given request when the server is
+    /// too busy to serve it, if any.
+    ///
+    /// The protocol traits forward this to their `busy_response` hook.
+    fn busy_response(_request: &Self::ServiceRequest) -> Option<Self::ServiceResponse> {
+        None
+    }
 }
 
 /// Binds an I/O object as a client of a service.
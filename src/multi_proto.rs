@@ -0,0 +1,400 @@
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use BindServer;
+use futures::{Future, Poll, Async};
+use tokio_core::io::Io;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use util::extensions::Extensions;
+
+/// The default maximum number of bytes read before giving up on sniffing.
+const DEFAULT_MAX_PEEK: usize = 1024;
+
+/// Serves two protocols on the same listener, selecting one per connection.
+///
+/// When a connection is bound, the first bytes sent by the peer are read and
+/// handed to a *sniffer*, which decides which of the two protocols the
+/// connection speaks. The selected protocol is then bound on a `Peeked` I/O
+/// object, which replays the bytes read so far before reading from the
+/// connection again, so the protocol's transport sees the stream from the
+/// very beginning.
+///
+/// Both protocols must agree on the request, response and error types of the
+/// service, as a single service is provided on either of them.
+///
+/// The `request_extensions` and `busy_response` hooks only get the request,
+/// which does not tell which protocol the connection speaks: the hook of the
+/// first protocol applies, falling back to the one of the second protocol
+/// when it returns `None`.
+///
+/// ```ignore
+/// let proto = MultiProto::new(LegacyTextProto, BinaryProto, |buf: &[u8]| {
+///     match buf.first() {
+///         Some(&MAGIC) => Sniff::Second,
+///         Some(_) => Sniff::First,
+///         None => Sniff::NeedMore,
+///     }
+/// });
+///
+/// TcpServer::new(proto, addr).serve(|| Ok(MyService));
+/// ```
+pub struct MultiProto<A, B, F> {
+    first: Arc<A>,
+    second: Arc<B>,
+    sniffer: Arc<F>,
+    max_peek: usize,
+}
+
+/// The decision made by a `MultiProto` sniffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sniff {
+    /// Bind the connection with the first protocol
+    First,
+    /// Bind the connection with the second protocol
+    Second,
+    /// More bytes are required to decide
+    NeedMore,
+}
+
+/// A zero-sized kind type for `MultiProto`, see `BindServer`.
+pub struct Sniffed<KA, KB>(PhantomData<(KA, KB)>);
+
+/// An I/O object that replays bytes read while sniffing the protocol.
+pub struct Peeked<T> {
+    buf: Vec<u8>,
+    pos: usize,
+    io: T,
+}
+
+impl<A, B, F> MultiProto<A, B, F>
+    where F: Fn(&[u8]) -> Sniff
+{
+    /// Create a binder selecting between `first` and `second` using the
+    /// given sniffer.
+    ///
+    /// The sniffer is invoked with all bytes read from the connection so far
+    /// every time new bytes arrive, until it returns something other than
+    /// `Sniff::NeedMore`.
+    pub fn new(first: A, second: B, sniffer: F) -> MultiProto<A, B, F> {
+        MultiProto {
+            first: Arc::new(first),
+            second: Arc::new(second),
+            sniffer: Arc::new(sniffer),
+            max_peek: DEFAULT_MAX_PEEK,
+        }
+    }
+
+    /// Set the maximum number of bytes read while sniffing.
+    ///
+    /// Connections for which the sniffer cannot decide within this many bytes
+    /// are closed. Defaults to 1024.
+    pub fn max_peek(&mut self, max_peek: usize) {
+        assert!(max_peek > 0);
+        self.max_peek = max_peek;
+    }
+}
+
+impl<KA, KB, T, A, B, F> BindServer<Sniffed<KA, KB>, T> for MultiProto<A, B, F> where
+    T: Io + 'static,
+    A: BindServer<KA, Peeked<T>>,
+    B: BindServer<KB, Peeked<T>,
+                  ServiceRequest = A::ServiceRequest,
+                  ServiceResponse = A::ServiceResponse,
+                  ServiceError = A::ServiceError>,
+    F: Fn(&[u8]) -> Sniff + 'static,
+{
+    type ServiceRequest = A::ServiceRequest;
+    type ServiceResponse = A::ServiceResponse;
+    type ServiceError = A::ServiceError;
+
+    fn bind_server<S>(&self, handle: &Handle, io: T, service: S)
+        where S: Service<Request = Self::ServiceRequest,
+                         Response = Self::ServiceResponse,
+                         Error = Self::ServiceError> + 'static
+    {
+        let first = self.first.clone();
+        let second = self.second.clone();
+        let inner_handle = handle.clone();
+
+        let sniff = Sniffing {
+            io: Some(io),
+            buf: Vec::new(),
+            max_peek: self.max_peek,
+            sniffer: self.sniffer.clone(),
+        };
+
+        let task = sniff.map(move |(selected, io)| {
+            match selected {
+                Sniff::First => first.bind_server(&inner_handle, io, service),
+                Sniff::Second => second.bind_server(&inner_handle, io, service),
+                Sniff::NeedMore => unreachable!(),
+            }
+        }).map_err(|e| {
+            debug!("protocol sniffing failed; err={:?}", e);
+        });
+
+        handle.spawn(task);
+    }
+
+    fn request_extensions(request: &mut Self::ServiceRequest) -> Option<&mut Extensions> {
+        if A::request_extensions(request).is_some() {
+            return A::request_extensions(request);
+        }
+
+        B::request_extensions(request)
+    }
+
+    fn busy_response(request: &Self::ServiceRequest) -> Option<Self::ServiceResponse> {
+        A::busy_response(request).or_else(|| B::busy_response(request))
+    }
+}
+
+/// Reads from the connection until the sniffer makes a decision
+struct Sniffing<T, F> {
+    io: Option<T>,
+    buf: Vec<u8>,
+    max_peek: usize,
+    sniffer: Arc<F>,
+}
+
+impl<T, F> Future for Sniffing<T, F>
+    where T: Io,
+          F: Fn(&[u8]) -> Sniff,
+{
+    type Item = (Sniff, Peeked<T>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let selected = (self.sniffer)(&self.buf);
+
+            if selected != Sniff::NeedMore {
+                trace!("sniffed protocol; selected={:?}; peeked={}", selected, self.buf.len());
+                let io = self.io.take().expect("polled after completion");
+                let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+                return Ok(Async::Ready((selected, Peeked::new(buf, io))));
+            }
+
+            if self.buf.len() >= self.max_peek {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "unable to select a protocol"));
+            }
+
+            let n = {
+                let io = self.io.as_mut().expect("polled after completion");
+
+                if !io.poll_read().is_ready() {
+                    return Ok(Async::NotReady);
+                }
+
+                let start = self.buf.len();
+                self.buf.resize(self.max_peek, 0);
+
+                let res = io.read(&mut self.buf[start..]);
+
+                match res {
+                    Ok(n) => {
+                        self.buf.truncate(start + n);
+                        n
+                    }
+                    Err(e) => {
+                        self.buf.truncate(start);
+
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            return Ok(Async::NotReady);
+                        }
+
+                        return Err(e);
+                    }
+                }
+            };
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "connection closed before a protocol was selected"));
+            }
+        }
+    }
+}
+
+impl<T> Peeked<T> {
+    /// Wrap `io`, replaying `buf` before any further reads.
+    pub fn new(buf: Vec<u8>, io: T) -> Peeked<T> {
+        Peeked {
+            buf: buf,
+            pos: 0,
+            io: io,
+        }
+    }
+
+    /// Returns a reference to the underlying I/O object
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Returns a mutable reference to the underlying I/O object
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Consumes the value, returning any bytes not replayed yet along with the
+    /// underlying I/O object.
+    pub fn into_parts(mut self) -> (Vec<u8>, T) {
+        let rem = self.buf.split_off(self.pos);
+        (rem, self.io)
+    }
+}
+
+impl<T: Read> Read for Peeked<T> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buf.len() {
+            let n = try!((&self.buf[self.pos..]).read(dst));
+            self.pos += n;
+
+            if self.pos == self.buf.len() {
+                // Release the replay buffer
+                self.buf = Vec::new();
+                self.pos = 0;
+            }
+
+            return Ok(n);
+        }
+
+        self.io.read(dst)
+    }
+}
+
+impl<T: Write> Write for Peeked<T> {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.io.write(src)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Io> Io for Peeked<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.pos < self.buf.len() {
+            Async::Ready(())
+        } else {
+            self.io.poll_read()
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.io.poll_write()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::io::{self, Read, Write};
+    use std::sync::Arc;
+
+    use futures::{Future, Async};
+    use tokio_core::io::Io;
+
+    use super::{Peeked, Sniff, Sniffing};
+
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+
+            if self.0[0].is_empty() {
+                self.0.remove(0);
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"));
+            }
+
+            let n = cmp::min(dst.len(), self.0[0].len());
+            dst[..n].copy_from_slice(&self.0[0][..n]);
+            self.0[0].drain(..n);
+
+            if self.0[0].is_empty() {
+                self.0.remove(0);
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Chunks {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Chunks {}
+
+    fn sniff(buf: &[u8]) -> Sniff {
+        if buf.len() < 2 {
+            Sniff::NeedMore
+        } else if buf.starts_with(b"GE") {
+            Sniff::First
+        } else {
+            Sniff::Second
+        }
+    }
+
+    #[test]
+    fn test_sniff_and_replay() {
+        let io = Chunks(vec![b"G".to_vec(), vec![], b"ET /".to_vec(), b" more".to_vec()]);
+        let mut sniffing = Sniffing {
+            io: Some(io),
+            buf: vec![],
+            max_peek: 16,
+            sniffer: Arc::new(sniff),
+        };
+
+        // The second read would block
+        assert!(!sniffing.poll().unwrap().is_ready());
+
+        let (selected, mut io) = match sniffing.poll().unwrap() {
+            Async::Ready(v) => v,
+            Async::NotReady => panic!("not ready"),
+        };
+
+        assert_eq!(Sniff::First, selected);
+
+        let mut dst = vec![];
+        io.read_to_end(&mut dst).unwrap();
+        assert_eq!(&b"GET / more"[..], &dst[..]);
+    }
+
+    #[test]
+    fn test_sniff_gives_up_after_max_peek() {
+        let io = Chunks(vec![b"x".to_vec(), b"y".to_vec()]);
+        let sniffing = Sniffing {
+            io: Some(io),
+            buf: vec![],
+            max_peek: 1,
+            sniffer: Arc::new(|_: &[u8]| Sniff::NeedMore),
+        };
+
+        assert_eq!(io::ErrorKind::InvalidData, sniffing.wait().err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_peeked_into_parts() {
+        let mut io = Peeked::new(b"abc".to_vec(), Chunks(vec![]));
+
+        let mut dst = [0; 1];
+        assert_eq!(1, io.read(&mut dst).unwrap());
+        assert!(io.poll_read().is_ready());
+
+        let (rem, _) = io.into_parts();
+        assert_eq!(&b"bc"[..], &rem[..]);
+    }
+}
@@ -0,0 +1,293 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use futures::{future, Future};
+use tokio_core::io::{read_exact, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+/// A proxy tunneling the connections made by a `TcpClient`.
+///
+/// The client connects to the proxy and asks it to open a tunnel to the
+/// target address, before the client protocol is bound to the connection.
+/// When connecting to an `Endpoint`, the host name is resolved by the proxy.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    addr: SocketAddr,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Socks5 {
+        auth: Option<(String, String)>,
+    },
+    Http {
+        headers: Vec<(String, String)>,
+    },
+}
+
+/// The address a tunnel is opened to
+pub enum Target {
+    Addr(SocketAddr),
+    Host(String),
+}
+
+/// The largest HTTP CONNECT response head accepted from a proxy
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+pub type Tunnel = Box<dyn Future<Item = TcpStream, Error = io::Error>>;
+
+impl Proxy {
+    /// A SOCKS5 proxy at the given address, not requiring authentication
+    pub fn socks5(addr: SocketAddr) -> Proxy {
+        Proxy {
+            addr: addr,
+            kind: Kind::Socks5 { auth: None },
+        }
+    }
+
+    /// A SOCKS5 proxy at the given address, using username/password
+    /// authentication (RFC 1929)
+    pub fn socks5_auth<U, P>(addr: SocketAddr, username: U, password: P) -> Proxy
+        where U: Into<String>,
+              P: Into<String>,
+    {
+        Proxy {
+            addr: addr,
+            kind: Kind::Socks5 { auth: Some((username.into(), password.into())) },
+        }
+    }
+
+    /// An HTTP proxy at the given address, supporting the `CONNECT` method
+    pub fn http(addr: SocketAddr) -> Proxy {
+        Proxy {
+            addr: addr,
+            kind: Kind::Http { headers: vec![] },
+        }
+    }
+
+    /// Add a header to the `CONNECT` requests sent to an HTTP proxy, e.g.
+    /// `Proxy-Authorization`.
+    ///
+    /// Headers are ignored by SOCKS5 proxies.
+    pub fn header<N, V>(&mut self, name: N, value: V)
+        where N: Into<String>,
+              V: Into<String>,
+    {
+        if let Kind::Http { ref mut headers } = self.kind {
+            headers.push((name.into(), value.into()));
+        }
+    }
+
+    /// Returns the address of the proxy
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+}
+
+/// Connect to the proxy and open a tunnel to the target
+pub fn tunnel(proxy: &Proxy, target: Target, handle: &Handle) -> Tunnel {
+    let socket = TcpStream::connect(&proxy.addr, handle);
+
+    match proxy.kind {
+        Kind::Socks5 { ref auth } => {
+            let auth = auth.clone();
+            Box::new(socket.and_then(move |socket| socks5(socket, target, auth)))
+        }
+        Kind::Http { ref headers } => {
+            let headers = headers.clone();
+            Box::new(socket.and_then(move |socket| http_connect(socket, target, headers)))
+        }
+    }
+}
+
+impl Target {
+    /// Split the target into a host and port
+    fn host_port(&self) -> io::Result<(Host, u16)> {
+        match *self {
+            Target::Addr(ref addr) => Ok((Host::Ip(addr.ip()), addr.port())),
+            Target::Host(ref host) => {
+                let i = try!(host.rfind(':').ok_or_else(|| invalid_input("missing port")));
+                let port = try!(host[i + 1..].parse().map_err(|_| invalid_input("invalid port")));
+                let name = host[..i].trim_start_matches('[').trim_end_matches(']');
+
+                match name.parse() {
+                    Ok(ip) => Ok((Host::Ip(ip), port)),
+                    Err(_) if name.len() <= 255 => Ok((Host::Name(name.to_string()), port)),
+                    Err(_) => Err(invalid_input("host name too long")),
+                }
+            }
+        }
+    }
+}
+
+enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+fn socks5(socket: TcpStream, target: Target, auth: Option<(String, String)>) -> Tunnel {
+    let (host, port) = match target.host_port() {
+        Ok(host_port) => host_port,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    // Version, number of methods, methods
+    let greeting = match auth {
+        Some(..) => vec![5, 2, 0, 2],
+        None => vec![5, 1, 0],
+    };
+
+    let negotiated = write_all(socket, greeting).and_then(|(socket, _)| {
+        read_exact(socket, [0; 2])
+    }).and_then(move |(socket, reply)| -> Tunnel {
+        if reply[0] != 5 {
+            return Box::new(future::err(proxy_error("invalid SOCKS5 reply")));
+        }
+
+        match (reply[1], auth) {
+            (0, _) => Box::new(future::ok(socket)),
+            (2, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Box::new(future::err(invalid_input("SOCKS5 credentials too long")));
+                }
+
+                let mut msg = vec![1, username.len() as u8];
+                msg.extend_from_slice(username.as_bytes());
+                msg.push(password.len() as u8);
+                msg.extend_from_slice(password.as_bytes());
+
+                Box::new(write_all(socket, msg).and_then(|(socket, _)| {
+                    read_exact(socket, [0; 2])
+                }).and_then(|(socket, reply)| {
+                    if reply[1] != 0 {
+                        return Err(proxy_error("SOCKS5 authentication failed"));
+                    }
+
+                    Ok(socket)
+                }))
+            }
+            _ => Box::new(future::err(proxy_error("no acceptable SOCKS5 authentication method"))),
+        }
+    });
+
+    // Version, CONNECT command, reserved, address
+    let mut request = vec![5, 1, 0];
+
+    match host {
+        Host::Ip(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Ip(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Name(name) => {
+            request.push(3);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+        }
+    }
+
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+
+    Box::new(negotiated.and_then(|socket| {
+        write_all(socket, request)
+    }).and_then(|(socket, _)| {
+        read_exact(socket, [0; 4])
+    }).and_then(|(socket, reply)| {
+        if reply[0] != 5 {
+            return Err(proxy_error("invalid SOCKS5 reply"));
+        }
+
+        if reply[1] != 0 {
+            return Err(proxy_error(socks5_reply_msg(reply[1])));
+        }
+
+        Ok((socket, reply[3]))
+    }).and_then(|(socket, atyp)| -> Box<dyn Future<Item = (TcpStream, usize), Error = io::Error>> {
+        // Skip the bound address, followed by the port
+        match atyp {
+            1 => Box::new(future::ok((socket, 4 + 2))),
+            4 => Box::new(future::ok((socket, 16 + 2))),
+            3 => Box::new(read_exact(socket, [0; 1]).map(|(socket, len)| (socket, len[0] as usize + 2))),
+            _ => Box::new(future::err(proxy_error("invalid SOCKS5 address type"))),
+        }
+    }).and_then(|(socket, len)| {
+        read_exact(socket, vec![0; len])
+    }).map(|(socket, _)| socket))
+}
+
+fn socks5_reply_msg(reply: u8) -> &'static str {
+    match reply {
+        1 => "SOCKS5 proxy: general failure",
+        2 => "SOCKS5 proxy: connection not allowed by ruleset",
+        3 => "SOCKS5 proxy: network unreachable",
+        4 => "SOCKS5 proxy: host unreachable",
+        5 => "SOCKS5 proxy: connection refused",
+        6 => "SOCKS5 proxy: TTL expired",
+        7 => "SOCKS5 proxy: command not supported",
+        8 => "SOCKS5 proxy: address type not supported",
+        _ => "SOCKS5 proxy: unknown error",
+    }
+}
+
+fn http_connect(socket: TcpStream, target: Target, headers: Vec<(String, String)>) -> Tunnel {
+    let authority = match target {
+        Target::Addr(addr) => addr.to_string(),
+        Target::Host(host) => host,
+    };
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+
+    for (name, value) in headers {
+        request.push_str(&name);
+        request.push_str(": ");
+        request.push_str(&value);
+        request.push_str("\r\n");
+    }
+
+    request.push_str("\r\n");
+
+    Box::new(write_all(socket, request.into_bytes()).and_then(|(socket, _)| {
+        // Read the response head one byte at a time, so that no byte sent
+        // through the tunnel is consumed
+        future::loop_fn((socket, Vec::new()), |(socket, mut head)| {
+            read_exact(socket, [0; 1]).and_then(move |(socket, byte)| {
+                head.push(byte[0]);
+
+                if head.ends_with(b"\r\n\r\n") {
+                    return Ok(future::Loop::Break((socket, head)));
+                }
+
+                if head.len() > MAX_RESPONSE_HEAD {
+                    return Err(proxy_error("HTTP proxy response head too large"));
+                }
+
+                Ok(future::Loop::Continue((socket, head)))
+            })
+        })
+    }).and_then(|(socket, head)| {
+        // e.g. "HTTP/1.1 200 Connection established"
+        let status = head.split(|&b| b == b' ').nth(1).unwrap_or(b"");
+
+        if !head.starts_with(b"HTTP/1.") || status.len() != 3 || status[0] != b'2' {
+            let line = head.split(|&b| b == b'\r').next().unwrap_or(b"");
+            let msg = format!("HTTP proxy refused the tunnel: {}", String::from_utf8_lossy(line));
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+
+        Ok(socket)
+    }))
+}
+
+fn proxy_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,194 @@
+//! Typed RPC services.
+//!
+//! The `rpc_service!` macro takes a list of RPC method signatures and
+//! generates, in a module of the given name:
+//!
+//! - `Request` and `Response` enums, with one variant per method, which are
+//!   sent over a multiplexed `SerdeProto`,
+//! - an `Rpc` trait, with one method per RPC, to be implemented by the
+//!   server,
+//! - a `Server` wrapper, turning an `Rpc` implementation into a `Service`
+//!   dispatching requests to the matching method,
+//! - a typed `Client`, wrapping the `Service` returned by `TcpClient` or
+//!   `BindClient` and offering one method per RPC,
+//! - a `proto` function returning the protocol for a given wire format.
+//!
+//! ```ignore
+//! #[macro_use]
+//! extern crate serde_derive;
+//! #[macro_use]
+//! extern crate tokio_proto;
+//!
+//! rpc_service! {
+//!     mod calc {
+//!         /// Add two numbers
+//!         rpc add(a: u32, b: u32) -> u32;
+//!         rpc neg(a: i64) -> i64;
+//!     }
+//! }
+//!
+//! struct Calc;
+//!
+//! impl calc::Rpc for Calc {
+//!     fn add(&self, a: u32, b: u32) -> calc::Future<u32> {
+//!         Box::new(future::ok(a + b))
+//!     }
+//!
+//!     fn neg(&self, a: i64) -> calc::Future<i64> {
+//!         Box::new(future::ok(-a))
+//!     }
+//! }
+//!
+//! TcpServer::new(calc::proto(Json), addr)
+//!     .serve(|| Ok(calc::Server::new(Calc)));
+//!
+//! let client = TcpClient::new(calc::proto(Json))
+//!     .connect(&addr, &handle)
+//!     .map(calc::Client::new);
+//! ```
+//!
+//! The generated request and response enums derive serde's `Serialize` and
+//! `Deserialize`, so the crate invoking the macro must depend on `serde` and
+//! have `serde_derive` in scope. Types used in the signatures are resolved
+//! from the module enclosing the macro invocation.
+//!
+//! This module is only available with the `rpc` feature.
+
+#[doc(hidden)]
+pub mod __export {
+    pub use futures::{Future, IntoFuture};
+    pub use tokio_service::Service;
+    pub use serde_proto::{SerdeProto, Format, Multiplexed};
+}
+
+/// Generate a typed RPC service, see the `rpc` module.
+#[macro_export]
+macro_rules! rpc_service {
+    (
+        $(#[$attr:meta])*
+        mod $name:ident {
+            $(
+                $(#[$method_attr:meta])*
+                rpc $method:ident ( $($arg:ident : $arg_ty:ty),* ) -> $ret:ty;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        pub mod $name {
+            #![allow(unused_imports)]
+
+            use super::*;
+            use std::io;
+            use $crate::rpc::__export as __rpc;
+            use self::__rpc::Future as __Future;
+
+            /// A boxed future returned by the RPC methods
+            pub type Future<T> = Box<__rpc::Future<Item = T, Error = io::Error>>;
+
+            /// The protocol carrying the RPCs
+            pub type Proto<F> = __rpc::SerdeProto<Request, Response, F, __rpc::Multiplexed>;
+
+            /// Return the protocol carrying the RPCs, using the given wire
+            /// format
+            pub fn proto<F: __rpc::Format>(format: F) -> Proto<F> {
+                __rpc::SerdeProto::multiplex(format)
+            }
+
+            /// RPC requests
+            #[allow(non_camel_case_types)]
+            #[derive(Debug, Serialize, Deserialize)]
+            pub enum Request {
+                $(
+                    #[doc(hidden)]
+                    $method($($arg_ty),*),
+                )*
+            }
+
+            /// RPC responses
+            #[allow(non_camel_case_types)]
+            #[derive(Debug, Serialize, Deserialize)]
+            pub enum Response {
+                $(
+                    #[doc(hidden)]
+                    $method($ret),
+                )*
+            }
+
+            /// The RPC methods, implemented by the server
+            pub trait Rpc: 'static {
+                $(
+                    $(#[$method_attr])*
+                    fn $method(&self, $($arg: $arg_ty),*) -> Future<$ret>;
+                )*
+            }
+
+            /// Dispatches requests to an `Rpc` implementation
+            pub struct Server<S> {
+                inner: S,
+            }
+
+            impl<S: Rpc> Server<S> {
+                /// Wrap the given RPC implementation
+                pub fn new(inner: S) -> Server<S> {
+                    Server { inner: inner }
+                }
+            }
+
+            impl<S: Rpc> __rpc::Service for Server<S> {
+                type Request = Request;
+                type Response = Response;
+                type Error = io::Error;
+                type Future = Future<Response>;
+
+                fn call(&self, request: Request) -> Self::Future {
+                    match request {
+                        $(
+                            Request::$method($($arg),*) => {
+                                Box::new(self.inner.$method($($arg),*).map(Response::$method))
+                            }
+                        )*
+                    }
+                }
+            }
+
+            /// A typed client, wrapping a connected `Service`
+            pub struct Client<C> {
+                inner: C,
+            }
+
+            impl<C> Client<C>
+                where C: __rpc::Service<Request = Request,
+                                        Response = Response,
+                                        Error = io::Error>,
+                      C::Future: 'static,
+            {
+                /// Wrap the given connected service
+                pub fn new(inner: C) -> Client<C> {
+                    Client { inner: inner }
+                }
+
+                /// Returns a reference to the wrapped service
+                pub fn get_ref(&self) -> &C {
+                    &self.inner
+                }
+
+                $(
+                    $(#[$method_attr])*
+                    pub fn $method(&self, $($arg: $arg_ty),*) -> Future<$ret> {
+                        let response = self.inner.call(Request::$method($($arg),*));
+
+                        Box::new(response.and_then(|response| {
+                            #[allow(unreachable_patterns)]
+                            match response {
+                                Response::$method(ret) => Ok(ret),
+                                _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                        concat!("unexpected response to `",
+                                                                stringify!($method), "`"))),
+                            }
+                        }))
+                    }
+                )*
+            }
+        }
+    };
+}
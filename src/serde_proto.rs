@@ -0,0 +1,430 @@
+//! Protocols generated from serde types.
+//!
+//! `SerdeProto` implements the simple pipeline or multiplex protocol traits
+//! for any request and response types implementing `Serialize` and
+//! `Deserialize`, so internal services don't need a hand written codec. The
+//! encoding of the messages themselves is delegated to a pluggable `Format`;
+//! `Json` and `Bincode` are provided when the `serde_json` and `bincode`
+//! features are enabled, respectively.
+//!
+//! On the wire, every message is a frame made of a 4 byte, big endian length
+//! followed by the encoded message. Multiplexed protocols prefix each frame
+//! with the 8 byte, big endian request ID.
+//!
+//! ```ignore
+//! let proto = SerdeProto::<Query, Answer, _>::multiplex(Json);
+//!
+//! TcpServer::new(proto, addr).serve(|| Ok(QueryService));
+//! ```
+//!
+//! This module is only available with the `serde` feature.
+
+use std::io;
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio_core::io::{Io, Codec, EasyBuf};
+use {pipeline, multiplex};
+use multiplex::RequestId;
+use util::framed::{Framed, BufferSizes};
+
+/// The default maximum length of an encoded message, 8MB.
+const DEFAULT_MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+const LEN_LEN: usize = 4;
+const ID_LEN: usize = 8;
+
+/// Encodes and decodes messages
+///
+/// A format is only concerned with single messages; framing is handled by
+/// the codec.
+pub trait Format: Clone + 'static {
+    /// Append the encoded `value` to `buf`
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Decode a value from the full contents of `buf`
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> io::Result<T>;
+}
+
+/// JSON messages, using `serde_json`
+#[cfg(feature = "serde_json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "serde_json")]
+impl Format for Json {
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+        try!(::serde_json::to_writer(buf, value));
+        Ok(())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> io::Result<T> {
+        Ok(try!(::serde_json::from_slice(buf)))
+    }
+}
+
+/// Binary messages, using `bincode`
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+        ::bincode::serialize_into(buf, value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> io::Result<T> {
+        ::bincode::deserialize(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// A zero-sized marker selecting the pipelined flavor of `SerdeProto`
+pub struct Pipelined;
+
+/// A zero-sized marker selecting the multiplexed flavor of `SerdeProto`
+pub struct Multiplexed;
+
+/// A protocol exchanging `Req` requests and `Res` responses, encoded with the
+/// format `F`.
+///
+/// The `M` parameter is either `Pipelined` or `Multiplexed`; use the
+/// `pipeline` and `multiplex` constructors to pick one.
+pub struct SerdeProto<Req, Res, F, M = Pipelined> {
+    format: F,
+    max_frame_len: usize,
+    buffer_sizes: BufferSizes,
+    _marker: PhantomData<fn() -> (Req, Res, M)>,
+}
+
+/// Codec for pipelined `SerdeProto` transports
+///
+/// Decodes `In` messages and encodes `Out` messages.
+pub struct SerdeCodec<In, Out, F> {
+    format: F,
+    max_frame_len: usize,
+    _marker: PhantomData<fn() -> (In, Out)>,
+}
+
+/// Codec for multiplexed `SerdeProto` transports
+///
+/// Decodes `In` messages and encodes `Out` messages, along with their request
+/// ID.
+pub struct SerdeMultiplexCodec<In, Out, F> {
+    inner: SerdeCodec<In, Out, F>,
+}
+
+impl<Req, Res, F: Format> SerdeProto<Req, Res, F, Pipelined> {
+    /// Return a pipelined protocol using the given format
+    pub fn pipeline(format: F) -> SerdeProto<Req, Res, F, Pipelined> {
+        SerdeProto::build(format)
+    }
+}
+
+impl<Req, Res, F: Format> SerdeProto<Req, Res, F, Multiplexed> {
+    /// Return a multiplexed protocol using the given format
+    pub fn multiplex(format: F) -> SerdeProto<Req, Res, F, Multiplexed> {
+        SerdeProto::build(format)
+    }
+}
+
+impl<Req, Res, F: Format, M> SerdeProto<Req, Res, F, M> {
+    fn build(format: F) -> SerdeProto<Req, Res, F, M> {
+        SerdeProto {
+            format: format,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            buffer_sizes: BufferSizes::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Set the maximum length of an encoded message.
+    ///
+    /// Receiving a larger message is an error which closes the connection.
+    /// Defaults to 8MB.
+    pub fn max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    /// Set the sizes of the transport read and write buffers.
+    ///
+    /// See `util::framed` for details. Like a message longer than
+    /// `max_frame_len`, a frame that does not fit in the maximum read buffer
+    /// size is an error which closes the connection.
+    pub fn buffer_sizes(&mut self, sizes: BufferSizes) {
+        self.buffer_sizes = sizes;
+    }
+
+    fn framed<T: Io, C: Codec>(&self, io: T, codec: C) -> Framed<T, C> {
+        Framed::new(io, codec, self.buffer_sizes)
+    }
+
+    fn codec<In, Out>(&self) -> SerdeCodec<In, Out, F> {
+        SerdeCodec {
+            format: self.format.clone(),
+            max_frame_len: self.max_frame_len,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, Req, Res, F> pipeline::ServerProto<T> for SerdeProto<Req, Res, F, Pipelined>
+    where T: Io + 'static,
+          Req: DeserializeOwned + 'static,
+          Res: Serialize + 'static,
+          F: Format,
+{
+    type Request = Req;
+    type Response = Res;
+    type Transport = Framed<T, SerdeCodec<Req, Res, F>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(self.framed(io, self.codec()))
+    }
+}
+
+impl<T, Req, Res, F> pipeline::ClientProto<T> for SerdeProto<Req, Res, F, Pipelined>
+    where T: Io + 'static,
+          Req: Serialize + 'static,
+          Res: DeserializeOwned + 'static,
+          F: Format,
+{
+    type Request = Req;
+    type Response = Res;
+    type Transport = Framed<T, SerdeCodec<Res, Req, F>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(self.framed(io, self.codec()))
+    }
+}
+
+impl<T, Req, Res, F> multiplex::ServerProto<T> for SerdeProto<Req, Res, F, Multiplexed>
+    where T: Io + 'static,
+          Req: DeserializeOwned + 'static,
+          Res: Serialize + 'static,
+          F: Format,
+{
+    type Request = Req;
+    type Response = Res;
+    type Transport = Framed<T, SerdeMultiplexCodec<Req, Res, F>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(self.framed(io, SerdeMultiplexCodec { inner: self.codec() }))
+    }
+}
+
+impl<T, Req, Res, F> multiplex::ClientProto<T> for SerdeProto<Req, Res, F, Multiplexed>
+    where T: Io + 'static,
+          Req: Serialize + 'static,
+          Res: DeserializeOwned + 'static,
+          F: Format,
+{
+    type Request = Req;
+    type Response = Res;
+    type Transport = Framed<T, SerdeMultiplexCodec<Res, Req, F>>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(self.framed(io, SerdeMultiplexCodec { inner: self.codec() }))
+    }
+}
+
+impl<In, Out, F: Format> SerdeCodec<In, Out, F> {
+    /// Decode a frame, skipping `skip` header bytes before the length.
+    fn decode_frame(&self, buf: &mut EasyBuf, skip: usize) -> io::Result<Option<EasyBuf>>
+    {
+        let head = skip + LEN_LEN;
+
+        if buf.len() < head {
+            return Ok(None);
+        }
+
+        let len = read_u32(&buf.as_slice()[skip..head]) as usize;
+
+        if len > self.max_frame_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "message exceeds max frame length"));
+        }
+
+        if buf.len() < head + len {
+            return Ok(None);
+        }
+
+        let mut frame = buf.drain_to(head + len);
+        frame.drain_to(head);
+        Ok(Some(frame))
+    }
+
+    fn encode_frame<T: Serialize>(&self, msg: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; LEN_LEN]);
+
+        try!(self.format.encode(msg, buf));
+
+        let len = buf.len() - start - LEN_LEN;
+
+        if len > self.max_frame_len || len > u32::max_value() as usize {
+            buf.truncate(start);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "message exceeds max frame length"));
+        }
+
+        write_u32(&mut buf[start..start + LEN_LEN], len as u32);
+        Ok(())
+    }
+}
+
+impl<In, Out, F> Codec for SerdeCodec<In, Out, F>
+    where In: DeserializeOwned,
+          Out: Serialize,
+          F: Format,
+{
+    type In = In;
+    type Out = Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<In>> {
+        match try!(self.decode_frame(buf, 0)) {
+            Some(frame) => self.format.decode(frame.as_slice()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.encode_frame(&msg, buf)
+    }
+}
+
+impl<In, Out, F> Codec for SerdeMultiplexCodec<In, Out, F>
+    where In: DeserializeOwned,
+          Out: Serialize,
+          F: Format,
+{
+    type In = (RequestId, In);
+    type Out = (RequestId, Out);
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<(RequestId, In)>> {
+        if buf.len() < ID_LEN {
+            return Ok(None);
+        }
+
+        let id = read_u64(&buf.as_slice()[..ID_LEN]);
+
+        match try!(self.inner.decode_frame(buf, ID_LEN)) {
+            Some(frame) => {
+                let msg = try!(self.inner.format.decode(frame.as_slice()));
+                Ok(Some((id, msg)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: (RequestId, Out), buf: &mut Vec<u8>) -> io::Result<()> {
+        let (id, msg) = msg;
+        let start = buf.len();
+
+        buf.extend_from_slice(&[0; ID_LEN]);
+        write_u64(&mut buf[start..], id);
+
+        let res = self.inner.encode_frame(&msg, buf);
+
+        if res.is_err() {
+            buf.truncate(start);
+        }
+
+        res
+    }
+}
+
+fn read_u32(src: &[u8]) -> u32 {
+    src[..4].iter().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn read_u64(src: &[u8]) -> u64 {
+    src[..8].iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn write_u32(dst: &mut [u8], val: u32) {
+    for i in 0..4 {
+        dst[i] = (val >> (8 * (3 - i))) as u8;
+    }
+}
+
+fn write_u64(dst: &mut [u8], val: u64) {
+    for i in 0..8 {
+        dst[i] = (val >> (8 * (7 - i))) as u8;
+    }
+}
+
+#[cfg(all(test, feature = "serde_json"))]
+mod test {
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use super::{SerdeCodec, SerdeMultiplexCodec, SerdeProto, Json, Pipelined};
+
+    fn codec() -> SerdeCodec<(String, u32), (String, u32), Json> {
+        SerdeProto::<(), (), Json, Pipelined>::pipeline(Json).codec()
+    }
+
+    #[test]
+    fn test_round_trip_partial_frames() {
+        let mut codec = codec();
+
+        let mut dst = vec![];
+        codec.encode(("hello".to_string(), 1), &mut dst).unwrap();
+        codec.encode(("world".to_string(), 2), &mut dst).unwrap();
+        assert_eq!(&[0, 0, 0, 11][..], &dst[..4]);
+        assert_eq!(&b"[\"hello\",1]"[..], &dst[4..15]);
+
+        // Feed the frames one byte at a time
+        let mut buf = EasyBuf::new();
+        let mut decoded = vec![];
+
+        for &b in &dst {
+            buf.get_mut().push(b);
+
+            if let Some(msg) = codec.decode(&mut buf).unwrap() {
+                decoded.push(msg);
+            }
+        }
+
+        assert_eq!(vec![("hello".to_string(), 1), ("world".to_string(), 2)], decoded);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        let mut proto = SerdeProto::<(), (), Json, Pipelined>::pipeline(Json);
+        proto.max_frame_len(4);
+        let mut codec: SerdeCodec<String, String, Json> = proto.codec();
+
+        let mut dst = vec![1];
+        assert!(codec.encode("too long".to_string(), &mut dst).is_err());
+        assert_eq!(vec![1], dst);
+
+        let mut buf = EasyBuf::new();
+        buf.get_mut().extend_from_slice(&[0, 0, 0, 5]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_multiplex_envelope() {
+        let mut codec = SerdeMultiplexCodec { inner: codec() };
+
+        let mut dst = vec![];
+        codec.encode((258, ("hi".to_string(), 3)), &mut dst).unwrap();
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 8][..], &dst[..12]);
+
+        let mut buf = EasyBuf::new();
+        buf.get_mut().extend_from_slice(&dst[..10]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.get_mut().extend_from_slice(&dst[10..]);
+        assert_eq!(Some((258, ("hi".to_string(), 3))), codec.decode(&mut buf).unwrap());
+    }
+}
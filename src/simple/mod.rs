@@ -1,5 +1,6 @@
 pub mod pipeline;
 pub mod multiplex;
+pub mod pubsub;
 
 // A utility struct to enable "lifting" from an RPC to a streaming proto, which
 // is how RPC protos are implemented under the hood. Unfortunately:
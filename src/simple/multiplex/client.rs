@@ -4,11 +4,13 @@ use super::lift::{LiftBind, LiftTransport};
 use simple::LiftProto;
 
 use std::io;
+use std::time::{Duration, Instant};
 
-use streaming::{self, Message};
-use streaming::multiplex::StreamingMultiplex;
+use streaming::{self, Message, FlushPolicy};
+use streaming::multiplex::{StreamingMultiplex, RequestIds};
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
+use util::client_proxy::{Close, Notifications};
 use futures::{stream, Stream, Sink, Future, IntoFuture, Poll};
 
 type MyStream<E> = stream::Empty<(), E>;
@@ -49,6 +51,30 @@ pub trait ClientProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed by the time the connection
+    /// is ready to write them are dropped, and the response future resolves
+    /// with a `TimedOut` error. By default, requests carry no deadline;
+    /// `streaming::WithDeadline` carries one from the client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
+
+    /// Returns the range of request IDs requests are tagged with on each
+    /// connection, and what happens once it is exhausted, see `RequestIds`.
+    /// By default, IDs span the whole `u64` range and wrap around.
+    fn request_ids(&self) -> RequestIds {
+        RequestIds::new()
+    }
 }
 
 impl<T: 'static, P: ClientProto<T>> BindClient<Multiplex, T> for P {
@@ -84,6 +110,18 @@ impl<T, P> streaming::multiplex::ClientProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ClientProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_deadline(request: &P::Request) -> Option<Instant> {
+        <P as ClientProto<T>>::request_deadline(request)
+    }
+
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        ClientProto::flush_policy(self.lower())
+    }
+
+    fn request_ids(&self) -> RequestIds {
+        ClientProto::request_ids(self.lower())
+    }
 }
 
 /// Client `Service` for simple multiplex protocols
@@ -91,6 +129,32 @@ pub struct ClientService<T, P> where T: 'static, P: ClientProto<T> {
     inner: <LiftProto<P> as BindClient<StreamingMultiplex<MyStream<io::Error>>, T>>::BindClient
 }
 
+impl<T, P> ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    /// Close the connection gracefully, once the outstanding requests got
+    /// their response.
+    ///
+    /// See `ClientProxy::close`.
+    pub fn close(&self) -> Close {
+        self.inner.close()
+    }
+
+    /// Close the connection gracefully, waiting at most `timeout` for the
+    /// outstanding responses.
+    ///
+    /// See `ClientProxy::close_timeout`.
+    pub fn close_timeout(&self, timeout: Duration, handle: &Handle) -> Close {
+        self.inner.close_timeout(timeout, handle)
+    }
+
+    /// Returns the stream of messages the server pushed without a matching
+    /// request, if not returned already.
+    ///
+    /// See `ClientProxy::notifications`.
+    pub fn notifications(&self) -> Option<ClientNotifications<T, P>> {
+        self.inner.notifications().map(|inner| ClientNotifications { inner: inner })
+    }
+}
+
 impl<T, P> Service for ClientService<T, P> where T: 'static, P: ClientProto<T> {
     type Request = P::Request;
     type Response = P::Response;
@@ -117,6 +181,24 @@ pub struct ClientFuture<T, P> where T: 'static, P: ClientProto<T> {
             as Service>::Future
 }
 
+/// Stream returned from `ClientService::notifications`
+pub struct ClientNotifications<T, P> where T: 'static, P: ClientProto<T> {
+    inner: Notifications<Message<P::Response, streaming::Body<(), io::Error>>, io::Error>,
+}
+
+impl<T, P> Stream for ClientNotifications<T, P> where T: 'static, P: ClientProto<T> {
+    type Item = P::Response;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<P::Response>, io::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(Message::WithoutBody(msg)) => Ok(Some(msg).into()),
+            Some(Message::WithBody(..)) => panic!("bodies not supported"),
+            None => Ok(None.into()),
+        }
+    }
+}
+
 impl<T, P> Future for ClientFuture<T, P>  where T: 'static, P: ClientProto<T> {
     type Item = P::Response;
     type Error = io::Error;
@@ -3,12 +3,14 @@ use super::Pipeline;
 use super::lift::{LiftBind, LiftTransport};
 use simple::LiftProto;
 
-use streaming::{self, Message};
+use streaming::{self, Message, FlushPolicy};
 use streaming::pipeline::StreamingPipeline;
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
+use util::client_proxy::Close;
 use futures::{stream, Stream, Sink, Future, Poll, IntoFuture};
 use std::io;
+use std::time::{Duration, Instant};
 
 type MyStream<E> = stream::Empty<(), E>;
 
@@ -48,6 +50,23 @@ pub trait ClientProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed by the time the connection
+    /// is ready to write them are dropped, and the response future resolves
+    /// with a `TimedOut` error. By default, requests carry no deadline;
+    /// `streaming::WithDeadline` carries one from the client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
 }
 
 impl<T: 'static, P: ClientProto<T>> BindClient<Pipeline, T> for P {
@@ -83,6 +102,14 @@ impl<T, P> streaming::pipeline::ClientProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ClientProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_deadline(request: &P::Request) -> Option<Instant> {
+        <P as ClientProto<T>>::request_deadline(request)
+    }
+
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        ClientProto::flush_policy(self.lower())
+    }
 }
 
 /// Client `Service` for simple pipeline protocols
@@ -98,6 +125,24 @@ impl<T, P> Clone for ClientService<T, P> where T: 'static, P: ClientProto<T> {
     }
 }
 
+impl<T, P> ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    /// Close the connection gracefully, once the outstanding requests got
+    /// their response.
+    ///
+    /// See `ClientProxy::close`.
+    pub fn close(&self) -> Close {
+        self.inner.close()
+    }
+
+    /// Close the connection gracefully, waiting at most `timeout` for the
+    /// outstanding responses.
+    ///
+    /// See `ClientProxy::close_timeout`.
+    pub fn close_timeout(&self, timeout: Duration, handle: &Handle) -> Close {
+        self.inner.close_timeout(timeout, handle)
+    }
+}
+
 impl<T, P> Service for ClientService<T, P> where T: 'static, P: ClientProto<T> {
     type Request = P::Request;
     type Response = P::Response;
@@ -9,6 +9,9 @@ pub use self::client::ClientService;
 mod server;
 pub use self::server::ServerProto;
 
+mod multi;
+pub use self::multi::{MultiResponseProto, MultiResponse, Responses};
+
 /// A marker used to flag protocols as being pipelined RPC.
 ///
 /// This is an implementation detail; to actually implement a protocol,
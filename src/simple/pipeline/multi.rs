@@ -0,0 +1,254 @@
+use std::io;
+use std::time::Instant;
+
+use BindServer;
+use util::extensions::Extensions;
+use streaming::{self, Message};
+use streaming::pipeline::StreamingPipeline;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use futures::{Stream, Sink, Future, IntoFuture, Poll, Async};
+use futures::future::Map;
+use futures::stream;
+
+/// A marker used to flag protocols as being pipelined with several responses
+/// per request.
+///
+/// This is an implementation detail; to actually implement a protocol,
+/// implement the `MultiResponseProto` trait in this module.
+pub struct MultiResponse;
+
+/// The responses given by a service to a single request of a
+/// `MultiResponseProto`.
+pub type Responses<R> = Box<dyn Stream<Item = R, Error = io::Error>>;
+
+/// A pipelined server protocol answering each request with a stream of
+/// complete response messages, e.g. several result sets followed by a
+/// terminator.
+///
+/// The service returns a `Responses` stream for every request. All responses
+/// of a request are written before any response of the next one, so ordering
+/// across requests is preserved just like for `ServerProto`. The stream must
+/// yield the terminator, if the protocol has one, as its last response. An
+/// error from the stream closes the connection.
+pub trait MultiResponseProto<T: 'static>: 'static {
+    /// Request messages.
+    type Request: 'static;
+
+    /// Response messages.
+    type Response: 'static;
+
+    /// The message transport, which works with I/O objects of type `T`.
+    type Transport: 'static +
+        Stream<Item = Self::Request, Error = io::Error> +
+        Sink<SinkItem = Self::Response, SinkError = io::Error>;
+
+    /// A future for initializing a transport from an I/O object.
+    ///
+    /// In simple cases, `Result<Self::Transport, Self::Error>` often suffices.
+    type BindTransport: IntoFuture<Item = Self::Transport, Error = io::Error>;
+
+    /// Build a transport from the given I/O object, using `self` for any
+    /// configuration.
+    fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// See `ServerProto::request_deadline`.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns the extensions map carried by the given request, if any.
+    ///
+    /// See `ServerProto::request_extensions`.
+    fn request_extensions(_request: &mut Self::Request) -> Option<&mut Extensions> {
+        None
+    }
+
+    /// Returns the response answering the given request when the server is
+    /// too busy to serve it, if any.
+    ///
+    /// The request is answered with this single response. See
+    /// `ServerProto::busy_response`.
+    fn busy_response(_request: &Self::Request) -> Option<Self::Response> {
+        None
+    }
+}
+
+impl<T: 'static, P: MultiResponseProto<T>> BindServer<MultiResponse, T> for P {
+    type ServiceRequest = P::Request;
+    type ServiceResponse = Responses<P::Response>;
+    type ServiceError = io::Error;
+
+    fn bind_server<S>(&self, handle: &Handle, io: T, service: S)
+        where S: Service<Request = Self::ServiceRequest,
+                         Response = Self::ServiceResponse,
+                         Error = io::Error> + 'static
+    {
+        BindServer::<StreamingPipeline<LiftResponses<P::Response>>, T>::bind_server(
+            LiftMulti::from_ref(self), handle, io, LiftService(service)
+        )
+    }
+
+    fn request_extensions(request: &mut P::Request) -> Option<&mut Extensions> {
+        <P as MultiResponseProto<T>>::request_extensions(request)
+    }
+
+    fn busy_response(request: &P::Request) -> Option<Responses<P::Response>> {
+        <P as MultiResponseProto<T>>::busy_response(request).map(|response| {
+            Box::new(stream::once(Ok(response))) as Responses<P::Response>
+        })
+    }
+}
+
+// Lifts to a streaming protocol whose responses have an empty head, followed
+// by the actual responses as body chunks. See `LiftProto` for why a newtype
+// is needed.
+struct LiftMulti<P>(P);
+
+impl<P> LiftMulti<P> {
+    fn from_ref(proto: &P) -> &LiftMulti<P> {
+        unsafe { ::std::mem::transmute(proto) }
+    }
+
+    fn lower(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<T, P> streaming::pipeline::ServerProto<T> for LiftMulti<P> where
+    T: 'static, P: MultiResponseProto<T>
+{
+    type Request = P::Request;
+    type RequestBody = ();
+
+    type Response = ();
+    type ResponseBody = Result<P::Response, io::Error>;
+
+    type Error = io::Error;
+
+    type Transport = lift::LiftMultiTransport<P::Transport>;
+    type BindTransport = Map<<P::BindTransport as IntoFuture>::Future,
+                             fn(P::Transport) -> lift::LiftMultiTransport<P::Transport>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        MultiResponseProto::bind_transport(self.lower(), io)
+            .into_future()
+            .map(lift::LiftMultiTransport as fn(_) -> _)
+    }
+
+    fn request_deadline(request: &P::Request) -> Option<Instant> {
+        <P as MultiResponseProto<T>>::request_deadline(request)
+    }
+
+    fn request_extensions(request: &mut P::Request) -> Option<&mut Extensions> {
+        <P as MultiResponseProto<T>>::request_extensions(request)
+    }
+}
+
+struct LiftService<S>(S);
+
+impl<S, R> Service for LiftService<S>
+    where S: Service<Response = Responses<R>, Error = io::Error>,
+{
+    type Request = Message<S::Request, streaming::Body<(), io::Error>>;
+    type Response = Message<(), LiftResponses<R>>;
+    type Error = io::Error;
+    type Future = Map<S::Future, fn(Responses<R>) -> Message<(), LiftResponses<R>>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        fn lift<R>(responses: Responses<R>) -> Message<(), LiftResponses<R>> {
+            Message::WithBody((), LiftResponses(Some(responses)))
+        }
+
+        match req {
+            Message::WithoutBody(msg) => self.0.call(msg).map(lift as fn(_) -> _),
+            Message::WithBody(..) => panic!("bodies not supported"),
+        }
+    }
+}
+
+// Hands errors of the responses stream to the transport as a body chunk,
+// since the pipeline does not support failing body streams.
+struct LiftResponses<R>(Option<Responses<R>>);
+
+impl<R> Stream for LiftResponses<R> {
+    type Item = Result<R, io::Error>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let res = match self.0 {
+            Some(ref mut responses) => responses.poll(),
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match res {
+            Ok(Async::Ready(Some(response))) => Ok(Async::Ready(Some(Ok(response)))),
+            Ok(Async::Ready(None)) => {
+                self.0 = None;
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.0 = None;
+                Ok(Async::Ready(Some(Err(e))))
+            }
+        }
+    }
+}
+
+// This is a submodule so that `LiftMultiTransport` can be marked `pub`, to
+// satisfy the no-private-in-public checker.
+mod lift {
+    use std::io;
+
+    use streaming::pipeline::{Frame, Transport};
+    use futures::{Stream, Sink, StartSend, Poll, AsyncSink};
+
+    // Writes the responses carried by body chunks, dropping the empty heads
+    pub struct LiftMultiTransport<T>(pub T);
+
+    impl<T: Stream<Error = io::Error>> Stream for LiftMultiTransport<T> {
+        type Item = Frame<T::Item, (), io::Error>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+            let item = try_ready!(self.0.poll());
+            Ok(item.map(|msg| {
+                Frame::Message { message: msg, body: false }
+            }).into())
+        }
+    }
+
+    impl<T: Sink<SinkError = io::Error>> Sink for LiftMultiTransport<T> {
+        type SinkItem = Frame<(), Result<T::SinkItem, io::Error>, io::Error>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: Self::SinkItem)
+                      -> StartSend<Self::SinkItem, io::Error> {
+            match frame {
+                Frame::Message { .. } |
+                Frame::Body { chunk: None } => Ok(AsyncSink::Ready),
+                Frame::Body { chunk: Some(Ok(response)) } => {
+                    match try!(self.0.start_send(response)) {
+                        AsyncSink::Ready => Ok(AsyncSink::Ready),
+                        AsyncSink::NotReady(response) => {
+                            Ok(AsyncSink::NotReady(Frame::Body { chunk: Some(Ok(response)) }))
+                        }
+                    }
+                }
+                Frame::Body { chunk: Some(Err(error)) } |
+                Frame::Error { error } => Err(error),
+            }
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.0.poll_complete()
+        }
+    }
+
+    impl<T> Transport for LiftMultiTransport<T>
+        where T: 'static + Stream<Error = io::Error> + Sink<SinkError = io::Error>
+    {}
+}
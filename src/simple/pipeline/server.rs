@@ -1,12 +1,15 @@
 use std::io;
+use std::time::Instant;
 use std::marker;
 
 use BindServer;
+use util::extensions::Extensions;
+use util::transport_info::TransportInfo;
 use super::Pipeline;
 use super::lift::{LiftBind, LiftTransport};
 use simple::LiftProto;
 
-use streaming::{self, Message};
+use streaming::{self, Message, FlushPolicy};
 use streaming::pipeline::StreamingPipeline;
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
@@ -50,6 +53,68 @@ pub trait ServerProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed when they are read from the
+    /// transport are not dispatched to the service, and the response future
+    /// of those whose deadline passes while in flight is dropped; either way,
+    /// a `TimedOut` error is returned in their place. By default, requests
+    /// carry no deadline; `streaming::WithDeadline` carries one from the
+    /// client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns the extensions map carried by the given request, if any.
+    ///
+    /// Servers and service middleware use the map to attach metadata to the
+    /// request that is not part of the wire format; `TcpServer`, for
+    /// instance, inserts the connection ID and peer address. By default,
+    /// requests carry no extensions.
+    fn request_extensions(_request: &mut Self::Request) -> Option<&mut Extensions> {
+        None
+    }
+
+    /// Returns the details of the connection reported by the transport, if
+    /// any.
+    ///
+    /// A `ConnectionInfo` snapshot of the details is inserted in the
+    /// extensions of every request, see `util::transport_info`. By default,
+    /// no details are reported.
+    fn transport_info(_transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+        None
+    }
+
+    /// Returns the response answering the given request when the server is
+    /// too busy to serve it, if any.
+    ///
+    /// When `TcpServer::max_in_flight` is reached, new requests are answered
+    /// with this response right away instead of being dispatched to the
+    /// service. Without a busy response, the request fails and the
+    /// connection is closed. By default, there is no busy response.
+    fn busy_response(_request: &Self::Request) -> Option<Self::Response> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
+
+    /// Decides whether to serve a connection, before its transport is bound.
+    ///
+    /// Returning a response rejects the connection: the response is written
+    /// as the only frame once the transport is bound, then the connection is
+    /// closed without reading any request. This tells the peer why it is
+    /// turned away, e.g. that the server is full and to retry later, rather
+    /// than resetting the connection. By default, every connection is
+    /// served.
+    fn reject_connection(&self, _io: &T) -> Option<Self::Response> {
+        None
+    }
 }
 
 impl<T: 'static, P: ServerProto<T>> BindServer<Pipeline, T> for P {
@@ -66,6 +131,14 @@ impl<T: 'static, P: ServerProto<T>> BindServer<Pipeline, T> for P {
             LiftProto::from_ref(self), handle, io, LiftService(service)
         )
     }
+
+    fn request_extensions(request: &mut P::Request) -> Option<&mut Extensions> {
+        <P as ServerProto<T>>::request_extensions(request)
+    }
+
+    fn busy_response(request: &P::Request) -> Option<P::Response> {
+        <P as ServerProto<T>>::busy_response(request)
+    }
 }
 
 impl<T, P> streaming::pipeline::ServerProto<T> for LiftProto<P> where
@@ -85,6 +158,30 @@ impl<T, P> streaming::pipeline::ServerProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ServerProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_deadline(request: &P::Request) -> Option<Instant> {
+        <P as ServerProto<T>>::request_deadline(request)
+    }
+
+    fn transport_info(transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+        <P as ServerProto<T>>::transport_info(&transport.0)
+    }
+
+    fn request_extensions(request: &mut P::Request) -> Option<&mut Extensions> {
+        <P as ServerProto<T>>::request_extensions(request)
+    }
+
+    fn busy_response(request: &P::Request) -> Option<P::Response> {
+        <P as ServerProto<T>>::busy_response(request)
+    }
+
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        ServerProto::flush_policy(self.lower())
+    }
+
+    fn reject_connection(&self, io: &T) -> Option<P::Response> {
+        ServerProto::reject_connection(self.lower(), io)
+    }
 }
 
 struct LiftService<S>(S);
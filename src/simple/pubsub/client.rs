@@ -0,0 +1,211 @@
+use std::io;
+use std::time::Duration;
+
+use BindClient;
+use super::{PubSub, LiftPubSub};
+use super::lift::ClientTransport;
+use super::{Request, Push, SubscriptionId};
+use streaming::{self, Message, Body};
+use streaming::multiplex::StreamingMultiplex;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use util::client_proxy::{self, ClientProxy, Close};
+use futures::{Stream, Sink, Future, IntoFuture, Poll, Async};
+use futures::future::Map;
+use futures::sync::mpsc;
+
+/// A pub/sub client protocol.
+///
+/// The `T` parameter is used for the I/O object used to communicate, which is
+/// supplied in `bind_transport`.
+///
+/// The client service is called with subscription requests, and resolves to
+/// the `Subscription` stream of the messages pushed by the server. Dropping
+/// the stream unsubscribes.
+///
+/// For simple protocols, the `Self` type is often a unit struct. In more
+/// advanced cases, `Self` may contain configuration information that is used
+/// for setting up the transport in `bind_transport`.
+pub trait ClientProto<T: 'static>: 'static {
+    /// Subscription requests.
+    type Subscribe: 'static;
+
+    /// Messages pushed to subscriptions.
+    type Message: 'static;
+
+    /// The frame transport, which works with I/O objects of type `T`.
+    ///
+    /// Frames are tagged with the ID of their subscription.
+    type Transport: 'static +
+        Stream<Item = (SubscriptionId, Push<Self::Message>), Error = io::Error> +
+        Sink<SinkItem = (SubscriptionId, Request<Self::Subscribe>), SinkError = io::Error>;
+
+    /// A future for initializing a transport from an I/O object.
+    ///
+    /// In simple cases, `Result<Self::Transport, Self::Error>` often suffices.
+    type BindTransport: IntoFuture<Item = Self::Transport, Error = io::Error>;
+
+    /// Build a transport from the given I/O object, using `self` for any
+    /// configuration.
+    fn bind_transport(&self, io: T) -> Self::BindTransport;
+}
+
+type Unsubscribed = Body<(), io::Error>;
+
+impl<T: 'static, P: ClientProto<T>> BindClient<PubSub, T> for P {
+    type ServiceRequest = P::Subscribe;
+    type ServiceResponse = Subscription<P::Message>;
+    type ServiceError = io::Error;
+
+    type BindClient = ClientService<T, P>;
+
+    fn bind_client(&self, handle: &Handle, io: T) -> Self::BindClient {
+        ClientService {
+            inner: BindClient::<StreamingMultiplex<Unsubscribed>, T>::bind_client(
+                LiftPubSub::from_ref(self), handle, io
+            )
+        }
+    }
+}
+
+impl<T, P> streaming::multiplex::ClientProto<T> for LiftPubSub<P> where
+    T: 'static, P: ClientProto<T>
+{
+    type Request = P::Subscribe;
+    type RequestBody = ();
+
+    type Response = ();
+    type ResponseBody = P::Message;
+
+    type Error = io::Error;
+
+    type Transport = ClientTransport<P::Transport>;
+    type BindTransport = Map<<P::BindTransport as IntoFuture>::Future,
+                             fn(P::Transport) -> ClientTransport<P::Transport>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        ClientProto::bind_transport(self.lower(), io)
+            .into_future()
+            .map(ClientTransport::new as fn(_) -> _)
+    }
+}
+
+/// Client `Service` for pub/sub protocols
+pub struct ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    inner: ClientProxy<Message<P::Subscribe, Unsubscribed>,
+                       Message<(), Body<P::Message, io::Error>>,
+                       io::Error>,
+}
+
+impl<T, P> ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    /// Close the connection gracefully, once every subscription ended.
+    ///
+    /// See `ClientProxy::close`.
+    pub fn close(&self) -> Close {
+        self.inner.close()
+    }
+
+    /// Close the connection gracefully, waiting at most `timeout` for the
+    /// subscriptions to end.
+    ///
+    /// See `ClientProxy::close_timeout`.
+    pub fn close_timeout(&self, timeout: Duration, handle: &Handle) -> Close {
+        self.inner.close_timeout(timeout, handle)
+    }
+}
+
+impl<T, P> Service for ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    type Request = P::Subscribe;
+    type Response = Subscription<P::Message>;
+    type Error = io::Error;
+    type Future = Subscribe<P::Message>;
+
+    fn call(&self, req: P::Subscribe) -> Self::Future {
+        // The request body stays open for as long as the subscription lasts,
+        // dropping the sender unsubscribes.
+        let (unsubscribe, body) = Body::pair();
+
+        Subscribe {
+            inner: self.inner.call(Message::WithBody(req, body)),
+            unsubscribe: Some(unsubscribe),
+        }
+    }
+}
+
+impl<T, P> Clone for ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    fn clone(&self) -> Self {
+        ClientService {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// Future returned by `ClientService::call`, resolving once the server
+/// acknowledged the subscription
+pub struct Subscribe<M> {
+    inner: client_proxy::Response<Message<(), Body<M, io::Error>>, io::Error>,
+    unsubscribe: Option<mpsc::Sender<Result<(), io::Error>>>,
+}
+
+impl<M> Future for Subscribe<M> {
+    type Item = Subscription<M>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Subscription<M>, io::Error> {
+        let messages = match try_ready!(self.inner.poll()) {
+            Message::WithBody((), messages) => Some(messages),
+            // The server acknowledged the subscription, but will never
+            // push anything to it
+            Message::WithoutBody(()) => None,
+        };
+
+        Ok(Async::Ready(Subscription {
+            messages: messages,
+            unsubscribe: self.unsubscribe.take(),
+        }))
+    }
+}
+
+/// The stream of messages pushed to a single subscription
+///
+/// The stream ends once the server ends the subscription. Dropping it, or
+/// calling `unsubscribe`, asks the server to end the subscription.
+pub struct Subscription<M> {
+    messages: Option<Body<M, io::Error>>,
+    unsubscribe: Option<mpsc::Sender<Result<(), io::Error>>>,
+}
+
+impl<M> Subscription<M> {
+    /// Asks the server to end the subscription.
+    ///
+    /// Messages the server pushed before getting the request are still
+    /// yielded, until the stream ends.
+    pub fn unsubscribe(&mut self) {
+        if self.unsubscribe.take().is_some() {
+            trace!("unsubscribing");
+        }
+    }
+}
+
+impl<M> Stream for Subscription<M> {
+    type Item = M;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<M>, io::Error> {
+        let res = match self.messages {
+            Some(ref mut messages) => messages.poll(),
+            None => Ok(Async::Ready(None)),
+        };
+
+        match res {
+            Ok(Async::Ready(None)) | Err(_) => {
+                // The subscription is over, end the exchange on our side too
+                self.messages = None;
+                self.unsubscribe = None;
+            }
+            _ => {}
+        }
+
+        res
+    }
+}
@@ -0,0 +1,264 @@
+//! Publish / subscribe protocols.
+//!
+//! Clients send subscription requests, and the server answers each of them
+//! with a stream of messages pushed over the same connection, until either
+//! side ends the subscription. Subscriptions are identified by a
+//! `SubscriptionId`, which the dispatcher attaches to every frame so that
+//! pushes are routed to the stream of their subscription on the client.
+//!
+//! On the wire, clients send `Request`s and servers send `Push`es, each
+//! tagged with the ID of their subscription:
+//!
+//! * `Request::Subscribe` opens a subscription, to which the server answers
+//!   with `Push::Subscribed`, followed by any number of `Push::Message`s.
+//! * `Request::Unsubscribe` is sent once the client drops the stream of the
+//!   subscription.
+//! * `Push::End` is sent once the server is done with a subscription, be it
+//!   because its stream of messages ended or failed, or because the client
+//!   unsubscribed. A subscription ended before being acknowledged, e.g.
+//!   because the service failed, fails on the client.
+//!
+//! Under the hood, pub/sub protocols are multiplexed streaming protocols in
+//! which every subscription is an exchange whose request and response
+//! bodies stay open for as long as the subscription lasts.
+
+use std::io;
+
+use futures::Stream;
+
+mod client;
+pub use self::client::{ClientProto, ClientService, Subscribe, Subscription};
+
+mod server;
+pub use self::server::ServerProto;
+
+/// Identifies a subscription on a connection
+pub type SubscriptionId = u64;
+
+/// A marker used to flag protocols as being pub/sub.
+///
+/// This is an implementation detail; to actually implement a protocol,
+/// implement the `ClientProto` or `ServerProto` traits in this module.
+pub struct PubSub;
+
+/// A frame sent by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request<S> {
+    /// Open a subscription
+    Subscribe(S),
+    /// End a subscription
+    Unsubscribe,
+}
+
+/// A frame sent by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Push<M> {
+    /// Acknowledges a subscription
+    Subscribed,
+    /// A message published to a subscription
+    Message(M),
+    /// The subscription ended, no more messages follow
+    End,
+}
+
+/// The messages published to a single subscription, returned by the service
+/// of a pub/sub server.
+pub type Messages<M> = Box<dyn Stream<Item = M, Error = io::Error>>;
+
+// Lifts pub/sub protocols to streaming multiplexed ones. See `LiftProto` for
+// why a newtype is needed; a distinct one is used so that the impls do not
+// overlap with those of `LiftProto`.
+struct LiftPubSub<P>(P);
+
+impl<P> LiftPubSub<P> {
+    fn from_ref(proto: &P) -> &LiftPubSub<P> {
+        unsafe { ::std::mem::transmute(proto) }
+    }
+
+    fn lower(&self) -> &P {
+        &self.0
+    }
+}
+
+// This is a submodule so that the transports can be marked `pub`, to satisfy
+// the no-private-in-public checker.
+mod lift {
+    use std::collections::HashSet;
+    use std::io;
+
+    use super::{Request, Push, SubscriptionId};
+    use streaming::multiplex::{Frame, Transport};
+    use futures::{Stream, Sink, StartSend, Poll, AsyncSink};
+
+    // Subscriptions are exchanges with a body in both directions: a
+    // subscription request opens the request body, which is ended by
+    // unsubscribing, and the pushed messages are the chunks of the response
+    // body.
+    pub struct ServerTransport<T>(pub T);
+
+    pub struct ClientTransport<T> {
+        inner: T,
+        // Subscriptions sent but not acknowledged yet
+        pending: HashSet<SubscriptionId>,
+    }
+
+    impl<T> ClientTransport<T> {
+        pub fn new(inner: T) -> ClientTransport<T> {
+            ClientTransport {
+                inner: inner,
+                pending: HashSet::new(),
+            }
+        }
+    }
+
+    fn no_metadata() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       "pub/sub protocols carry no metadata nor control frames")
+    }
+
+    impl<T, S> Stream for ServerTransport<T>
+        where T: Stream<Item = (SubscriptionId, Request<S>), Error = io::Error>,
+    {
+        type Item = Frame<S, (), io::Error>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+            let frame = match try_ready!(self.0.poll()) {
+                Some((id, Request::Subscribe(subscribe))) => {
+                    Frame::Message { id: id, message: subscribe, body: true, solo: false }
+                }
+                Some((id, Request::Unsubscribe)) => Frame::Body { id: id, chunk: None },
+                None => return Ok(None.into()),
+            };
+
+            Ok(Some(frame).into())
+        }
+    }
+
+    impl<T, M> Sink for ServerTransport<T>
+        where T: Sink<SinkItem = (SubscriptionId, Push<M>), SinkError = io::Error>,
+    {
+        type SinkItem = Frame<(), M, io::Error>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: Self::SinkItem)
+                      -> StartSend<Self::SinkItem, io::Error> {
+            let push = match frame {
+                Frame::Message { id, .. } => (id, Push::Subscribed),
+                Frame::Body { id, chunk: Some(message) } => (id, Push::Message(message)),
+                Frame::Body { id, chunk: None } => (id, Push::End),
+                // The service or the messages of the subscription failed,
+                // which only ends that subscription
+                Frame::Error { id, error } => {
+                    debug!("subscription failed; id={:?}; err={}", id, error);
+                    (id, Push::End)
+                }
+                Frame::Metadata { .. } | Frame::Control { .. } => return Err(no_metadata()),
+            };
+
+            match try!(self.0.start_send(push)) {
+                AsyncSink::Ready => Ok(AsyncSink::Ready),
+                AsyncSink::NotReady((id, push)) => {
+                    let frame = match push {
+                        Push::Subscribed => {
+                            Frame::Message { id: id, message: (), body: true, solo: false }
+                        }
+                        Push::Message(message) => Frame::Body { id: id, chunk: Some(message) },
+                        Push::End => Frame::Body { id: id, chunk: None },
+                    };
+                    Ok(AsyncSink::NotReady(frame))
+                }
+            }
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.0.poll_complete()
+        }
+    }
+
+    impl<T, S, M> Transport<()> for ServerTransport<T>
+        where T: 'static,
+              T: Stream<Item = (SubscriptionId, Request<S>), Error = io::Error>,
+              T: Sink<SinkItem = (SubscriptionId, Push<M>), SinkError = io::Error>,
+    {}
+
+    impl<T, M> Stream for ClientTransport<T>
+        where T: Stream<Item = (SubscriptionId, Push<M>), Error = io::Error>,
+    {
+        type Item = Frame<(), M, io::Error>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+            let frame = match try_ready!(self.inner.poll()) {
+                Some((id, Push::Subscribed)) => {
+                    self.pending.remove(&id);
+                    Frame::Message { id: id, message: (), body: true, solo: false }
+                }
+                Some((id, Push::End)) if self.pending.remove(&id) => {
+                    let error = io::Error::new(io::ErrorKind::Other, "subscription rejected");
+                    Frame::Error { id: id, error: error }
+                }
+                Some((id, Push::Message(message))) => Frame::Body { id: id, chunk: Some(message) },
+                Some((id, Push::End)) => Frame::Body { id: id, chunk: None },
+                None => return Ok(None.into()),
+            };
+
+            Ok(Some(frame).into())
+        }
+    }
+
+    impl<T, S> Sink for ClientTransport<T>
+        where T: Sink<SinkItem = (SubscriptionId, Request<S>), SinkError = io::Error>,
+    {
+        type SinkItem = Frame<S, (), io::Error>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: Self::SinkItem)
+                      -> StartSend<Self::SinkItem, io::Error> {
+            let request = match frame {
+                Frame::Message { id, message, .. } => (id, Request::Subscribe(message)),
+                Frame::Body { id, chunk: None } => (id, Request::Unsubscribe),
+                // Subscription requests have no actual body
+                Frame::Body { chunk: Some(()), .. } => return Ok(AsyncSink::Ready),
+                Frame::Error { id, error } => {
+                    debug!("unsubscribing from failed subscription; id={:?}; err={}", id, error);
+                    (id, Request::Unsubscribe)
+                }
+                Frame::Metadata { .. } | Frame::Control { .. } => return Err(no_metadata()),
+            };
+
+            let subscribe = match request {
+                (id, Request::Subscribe(_)) => Some(id),
+                _ => None,
+            };
+
+            match try!(self.inner.start_send(request)) {
+                AsyncSink::Ready => {
+                    if let Some(id) = subscribe {
+                        self.pending.insert(id);
+                    }
+                    Ok(AsyncSink::Ready)
+                }
+                AsyncSink::NotReady((id, request)) => {
+                    let frame = match request {
+                        Request::Subscribe(message) => {
+                            Frame::Message { id: id, message: message, body: true, solo: false }
+                        }
+                        Request::Unsubscribe => Frame::Body { id: id, chunk: None },
+                    };
+                    Ok(AsyncSink::NotReady(frame))
+                }
+            }
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.inner.poll_complete()
+        }
+    }
+
+    impl<T, S, M> Transport<M> for ClientTransport<T>
+        where T: 'static,
+              T: Stream<Item = (SubscriptionId, Push<M>), Error = io::Error>,
+              T: Sink<SinkItem = (SubscriptionId, Request<S>), SinkError = io::Error>,
+    {}
+}
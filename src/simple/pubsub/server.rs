@@ -0,0 +1,180 @@
+use std::io;
+
+use BindServer;
+use super::{PubSub, Messages, LiftPubSub};
+use super::lift::ServerTransport;
+use super::{Request, Push, SubscriptionId};
+use streaming::{self, Message, Body};
+use streaming::multiplex::StreamingMultiplex;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use futures::{Stream, Sink, Future, IntoFuture, Poll, Async};
+use futures::future::Map;
+
+/// A pub/sub server protocol.
+///
+/// The `T` parameter is used for the I/O object used to communicate, which is
+/// supplied in `bind_transport`.
+///
+/// The service is called with every subscription request, and returns the
+/// stream of messages to push to the subscription. The subscription ends
+/// once the stream does, or once the client unsubscribes, in which case the
+/// stream is dropped. A failing subscription, or stream, closes the
+/// connection.
+///
+/// For simple protocols, the `Self` type is often a unit struct. In more
+/// advanced cases, `Self` may contain configuration information that is used
+/// for setting up the transport in `bind_transport`.
+pub trait ServerProto<T: 'static>: 'static {
+    /// Subscription requests.
+    type Subscribe: 'static;
+
+    /// Messages pushed to subscriptions.
+    type Message: 'static;
+
+    /// The frame transport, which works with I/O objects of type `T`.
+    ///
+    /// Frames are tagged with the ID of their subscription.
+    type Transport: 'static +
+        Stream<Item = (SubscriptionId, Request<Self::Subscribe>), Error = io::Error> +
+        Sink<SinkItem = (SubscriptionId, Push<Self::Message>), SinkError = io::Error>;
+
+    /// A future for initializing a transport from an I/O object.
+    ///
+    /// In simple cases, `Result<Self::Transport, Self::Error>` often suffices.
+    type BindTransport: IntoFuture<Item = Self::Transport, Error = io::Error>;
+
+    /// Build a transport from the given I/O object, using `self` for any
+    /// configuration.
+    fn bind_transport(&self, io: T) -> Self::BindTransport;
+}
+
+impl<T: 'static, P: ServerProto<T>> BindServer<PubSub, T> for P {
+    type ServiceRequest = P::Subscribe;
+    type ServiceResponse = Messages<P::Message>;
+    type ServiceError = io::Error;
+
+    fn bind_server<S>(&self, handle: &Handle, io: T, service: S)
+        where S: Service<Request = Self::ServiceRequest,
+                         Response = Self::ServiceResponse,
+                         Error = io::Error> + 'static
+    {
+        BindServer::<StreamingMultiplex<Until<P::Message>>, T>::bind_server(
+            LiftPubSub::from_ref(self), handle, io, LiftService(service)
+        )
+    }
+}
+
+impl<T, P> streaming::multiplex::ServerProto<T> for LiftPubSub<P> where
+    T: 'static, P: ServerProto<T>
+{
+    type Request = P::Subscribe;
+    type RequestBody = ();
+
+    type Response = ();
+    type ResponseBody = P::Message;
+
+    type Error = io::Error;
+
+    type Transport = ServerTransport<P::Transport>;
+    type BindTransport = Map<<P::BindTransport as IntoFuture>::Future,
+                             fn(P::Transport) -> ServerTransport<P::Transport>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        ServerProto::bind_transport(self.lower(), io)
+            .into_future()
+            .map(ServerTransport as fn(_) -> _)
+    }
+}
+
+struct LiftService<S>(S);
+
+impl<S, M> Service for LiftService<S>
+    where S: Service<Response = Messages<M>, Error = io::Error>,
+{
+    type Request = Message<S::Request, Body<(), io::Error>>;
+    type Response = Message<(), Until<M>>;
+    type Error = io::Error;
+    type Future = LiftFuture<S::Future>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (subscribe, unsubscribed) = match req {
+            Message::WithBody(subscribe, body) => (subscribe, Some(body)),
+            Message::WithoutBody(subscribe) => (subscribe, None),
+        };
+
+        LiftFuture {
+            inner: self.0.call(subscribe),
+            unsubscribed: unsubscribed,
+        }
+    }
+}
+
+struct LiftFuture<F> {
+    inner: F,
+    unsubscribed: Option<Body<(), io::Error>>,
+}
+
+impl<F, M> Future for LiftFuture<F>
+    where F: Future<Item = Messages<M>, Error = io::Error>,
+{
+    type Item = Message<(), Until<M>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let messages = try_ready!(self.inner.poll());
+
+        let until = Until {
+            messages: Some(messages),
+            unsubscribed: self.unsubscribed.take(),
+        };
+
+        Ok(Async::Ready(Message::WithBody((), until)))
+    }
+}
+
+// The messages of a subscription, until the client unsubscribes, which ends
+// the request body of the exchange.
+struct Until<M> {
+    messages: Option<Messages<M>>,
+    unsubscribed: Option<Body<(), io::Error>>,
+}
+
+impl<M> Stream for Until<M> {
+    type Item = M;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<M>, io::Error> {
+        loop {
+            let done = match self.unsubscribed {
+                Some(ref mut body) => {
+                    match body.poll() {
+                        Ok(Async::Ready(Some(()))) => continue,
+                        Ok(Async::NotReady) => false,
+                        Ok(Async::Ready(None)) | Err(_) => true,
+                    }
+                }
+                None => false,
+            };
+
+            if done {
+                trace!("client unsubscribed; dropping messages");
+                self.unsubscribed = None;
+                self.messages = None;
+            }
+
+            break;
+        }
+
+        let res = match self.messages {
+            Some(ref mut messages) => try!(messages.poll()),
+            None => return Ok(Async::Ready(None)),
+        };
+
+        if let Async::Ready(None) = res {
+            self.messages = None;
+        }
+
+        Ok(res)
+    }
+}
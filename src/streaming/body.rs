@@ -1,6 +1,7 @@
 use std::fmt;
+use std::io::{self, Read, Write};
 
-use futures::{Async, Poll, Stream};
+use futures::{Async, Future, Poll, Stream};
 use futures::sync::mpsc;
 
 /// Body stream
@@ -11,6 +12,7 @@ pub struct Body<T, E> {
 enum Inner<T, E> {
     Once(Option<T>),
     Stream(mpsc::Receiver<Result<T, E>>),
+    Boxed(Box<dyn Stream<Item = T, Error = E> + Send>),
     Empty,
 }
 
@@ -28,6 +30,28 @@ impl<T, E> Body<T, E> {
     }
 }
 
+impl<E: From<io::Error> + Send + 'static> Body<Vec<u8>, E> {
+    /// Return a body stream reading `reader` to its end, in chunks of at most
+    /// `chunk_size` bytes
+    ///
+    /// The reader may be non-blocking, in which case `WouldBlock` errors
+    /// leave the stream not ready, as with other I/O objects of the event
+    /// loop. Any other error fails the stream.
+    pub fn from_reader<R>(reader: R, chunk_size: usize) -> Body<Vec<u8>, E>
+        where R: Read + Send + 'static,
+    {
+        assert!(chunk_size > 0, "chunk size must be positive");
+
+        let chunks = ReadChunks {
+            reader: reader,
+            chunk_size: chunk_size,
+            done: false,
+        };
+
+        Body { inner: Inner::Boxed(Box::new(chunks.map_err(E::from))) }
+    }
+}
+
 impl<T, E> Stream for Body<T, E> {
     type Item = T;
     type Error = E;
@@ -43,6 +67,7 @@ impl<T, E> Stream for Body<T, E> {
                     Async::NotReady => Ok(Async::NotReady),
                 }
             }
+            Inner::Boxed(ref mut s) => s.poll(),
             Inner::Empty => Ok(Async::Ready(None)),
         }
     }
@@ -65,3 +90,152 @@ impl<T, E> fmt::Debug for Body<T, E> {
         write!(fmt, "Body {{ [stream of values] }}")
     }
 }
+
+struct ReadChunks<R> {
+    reader: R,
+    chunk_size: usize,
+    done: bool,
+}
+
+impl<R: Read> Stream for ReadChunks<R> {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        let mut chunk = vec![0; self.chunk_size];
+
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    self.done = true;
+                    return Ok(Async::Ready(None));
+                }
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Ok(Async::Ready(Some(chunk)));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Returns a future writing every chunk of `body` to `writer`, then flushing
+/// it
+///
+/// The future resolves with the number of bytes written along with the
+/// writer, once the body ended. The writer may be non-blocking, in which case
+/// `WouldBlock` errors leave the future not ready. An error of the body, or
+/// of the writer, fails the future.
+pub fn sink_to_writer<S, W>(body: S, writer: W) -> SinkToWriter<S, W>
+    where S: Stream,
+          S::Item: AsRef<[u8]>,
+          S::Error: From<io::Error>,
+          W: Write,
+{
+    SinkToWriter {
+        body: body,
+        writer: Some(writer),
+        chunk: None,
+        pos: 0,
+        written: 0,
+    }
+}
+
+/// Future returned by `sink_to_writer`
+pub struct SinkToWriter<S: Stream, W> {
+    body: S,
+    writer: Option<W>,
+    // The chunk being written, and how much of it was already
+    chunk: Option<S::Item>,
+    pos: usize,
+    written: u64,
+}
+
+impl<S, W> Future for SinkToWriter<S, W>
+    where S: Stream,
+          S::Item: AsRef<[u8]>,
+          S::Error: From<io::Error>,
+          W: Write,
+{
+    type Item = (u64, W);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<(u64, W), S::Error> {
+        loop {
+            if let Some(chunk) = self.chunk.take() {
+                let writer = self.writer.as_mut().expect("polled after completion");
+
+                while self.pos < chunk.as_ref().len() {
+                    match writer.write(&chunk.as_ref()[self.pos..]) {
+                        Ok(0) => {
+                            let err = io::Error::new(io::ErrorKind::WriteZero,
+                                                     "failed to write body chunk");
+                            return Err(err.into());
+                        }
+                        Ok(n) => {
+                            self.pos += n;
+                            self.written += n as u64;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            self.chunk = Some(chunk);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+
+                self.pos = 0;
+            }
+
+            match try_ready!(self.body.poll()) {
+                Some(chunk) => self.chunk = Some(chunk),
+                None => break,
+            }
+        }
+
+        match self.writer.as_mut().expect("polled after completion").flush() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e.into()),
+        }
+
+        trace!("body written; bytes={}", self.written);
+        Ok(Async::Ready((self.written, self.writer.take().unwrap())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor};
+
+    use futures::{Future, Stream};
+
+    use super::{Body, sink_to_writer};
+
+    #[test]
+    fn test_body_from_reader_is_chunked() {
+        let body: Body<Vec<u8>, io::Error> = Body::from_reader(Cursor::new(b"hello world"), 4);
+
+        let chunks = body.collect().wait().unwrap();
+        assert_eq!(vec![b"hell".to_vec(), b"o wo".to_vec(), b"rld".to_vec()], chunks);
+    }
+
+    #[test]
+    fn test_body_drained_into_writer() {
+        let body: Body<Vec<u8>, io::Error> = Body::from_reader(Cursor::new(b"hello world"), 4);
+
+        let (written, out) = sink_to_writer(body, Vec::new()).wait().unwrap();
+        assert_eq!(11, written);
+        assert_eq!(b"hello world".to_vec(), out);
+    }
+}
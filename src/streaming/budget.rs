@@ -0,0 +1,75 @@
+use std::io;
+
+/// A bound on the memory used by a single connection
+///
+/// The dispatchers account for the approximate number of bytes buffered on
+/// behalf of a connection: the buffers of its transport, as reported by
+/// `Transport::memory_used`, plus, for multiplexed protocols, the table of
+/// in-progress exchanges and the body frames queued for them.
+///
+/// Once the budget is exceeded, no more frames are read from the connection
+/// until usage drops back below it, e.g. as responses are flushed. A
+/// connection can instead be closed right away, see `close_when_exceeded`;
+/// this is the safer choice when the transport buffers partially received
+/// frames, which pausing reads does not release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    limit: usize,
+    close: bool,
+}
+
+impl MemoryBudget {
+    /// Returns a budget of `limit` bytes, pausing reads once exceeded
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit: limit,
+            close: false,
+        }
+    }
+
+    /// Close the connection with an error instead of pausing reads once the
+    /// budget is exceeded
+    pub fn close_when_exceeded(&mut self) {
+        self.close = true;
+    }
+
+    /// Returns the number of bytes a connection may use
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns true if frames may be read given the bytes used, or an error
+    /// if the connection is to be closed.
+    pub fn check(&self, used: usize) -> io::Result<bool> {
+        if used <= self.limit {
+            return Ok(true);
+        }
+
+        debug!("connection memory budget exceeded; used={}; limit={}", used, self.limit);
+
+        if self.close {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "connection memory budget exceeded"));
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_check() {
+        let mut budget = MemoryBudget::new(100);
+        assert!(budget.check(100).unwrap());
+        assert!(!budget.check(101).unwrap());
+
+        budget.close_when_exceeded();
+        assert!(budget.check(0).unwrap());
+        assert_eq!(io::ErrorKind::Other, budget.check(101).unwrap_err().kind());
+    }
+}
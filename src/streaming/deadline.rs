@@ -0,0 +1,250 @@
+use std::io;
+use std::ops;
+use std::time::{Duration, Instant};
+
+use tokio_core::io::{Codec, EasyBuf};
+
+/// A message payload along with the deadline of its request
+///
+/// Clients set the instant after which nobody waits on the response; the
+/// server reads it back on the request it receives. Protocols carrying
+/// deadlines use it as their request type, or as the head of a streaming
+/// `Message`, and return `WithDeadline::deadline` from their
+/// `request_deadline` hook so that dispatchers drop expired requests:
+///
+/// ```ignore
+/// fn request_deadline(request: &WithDeadline<MyRequest>) -> Option<Instant> {
+///     request.deadline()
+/// }
+/// ```
+///
+/// The deadline is encoded ahead of the payload by `DeadlineCodec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithDeadline<T> {
+    deadline: Option<Instant>,
+    payload: T,
+}
+
+/// A `Codec` encoding the deadline of a message ahead of its payload, which
+/// is encoded by the wrapped codec
+///
+/// Instants are local to a host, so the time left until the deadline is sent
+/// instead: one byte, 0 for no deadline or 1 for a deadline, followed in the
+/// latter case by the remaining milliseconds on 4 bytes, big-endian. An
+/// expired deadline is sent as 0ms. The deadline of a decoded message is
+/// relative to when its header was read, which makes it later than the
+/// deadline of the sender by the transit time of the message.
+pub struct DeadlineCodec<C> {
+    inner: C,
+    // Deadline decoded ahead of a payload not completely received yet
+    pending: Option<Option<Instant>>,
+}
+
+impl<T> WithDeadline<T> {
+    /// Returns `payload` with no deadline
+    pub fn new(payload: T) -> WithDeadline<T> {
+        WithDeadline::from_parts(None, payload)
+    }
+
+    /// Returns `payload` along with the given deadline
+    pub fn from_parts(deadline: Option<Instant>, payload: T) -> WithDeadline<T> {
+        WithDeadline {
+            deadline: deadline,
+            payload: payload,
+        }
+    }
+
+    /// Returns the deadline, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Set the deadline
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Consumes the value, returning the deadline and the payload
+    pub fn into_parts(self) -> (Option<Instant>, T) {
+        (self.deadline, self.payload)
+    }
+
+    /// Consumes the value, returning the payload
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+}
+
+impl<T> ops::Deref for WithDeadline<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.payload
+    }
+}
+
+impl<T> ops::DerefMut for WithDeadline<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.payload
+    }
+}
+
+impl<C: Codec> DeadlineCodec<C> {
+    /// Wrap `inner`, which encodes the payload of messages
+    pub fn new(inner: C) -> DeadlineCodec<C> {
+        DeadlineCodec {
+            inner: inner,
+            pending: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped codec
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    // Decode the deadline, if completely received
+    fn decode_deadline(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Option<Instant>>> {
+        let len = match buf.as_slice().first() {
+            Some(&0) => 1,
+            Some(&1) => 5,
+            Some(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid deadline flag"));
+            }
+            None => return Ok(None),
+        };
+
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let section = buf.drain_to(len);
+
+        if len == 1 {
+            return Ok(Some(None));
+        }
+
+        let millis = section.as_slice()[1..].iter().fold(0, |v, &b| v << 8 | b as u64);
+        Ok(Some(Some(Instant::now() + Duration::from_millis(millis))))
+    }
+}
+
+impl<C: Codec> Codec for DeadlineCodec<C> {
+    type In = WithDeadline<C::In>;
+    type Out = WithDeadline<C::Out>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<WithDeadline<C::In>>> {
+        if self.pending.is_none() {
+            self.pending = try!(self.decode_deadline(buf));
+
+            if self.pending.is_none() {
+                return Ok(None);
+            }
+        }
+
+        match try!(self.inner.decode(buf)) {
+            Some(payload) => {
+                let deadline = self.pending.take().unwrap();
+                Ok(Some(WithDeadline::from_parts(deadline, payload)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: WithDeadline<C::Out>, buf: &mut Vec<u8>) -> io::Result<()> {
+        let (deadline, payload) = msg.into_parts();
+
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                let left = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+                let millis = left.as_secs()
+                    .saturating_mul(1000)
+                    .saturating_add(left.subsec_nanos() as u64 / 1_000_000);
+                let millis = if millis > u32::max_value() as u64 {
+                    u32::max_value()
+                } else {
+                    millis as u32
+                };
+
+                buf.push(1);
+                for i in (0..4).rev() {
+                    buf.push((millis >> (i * 8)) as u8);
+                }
+            }
+            None => buf.push(0),
+        }
+
+        self.inner.encode(payload, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use streaming::Message;
+    use test_support::Lines;
+    use super::{DeadlineCodec, WithDeadline};
+
+    #[test]
+    fn test_deadline_sent_as_time_left() {
+        let mut codec = DeadlineCodec::new(Lines);
+
+        let before = Instant::now();
+        let deadline = before + Duration::from_secs(10);
+        let msg = WithDeadline::from_parts(Some(deadline), b"hello".to_vec());
+
+        let mut buf = vec![];
+        codec.encode(msg, &mut buf).unwrap();
+        assert_eq!(1, buf[0]);
+        assert_eq!(&b"hello\n"[..], &buf[5..]);
+
+        // Decoded as it arrives, the payload being received after the deadline
+        let mut rd = EasyBuf::new();
+        let mut decoded = None;
+        for (i, &b) in buf.iter().enumerate() {
+            rd.get_mut().push(b);
+            decoded = codec.decode(&mut rd).unwrap();
+            assert_eq!(i + 1 == buf.len(), decoded.is_some());
+        }
+
+        let decoded = decoded.unwrap();
+        assert_eq!(&b"hello"[..], &decoded[..]);
+
+        let received = decoded.deadline().unwrap();
+        assert!(received > before + Duration::from_secs(9));
+        assert!(received <= Instant::now() + Duration::from_secs(10));
+
+        let message: Message<_, ()> = Message::WithoutBody(decoded);
+        assert_eq!(Some(received), message.deadline());
+    }
+
+    #[test]
+    fn test_missing_and_expired_deadlines() {
+        let mut codec = DeadlineCodec::new(Lines);
+
+        let mut buf = vec![];
+        codec.encode(WithDeadline::new(b"a".to_vec()), &mut buf).unwrap();
+        assert_eq!(&b"\0a\n"[..], &buf[..]);
+
+        let expired = Instant::now() - Duration::from_millis(10);
+        codec.encode(WithDeadline::from_parts(Some(expired), b"b".to_vec()), &mut buf).unwrap();
+        assert_eq!(&b"\x01\0\0\0\0b\n"[..], &buf[3..]);
+
+        let mut rd = EasyBuf::from(buf);
+        assert_eq!(None, codec.decode(&mut rd).unwrap().unwrap().deadline());
+        assert!(codec.decode(&mut rd).unwrap().unwrap().deadline().unwrap() <= Instant::now());
+
+        let err = codec.decode(&mut EasyBuf::from(b"\x02b\n".to_vec())).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
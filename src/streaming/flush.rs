@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+/// When a dispatcher flushes the frames it writes to its transport
+///
+/// By default, the transport is flushed every time the dispatcher runs, so
+/// every response or request is handed to the socket as soon as possible.
+/// With a flush policy, written frames are instead accumulated until either
+/// `max_frames` of them are pending or the oldest one waited for
+/// `max_delay`, trading a bounded amount of latency for fewer, larger writes.
+/// This pays off for protocols exchanging many small messages.
+///
+/// Pending frames are flushed right away once the connection winds down, or
+/// when the transport stops accepting frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    max_frames: usize,
+    max_delay: Duration,
+}
+
+impl FlushPolicy {
+    /// Returns a policy flushing once `max_frames` frames are pending, or
+    /// once the oldest pending frame was written `max_delay` ago
+    pub fn new(max_frames: usize, max_delay: Duration) -> FlushPolicy {
+        FlushPolicy {
+            max_frames: max_frames,
+            max_delay: max_delay,
+        }
+    }
+
+    /// Returns the number of frames accumulated before flushing
+    pub fn max_frames(&self) -> usize {
+        self.max_frames
+    }
+
+    /// Returns how long a written frame may wait to be flushed
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+}
+
+/// Tracks the frames written since the transport was last flushed, and
+/// decides when to flush it again
+pub struct FlushBatch {
+    policy: Option<FlushPolicy>,
+    // Frames written since the last flush
+    frames: usize,
+    // When the oldest of them was written
+    since: Option<Instant>,
+}
+
+impl FlushBatch {
+    pub fn new() -> FlushBatch {
+        FlushBatch {
+            policy: None,
+            frames: 0,
+            since: None,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: FlushPolicy) {
+        self.policy = Some(policy);
+    }
+
+    pub fn wrote_frame(&mut self) {
+        if self.policy.is_some() {
+            self.frames += 1;
+
+            if self.since.is_none() {
+                self.since = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Flush the pending frames on the next poll, regardless of the policy
+    pub fn flush_now(&mut self) {
+        self.frames = 0;
+        self.since = None;
+    }
+
+    /// Returns true if frames are held back by the policy
+    pub fn is_pending(&self) -> bool {
+        self.frames > 0
+    }
+
+    /// Returns true if the transport is to be flushed, resetting the batch if
+    /// so. A transport is always flushed when no frame is pending, to finish
+    /// writing the previous batch.
+    pub fn poll_due(&mut self) -> bool {
+        let due = match (self.policy, self.since) {
+            (Some(policy), Some(since)) => {
+                self.frames >= policy.max_frames ||
+                    since + policy.max_delay <= Instant::now()
+            }
+            _ => true,
+        };
+
+        if due {
+            self.flush_now();
+        }
+
+        due
+    }
+
+    /// Returns the instant at which the pending frames are due
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match (self.policy, self.since) {
+            (Some(policy), Some(since)) => Some(since + policy.max_delay),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{FlushBatch, FlushPolicy};
+
+    #[test]
+    fn test_flush_due_after_max_frames() {
+        let mut batch = FlushBatch::new();
+        batch.set_policy(FlushPolicy::new(2, Duration::from_secs(60)));
+
+        // Nothing pending, finishing the previous flush
+        assert!(batch.poll_due());
+
+        batch.wrote_frame();
+        assert!(batch.is_pending());
+        assert!(!batch.poll_due());
+        assert!(batch.poll_timeout().is_some());
+
+        batch.wrote_frame();
+        assert!(batch.poll_due());
+        assert!(!batch.is_pending());
+        assert!(batch.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn test_flush_due_after_max_delay() {
+        let mut batch = FlushBatch::new();
+        batch.set_policy(FlushPolicy::new(100, Duration::from_millis(0)));
+
+        batch.wrote_frame();
+        assert!(batch.poll_due());
+    }
+
+    #[test]
+    fn test_flush_always_due_without_policy() {
+        let mut batch = FlushBatch::new();
+
+        batch.wrote_frame();
+        assert!(!batch.is_pending());
+        assert!(batch.poll_due());
+    }
+}
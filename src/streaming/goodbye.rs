@@ -0,0 +1,49 @@
+use std::io;
+
+use futures::{Future, Poll, Async, Sink, AsyncSink};
+
+/// A future writing a single frame to a transport, then closing it
+///
+/// This is how servers reject a connection while still telling the peer why,
+/// e.g. that the server is full and to retry later, rather than resetting it
+/// silently; see `reject_connection` on the server protocols. The transport
+/// is dropped, closing the connection, once the frame is flushed. No frame is
+/// read from it.
+pub struct Goodbye<T: Sink> {
+    transport: Option<T>,
+    frame: Option<T::SinkItem>,
+}
+
+impl<T: Sink<SinkError = io::Error>> Goodbye<T> {
+    /// Returns a future writing `frame` to `transport` before closing it
+    pub fn new(transport: T, frame: T::SinkItem) -> Goodbye<T> {
+        Goodbye {
+            transport: Some(transport),
+            frame: Some(frame),
+        }
+    }
+}
+
+impl<T: Sink<SinkError = io::Error>> Future for Goodbye<T> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        {
+            let transport = self.transport.as_mut().expect("polled after completion");
+
+            if let Some(frame) = self.frame.take() {
+                if let AsyncSink::NotReady(frame) = try!(transport.start_send(frame)) {
+                    self.frame = Some(frame);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            try_ready!(transport.poll_complete());
+        }
+
+        trace!("goodbye frame flushed; closing connection");
+        self.transport = None;
+        Ok(Async::Ready(()))
+    }
+}
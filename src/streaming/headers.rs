@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io;
+use std::ops;
+
+use tokio_core::io::{Codec, EasyBuf};
+
+/// The default maximum size of the header section of a decoded message
+const DEFAULT_MAX_HEADERS: usize = 64 * 1024;
+
+/// A map of named headers, carried alongside the payload of a message
+///
+/// Headers hold metadata that is not part of the application's request and
+/// response types, such as authentication tokens or tracing ids, so that
+/// middleware can read and set them on any message. Values are arbitrary
+/// bytes; names are case sensitive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    map: BTreeMap<String, Vec<u8>>,
+}
+
+/// A message payload along with its headers
+///
+/// Protocols carrying headers use it as their request and response types, or
+/// as the head of a streaming `Message`, see `Message::headers`. The headers
+/// are encoded ahead of the payload by `HeaderCodec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithHeaders<T> {
+    headers: Headers,
+    payload: T,
+}
+
+/// A `Codec` encoding the headers of a message ahead of its payload, which is
+/// encoded by the wrapped codec
+///
+/// The header section starts with the number of headers, on 2 bytes, and
+/// every header with the length of its name, on 2 bytes, and the length of
+/// its value, on 4 bytes, all of them big-endian.
+pub struct HeaderCodec<C> {
+    inner: C,
+    max_headers: usize,
+    // Headers decoded ahead of a payload not completely received yet
+    pending: Option<Headers>,
+}
+
+impl Headers {
+    /// Returns an empty map
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Set the header `name`, returning its previous value if any
+    pub fn insert<N, V>(&mut self, name: N, value: V) -> Option<Vec<u8>>
+        where N: Into<String>,
+              V: Into<Vec<u8>>,
+    {
+        self.map.insert(name.into(), value.into())
+    }
+
+    /// Returns the value of the header `name`
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.map.get(name).map(|v| &v[..])
+    }
+
+    /// Remove the header `name`, returning its value
+    pub fn remove(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.map.remove(name)
+    }
+
+    /// Returns the number of headers
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if there are no headers
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Move the headers of `other` into this map, overriding the values of
+    /// the headers set in both
+    pub fn extend(&mut self, other: Headers) {
+        self.map.extend(other.map);
+    }
+
+    /// Returns an iterator over the headers, ordered by name
+    pub fn iter<'a>(&'a self) -> HeaderIter<'a> {
+        HeaderIter { inner: self.map.iter() }
+    }
+}
+
+/// Iterator over the headers of a `Headers` map
+pub struct HeaderIter<'a> {
+    inner: btree_map::Iter<'a, String, Vec<u8>>,
+}
+
+impl<'a> Iterator for HeaderIter<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a str, &'a [u8])> {
+        self.inner.next().map(|(name, value)| (&name[..], &value[..]))
+    }
+}
+
+impl<T> WithHeaders<T> {
+    /// Returns `payload` with no headers
+    pub fn new(payload: T) -> WithHeaders<T> {
+        WithHeaders::from_parts(Headers::new(), payload)
+    }
+
+    /// Returns `payload` along with the given headers
+    pub fn from_parts(headers: Headers, payload: T) -> WithHeaders<T> {
+        WithHeaders {
+            headers: headers,
+            payload: payload,
+        }
+    }
+
+    /// Returns the headers
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Returns the headers, mutably
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Consumes the value, returning the headers and the payload
+    pub fn into_parts(self) -> (Headers, T) {
+        (self.headers, self.payload)
+    }
+
+    /// Consumes the value, returning the payload
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+}
+
+impl<T> ops::Deref for WithHeaders<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.payload
+    }
+}
+
+impl<T> ops::DerefMut for WithHeaders<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.payload
+    }
+}
+
+impl<C: Codec> HeaderCodec<C> {
+    /// Wrap `inner`, which encodes the payload of messages
+    pub fn new(inner: C) -> HeaderCodec<C> {
+        HeaderCodec {
+            inner: inner,
+            max_headers: DEFAULT_MAX_HEADERS,
+            pending: None,
+        }
+    }
+
+    /// Set the maximum size of the header section of a received message. A
+    /// larger section is an error. Defaults to 64KB.
+    pub fn max_headers(&mut self, size: usize) {
+        self.max_headers = size;
+    }
+
+    /// Returns a reference to the wrapped codec
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    // Decode the header section, if completely received
+    fn decode_headers(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Headers>> {
+        let len = {
+            let mut rd = Reader { buf: buf.as_slice(), pos: 0 };
+
+            let count = match rd.read(2) {
+                Some(count) => count,
+                None => return Ok(None),
+            };
+
+            for _ in 0..count {
+                let name = match rd.read(2) {
+                    Some(len) => len,
+                    None => return Ok(None),
+                };
+                let value = match rd.read(4) {
+                    Some(len) => len,
+                    None => return Ok(None),
+                };
+
+                // Checked before the header is buffered
+                if rd.pos + name + value > self.max_headers {
+                    return Err(invalid_data("header section larger than the max size"));
+                }
+
+                if !rd.skip(name + value) {
+                    return Ok(None);
+                }
+            }
+
+            rd.pos
+        };
+
+        // The section is complete, and its lengths were checked above
+        let section = buf.drain_to(len);
+        let mut rd = Reader { buf: section.as_slice(), pos: 0 };
+        let mut headers = Headers::new();
+
+        for _ in 0..rd.read(2).unwrap() {
+            let name_len = rd.read(2).unwrap();
+            let value_len = rd.read(4).unwrap();
+
+            let name = match String::from_utf8(rd.bytes(name_len).to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(invalid_data("header name is not valid UTF-8")),
+            };
+            let value = rd.bytes(value_len).to_vec();
+
+            headers.insert(name, value);
+        }
+
+        Ok(Some(headers))
+    }
+}
+
+impl<C: Codec> Codec for HeaderCodec<C> {
+    type In = WithHeaders<C::In>;
+    type Out = WithHeaders<C::Out>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<WithHeaders<C::In>>> {
+        if self.pending.is_none() {
+            self.pending = try!(self.decode_headers(buf));
+
+            if self.pending.is_none() {
+                return Ok(None);
+            }
+        }
+
+        match try!(self.inner.decode(buf)) {
+            Some(payload) => {
+                let headers = self.pending.take().unwrap();
+                Ok(Some(WithHeaders::from_parts(headers, payload)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: WithHeaders<C::Out>, buf: &mut Vec<u8>) -> io::Result<()> {
+        let (headers, payload) = msg.into_parts();
+
+        if headers.len() > u16::max_value() as usize {
+            return Err(invalid_input("too many headers"));
+        }
+
+        write_be(buf, headers.len(), 2);
+
+        for (name, value) in headers.iter() {
+            if name.len() > u16::max_value() as usize {
+                return Err(invalid_input("header name too long"));
+            }
+
+            if value.len() > u32::max_value() as usize {
+                return Err(invalid_input("header value too long"));
+            }
+
+            write_be(buf, name.len(), 2);
+            write_be(buf, value.len(), 4);
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(value);
+        }
+
+        self.inner.encode(payload, buf)
+    }
+}
+
+/// Reads big-endian lengths and bytes from a header section
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, n: usize) -> Option<usize> {
+        if self.buf.len() - self.pos < n {
+            return None;
+        }
+
+        let v = self.buf[self.pos..self.pos + n].iter()
+            .fold(0, |v, &b| v << 8 | b as usize);
+
+        self.pos += n;
+        Some(v)
+    }
+
+    fn skip(&mut self, n: usize) -> bool {
+        if self.buf.len() - self.pos < n {
+            return false;
+        }
+
+        self.pos += n;
+        true
+    }
+
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        bytes
+    }
+}
+
+fn write_be(buf: &mut Vec<u8>, v: usize, n: usize) {
+    for i in (0..n).rev() {
+        buf.push((v >> (i * 8)) as u8);
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use streaming::Message;
+    use test_support::Lines;
+    use super::{HeaderCodec, WithHeaders};
+
+    #[test]
+    fn test_headers_encoded_ahead_of_payload() {
+        let mut codec = HeaderCodec::new(Lines);
+
+        let mut msg = WithHeaders::new(b"hello".to_vec());
+        msg.headers_mut().insert("trace", "42");
+        msg.headers_mut().insert("auth", "secret");
+
+        let mut buf = vec![];
+        codec.encode(msg.clone(), &mut buf).unwrap();
+        assert_eq!(&b"\0\x02\0\x04\0\0\0\x06authsecret\0\x05\0\0\0\x02trace42hello\n"[..],
+                   &buf[..]);
+
+        // Decoded as it arrives, the payload being received after headers
+        let mut rd = EasyBuf::new();
+        for (i, &b) in buf.iter().enumerate() {
+            rd.get_mut().push(b);
+            let decoded = codec.decode(&mut rd).unwrap();
+
+            if i + 1 < buf.len() {
+                assert_eq!(None, decoded);
+            } else {
+                assert_eq!(Some(msg.clone()), decoded);
+            }
+        }
+
+        // Messages without headers only carry the header count
+        let mut buf = vec![];
+        codec.encode(WithHeaders::new(b"bye".to_vec()), &mut buf).unwrap();
+        assert_eq!(&b"\0\0bye\n"[..], &buf[..]);
+
+        let message: Message<_, ()> = Message::WithoutBody(msg);
+        assert_eq!(Some(&b"42"[..]), message.headers().get("trace"));
+        assert_eq!(&b"hello"[..], &message.get_ref()[..]);
+    }
+
+    #[test]
+    fn test_max_headers() {
+        let mut codec = HeaderCodec::new(Lines);
+
+        let mut msg = WithHeaders::new(vec![]);
+        msg.headers_mut().insert("trace", "42");
+
+        let mut buf = vec![];
+        codec.encode(msg, &mut buf).unwrap();
+
+        // The section is rejected as soon as the lengths exceed the limit
+        buf.truncate(8);
+        codec.max_headers(8);
+        let err = codec.decode(&mut EasyBuf::from(buf)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -1,4 +1,7 @@
 use std::{cmp, fmt, ops};
+use std::time::Instant;
+
+use super::{Headers, WithHeaders, WithDeadline};
 
 /// Message sent and received from a multiplexed service
 pub enum Message<T, B> {
@@ -56,6 +59,25 @@ impl<T, B> Message<T, B> {
     }
 }
 
+impl<T, B> Message<WithHeaders<T>, B> {
+    /// Returns the headers carried by the message
+    pub fn headers(&self) -> &Headers {
+        self.get_ref().headers()
+    }
+
+    /// Returns the headers carried by the message, mutably
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        self.get_mut().headers_mut()
+    }
+}
+
+impl<T, B> Message<WithDeadline<T>, B> {
+    /// Returns the deadline carried by the message, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.get_ref().deadline()
+    }
+}
+
 impl<T, B> cmp::PartialEq<T> for Message<T, B>
     where T: cmp::PartialEq
 {
@@ -9,7 +9,111 @@ pub mod pipeline;
 pub mod multiplex;
 
 mod body;
-pub use self::body::Body;
+pub use self::body::{Body, sink_to_writer, SinkToWriter};
 
 mod message;
 pub use self::message::Message;
+
+mod headers;
+pub use self::headers::{Headers, HeaderIter, WithHeaders, HeaderCodec};
+
+mod deadline;
+pub use self::deadline::{WithDeadline, DeadlineCodec};
+
+mod budget;
+pub use self::budget::MemoryBudget;
+
+mod flush;
+pub use self::flush::FlushPolicy;
+
+mod goodbye;
+pub use self::goodbye::Goodbye;
+
+use std::io;
+use std::time::Instant;
+use futures::{Future, Async};
+use futures::task;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Returns true if the given request deadline has already passed.
+fn deadline_expired(deadline: Option<Instant>) -> bool {
+    match deadline {
+        Some(deadline) => deadline <= Instant::now(),
+        None => false,
+    }
+}
+
+/// Returns the earliest of the two instants, if any.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// The error used to complete requests that were dropped because their
+/// deadline passed before they could be processed.
+fn deadline_error() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request deadline expired")
+}
+
+/// Wakes up a dispatcher task at the instant requested by the `poll_timeout`
+/// of its transport or dispatch.
+///
+/// Without a reactor handle, no timer can be created and requested instants
+/// are ignored.
+struct TickTimer {
+    handle: Option<Handle>,
+    timeout: Option<(Instant, Timeout)>,
+}
+
+impl TickTimer {
+    fn new(handle: Option<Handle>) -> TickTimer {
+        TickTimer {
+            handle: handle,
+            timeout: None,
+        }
+    }
+
+    /// Arm the timer for the given instant, replacing any previous one.
+    ///
+    /// Must be called from within the dispatcher task, which is notified once
+    /// the instant is reached.
+    fn poll(&mut self, at: Option<Instant>) -> io::Result<()> {
+        let at = match at {
+            Some(at) => at,
+            None => {
+                self.timeout = None;
+                return Ok(());
+            }
+        };
+
+        let handle = match self.handle {
+            Some(ref handle) => handle,
+            None => return Ok(()),
+        };
+
+        let rearm = match self.timeout {
+            Some((prev, _)) => prev != at,
+            None => true,
+        };
+
+        if rearm {
+            self.timeout = Some((at, try!(Timeout::new_at(at, handle))));
+        }
+
+        let fired = match self.timeout {
+            Some((_, ref mut timeout)) => try!(timeout.poll()) == Async::Ready(()),
+            None => false,
+        };
+
+        if fired {
+            // Tick again right away
+            self.timeout = None;
+            task::park().unpark();
+        }
+
+        Ok(())
+    }
+}
@@ -4,16 +4,42 @@
 //! Server}` instead. But for some advanced protocols in which the client and
 //! servers have more of a peer relationship, it's useful to work directly with
 //! these implementation details.
+//!
+//! The dispatchers used by `BindServer` and `BindClient`, `ServerDispatch` and
+//! `ClientDispatch`, are exposed here as well. They hold the logic matching
+//! responses to requests, and can be driven without going through the
+//! `Bind*` traits, e.g. to run a protocol on a custom event loop or over an
+//! exotic I/O object:
+//!
+//! ```ignore
+//! let transport = proto.bind_transport(io)?;
+//! let dispatch: ServerDispatch<_, MyIo, _> = ServerDispatch::new(&proto, transport, service);
+//!
+//! // A future completing once the connection is closed. `Multiplex::with_handle`
+//! // additionally ticks the transport when it asks for it, see
+//! // `Transport::poll_timeout`.
+//! let connection = Multiplex::new(dispatch);
+//! ```
+//!
+//! The dispatcher future can be run on any executor and does not require a
+//! reactor on its own; only the transport might.
 
-use streaming::{Message, Body};
+use streaming::{self, Message, Body, MemoryBudget, FlushPolicy, TickTimer};
+use streaming::flush::FlushBatch;
 use futures::sync::mpsc;
 use futures::{Future, Poll, Async, Stream, Sink, AsyncSink, StartSend};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::time::Instant;
+use std::mem;
 use super::frame_buf::{FrameBuf, FrameDeque};
-use super::{Frame, RequestId, Transport};
+use super::{Frame, RequestId, Transport, ControlHandler, ControlFrames};
 use buffer_one::BufferOne;
+use tokio_core::reactor::Handle;
+
+pub use super::server::ServerDispatch;
+pub use super::client::ClientDispatch;
 
 /*
  * TODO:
@@ -44,6 +70,10 @@ pub struct Multiplex<T> where T: Dispatch {
     // True as long as the connection has more request frames to read.
     run: bool,
 
+    // True once the dispatch has no more messages to write, e.g. when the
+    // client is closed.
+    dispatch_done: bool,
+
     // Used to track if any operations make progress
     made_progress: bool,
 
@@ -56,9 +86,22 @@ pub struct Multiplex<T> where T: Dispatch {
     // Glues the service with the pipeline task
     dispatch: BufferOne<DispatchSink<T>>,
 
+    // Ticks the transport when it asks for it
+    timer: TickTimer,
+
     // Tracks in-progress exchanges
     exchanges: HashMap<RequestId, Exchange<T>>,
 
+    // No more frames are read once this many exchanges are in progress
+    max_exchanges: usize,
+
+    // Bounds the memory used by the connection
+    budget: Option<MemoryBudget>,
+
+    // True when frames are not read because of `max_exchanges` or the
+    // memory budget
+    reads_paused: bool,
+
     // True when the transport is fully flushed
     is_flushed: bool,
 
@@ -68,12 +111,33 @@ pub struct Multiplex<T> where T: Dispatch {
     // Storage for buffered frames
     frame_buf: FrameBuf<Option<Result<T::BodyOut, T::Error>>>,
 
+    // Handles the control frames read, if any
+    control: Option<Box<dyn ControlHandler>>,
+
+    // Control frames waiting to be written
+    control_out: ControlFrames,
+
     // Temporary storage for RequestIds...
     scratch: Vec<RequestId>,
 }
 
+/// Bounds on the number of exchanges a `Multiplex` tracks at once
+///
+/// An exchange is tracked from the moment its first frame is read or written
+/// until both its request and response are complete, bodies included. Once
+/// the maximum is reached, no more frames are read from the transport until
+/// an exchange completes, so a peer opening requests without ever letting
+/// them complete cannot make the table grow without bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExchangeLimits {
+    initial: usize,
+    max: usize,
+}
+
 struct DispatchSink<T> {
     inner: T,
+    // Frames written since the transport was last flushed
+    batch: FlushBatch,
 }
 
 type BodySender<B, E> = mpsc::Sender<Result<B, E>>;
@@ -177,6 +241,67 @@ pub trait Dispatch {
 
     /// Cancel interest in the exchange identified by RequestId
     fn cancel(&mut self, request_id: RequestId) -> io::Result<()>;
+
+    /// Called once the exchange identified by `request_id` is tracked by the
+    /// `Multiplex`, until `exchange_closed` is called for it. A message
+    /// tagged with its request ID must not be yielded by `poll` meanwhile,
+    /// e.g. when request IDs wrap around.
+    fn exchange_opened(&mut self, _request_id: RequestId) {
+    }
+
+    /// Called once the exchange identified by `request_id` is complete,
+    /// bodies included, and no longer tracked by the `Multiplex`
+    fn exchange_closed(&mut self, _request_id: RequestId) {
+    }
+
+    /// Returns the request ID of an exchange the `Dispatch` gave up on, e.g.
+    /// a request canceled long ago which the peer never answered, for the
+    /// `Multiplex` to stop tracking it. Polled on every tick until it
+    /// returns `None`, which it does by default.
+    fn poll_abandoned(&mut self) -> Option<RequestId> {
+        None
+    }
+
+    /// Returns the instant at which the dispatcher wants to be polled again,
+    /// e.g. to expire in-flight requests whose deadline passes. It is
+    /// combined with the transport's `poll_timeout`; by default, the
+    /// dispatcher is only polled when there is I/O or dispatch work to do.
+    fn poll_timeout(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/*
+ *
+ * ===== impl ExchangeLimits =====
+ *
+ */
+
+impl ExchangeLimits {
+    /// Returns the default limits: the table starts empty and is unbounded
+    pub fn new() -> ExchangeLimits {
+        ExchangeLimits {
+            initial: 0,
+            max: usize::max_value(),
+        }
+    }
+
+    /// Set the number of exchanges room is reserved for up front
+    pub fn initial(&mut self, exchanges: usize) {
+        self.initial = exchanges;
+    }
+
+    /// Set the maximum number of exchanges in progress at once
+    pub fn max(&mut self, exchanges: usize) {
+        assert!(exchanges > 0);
+        self.max = exchanges;
+    }
+}
+
+impl Default for ExchangeLimits {
+    fn default() -> ExchangeLimits {
+        ExchangeLimits::new()
+    }
 }
 
 /*
@@ -189,8 +314,21 @@ impl<T> Multiplex<T> where T: Dispatch {
     /// Create a new pipeline `Multiplex` dispatcher with the given service and
     /// transport
     pub fn new(dispatch: T) -> Multiplex<T> {
+        Multiplex::build(dispatch, None)
+    }
+
+    /// Create a new `Multiplex` dispatcher which uses the given reactor handle to
+    /// tick the transport at the instants requested by its `poll_timeout`
+    pub fn with_handle(dispatch: T, handle: &Handle) -> Multiplex<T> {
+        Multiplex::build(dispatch, Some(handle.clone()))
+    }
+
+    fn build(dispatch: T, handle: Option<Handle>) -> Multiplex<T> {
         // Add `Sink` impl for `Dispatch`
-        let dispatch = DispatchSink { inner: dispatch };
+        let dispatch = DispatchSink {
+            inner: dispatch,
+            batch: FlushBatch::new(),
+        };
 
         // Add a single slot buffer for the sink
         let dispatch = BufferOne::new(dispatch);
@@ -199,21 +337,80 @@ impl<T> Multiplex<T> where T: Dispatch {
 
         Multiplex {
             run: true,
+            dispatch_done: false,
             made_progress: false,
             blocked_on_dispatch: false,
             blocked_on_flush: WriteState::NoWrite,
             dispatch: dispatch,
+            timer: TickTimer::new(handle),
             exchanges: HashMap::new(),
+            max_exchanges: usize::max_value(),
+            budget: None,
+            reads_paused: false,
             is_flushed: true,
             dispatch_deque: VecDeque::new(),
             frame_buf: frame_buf,
+            control: None,
+            control_out: ControlFrames::new(),
             scratch: vec![],
         }
     }
 
+    /// Set the bounds on the number of exchanges in progress at once
+    pub fn exchange_limits(&mut self, limits: ExchangeLimits) {
+        let additional = limits.initial.saturating_sub(self.exchanges.len());
+        self.exchanges.reserve(additional);
+        self.max_exchanges = limits.max;
+    }
+
+    /// Set the memory budget of the connection
+    pub fn memory_budget(&mut self, budget: MemoryBudget) {
+        self.budget = Some(budget);
+    }
+
+    /// Set when the frames written to the transport are flushed, see
+    /// `FlushPolicy`
+    pub fn flush_policy(&mut self, policy: FlushPolicy) {
+        self.dispatch.get_mut().batch.set_policy(policy);
+    }
+
+    /// Set the handler of the control frames read from the transport.
+    /// Without one, control frames are ignored.
+    pub fn control_handler(&mut self, handler: Box<dyn ControlHandler>) {
+        self.control = Some(handler);
+    }
+
+    /// Returns the approximate number of bytes used by the connection
+    fn memory_used(&mut self) -> usize {
+        let exchange = mem::size_of::<RequestId>() + mem::size_of::<Exchange<T>>();
+
+        self.dispatch.get_mut().inner.transport().memory_used() +
+            self.exchanges.capacity() * exchange +
+            self.dispatch_deque.capacity() * mem::size_of::<RequestId>() +
+            self.frame_buf.memory_used()
+    }
+
+    /// Returns true if more frames may be read
+    fn can_read(&mut self) -> io::Result<bool> {
+        // A frame might open a new exchange, so none is read at the limit
+        if self.exchanges.len() >= self.max_exchanges {
+            trace!("   --> max exchanges reached");
+            return Ok(false);
+        }
+
+        match self.budget {
+            Some(budget) => {
+                let used = self.memory_used();
+                budget.check(used)
+            }
+            None => Ok(true),
+        }
+    }
+
     /// Returns true if the multiplexer has nothing left to do
     fn is_done(&self) -> bool {
-        !self.run && self.is_flushed && self.exchanges.len() == 0
+        (!self.run || self.dispatch_done) && self.is_flushed && self.exchanges.len() == 0 &&
+            self.control_out.is_empty()
     }
 
     /// Attempt to dispatch any outbound request messages
@@ -252,9 +449,15 @@ impl<T> Multiplex<T> where T: Dispatch {
         trace!("flush out bodies");
 
         self.scratch.clear();
+        let mut aborted = vec![];
 
         for (id, exchange) in self.exchanges.iter_mut() {
             trace!("   --> request={}", id);
+
+            if exchange.drop_closed_out_body() {
+                aborted.push(*id);
+            }
+
             try!(exchange.flush_out_body());
 
             // If the exchange is complete, track it for removal
@@ -264,17 +467,50 @@ impl<T> Multiplex<T> where T: Dispatch {
         }
 
         // Purge the scratch
-        for id in &self.scratch {
+        for i in 0..self.scratch.len() {
+            let id = self.scratch[i];
             trace!("drop exchange; id={}", id);
-            self.exchanges.remove(id);
+            self.remove_exchange(id);
+        }
+
+        for id in aborted {
+            try!(self.abort_out_body(id));
         }
 
         Ok(())
     }
 
+    /// Stop tracking the exchange identified by `id`, letting the dispatcher
+    /// know
+    fn remove_exchange(&mut self, id: RequestId) {
+        if self.exchanges.remove(&id).is_some() {
+            self.dispatch.get_mut().inner.exchange_closed(id);
+        }
+    }
+
+    fn drop_abandoned_exchanges(&mut self) {
+        while let Some(id) = self.dispatch.get_mut().inner.poll_abandoned() {
+            trace!("dropping abandoned exchange; request-id={:?}", id);
+            self.remove_exchange(id);
+        }
+    }
+
+    /// Let the transport know that the body read for `id` is not wanted
+    /// anymore
+    fn abort_out_body(&mut self, id: RequestId) -> io::Result<()> {
+        debug!("out body dropped before it ended; request-id={:?}", id);
+        self.dispatch.get_mut().inner.transport().abort_body(id)
+    }
+
     /// Read and process frames from transport
     fn read_out_frames(&mut self) -> io::Result<()> {
         while self.run {
+            if !try!(self.can_read()) {
+                trace!("   --> not reading frames");
+                self.reads_paused = true;
+                break;
+            }
+
             // TODO: Only read frames if there is available space in the frame
             // buffer
             if let Async::Ready(frame) = try!(self.dispatch.get_mut().inner.transport().poll()) {
@@ -308,15 +544,56 @@ impl<T> Multiplex<T> where T: Dispatch {
             }
             Some(Frame::Body { id, chunk }) => {
                 trace!("   --> read out body chunk");
-                self.process_out_body_chunk(id, Ok(chunk));
+                try!(self.process_out_body_chunk(id, Ok(chunk)));
             }
             Some(Frame::Error { id, error }) => {
                 try!(self.process_out_err(id, error));
             }
+            Some(Frame::Metadata { id, .. }) => {
+                // Only `MetadataTransport` knows which message they belong to
+                debug!("unexpected metadata frame; request-id={:?}", id);
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "metadata frame not merged by a MetadataTransport"));
+            }
+            Some(Frame::Control { id, control }) => {
+                match self.control {
+                    Some(ref mut handler) => {
+                        trace!("   --> read control frame; request-id={:?}", id);
+                        try!(handler.control(id, control, &mut self.control_out));
+                    }
+                    None => debug!("ignoring control frame; request-id={:?}", id),
+                }
+            }
             None => {
                 trace!("read Frame::Done");
-                // TODO: Ensure all bodies have been completed
                 self.run = false;
+
+                try!(self.fail_waiting_exchanges());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail the exchanges still waiting on the peer once the transport
+    /// ended, rather than letting them wait forever: requests which were not
+    /// responded to, and bodies which did not end.
+    fn fail_waiting_exchanges(&mut self) -> io::Result<()> {
+        let waiting = self.exchanges.iter()
+            .filter(|&(_, exchange)| {
+                exchange.out_body.is_some() || (exchange.is_inbound() && !exchange.responded)
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in waiting {
+            trace!("   --> connection closed; failing request-id={:?}", id);
+            let err = io::Error::new(io::ErrorKind::BrokenPipe, "connection closed");
+            try!(self.process_out_err(id, err.into()));
+
+            // An error does not end a body, which may still be receiving
+            if self.exchanges.contains_key(&id) {
+                try!(self.process_out_body_chunk(id, Ok(None)));
             }
         }
 
@@ -357,6 +634,7 @@ impl<T> Multiplex<T> where T: Dispatch {
                 // If the exchange is complete, clean up resources
                 if e.get().is_complete() {
                     e.remove();
+                    self.dispatch.get_mut().inner.exchange_closed(id);
                 }
             }
             Entry::Vacant(e) => {
@@ -379,6 +657,7 @@ impl<T> Multiplex<T> where T: Dispatch {
                     if !exchange.is_complete() {
                         // Track the exchange
                         e.insert(exchange);
+                        self.dispatch.get_mut().inner.exchange_opened(id);
                     }
 
                     // Dispatch the message
@@ -406,6 +685,7 @@ impl<T> Multiplex<T> where T: Dispatch {
 
                     // Track the exchange state
                     e.insert(exchange);
+                    self.dispatch.get_mut().inner.exchange_opened(id);
 
                     // Track the request ID as pending dispatch
                     self.dispatch_deque.push_back(id);
@@ -462,41 +742,72 @@ impl<T> Multiplex<T> where T: Dispatch {
         }
 
         if remove {
-            self.exchanges.remove(&id);
+            self.remove_exchange(id);
         }
 
         Ok(())
     }
 
-    fn process_out_body_chunk(&mut self, id: RequestId, chunk: Result<Option<T::BodyOut>, T::Error>) {
+    fn process_out_body_chunk(&mut self, id: RequestId, chunk: Result<Option<T::BodyOut>, T::Error>)
+                              -> io::Result<()>
+    {
         trace!("process out body chunk; id={:?}", id);
 
-        {
+        let aborted = {
             let exchange = match self.exchanges.get_mut(&id) {
                 Some(v) => v,
                 _ => {
                     trace!("   --> exchange previously aborted; id={:?}", id);
-                    return;
+                    return Ok(());
                 }
             };
 
+            // The chunk is dropped along with the sender, if the body was
+            // dropped during this tick
+            let aborted = exchange.drop_closed_out_body();
             exchange.send_out_chunk(chunk);
 
             if !exchange.is_complete() {
-                return;
+                if aborted {
+                    try!(self.abort_out_body(id));
+                }
+                return Ok(());
             }
-        }
+
+            aborted
+        };
 
         trace!("dropping out body handle; id={:?}", id);
-        self.exchanges.remove(&id);
+        self.remove_exchange(id);
+
+        if aborted {
+            try!(self.abort_out_body(id));
+        }
+
+        Ok(())
     }
 
     fn write_in_frames(&mut self) -> io::Result<()> {
+        try!(self.write_control_frames());
         try!(self.write_in_messages());
         try!(self.write_in_body());
         Ok(())
     }
 
+    /// Write the control frames sent by the control handler, ahead of any
+    /// other frame
+    fn write_control_frames(&mut self) -> io::Result<()> {
+        while !self.control_out.is_empty() && self.dispatch.poll_ready().is_ready() {
+            let (id, control) = self.control_out.next().unwrap();
+            trace!("   --> writing control frame; request-id={:?}", id);
+
+            try!(assert_send(&mut self.dispatch, Frame::Control { id: id, control: control }));
+            self.blocked_on_flush.wrote_frame();
+        }
+
+        Ok(())
+    }
+
     fn write_in_messages(&mut self) -> io::Result<()> {
         trace!("write in messages");
 
@@ -519,16 +830,11 @@ impl<T> Multiplex<T> where T: Dispatch {
                     }
                 }
                 Async::Ready(None) => {
-                    trace!("   --> got error");
                     trace!("   --> got None");
-                    // The service is done with the connection. In this case, a
-                    // `Done` frame should be written to the transport and the
-                    // transport should start shutting down.
-                    //
-                    // However, the `Done` frame should only be written once
-                    // all the in-flight bodies have been written.
-                    //
-                    // For now, do nothing...
+                    // The service is done with the connection. It is closed
+                    // once all the in-flight exchanges are complete, which
+                    // includes writing their bodies.
+                    self.dispatch_done = true;
                     break;
                 }
                 // Nothing to dispatch
@@ -580,6 +886,7 @@ impl<T> Multiplex<T> where T: Dispatch {
                 // If the exchange is complete, clean up the resources
                 if e.get().is_complete() {
                     e.remove();
+                    self.dispatch.get_mut().inner.exchange_closed(id);
                 }
             }
             Entry::Vacant(e) => {
@@ -595,6 +902,7 @@ impl<T> Multiplex<T> where T: Dispatch {
                 if !exchange.is_complete() {
                     // Track the exchange
                     e.insert(exchange);
+                    self.dispatch.get_mut().inner.exchange_opened(id);
                 }
             }
         }
@@ -625,6 +933,7 @@ impl<T> Multiplex<T> where T: Dispatch {
             self.blocked_on_flush.wrote_frame();
 
             e.remove();
+            self.dispatch.get_mut().inner.exchange_closed(id);
         } else {
             trace!("exchange does not exist; id={:?}", id);
         }
@@ -696,16 +1005,23 @@ impl<T> Multiplex<T> where T: Dispatch {
             }
         }
 
-        for id in &self.scratch {
+        for i in 0..self.scratch.len() {
+            let id = self.scratch[i];
             trace!("dropping in body handle; id={:?}", id);
-            self.exchanges.remove(id);
+            self.remove_exchange(id);
         }
 
         Ok(())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.is_flushed = try!(self.dispatch.poll_complete()).is_ready();
+        // Nothing is held back once the connection winds down
+        if !self.run || self.dispatch_done {
+            self.dispatch.get_mut().batch.flush_now();
+        }
+
+        self.is_flushed = try!(self.dispatch.poll_complete()).is_ready() &&
+            !self.dispatch.get_ref().batch.is_pending();
 
         // TODO: Technically, poll_complete needs to be called on the exchange body senders.
         // However, mpsc::Sender doesn't actually need to have poll_complete called as it is
@@ -716,12 +1032,19 @@ impl<T> Multiplex<T> where T: Dispatch {
             self.made_progress = true;
         }
 
+        // Exchanges completed or memory was released since reading stopped,
+        // keep reading
+        if self.reads_paused && try!(self.can_read()) {
+            self.made_progress = true;
+        }
+
         Ok(())
     }
 
     fn reset_flags(&mut self) {
         self.made_progress = false;
         self.blocked_on_dispatch = false;
+        self.reads_paused = false;
         self.blocked_on_flush = WriteState::NoWrite;
     }
 
@@ -745,6 +1068,10 @@ impl<T> Future for Multiplex<T>
         // Always tick the transport first
         self.dispatch.get_mut().inner.transport().tick();
 
+        if let Some(ref mut handler) = self.control {
+            try!(handler.tick(&mut self.control_out));
+        }
+
         // Try to send any buffered body chunks on their senders
         //
         // This has to happen at the start of the tick. The sender readiness is computed for later
@@ -770,6 +1097,9 @@ impl<T> Future for Multiplex<T>
             // Handle completed responses
             try!(self.write_in_frames());
 
+            // Forget the exchanges the dispatch gave up on
+            self.drop_abandoned_exchanges();
+
             // Try flushing buffered writes
             try!(self.flush());
         }
@@ -792,6 +1122,16 @@ impl<T> Future for Multiplex<T>
             return Ok(Async::Ready(()));
         }
 
+        // Make sure the transport is ticked when it asked for it
+        let at = {
+            let sink = self.dispatch.get_mut();
+            let at = sink.inner.transport().poll_timeout();
+            let at = streaming::earliest(at, sink.inner.poll_timeout());
+            let at = streaming::earliest(at, self.control.as_ref().and_then(|c| c.poll_timeout()));
+            streaming::earliest(at, sink.batch.poll_timeout())
+        };
+        try!(self.timer.poll(at));
+
         trace!("tick done; waiting for wake-up");
 
         // Tick again later
@@ -922,6 +1262,23 @@ impl<T: Dispatch> Exchange<T> {
         self.out_body = None;
     }
 
+    /// Drop the outbound body sender if the body stream it feeds was
+    /// dropped, along with the chunks buffered for it. Returns true if so.
+    fn drop_closed_out_body(&mut self) -> bool {
+        let closed = match self.out_body {
+            Some(ref sender) => sender.is_closed(),
+            None => false,
+        };
+
+        if closed {
+            self.out_deque.clear();
+            self.out_is_ready = false;
+            self.out_body = None;
+        }
+
+        closed
+    }
+
     fn try_poll_in_body(&mut self) -> Poll<Option<T::BodyIn>, T::Error> {
         match self.in_body {
             Some(ref mut b) => b.poll(),
@@ -975,9 +1332,9 @@ impl<T: Dispatch> Exchange<T> {
                         // stream. In this case, the sender and the frame
                         // buffer is dropped. If future body frames are
                         // received, the sender will be gone and the frames
-                        // will be dropped.
-                        //
-                        // TODO: Notify transport
+                        // will be dropped. The transport is notified once
+                        // the sender is found closed, see
+                        // `drop_closed_out_body`.
                         break;
                     }
                 }
@@ -1066,10 +1423,23 @@ impl<T: Dispatch> Sink for DispatchSink<T> {
     fn start_send(&mut self, item: Self::SinkItem)
                   -> StartSend<Self::SinkItem, io::Error>
     {
-        self.inner.transport().start_send(item)
+        let res = try!(self.inner.transport().start_send(item));
+
+        match res {
+            AsyncSink::Ready => self.batch.wrote_frame(),
+            // The transport needs to be flushed to make room
+            AsyncSink::NotReady(_) => self.batch.flush_now(),
+        }
+
+        Ok(res)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if !self.batch.poll_due() {
+            // The frames are held back, the transport can take more
+            return Ok(Async::Ready(()));
+        }
+
         self.inner.transport().poll_complete()
     }
 }
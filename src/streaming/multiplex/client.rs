@@ -1,14 +1,18 @@
-use super::{Frame, RequestId, StreamingMultiplex, Transport};
+use super::{Frame, RequestId, StreamingMultiplex, Transport, ControlHandler};
 use super::advanced::{Multiplex, MultiplexMessage};
 
 use BindClient;
-use streaming::{Body, Message};
+use streaming::{self, Body, Message, FlushPolicy};
 use util::client_proxy::{self, ClientProxy, Receiver};
 use futures::{Future, IntoFuture, Complete, Poll, Async};
 use futures::stream::Stream;
+use futures::task::{self, EventSet, UnparkEvent};
 use tokio_core::reactor::Handle;
 use std::io;
-use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 /// A streaming, multiplexed client protocol.
 ///
@@ -32,6 +36,10 @@ pub trait ClientProto<T: 'static>: 'static {
     type ResponseBody: 'static;
 
     /// Errors, which are used both for error frames and for the service itself.
+    ///
+    /// Body streams fail with this type too. An error yielded by a body being
+    /// written is sent as an error frame, which fails the body on the other
+    /// end, so application errors keep their type across the connection.
     type Error: From<io::Error> + 'static;
 
     /// The frame transport, which usually take `T` as a parameter.
@@ -48,6 +56,107 @@ pub trait ClientProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed by the time the connection
+    /// is ready to write them are dropped, and the response future resolves
+    /// with a `TimedOut` error. By default, requests carry no deadline;
+    /// `streaming::WithDeadline` carries one from the client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
+
+    /// Returns the handler of the control frames of a new connection, if
+    /// any, see `ControlHandler`. By default, control frames are ignored.
+    fn control_handler(&self) -> Option<Box<dyn ControlHandler>> {
+        None
+    }
+
+    /// Returns the range of request IDs requests are tagged with on each
+    /// connection, and what happens once it is exhausted, see `RequestIds`.
+    /// By default, IDs span the whole `u64` range and wrap around.
+    fn request_ids(&self) -> RequestIds {
+        RequestIds::new()
+    }
+}
+
+/// The request IDs a multiplexed client tags its requests with
+///
+/// Requests are tagged with increasing IDs, starting at 0, up to a maximum
+/// which defaults to `u64::max_value()`. Long-lived connections, or protocols
+/// carrying fewer bits of ID on the wire, eventually run past the maximum, at
+/// which point the `RequestIdPolicy` decides what happens next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestIds {
+    max: RequestId,
+    policy: RequestIdPolicy,
+    max_canceled: usize,
+}
+
+/// What a multiplexed client does once it ran out of request IDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestIdPolicy {
+    /// Wrap around to 0, skipping the IDs of requests still in flight, still
+    /// expecting a response after being canceled, or whose bodies are still
+    /// streaming, as well as those of the exchanges the server opened. A
+    /// request finding every ID in use fails with an error. This is the
+    /// default.
+    Skip,
+
+    /// Fail every further request with an error, leaving the requests in
+    /// flight to complete.
+    Error,
+
+    /// Fail the request with a `BrokenPipe` error and close the connection
+    /// once the requests in flight complete, so that the caller reconnects
+    /// and starts over from 0.
+    Reconnect,
+}
+
+impl RequestIds {
+    /// Returns the default: every `u64`, wrapping around with
+    /// `RequestIdPolicy::Skip`
+    pub fn new() -> RequestIds {
+        RequestIds {
+            max: RequestId::max_value(),
+            policy: RequestIdPolicy::Skip,
+            max_canceled: 1024,
+        }
+    }
+
+    /// Set the largest request ID used
+    pub fn max(&mut self, max: RequestId) {
+        self.max = max;
+    }
+
+    /// Set what happens once the largest request ID was used
+    pub fn policy(&mut self, policy: RequestIdPolicy) {
+        self.policy = policy;
+    }
+
+    /// Set how many canceled requests may still expect a response, 1024 by
+    /// default.
+    ///
+    /// Past it, the oldest is forgotten, as the server is unlikely to ever
+    /// answer it: its ID may be reused, and responses matching no request
+    /// are dropped from then on instead of failing the connection.
+    pub fn max_canceled(&mut self, max: usize) {
+        self.max_canceled = max;
+    }
+}
+
+impl Default for RequestIds {
+    fn default() -> RequestIds {
+        RequestIds::new()
+    }
 }
 
 impl<P, T, B> BindClient<StreamingMultiplex<B>, T> for P where
@@ -64,14 +173,21 @@ impl<P, T, B> BindClient<StreamingMultiplex<B>, T> for P where
     fn bind_client(&self, handle: &Handle, io: T) -> Self::BindClient {
         let (client, rx) = client_proxy::pair();
 
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
-            let dispatch: Dispatch<P, T, B> = Dispatch {
-                transport: transport,
-                requests: rx,
-                in_flight: HashMap::new(),
-                next_request_id: 0,
-            };
-            Multiplex::new(dispatch)
+        let inner_handle = handle.clone();
+        let flush = self.flush_policy();
+        let ids = self.request_ids();
+        let control = self.control_handler();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
+            let dispatch: ClientDispatch<P, T, B> = ClientDispatch::from_parts(transport, rx, ids);
+            let mut multiplex = Multiplex::with_handle(dispatch, &inner_handle);
+            if let Some(flush) = flush {
+                multiplex.flush_policy(flush);
+            }
+            if let Some(control) = control {
+                multiplex.control_handler(control);
+            }
+            multiplex
         }).map_err(|e| {
             // TODO: where to punt this error to?
             debug!("multiplex task failed with error; err={:?}", e);
@@ -85,7 +201,32 @@ impl<P, T, B> BindClient<StreamingMultiplex<B>, T> for P where
     }
 }
 
-struct Dispatch<P, T, B> where
+/// Writes the requests sent through a `ClientProxy` to a transport, tagged
+/// with a request ID, and completes them with the matching responses.
+///
+/// Servers push events by sending solo messages, which are not responses to
+/// a request, under a request ID of their choosing. They are yielded by
+/// `ClientProxy::notifications`, or dropped if the client did not ask for
+/// them. Any other message whose request ID matches no request fails the
+/// connection.
+///
+/// When a response future is dropped before the response arrives, the
+/// transport is notified through `Transport::cancel`, e.g. so that it can send
+/// a cancel frame to the server. The response, or an error frame, is still
+/// expected for the request ID, and is discarded. At most
+/// `RequestIds::max_canceled` canceled requests are kept waiting on one.
+///
+/// Requests are tagged with the IDs described by `ClientProto::request_ids`.
+/// Once they wrap around, an ID is never reused while a response, or body
+/// chunks in either direction, are still expected for it, see
+/// `RequestIdPolicy`.
+///
+/// This is the dispatcher `bind_client` spawns for each connection. It is
+/// driven by wrapping it in an `advanced::Multiplex`, which is a future
+/// completing once the connection is closed. Embedders providing their own
+/// event loop or I/O can build and drive it directly instead of going through
+/// `BindClient`.
+pub struct ClientDispatch<P, T, B> where
     P: ClientProto<T> + BindClient<StreamingMultiplex<B>, T>,
     T: 'static,
     B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
@@ -93,10 +234,201 @@ struct Dispatch<P, T, B> where
     transport: P::Transport,
     requests: Receiver<P::ServiceRequest, P::ServiceResponse, P::Error>,
     in_flight: HashMap<RequestId, Complete<Result<P::ServiceResponse, P::Error>>>,
+    // Requests whose response future was dropped before the response
+    // arrived, and the order they were canceled in, which may still list
+    // some that were answered since
+    canceled: HashSet<RequestId>,
+    canceled_order: VecDeque<RequestId>,
+    // Whether canceled requests were forgotten before being answered
+    forgot_canceled: bool,
+    // Exchanges for the multiplexer to stop tracking
+    abandoned: VecDeque<RequestId>,
+    // The requests whose response future notified the task
+    cancel_events: Arc<CancelEvents>,
+    // Exchanges tracked by the multiplexer, whose request ID is not reused
+    // until their bodies are done streaming
+    open: HashSet<RequestId>,
     next_request_id: u64,
+    request_ids: RequestIds,
+    // Whether the largest request ID was used already
+    ids_wrapped: bool,
+    // Whether the connection is closing for lack of request IDs
+    ids_exhausted: bool,
+}
+
+impl<P, T, B> ClientDispatch<P, T, B> where
+    P: ClientProto<T> + BindClient<StreamingMultiplex<B>, T>,
+    T: 'static,
+    B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
+{
+    /// Create a dispatcher for a transport bound by `proto`, returning it
+    /// along with the client sending requests through it.
+    ///
+    /// The protocol is only used for its hooks, e.g. `request_deadline` and
+    /// `request_ids`.
+    pub fn new(proto: &P, transport: P::Transport)
+               -> (ClientProxy<P::ServiceRequest, P::ServiceResponse, P::Error>,
+                   ClientDispatch<P, T, B>)
+    {
+        let (client, rx) = client_proxy::pair();
+        (client, ClientDispatch::from_parts(transport, rx, proto.request_ids()))
+    }
+
+    fn from_parts(transport: P::Transport,
+                  requests: Receiver<P::ServiceRequest, P::ServiceResponse, P::Error>,
+                  request_ids: RequestIds)
+                  -> ClientDispatch<P, T, B>
+    {
+        ClientDispatch {
+            transport: transport,
+            requests: requests,
+            in_flight: HashMap::new(),
+            canceled: HashSet::new(),
+            canceled_order: VecDeque::new(),
+            forgot_canceled: false,
+            abandoned: VecDeque::new(),
+            cancel_events: Arc::new(CancelEvents(Mutex::new(Vec::new()))),
+            open: HashSet::new(),
+            next_request_id: 0,
+            request_ids: request_ids,
+            ids_wrapped: false,
+            ids_exhausted: false,
+        }
+    }
+
+    /// Set the request IDs used from now on, overriding those of the
+    /// protocol
+    pub fn request_ids(&mut self, ids: RequestIds) {
+        self.request_ids = ids;
+    }
+}
+
+impl<P, T, B> ClientDispatch<P, T, B> where
+    P: ClientProto<T>,
+    T: 'static,
+    B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
+{
+    // Forget the in-flight requests whose response future was dropped.
+    // Only the requests whose future notified the task are checked.
+    fn poll_canceled(&mut self) -> io::Result<()> {
+        let events = mem::replace(&mut *self.cancel_events.0.lock().unwrap(), Vec::new());
+
+        for id in events {
+            let id = id as RequestId;
+
+            if !self.is_canceled(id) {
+                continue;
+            }
+
+            trace!("   --> response future dropped; request-id={:?}", id);
+            drop(self.in_flight.remove(&id));
+            self.canceled.insert(id);
+            self.canceled_order.push_back(id);
+            try!(self.transport.cancel(id));
+        }
+
+        self.prune_canceled();
+        Ok(())
+    }
+
+    // Returns true if the response future of the in-flight request `id` was
+    // dropped. Otherwise, the task is notified with an event for `id` once it
+    // is.
+    fn is_canceled(&mut self, id: RequestId) -> bool {
+        let complete = match self.in_flight.get_mut(&id) {
+            Some(complete) => complete,
+            None => return false,
+        };
+
+        let event = UnparkEvent::new(self.cancel_events.clone(), id as usize);
+
+        match task::with_unpark_event(event, || complete.poll_cancel()) {
+            Ok(Async::NotReady) => false,
+            _ => true,
+        }
+    }
+
+    // Forget the oldest canceled requests past the maximum
+    fn prune_canceled(&mut self) {
+        while self.canceled.len() > self.request_ids.max_canceled {
+            let id = match self.canceled_order.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+
+            if self.canceled.remove(&id) {
+                debug!("forgetting canceled request never answered; request-id={:?}", id);
+                self.forgot_canceled = true;
+                self.abandoned.push_back(id);
+            }
+        }
+
+        // Drop the requests answered since they were canceled
+        if self.canceled_order.len() > 2 * self.canceled.len() {
+            let canceled = &self.canceled;
+            self.canceled_order.retain(|id| canceled.contains(id));
+        }
+    }
+
+    // Returns true if a response, or body chunks, may still arrive for `id`
+    fn in_use(&self, id: RequestId) -> bool {
+        self.in_flight.contains_key(&id) || self.canceled.contains(&id) || self.open.contains(&id)
+    }
+
+    // Returns the ID to tag the next request with, or `None` if there is no
+    // ID left to use under the policy
+    fn assign_request_id(&mut self) -> Option<RequestId> {
+        // Past the wrap, at most this many IDs are found in use in a row
+        let mut attempts = self.in_flight.len() + self.canceled.len() + self.open.len() + 1;
+
+        while attempts > 0 {
+            if self.ids_wrapped && self.request_ids.policy != RequestIdPolicy::Skip {
+                return None;
+            }
+
+            let id = self.next_request_id;
+
+            if id > self.request_ids.max {
+                // The maximum was lowered below the IDs used so far
+                self.next_request_id = 0;
+                self.ids_wrapped = true;
+                continue;
+            }
+
+            // Until the first wrap, every ID is fresh
+            let reused = self.ids_wrapped;
+
+            if id == self.request_ids.max {
+                trace!("   --> request-id wrapping around; max={:?}", id);
+                self.next_request_id = 0;
+                self.ids_wrapped = true;
+            } else {
+                self.next_request_id += 1;
+            }
+
+            if !reused || !self.in_use(id) {
+                return Some(id);
+            }
+
+            trace!("   --> skipping request-id in use; request-id={:?}", id);
+            attempts -= 1;
+        }
+
+        None
+    }
 }
 
-impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
+/// The request IDs whose response future notified the dispatch task, e.g.
+/// because it was dropped
+struct CancelEvents(Mutex<Vec<usize>>);
+
+impl EventSet for CancelEvents {
+    fn insert(&self, id: usize) {
+        self.0.lock().unwrap().push(id);
+    }
+}
+
+impl<P, T, B> super::advanced::Dispatch for ClientDispatch<P, T, B> where
     P: ClientProto<T>,
     T: 'static,
     B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
@@ -117,10 +449,20 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
     fn dispatch(&mut self, message: MultiplexMessage<Self::Out, Body<Self::BodyOut, Self::Error>, Self::Error>) -> io::Result<()> {
         let MultiplexMessage { id, message, solo } = message;
 
-        assert!(!solo);
-
-        if let Some(complete) = self.in_flight.remove(&id) {
+        if solo {
+            // The server does not expect a response, so this is not the
+            // response to a request either
+            if !self.requests.notify(message) {
+                trace!("   --> dropping unsolicited message; request-id={:?}", id);
+            }
+        } else if let Some(complete) = self.in_flight.remove(&id) {
             complete.complete(message);
+        } else if self.canceled.remove(&id) {
+            trace!("   --> dropping response to canceled request-id={:?}", id);
+        } else if self.forgot_canceled {
+            debug!("dropping response matching no request, possibly a forgotten canceled one; \
+                    request-id={:?}", id);
+            self.abandoned.push_back(id);
         } else {
             return Err(io::Error::new(io::ErrorKind::Other, "request / response mismatch"));
         }
@@ -130,38 +472,71 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
 
     fn poll(&mut self) -> Poll<Option<MultiplexMessage<Self::In, B, Self::Error>>, io::Error> {
         trace!("Dispatch::poll");
-        // Try to get a new request frame
-        match self.requests.poll() {
-            Ok(Async::Ready(Some(Ok((request, complete))))) => {
-                trace!("   --> received request");
-
-                let request_id = self.next_request_id;
-                self.next_request_id += 1;
+        try!(self.poll_canceled());
 
-                trace!("   --> assigning request-id={:?}", request_id);
-
-                // Track complete handle
-                self.in_flight.insert(request_id, complete);
-
-                Ok(Async::Ready(Some(MultiplexMessage::new(request_id, request))))
+        if self.ids_exhausted {
+            return Ok(Async::Ready(None));
+        }
 
+        loop {
+            // Try to get a new request frame
+            match self.requests.poll() {
+                Ok(Async::Ready(Some(Ok((request, complete))))) => {
+                    trace!("   --> received request");
+
+                    if streaming::deadline_expired(P::request_deadline(request.get_ref())) {
+                        // The caller gave up on the response already, there is
+                        // no point in writing the request.
+                        trace!("   --> request deadline expired; dropping");
+                        complete.complete(Err(streaming::deadline_error().into()));
+                        continue;
+                    }
+
+                    let request_id = match self.assign_request_id() {
+                        Some(id) => id,
+                        None => {
+                            trace!("   --> request-ids exhausted; policy={:?}",
+                                   self.request_ids.policy);
+
+                            if self.request_ids.policy == RequestIdPolicy::Reconnect {
+                                complete.complete(Err(ids_exhausted(io::ErrorKind::BrokenPipe).into()));
+                                self.ids_exhausted = true;
+                                return Ok(Async::Ready(None));
+                            }
+
+                            complete.complete(Err(ids_exhausted(io::ErrorKind::Other).into()));
+                            continue;
+                        }
+                    };
+
+                    trace!("   --> assigning request-id={:?}", request_id);
+
+                    // Track complete handle, checking right away whether the
+                    // response future is gone to get notified once it is
+                    self.in_flight.insert(request_id, complete);
+
+                    if self.is_canceled(request_id) {
+                        self.cancel_events.insert(request_id as usize);
+                    }
+
+                    return Ok(Async::Ready(Some(MultiplexMessage::new(request_id, request))));
+                }
+                Ok(Async::Ready(None)) => {
+                    trace!("   --> client dropped or closed");
+                    return Ok(Async::Ready(None));
+                }
+                Ok(Async::Ready(Some(Err(e)))) => {
+                    trace!("   --> error");
+                    // The client timed out waiting for the connection to
+                    // close, fail it
+                    return Err(e);
+                }
+                Ok(Async::NotReady) => {
+                    trace!("   --> not ready");
+                    return Ok(Async::NotReady);
+                }
+                Err(()) => panic!(),
             }
-            Ok(Async::Ready(None)) => {
-                trace!("   --> client dropped");
-                Ok(Async::Ready(None))
-            }
-            Ok(Async::Ready(Some(Err(e)))) => {
-                trace!("   --> error");
-                // An error on receive can only happen when the other half
-                // disconnected. In this case, the client needs to be
-                // shutdown
-                panic!("unimplemented error handling: {:?}", e);
-            }
-            Ok(Async::NotReady) => {
-                trace!("   --> not ready");
-                Ok(Async::NotReady)
-            }
-            Err(()) => panic!(),
         }
     }
 
@@ -174,9 +549,21 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
         // TODO: implement
         Ok(())
     }
+
+    fn exchange_opened(&mut self, request_id: RequestId) {
+        self.open.insert(request_id);
+    }
+
+    fn exchange_closed(&mut self, request_id: RequestId) {
+        self.open.remove(&request_id);
+    }
+
+    fn poll_abandoned(&mut self) -> Option<RequestId> {
+        self.abandoned.pop_front()
+    }
 }
 
-impl<P, T, B> Drop for Dispatch<P, T, B> where
+impl<P, T, B> Drop for ClientDispatch<P, T, B> where
     P: ClientProto<T> + BindClient<StreamingMultiplex<B>, T>,
     T: 'static,
     B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
@@ -193,6 +580,10 @@ impl<P, T, B> Drop for Dispatch<P, T, B> where
     }
 }
 
+fn ids_exhausted(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "request ids exhausted")
+}
+
 fn broken_pipe() -> io::Error {
     io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")
 }
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::Instant;
+
+use super::RequestId;
+
+/// An out-of-band control frame, e.g. settings, a window update or a ping
+///
+/// Control frames are exchanged between the two ends of a connection, not
+/// between their services: they are read as `Frame::Control` and handed to
+/// the `ControlHandler` of the connection, if any, and ignored otherwise.
+/// The kind and payload are up to the protocol; its codec encodes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Control {
+    kind: u32,
+    payload: Vec<u8>,
+}
+
+/// Handles the control frames of a connection
+///
+/// Protocols supply a handler per connection through
+/// `ServerProto::control_handler` or `ClientProto::control_handler`, which is
+/// all it takes to implement, say, the settings and pings of HTTP/2 on top of
+/// the multiplex dispatchers. The handler runs on the task of the
+/// connection.
+pub trait ControlHandler {
+    /// Handle a control frame read with the given request ID.
+    ///
+    /// Control frames sent through `out`, e.g. acknowledgements, are written
+    /// ahead of any other frame. An error fails the connection.
+    fn control(&mut self, id: RequestId, control: Control, out: &mut ControlFrames)
+               -> io::Result<()>;
+
+    /// Called every time the dispatcher runs, e.g. to send the settings of
+    /// the connection once it is established, or pings at an interval. By
+    /// default, nothing is sent.
+    fn tick(&mut self, _out: &mut ControlFrames) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the instant at which the dispatcher should run again to tick
+    /// the handler, if any. Only honored by dispatchers given a reactor
+    /// handle, see `advanced::Multiplex::with_handle`.
+    fn poll_timeout(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Control frames waiting to be written, in order
+///
+/// Iterating takes the frames out of the queue.
+#[derive(Debug, Default)]
+pub struct ControlFrames {
+    queue: VecDeque<(RequestId, Control)>,
+}
+
+impl Control {
+    /// Returns a control frame of the given kind
+    pub fn new(kind: u32, payload: Vec<u8>) -> Control {
+        Control {
+            kind: kind,
+            payload: payload,
+        }
+    }
+
+    /// Returns the kind of control frame
+    pub fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    /// Returns the payload of the frame
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Consumes the frame, returning its payload
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl ControlFrames {
+    /// Returns an empty queue
+    pub fn new() -> ControlFrames {
+        ControlFrames::default()
+    }
+
+    /// Queue `control` to be written with the given request ID
+    pub fn send(&mut self, id: RequestId, control: Control) {
+        self.queue.push_back((id, control));
+    }
+
+    /// Returns the number of frames waiting to be written
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true if no frame is waiting to be written
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl Iterator for ControlFrames {
+    type Item = (RequestId, Control);
+
+    fn next(&mut self) -> Option<(RequestId, Control)> {
+        self.queue.pop_front()
+    }
+}
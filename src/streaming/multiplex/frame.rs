@@ -1,8 +1,34 @@
 use super::RequestId;
+use streaming::Headers;
+use super::Control;
 
 /// A multiplexed protocol frame
 #[derive(Debug, Clone)]
 pub enum Frame<T, B, E> {
+    /// Headers sent ahead of the message of an exchange, for protocols which
+    /// separate them from the message head, e.g. gRPC.
+    ///
+    /// They are merged into the next message with the same request ID by
+    /// `MetadataTransport`. A metadata frame reaching the dispatcher fails
+    /// the connection.
+    Metadata {
+        /// Message exchange identifier
+        id: RequestId,
+        /// The headers
+        headers: Headers,
+    },
+    /// An out-of-band control frame, e.g. settings or a ping.
+    ///
+    /// It is handed to the `ControlHandler` of the connection instead of the
+    /// service, and ignored by the dispatcher without one. The request ID is
+    /// up to the protocol, e.g. to tell connection and exchange level
+    /// control frames apart.
+    Control {
+        /// Message exchange identifier
+        id: RequestId,
+        /// The control frame
+        control: Control,
+    },
     /// Either a request or a response.
     Message {
         /// Message exchange identifier
@@ -37,6 +63,8 @@ impl<T, B, E> Frame<T, B, E> {
     /// Return the request ID associated with the frame.
     pub fn request_id(&self) -> RequestId {
         match *self {
+            Frame::Metadata { id, .. } => id,
+            Frame::Control { id, .. } => id,
             Frame::Message { id, .. } => id,
             Frame::Body { id, .. } => id,
             Frame::Error { id, .. } => id,
@@ -47,6 +75,8 @@ impl<T, B, E> Frame<T, B, E> {
     pub fn unwrap_msg(self) -> T {
         match self {
             Frame::Message { message, .. } => message,
+            Frame::Metadata { .. } => panic!("called `Frame::unwrap_msg()` on a `Metadata` value"),
+            Frame::Control { .. } => panic!("called `Frame::unwrap_msg()` on a `Control` value"),
             Frame::Body { .. } => panic!("called `Frame::unwrap_msg()` on a `Body` value"),
             Frame::Error { .. } => panic!("called `Frame::unwrap_msg()` on an `Error` value"),
         }
@@ -56,6 +86,8 @@ impl<T, B, E> Frame<T, B, E> {
     pub fn unwrap_body(self) -> Option<B> {
         match self {
             Frame::Body { chunk, .. } => chunk,
+            Frame::Metadata { .. } => panic!("called `Frame::unwrap_body()` on a `Metadata` value"),
+            Frame::Control { .. } => panic!("called `Frame::unwrap_body()` on a `Control` value"),
             Frame::Message { .. } => panic!("called `Frame::unwrap_body()` on a `Message` value"),
             Frame::Error { .. } => panic!("called `Frame::unwrap_body()` on an `Error` value"),
         }
@@ -65,6 +97,8 @@ impl<T, B, E> Frame<T, B, E> {
     pub fn unwrap_err(self) -> E {
         match self {
             Frame::Error { error, .. } => error,
+            Frame::Metadata { .. } => panic!("called `Frame::unwrap_err()` on a `Metadata` value"),
+            Frame::Control { .. } => panic!("called `Frame::unwrap_err()` on a `Control` value"),
             Frame::Body { .. } => panic!("called `Frame::unwrap_err()` on a `Body` value"),
             Frame::Message { .. } => panic!("called `Frame::unwrap_err()` on a `Message` value"),
         }
@@ -1,7 +1,7 @@
 //! Frame buffer
 
 use smallvec::SmallVec;
-use std::{cmp, ptr};
+use std::{cmp, mem, ptr};
 use std::cell::{Cell, UnsafeCell};
 use std::rc::Rc;
 
@@ -61,6 +61,11 @@ impl<T> FrameBuf<T> {
         unsafe { &*self.inner.get() }.allocated
     }
 
+    /// Returns the number of bytes allocated for frames
+    pub fn memory_used(&self) -> usize {
+        unsafe { &*self.inner.get() }.allocated * mem::size_of::<Slot<T>>()
+    }
+
     pub fn deque(&self) -> FrameDeque<T> {
         FrameDeque {
             inner: self.inner.clone(),
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+
+use futures::{Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use streaming::{Headers, WithHeaders};
+use super::{Frame, RequestId, Transport};
+
+/// The default maximum number of request IDs with pending metadata
+const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// A transport merging metadata frames into the message they precede
+///
+/// Some protocols, e.g. gRPC, send the headers of a request or response in a
+/// frame of their own, ahead of the message head. The wrapped transport
+/// yields them as `Frame::Metadata`, and this transport merges them into the
+/// headers of the next message with the same request ID, whose head is then
+/// a `WithHeaders<T>`; see `Message::headers`. Metadata frames for the same
+/// message are merged, the later ones overriding the earlier ones.
+///
+/// The other way around, the headers of the messages written are sent as a
+/// `Frame::Metadata` ahead of the message, unless there are none.
+///
+/// Headers read for a request ID are kept until its message arrives, and
+/// dropped if an error frame arrives instead, or the request is canceled. A
+/// peer sending metadata for more requests than `max_pending` without the
+/// messages fails the transport.
+///
+/// The dispatchers do not handle metadata frames themselves: protocols whose
+/// transports yield them must wrap them in a `MetadataTransport`.
+pub struct MetadataTransport<T, M, B, E> {
+    inner: T,
+    // Headers read ahead of a message not read yet
+    pending: HashMap<RequestId, Headers>,
+    max_pending: usize,
+    // A message whose metadata was written, but not the message itself
+    buffered: Option<Frame<M, B, E>>,
+}
+
+impl<T, M, B, E> MetadataTransport<T, M, B, E> {
+    /// Wrap the given transport
+    pub fn new(inner: T) -> MetadataTransport<T, M, B, E> {
+        MetadataTransport {
+            inner: inner,
+            pending: HashMap::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+            buffered: None,
+        }
+    }
+
+    /// Set the maximum number of request IDs headers are kept for until
+    /// their message arrives. Defaults to 1024.
+    pub fn max_pending(&mut self, max: usize) {
+        self.max_pending = max;
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T, M, B, E> MetadataTransport<T, M, B, E>
+    where T: Sink<SinkItem = Frame<M, B, E>, SinkError = io::Error>,
+{
+    fn poll_buffered(&mut self) -> Poll<(), io::Error> {
+        if let Some(frame) = self.buffered.take() {
+            if let AsyncSink::NotReady(frame) = try!(self.inner.start_send(frame)) {
+                self.buffered = Some(frame);
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T, M, B, E> Stream for MetadataTransport<T, M, B, E>
+    where T: Stream<Item = Frame<M, B, E>, Error = io::Error>,
+{
+    type Item = Frame<WithHeaders<M>, B, E>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            let frame = match try_ready!(self.inner.poll()) {
+                Some(Frame::Metadata { id, headers }) => {
+                    trace!("   --> read metadata; request-id={:?}", id);
+                    if !self.pending.contains_key(&id) && self.pending.len() >= self.max_pending {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "too many pending metadata frames"));
+                    }
+                    let pending = self.pending.entry(id).or_insert_with(Headers::new);
+                    pending.extend(headers);
+                    continue;
+                }
+                Some(Frame::Message { id, message, body, solo }) => {
+                    let headers = self.pending.remove(&id).unwrap_or_default();
+                    Frame::Message {
+                        id: id,
+                        message: WithHeaders::from_parts(headers, message),
+                        body: body,
+                        solo: solo,
+                    }
+                }
+                Some(Frame::Body { id, chunk }) => Frame::Body { id: id, chunk: chunk },
+                Some(Frame::Control { id, control }) => Frame::Control { id: id, control: control },
+                Some(Frame::Error { id, error }) => {
+                    drop(self.pending.remove(&id));
+                    Frame::Error { id: id, error: error }
+                }
+                None => return Ok(Async::Ready(None)),
+            };
+
+            return Ok(Async::Ready(Some(frame)));
+        }
+    }
+}
+
+impl<T, M, B, E> Sink for MetadataTransport<T, M, B, E>
+    where T: Sink<SinkItem = Frame<M, B, E>, SinkError = io::Error>,
+{
+    type SinkItem = Frame<WithHeaders<M>, B, E>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, frame: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        // Frames are written in order, after the buffered message
+        if !try!(self.poll_buffered()).is_ready() {
+            return Ok(AsyncSink::NotReady(frame));
+        }
+
+        let frame = match frame {
+            Frame::Message { id, message, body, solo } => {
+                let (headers, message) = message.into_parts();
+
+                if !headers.is_empty() {
+                    let metadata = Frame::Metadata { id: id, headers: headers };
+
+                    if let AsyncSink::NotReady(metadata) = try!(self.inner.start_send(metadata)) {
+                        let headers = match metadata {
+                            Frame::Metadata { headers, .. } => headers,
+                            _ => unreachable!(),
+                        };
+
+                        return Ok(AsyncSink::NotReady(Frame::Message {
+                            id: id,
+                            message: WithHeaders::from_parts(headers, message),
+                            body: body,
+                            solo: solo,
+                        }));
+                    }
+
+                    // The message goes out once the transport accepts it,
+                    // the frame is accepted along with its metadata
+                    self.buffered = Some(Frame::Message {
+                        id: id,
+                        message: message,
+                        body: body,
+                        solo: solo,
+                    });
+                    try!(self.poll_buffered());
+                    return Ok(AsyncSink::Ready);
+                }
+
+                Frame::Message { id: id, message: message, body: body, solo: solo }
+            }
+            Frame::Body { id, chunk } => Frame::Body { id: id, chunk: chunk },
+            Frame::Error { id, error } => Frame::Error { id: id, error: error },
+            Frame::Metadata { id, headers } => Frame::Metadata { id: id, headers: headers },
+            Frame::Control { id, control } => Frame::Control { id: id, control: control },
+        };
+
+        match try!(self.inner.start_send(frame)) {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(frame) => Ok(AsyncSink::NotReady(lift(frame))),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_buffered());
+        self.inner.poll_complete()
+    }
+}
+
+// Wraps the head of a frame which was not written, and had no headers
+fn lift<M, B, E>(frame: Frame<M, B, E>) -> Frame<WithHeaders<M>, B, E> {
+    match frame {
+        Frame::Message { id, message, body, solo } => {
+            Frame::Message { id: id, message: WithHeaders::new(message), body: body, solo: solo }
+        }
+        Frame::Body { id, chunk } => Frame::Body { id: id, chunk: chunk },
+        Frame::Error { id, error } => Frame::Error { id: id, error: error },
+        Frame::Metadata { id, headers } => Frame::Metadata { id: id, headers: headers },
+        Frame::Control { id, control } => Frame::Control { id: id, control: control },
+    }
+}
+
+impl<T, M, B, E, ReadBody> Transport<ReadBody> for MetadataTransport<T, M, B, E>
+    where T: Transport<ReadBody, Item = Frame<M, B, E>, SinkItem = Frame<M, B, E>>,
+          M: 'static,
+          B: 'static,
+          E: 'static,
+{
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        // No message is expected for the request anymore
+        drop(self.pending.remove(&request_id));
+        self.inner.cancel(request_id)
+    }
+
+    fn abort_body(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.abort_body(request_id)
+    }
+
+    fn poll_write_body(&mut self, id: RequestId) -> Async<()> {
+        self.inner.poll_write_body(id)
+    }
+
+    fn dispatching_body(&mut self, id: RequestId, body: &ReadBody) {
+        self.inner.dispatching_body(id, body)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
@@ -1,15 +1,23 @@
 //! Pipelined, multiplexed protocols.
 //!
 //! See the crate-level docs for an overview.
+//!
+//! Exchanges are full duplex: a response, along with its body stream, may be
+//! produced as soon as the request headers have been received, and the
+//! request and response bodies then stream concurrently under the same
+//! request ID. A server can, for instance, echo request body chunks back as
+//! they arrive, and a client receives the response while still writing the
+//! request body. The exchange completes once both bodies have ended.
 
 use std::io;
+use std::time::Instant;
 use futures::{Stream, Sink, Async};
 use tokio_core::io::{Io, Framed, Codec};
 
 mod frame_buf;
 
 mod client;
-pub use self::client::ClientProto;
+pub use self::client::{ClientProto, RequestIds, RequestIdPolicy};
 
 mod server;
 pub use self::server::ServerProto;
@@ -17,6 +25,12 @@ pub use self::server::ServerProto;
 mod frame;
 pub use self::frame::Frame;
 
+mod metadata;
+pub use self::metadata::MetadataTransport;
+
+mod control;
+pub use self::control::{Control, ControlHandler, ControlFrames};
+
 
 pub mod advanced;
 
@@ -43,12 +57,35 @@ pub trait Transport<ReadBody>: 'static +
     /// executing.
     fn tick(&mut self) {}
 
+    /// Returns the instant at which the transport next needs to be ticked,
+    /// if any.
+    ///
+    /// This lets transports implement their own timeouts or send periodic
+    /// frames (e.g., keep-alives). The dispatcher checks this after every
+    /// tick and, when it was given a reactor handle, arranges for `tick` to be
+    /// called again no later than the returned instant. By default, the
+    /// transport is only ticked when there is I/O or dispatch work to do.
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        None
+    }
+
     /// Cancel interest in the exchange identified by RequestId
     fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
         drop(request_id);
         Ok(())
     }
 
+    /// Invoked when the body of the message read for the given request ID is
+    /// dropped before it ended, e.g. by a server which rejected an upload
+    /// without reading it.
+    ///
+    /// Chunks still received for the body are discarded. Transports may
+    /// write a frame asking the peer to stop sending them, if the protocol
+    /// defines one.
+    fn abort_body(&mut self, _request_id: RequestId) -> io::Result<()> {
+        Ok(())
+    }
+
     /// Tests to see if this I/O object may accept a body frame for the given
     /// request ID
     fn poll_write_body(&mut self, id: RequestId) -> Async<()> {
@@ -62,6 +99,15 @@ pub trait Transport<ReadBody>: 'static +
         drop(id);
         drop(body);
     }
+
+    /// Returns the approximate number of bytes buffered by the transport,
+    /// e.g. the capacity of its read and write buffers.
+    ///
+    /// This is accounted against the `MemoryBudget` of the connection, if
+    /// any. By default, the transport reports no memory.
+    fn memory_used(&self) -> usize {
+        0
+    }
 }
 
 impl<T:Io + 'static, C: Codec + 'static, ReadBody> Transport<ReadBody> for Framed<T,C> {}
@@ -1,13 +1,16 @@
-use super::{Frame, RequestId, Transport};
-use super::advanced::{Multiplex, MultiplexMessage};
+use super::{Frame, RequestId, Transport, ControlHandler};
+use super::advanced::{Multiplex, MultiplexMessage, ExchangeLimits};
 
 use BindServer;
-use streaming::{Message, Body};
+use util::extensions::Extensions;
+use util::transport_info::{TransportInfo, ConnectionInfo};
+use streaming::{self, Message, Body, MemoryBudget, FlushPolicy, Goodbye};
 use tokio_service::Service;
 use tokio_core::reactor::Handle;
 use futures::{Future, Poll, Async};
 use futures::{IntoFuture, Stream};
 use std::io;
+use std::time::Instant;
 
 /// A streaming, multiplexed server protocol.
 ///
@@ -49,6 +52,10 @@ pub trait ServerProto<T: 'static>: 'static {
     type ResponseBody: 'static;
 
     /// Errors, which are used both for error frames and for the service itself.
+    ///
+    /// Body streams fail with this type too. An error yielded by a body being
+    /// written is sent as an error frame, which fails the body on the other
+    /// end, so application errors keep their type across the connection.
     type Error: From<io::Error> + 'static;
 
     /// The frame transport, which usually take `T` as a parameter.
@@ -65,6 +72,94 @@ pub trait ServerProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed when they are read from the
+    /// transport are not dispatched to the service, and the response future
+    /// of those whose deadline passes while in flight is dropped; either way,
+    /// a `TimedOut` error is written in their place. By default, requests
+    /// carry no deadline; `streaming::WithDeadline` carries one from the
+    /// client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns the extensions map carried by the given request, if any.
+    ///
+    /// Servers and service middleware use the map to attach metadata to the
+    /// request that is not part of the wire format; `TcpServer`, for
+    /// instance, inserts the connection ID and peer address. By default,
+    /// requests carry no extensions.
+    fn request_extensions(_request: &mut Self::Request) -> Option<&mut Extensions> {
+        None
+    }
+
+    /// Returns the details of the connection reported by the transport, if
+    /// any.
+    ///
+    /// A `ConnectionInfo` snapshot of the details is inserted in the
+    /// extensions of every request, see `util::transport_info`. By default,
+    /// no details are reported.
+    fn transport_info(_transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+        None
+    }
+
+    /// Returns the response answering the given request when the server is
+    /// too busy to serve it, if any.
+    ///
+    /// When `TcpServer::max_in_flight` is reached, new requests are answered
+    /// with this response right away instead of being dispatched to the
+    /// service. Without a busy response, the request fails and the error is
+    /// handed to the transport. By default, there is no busy response.
+    fn busy_response(_request: &Self::Request) -> Option<Self::Response> {
+        None
+    }
+
+    /// Returns the bounds on the number of requests tracked at once on a
+    /// connection.
+    ///
+    /// At the maximum, no more frames are read from the connection until a
+    /// request and its response complete, so a peer never letting its
+    /// requests complete cannot grow server memory without bound. By
+    /// default, the number of requests is not bounded.
+    fn exchange_limits(&self) -> ExchangeLimits {
+        ExchangeLimits::new()
+    }
+
+    /// Returns the memory budget of each connection, if any.
+    ///
+    /// The budget covers the buffers of the transport, as reported by
+    /// `Transport::memory_used`, and the state the dispatcher keeps for the
+    /// connection. By default, connections have no budget.
+    fn memory_budget(&self) -> Option<MemoryBudget> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
+
+    /// Returns the handler of the control frames of a new connection, if
+    /// any, see `ControlHandler`. By default, control frames are ignored.
+    fn control_handler(&self) -> Option<Box<dyn ControlHandler>> {
+        None
+    }
+
+    /// Decides whether to serve a connection, before its transport is bound.
+    ///
+    /// Returning a response rejects the connection: the response is written
+    /// as the only frame once the transport is bound, then the connection is
+    /// closed without reading any request. This tells the peer why it is
+    /// turned away, e.g. that the server is full and to retry later, rather
+    /// than resetting the connection. By default, every connection is
+    /// served.
+    fn reject_connection(&self, _io: &T) -> Option<Self::Response> {
+        None
+    }
 }
 
 impl<P, T, B> BindServer<super::StreamingMultiplex<B>, T> for P where
@@ -81,38 +176,104 @@ impl<P, T, B> BindServer<super::StreamingMultiplex<B>, T> for P where
                          Response = Self::ServiceResponse,
                          Error = Self::ServiceError> + 'static
     {
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
-            let dispatch: Dispatch<S, T, P> = Dispatch {
+        if let Some(response) = self.reject_connection(&io) {
+            // Not answering any request, the response is sent on its own
+            trace!("rejecting connection");
+            let frame = Frame::Message { id: 0, message: response, body: false, solo: true };
+            let task = self.bind_transport(io).into_future().and_then(|transport| {
+                Goodbye::new(transport, frame)
+            });
+
+            handle.spawn(task.map_err(|_| ()));
+            return;
+        }
+
+        let inner_handle = handle.clone();
+        let limits = self.exchange_limits();
+        let budget = self.memory_budget();
+        let flush = self.flush_policy();
+        let control = self.control_handler();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
+            let dispatch: ServerDispatch<S, T, P> = ServerDispatch {
                 service: service,
+                info: P::transport_info(&transport).map(ConnectionInfo::new),
                 transport: transport,
                 in_flight: vec![],
             };
-            Multiplex::new(dispatch)
+            let mut multiplex = Multiplex::with_handle(dispatch, &inner_handle);
+            multiplex.exchange_limits(limits);
+            if let Some(budget) = budget {
+                multiplex.memory_budget(budget);
+            }
+            if let Some(flush) = flush {
+                multiplex.flush_policy(flush);
+            }
+            if let Some(control) = control {
+                multiplex.control_handler(control);
+            }
+            multiplex
         }).map_err(|_| ());
 
         // Spawn the multiplex dispatcher
         handle.spawn(task)
     }
+
+    fn request_extensions(request: &mut Self::ServiceRequest) -> Option<&mut Extensions> {
+        P::request_extensions(request.get_mut())
+    }
+
+    fn busy_response(request: &Self::ServiceRequest) -> Option<Self::ServiceResponse> {
+        P::busy_response(request.get_ref()).map(Message::WithoutBody)
+    }
 }
 
-struct Dispatch<S, T, P> where
+/// Dispatches the requests read from a transport to a service, and writes
+/// the responses back as they complete, tagged with the request ID.
+///
+/// This is the dispatcher `bind_server` spawns for each connection. It is
+/// driven by wrapping it in an `advanced::Multiplex`, which is a future
+/// completing once the connection is closed. Embedders providing their own
+/// event loop or I/O can build and drive it directly instead of going through
+/// `BindServer`.
+pub struct ServerDispatch<S, T, P> where
     T: 'static, P: ServerProto<T>, S: Service
 {
     // The service handling the connection
     service: S,
     transport: P::Transport,
+    // Inserted in the extensions of every request
+    info: Option<ConnectionInfo>,
     in_flight: Vec<(RequestId, InFlight<S::Future>)>,
 }
 
+impl<S, T, P> ServerDispatch<S, T, P> where
+    T: 'static, P: ServerProto<T>, S: Service
+{
+    /// Create a dispatcher serving `service` over a transport bound by
+    /// `proto`.
+    ///
+    /// The protocol is only used for its hooks, e.g. `request_deadline`.
+    pub fn new(_proto: &P, transport: P::Transport, service: S) -> ServerDispatch<S, T, P> {
+        ServerDispatch {
+            service: service,
+            info: P::transport_info(&transport).map(ConnectionInfo::new),
+            transport: transport,
+            in_flight: vec![],
+        }
+    }
+}
+
 enum InFlight<F: Future> {
-    Active(F),
+    // The service future, and the deadline of its request
+    Active(F, Option<Instant>),
     Done(Result<F::Item, F::Error>),
 }
 
 /// The total number of requests that can be in flight at once.
 const MAX_IN_FLIGHT_REQUESTS: usize = 32;
 
-impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
+impl<P, T, B, S> super::advanced::Dispatch for ServerDispatch<S, T, P> where
     P: ServerProto<T>,
     B: Stream<Item = P::ResponseBody, Error = P::Error>,
     S: Service<Request = Message<P::Request, Body<P::RequestBody, P::Error>>,
@@ -165,9 +326,24 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
         assert!(!solo);
 
-        if let Ok(request) = message {
-            let response = self.service.call(request);
-            self.in_flight.push((id, InFlight::Active(response)));
+        if let Ok(mut request) = message {
+            if let Some(ref info) = self.info {
+                if let Some(extensions) = P::request_extensions(request.get_mut()) {
+                    extensions.insert(info.clone());
+                }
+            }
+
+            if streaming::deadline_expired(P::request_deadline(request.get_ref())) {
+                // Nobody is waiting on the response anymore, so skip the
+                // service and answer the exchange with an error.
+                trace!("   --> request deadline expired; not dispatching");
+                let error = streaming::deadline_error().into();
+                self.in_flight.push((id, InFlight::Done(Err(error))));
+            } else {
+                let deadline = P::request_deadline(request.get_ref());
+                let response = self.service.call(request);
+                self.in_flight.push((id, InFlight::Active(response, deadline)));
+            }
         }
 
         // TODO: Should the error be handled differently?
@@ -187,6 +363,11 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
         // TODO: implement
         Ok(())
     }
+
+    fn poll_timeout(&self) -> Option<Instant> {
+        // Wake up once the earliest in-flight deadline passes
+        self.in_flight.iter().filter_map(|&(_, ref slot)| slot.deadline()).min()
+    }
 }
 
 /*
@@ -197,16 +378,26 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
 impl<F> InFlight<F>
     where F: Future,
+          F::Error: From<io::Error>,
 {
     // Returns true if done
     fn poll(&mut self) -> bool {
         let res = match *self {
-            InFlight::Active(ref mut f) => {
+            InFlight::Active(ref mut f, deadline) => {
                 trace!("   --> polling future");
                 match f.poll() {
                     Ok(Async::Ready(e)) => Ok(e),
                     Err(e) => Err(e),
-                    Ok(Async::NotReady) => return false,
+                    Ok(Async::NotReady) => {
+                        if !streaming::deadline_expired(deadline) {
+                            return false;
+                        }
+
+                        // Nobody will read the response, so stop working on
+                        // it and answer the exchange with an error instead.
+                        trace!("   --> request deadline expired; dropping response future");
+                        Err(streaming::deadline_error().into())
+                    }
                 }
             }
             _ => return true,
@@ -216,6 +407,13 @@ impl<F> InFlight<F>
         true
     }
 
+    fn deadline(&self) -> Option<Instant> {
+        match *self {
+            InFlight::Active(_, deadline) => deadline,
+            InFlight::Done(_) => None,
+        }
+    }
+
     fn unwrap_done(self) -> Result<F::Item, F::Error> {
         match self {
             InFlight::Done(res) => res,
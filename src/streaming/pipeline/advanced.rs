@@ -4,13 +4,39 @@
 //! Server}` instead. But for some advanced protocols in which the client and
 //! servers have more of a peer relationship, it's useful to work directly with
 //! these implementation details.
+//!
+//! The dispatchers used by `BindServer` and `BindClient`, `ServerDispatch` and
+//! `ClientDispatch`, are exposed here as well. They hold the logic matching
+//! responses to requests, and can be driven without going through the
+//! `Bind*` traits, e.g. to run a protocol on a custom event loop or over an
+//! exotic I/O object:
+//!
+//! ```ignore
+//! let transport = proto.bind_transport(io)?;
+//! let dispatch: ServerDispatch<_, MyIo, _> = ServerDispatch::new(&proto, transport, service);
+//!
+//! // A future completing once the connection is closed. `Pipeline::with_handle`
+//! // additionally ticks the transport when it asks for it, see
+//! // `Transport::poll_timeout`.
+//! let connection = Pipeline::new(dispatch);
+//! ```
+//!
+//! The dispatcher future can be run on any executor and does not require a
+//! reactor on its own; only the transport might.
 
 use futures::sync::mpsc;
+use futures::task;
 use futures::{Future, Poll, Async, Stream, Sink, AsyncSink, StartSend};
 use std::io;
-use streaming::{Message, Body};
+use std::time::Instant;
+use streaming::{self, Message, Body, MemoryBudget, FlushPolicy, TickTimer};
+use streaming::flush::FlushBatch;
 use super::{Frame, Transport};
 use buffer_one::BufferOne;
+use tokio_core::reactor::Handle;
+
+pub use super::server::ServerDispatch;
+pub use super::client::ClientDispatch;
 
 // TODO:
 //
@@ -23,17 +49,34 @@ pub struct Pipeline<T> where T: Dispatch {
     // True as long as the connection has more request frames to read.
     run: bool,
 
+    // True once the dispatch has no more messages to write, e.g. when the
+    // client is closed.
+    dispatch_done: bool,
+
     // Glues the service with the pipeline task
     dispatch: BufferOne<DispatchSink<T>>,
 
+    // Ticks the transport when it asks for it
+    timer: TickTimer,
+
     // The `Sender` for the current request body stream
     out_body: Option<BodySender<T::BodyOut, T::Error>>,
 
+    // True once an error frame failed the current request body stream; the
+    // sender is dropped once the error is flushed to it.
+    out_body_failed: bool,
+
     // The response body stream
     in_body: Option<T::Stream>,
 
     // True when the transport is fully flushed
     is_flushed: bool,
+
+    // Bounds the memory used by the connection
+    budget: Option<MemoryBudget>,
+
+    // True when frames are not read because of the memory budget
+    reads_paused: bool,
 }
 
 /// Message used to communicate through the multiplex dispatch
@@ -78,10 +121,27 @@ pub trait Dispatch {
     /// RPC currently in flight
     /// TODO: Get rid of
     fn has_in_flight(&self) -> bool;
+
+    /// Invoked once the transport has no more frames to read, e.g. because
+    /// the peer closed the connection.
+    ///
+    /// Clients fail the requests waiting on a response, which is never
+    /// coming. By default, nothing happens.
+    fn transport_done(&mut self) {}
+
+    /// Returns the instant at which the dispatcher wants to be polled again,
+    /// e.g. to expire in-flight requests whose deadline passes. It is
+    /// combined with the transport's `poll_timeout`; by default, the
+    /// dispatcher is only polled when there is I/O or dispatch work to do.
+    fn poll_timeout(&self) -> Option<Instant> {
+        None
+    }
 }
 
 struct DispatchSink<T> {
     inner: T,
+    // Frames written since the transport was last flushed
+    batch: FlushBatch,
 }
 
 type BodySender<B, E> = BufferOne<mpsc::Sender<Result<B, E>>>;
@@ -90,28 +150,77 @@ impl<T> Pipeline<T> where T: Dispatch {
     /// Create a new pipeline `Pipeline` dispatcher with the given service and
     /// transport
     pub fn new(dispatch: T) -> Pipeline<T> {
+        Pipeline::build(dispatch, None)
+    }
+
+    /// Create a new `Pipeline` dispatcher which uses the given reactor handle to
+    /// tick the transport at the instants requested by its `poll_timeout`
+    pub fn with_handle(dispatch: T, handle: &Handle) -> Pipeline<T> {
+        Pipeline::build(dispatch, Some(handle.clone()))
+    }
+
+    fn build(dispatch: T, handle: Option<Handle>) -> Pipeline<T> {
         // Add `Sink` impl for `Dispatch`
-        let dispatch = DispatchSink { inner: dispatch };
+        let dispatch = DispatchSink {
+            inner: dispatch,
+            batch: FlushBatch::new(),
+        };
 
         // Add a single slot buffer for the sink
         let dispatch = BufferOne::new(dispatch);
 
         Pipeline {
             run: true,
+            dispatch_done: false,
             dispatch: dispatch,
+            timer: TickTimer::new(handle),
             out_body: None,
+            out_body_failed: false,
             in_body: None,
             is_flushed: true,
+            budget: None,
+            reads_paused: false,
+        }
+    }
+
+    /// Set the memory budget of the connection
+    pub fn memory_budget(&mut self, budget: MemoryBudget) {
+        self.budget = Some(budget);
+    }
+
+    /// Set when the frames written to the transport are flushed, see
+    /// `FlushPolicy`
+    pub fn flush_policy(&mut self, policy: FlushPolicy) {
+        self.dispatch.get_mut().batch.set_policy(policy);
+    }
+
+    /// Returns true if more frames may be read
+    fn can_read(&mut self) -> io::Result<bool> {
+        match self.budget {
+            Some(budget) => {
+                let used = self.dispatch.get_mut().inner.transport().memory_used();
+                budget.check(used)
+            }
+            None => Ok(true),
         }
     }
 
     /// Returns true if the pipeline server dispatch has nothing left to do
     fn is_done(&self) -> bool {
-        !self.run && self.is_flushed && !self.has_in_flight()
+        let closed = self.dispatch_done && self.in_body.is_none() && self.out_body.is_none();
+        (!self.run || closed) && self.is_flushed && !self.has_in_flight()
     }
 
     fn read_out_frames(&mut self) -> io::Result<()> {
+        self.reads_paused = false;
+
         while self.run {
+            if !try!(self.can_read()) {
+                trace!("memory budget exceeded; not reading frames");
+                self.reads_paused = true;
+                break;
+            }
+
             // Return true if the pipeliner can process new outbound frames
             if !self.check_out_body_stream() {
                 break;
@@ -154,6 +263,7 @@ impl<T> Pipeline<T> where T: Dispatch {
                     // currently holds a sender for the previous out body, it
                     // will get dropped. This terminates the stream.
                     self.out_body = Some(BufferOne::new(tx));
+                    self.out_body_failed = false;
 
                     if let Err(_) = self.dispatch.get_mut().inner.dispatch(Ok(message)) {
                         // TODO: Should dispatch be infallible
@@ -194,8 +304,20 @@ impl<T> Pipeline<T> where T: Dispatch {
                 // because tick() will be called again and go
                 // through the read-cycle again.
                 self.run = false;
+                self.dispatch.get_mut().inner.transport_done();
             }
-            Some(Frame::Error { .. }) => {
+            Some(Frame::Error { error }) => {
+                // An error read while a body is being received fails the
+                // body, with the error of the protocol.
+                if let Some(ref mut body) = self.out_body {
+                    if !self.out_body_failed {
+                        trace!("read body error");
+                        drop(body.start_send(Err(error)));
+                        self.out_body_failed = true;
+                        return Ok(());
+                    }
+                }
+
                 // At this point, the transport is toast, there
                 // isn't much else that we can do. Killing the task
                 // will cause all in-flight requests to abort, but
@@ -257,7 +379,9 @@ impl<T> Pipeline<T> where T: Dispatch {
                 }
                 Async::Ready(None) => {
                     trace!("   --> got None");
-                    // The service is done with the connection.
+                    // The service is done with the connection; it is closed
+                    // once the in-flight responses are read.
+                    self.dispatch_done = true;
                     break;
                 }
                 // Nothing to dispatch
@@ -325,8 +449,11 @@ impl<T> Pipeline<T> where T: Dispatch {
                                          Frame::Body { chunk: None }));
                         break;
                     }
-                    Err(_) => {
-                        unimplemented!();
+                    Err(error) => {
+                        // Tell the peer the body failed, the error frame
+                        // ends the body
+                        try!(assert_send(&mut self.dispatch, Frame::Error { error: error }));
+                        break;
                     }
                     Ok(Async::NotReady) => {
                         debug!("not ready");
@@ -341,11 +468,20 @@ impl<T> Pipeline<T> where T: Dispatch {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.is_flushed = try!(self.dispatch.poll_complete()).is_ready();
+        // Nothing is held back once the connection winds down
+        if !self.run || self.dispatch_done {
+            self.dispatch.get_mut().batch.flush_now();
+        }
+
+        self.is_flushed = try!(self.dispatch.poll_complete()).is_ready() &&
+            !self.dispatch.get_ref().batch.is_pending();
 
         if let Some(ref mut out_body) = self.out_body {
-            if out_body.poll_complete().is_ok() {
-                return Ok(());
+            match out_body.poll_complete() {
+                // Keep the sender of a failed body until the error is sent
+                Ok(Async::Ready(())) if self.out_body_failed => {}
+                Ok(_) => return Ok(()),
+                Err(_) => {}
             }
         } else {
             return Ok(());
@@ -353,6 +489,7 @@ impl<T> Pipeline<T> where T: Dispatch {
 
         // Fall through and unset out_body
         self.out_body = None;
+        self.out_body_failed = false;
         Ok(())
     }
 
@@ -381,6 +518,11 @@ impl<T> Future for Pipeline<T> where T: Dispatch {
         // Try flushing buffered writes
         try!(self.flush());
 
+        // Memory was released since reading stopped, tick again to read
+        if self.reads_paused && try!(self.can_read()) {
+            task::park().unpark();
+        }
+
         // Clean shutdown of the pipeline server can happen when
         //
         // 1. The server is done running, this is signaled by Transport::read()
@@ -398,6 +540,15 @@ impl<T> Future for Pipeline<T> where T: Dispatch {
             return Ok(().into())
         }
 
+        // Make sure the transport is ticked when it asked for it
+        let at = {
+            let sink = self.dispatch.get_mut();
+            let at = sink.inner.transport().poll_timeout();
+            let at = streaming::earliest(at, sink.inner.poll_timeout());
+            streaming::earliest(at, sink.batch.poll_timeout())
+        };
+        try!(self.timer.poll(at));
+
         // Tick again later
         Ok(Async::NotReady)
     }
@@ -410,10 +561,23 @@ impl<T: Dispatch> Sink for DispatchSink<T> {
     fn start_send(&mut self, item: Self::SinkItem)
                   -> StartSend<Self::SinkItem, io::Error>
     {
-        self.inner.transport().start_send(item)
+        let res = try!(self.inner.transport().start_send(item));
+
+        match res {
+            AsyncSink::Ready => self.batch.wrote_frame(),
+            // The transport needs to be flushed to make room
+            AsyncSink::NotReady(_) => self.batch.flush_now(),
+        }
+
+        Ok(res)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if !self.batch.poll_due() {
+            // The frames are held back, the transport can take more
+            return Ok(Async::Ready(()));
+        }
+
         self.inner.transport().poll_complete()
     }
 }
@@ -1,5 +1,5 @@
 use BindClient;
-use streaming::{Body, Message};
+use streaming::{self, Body, Message, FlushPolicy};
 use super::{StreamingPipeline, Frame, Transport};
 use super::advanced::{Pipeline, PipelineMessage};
 use util::client_proxy::{self, ClientProxy, Receiver};
@@ -8,6 +8,7 @@ use futures::{Future, IntoFuture, Complete, Poll, Async};
 use tokio_core::reactor::Handle;
 use std::collections::VecDeque;
 use std::io;
+use std::time::Instant;
 
 /// A streaming, pipelined client protocol.
 ///
@@ -31,6 +32,10 @@ pub trait ClientProto<T: 'static>: 'static {
     type ResponseBody: 'static;
 
     /// The type of error frames.
+    ///
+    /// Body streams fail with this type too. An error yielded by a body being
+    /// written is sent as an error frame, which fails the body on the other
+    /// end, so application errors keep their type across the connection.
     type Error: From<io::Error> + 'static;
 
     /// The frame transport, which usually take `T` as a parameter.
@@ -46,6 +51,23 @@ pub trait ClientProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed by the time the connection
+    /// is ready to write them are dropped, and the response future resolves
+    /// with a `TimedOut` error. By default, requests carry no deadline;
+    /// `streaming::WithDeadline` carries one from the client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
 }
 
 impl<P, T, B> BindClient<StreamingPipeline<B>, T> for P where
@@ -62,13 +84,20 @@ impl<P, T, B> BindClient<StreamingPipeline<B>, T> for P where
     fn bind_client(&self, handle: &Handle, io: T) -> Self::BindClient {
         let (client, rx) = client_proxy::pair();
 
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
-            let dispatch: Dispatch<P, T, B> = Dispatch {
+        let inner_handle = handle.clone();
+        let flush = self.flush_policy();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
+            let dispatch: ClientDispatch<P, T, B> = ClientDispatch {
                 transport: transport,
                 requests: rx,
                 in_flight: VecDeque::with_capacity(32),
             };
-            Pipeline::new(dispatch)
+            let mut pipeline = Pipeline::with_handle(dispatch, &inner_handle);
+            if let Some(flush) = flush {
+                pipeline.flush_policy(flush);
+            }
+            pipeline
         }).map_err(|e| {
             // TODO: where to punt this error to?
             error!("pipeline error: {}", e);
@@ -82,7 +111,15 @@ impl<P, T, B> BindClient<StreamingPipeline<B>, T> for P where
     }
 }
 
-struct Dispatch<P, T, B> where
+/// Writes the requests sent through a `ClientProxy` to a transport, and
+/// completes them with the responses read back in order.
+///
+/// This is the dispatcher `bind_client` spawns for each connection. It is
+/// driven by wrapping it in an `advanced::Pipeline`, which is a future
+/// completing once the connection is closed. Embedders providing their own
+/// event loop or I/O can build and drive it directly instead of going through
+/// `BindClient`.
+pub struct ClientDispatch<P, T, B> where
     P: ClientProto<T> + BindClient<StreamingPipeline<B>, T>,
     T: 'static,
     B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
@@ -92,7 +129,32 @@ struct Dispatch<P, T, B> where
     in_flight: VecDeque<Complete<Result<P::ServiceResponse, P::Error>>>,
 }
 
-impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
+impl<P, T, B> ClientDispatch<P, T, B> where
+    P: ClientProto<T> + BindClient<StreamingPipeline<B>, T>,
+    T: 'static,
+    B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
+{
+    /// Create a dispatcher for a transport bound by `proto`, returning it
+    /// along with the client sending requests through it.
+    ///
+    /// The protocol is only used for its hooks, e.g. `request_deadline`.
+    pub fn new(_proto: &P, transport: P::Transport)
+               -> (ClientProxy<P::ServiceRequest, P::ServiceResponse, P::Error>,
+                   ClientDispatch<P, T, B>)
+    {
+        let (client, rx) = client_proxy::pair();
+
+        let dispatch = ClientDispatch {
+            transport: transport,
+            requests: rx,
+            in_flight: VecDeque::with_capacity(32),
+        };
+
+        (client, dispatch)
+    }
+}
+
+impl<P, T, B> super::advanced::Dispatch for ClientDispatch<P, T, B> where
     P: ClientProto<T>,
     B: Stream<Item = P::RequestBody, Error = P::Error>,
 {
@@ -126,42 +188,58 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
                                io::Error>
     {
         trace!("Dispatch::poll");
-        // Try to get a new request frame
-        match self.requests.poll() {
-            Ok(Async::Ready(Some(Ok((request, complete))))) => {
-                trace!("   --> received request");
+        loop {
+            // Try to get a new request frame
+            match self.requests.poll() {
+                Ok(Async::Ready(Some(Ok((request, complete))))) => {
+                    trace!("   --> received request");
 
-                // Track complete handle
-                self.in_flight.push_back(complete);
+                    if streaming::deadline_expired(P::request_deadline(request.get_ref())) {
+                        // The caller gave up on the response already, there is
+                        // no point in writing the request.
+                        trace!("   --> request deadline expired; dropping");
+                        complete.complete(Err(streaming::deadline_error().into()));
+                        continue;
+                    }
 
-                Ok(Async::Ready(Some(Ok(request))))
+                    // Track complete handle
+                    self.in_flight.push_back(complete);
 
+                    return Ok(Async::Ready(Some(Ok(request))));
+                }
+                Ok(Async::Ready(None)) => {
+                    trace!("   --> client dropped or closed");
+                    return Ok(Async::Ready(None));
+                }
+                Ok(Async::Ready(Some(Err(e)))) => {
+                    trace!("   --> error");
+                    // The client timed out waiting for the connection to
+                    // close, fail it
+                    return Err(e);
+                }
+                Ok(Async::NotReady) => {
+                    trace!("   --> not ready");
+                    return Ok(Async::NotReady);
+                }
+                Err(()) => panic!(),
             }
-            Ok(Async::Ready(None)) => {
-                trace!("   --> client dropped");
-                Ok(Async::Ready(None))
-            }
-            Ok(Async::Ready(Some(Err(e)))) => {
-                trace!("   --> error");
-                // An error on receive can only happen when the other half
-                // disconnected. In this case, the client needs to be
-                // shutdown
-                panic!("unimplemented error handling: {:?}", e);
-            }
-            Ok(Async::NotReady) => {
-                trace!("   --> not ready");
-                Ok(Async::NotReady)
-            }
-            Err(()) => panic!(),
         }
     }
 
     fn has_in_flight(&self) -> bool {
         !self.in_flight.is_empty()
     }
+
+    fn transport_done(&mut self) {
+        // No response is coming for the requests in flight
+        while let Some(complete) = self.in_flight.pop_front() {
+            trace!("   --> connection closed; failing request");
+            complete.complete(Err(broken_pipe().into()));
+        }
+    }
 }
 
-impl<P, T, B> Drop for Dispatch<P, T, B> where
+impl<P, T, B> Drop for ClientDispatch<P, T, B> where
     P: ClientProto<T> + BindClient<StreamingPipeline<B>, T>,
     T: 'static,
     B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
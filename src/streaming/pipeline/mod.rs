@@ -3,6 +3,7 @@
 //! See the crate-level docs for an overview.
 
 use std::io;
+use std::time::Instant;
 use futures::{Stream, Sink};
 use tokio_core::io::{Io, Framed, Codec};
 
@@ -37,10 +38,31 @@ pub trait Transport: 'static +
     /// executing.
     fn tick(&mut self) {}
 
+    /// Returns the instant at which the transport next needs to be ticked,
+    /// if any.
+    ///
+    /// This lets transports implement their own timeouts or send periodic
+    /// frames (e.g., keep-alives). The dispatcher checks this after every
+    /// tick and, when it was given a reactor handle, arranges for `tick` to be
+    /// called again no later than the returned instant. By default, the
+    /// transport is only ticked when there is I/O or dispatch work to do.
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        None
+    }
+
     /// Cancel interest in the current stream
     fn cancel(&mut self) -> io::Result<()> {
         Ok(())
     }
+
+    /// Returns the approximate number of bytes buffered by the transport,
+    /// e.g. the capacity of its read and write buffers.
+    ///
+    /// This is accounted against the `MemoryBudget` of the connection, if
+    /// any. By default, the transport reports no memory.
+    fn memory_used(&self) -> usize {
+        0
+    }
 }
 
 impl<T:Io + 'static, C: Codec + 'static> Transport for Framed<T,C> {}
@@ -1,9 +1,12 @@
 use BindServer;
+use util::extensions::Extensions;
+use util::transport_info::{TransportInfo, ConnectionInfo};
 use futures::stream::Stream;
 use futures::{Future, IntoFuture, Poll, Async};
 use std::collections::VecDeque;
 use std::io;
-use streaming::{Message, Body};
+use std::time::Instant;
+use streaming::{self, Message, Body, MemoryBudget, FlushPolicy, Goodbye};
 use super::advanced::{Pipeline, PipelineMessage};
 use super::{Frame, Transport};
 use tokio_core::reactor::Handle;
@@ -36,6 +39,10 @@ pub trait ServerProto<T: 'static>: 'static {
     type ResponseBody: 'static;
 
     /// Errors, which are used both for error frames and for the service itself.
+    ///
+    /// Body streams fail with this type too. An error yielded by a body being
+    /// written is sent as an error frame, which fails the body on the other
+    /// end, so application errors keep their type across the connection.
     type Error: From<io::Error> + 'static;
 
     /// The frame transport, which usually take `T` as a parameter.
@@ -51,6 +58,77 @@ pub trait ServerProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// Returns the deadline carried by the given request, if any.
+    ///
+    /// Requests whose deadline has already passed when they are read from the
+    /// transport are not dispatched to the service, and the response future
+    /// of those whose deadline passes while in flight is dropped; either way,
+    /// a `TimedOut` error is written in their place. By default, requests
+    /// carry no deadline; `streaming::WithDeadline` carries one from the
+    /// client to the server.
+    fn request_deadline(_request: &Self::Request) -> Option<Instant> {
+        None
+    }
+
+    /// Returns the extensions map carried by the given request, if any.
+    ///
+    /// Servers and service middleware use the map to attach metadata to the
+    /// request that is not part of the wire format; `TcpServer`, for
+    /// instance, inserts the connection ID and peer address. By default,
+    /// requests carry no extensions.
+    fn request_extensions(_request: &mut Self::Request) -> Option<&mut Extensions> {
+        None
+    }
+
+    /// Returns the details of the connection reported by the transport, if
+    /// any.
+    ///
+    /// A `ConnectionInfo` snapshot of the details is inserted in the
+    /// extensions of every request, see `util::transport_info`. By default,
+    /// no details are reported.
+    fn transport_info(_transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+        None
+    }
+
+    /// Returns the response answering the given request when the server is
+    /// too busy to serve it, if any.
+    ///
+    /// When `TcpServer::max_in_flight` is reached, new requests are answered
+    /// with this response right away instead of being dispatched to the
+    /// service. Without a busy response, the request fails and the error is
+    /// handed to the transport. By default, there is no busy response.
+    fn busy_response(_request: &Self::Request) -> Option<Self::Response> {
+        None
+    }
+
+    /// Returns the memory budget of each connection, if any.
+    ///
+    /// The budget covers the buffers of the transport, as reported by
+    /// `Transport::memory_used`, and the state the dispatcher keeps for the
+    /// connection. By default, connections have no budget.
+    fn memory_budget(&self) -> Option<MemoryBudget> {
+        None
+    }
+
+    /// Returns when the frames written to each connection are flushed, see
+    /// `FlushPolicy`. By default, the transport is flushed every time the
+    /// dispatcher runs.
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        None
+    }
+
+    /// Decides whether to serve a connection, before its transport is bound.
+    ///
+    /// Returning a response rejects the connection: the response is written
+    /// as the only frame once the transport is bound, then the connection is
+    /// closed without reading any request. This tells the peer why it is
+    /// turned away, e.g. that the server is full and to retry later, rather
+    /// than resetting the connection. By default, every connection is
+    /// served.
+    fn reject_connection(&self, _io: &T) -> Option<Self::Response> {
+        None
+    }
 }
 
 impl<P, T, B> BindServer<super::StreamingPipeline<B>, T> for P where
@@ -67,35 +145,94 @@ impl<P, T, B> BindServer<super::StreamingPipeline<B>, T> for P where
                          Response = Self::ServiceResponse,
                          Error = Self::ServiceError> + 'static
     {
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
-            let dispatch: Dispatch<S, T, P> = Dispatch {
+        if let Some(response) = self.reject_connection(&io) {
+            trace!("rejecting connection");
+            let frame = Frame::Message { message: response, body: false };
+            let task = self.bind_transport(io).into_future().and_then(|transport| {
+                Goodbye::new(transport, frame)
+            });
+
+            handle.spawn(task.map_err(|_| ()));
+            return;
+        }
+
+        let inner_handle = handle.clone();
+        let budget = self.memory_budget();
+        let flush = self.flush_policy();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
+            let dispatch: ServerDispatch<S, T, P> = ServerDispatch {
                 service: service,
+                info: P::transport_info(&transport).map(ConnectionInfo::new),
                 transport: transport,
                 in_flight: VecDeque::with_capacity(32),
             };
-            Pipeline::new(dispatch)
+            let mut pipeline = Pipeline::with_handle(dispatch, &inner_handle);
+            if let Some(budget) = budget {
+                pipeline.memory_budget(budget);
+            }
+            if let Some(flush) = flush {
+                pipeline.flush_policy(flush);
+            }
+            pipeline
         });
 
         // Spawn the pipeline dispatcher
         handle.spawn(task.map_err(|_| ()))
     }
+
+    fn request_extensions(request: &mut Self::ServiceRequest) -> Option<&mut Extensions> {
+        P::request_extensions(request.get_mut())
+    }
+
+    fn busy_response(request: &Self::ServiceRequest) -> Option<Self::ServiceResponse> {
+        P::busy_response(request.get_ref()).map(Message::WithoutBody)
+    }
 }
 
-struct Dispatch<S, T, P> where
+/// Dispatches the requests read from a transport to a service, and writes
+/// the responses back in order.
+///
+/// This is the dispatcher `bind_server` spawns for each connection. It is
+/// driven by wrapping it in an `advanced::Pipeline`, which is a future
+/// completing once the connection is closed. Embedders providing their own
+/// event loop or I/O can build and drive it directly instead of going through
+/// `BindServer`.
+pub struct ServerDispatch<S, T, P> where
     T: 'static, P: ServerProto<T>, S: Service
 {
     // The service handling the connection
     service: S,
     transport: P::Transport,
+    // Inserted in the extensions of every request
+    info: Option<ConnectionInfo>,
     in_flight: VecDeque<InFlight<S::Future>>,
 }
 
+impl<S, T, P> ServerDispatch<S, T, P> where
+    T: 'static, P: ServerProto<T>, S: Service
+{
+    /// Create a dispatcher serving `service` over a transport bound by
+    /// `proto`.
+    ///
+    /// The protocol is only used for its hooks, e.g. `request_deadline`.
+    pub fn new(_proto: &P, transport: P::Transport, service: S) -> ServerDispatch<S, T, P> {
+        ServerDispatch {
+            service: service,
+            info: P::transport_info(&transport).map(ConnectionInfo::new),
+            transport: transport,
+            in_flight: VecDeque::with_capacity(32),
+        }
+    }
+}
+
 enum InFlight<F: Future> {
-    Active(F),
+    // The service future, and the deadline of its request
+    Active(F, Option<Instant>),
     Done(Result<F::Item, F::Error>),
 }
 
-impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
+impl<P, T, B, S> super::advanced::Dispatch for ServerDispatch<S, T, P> where
     P: ServerProto<T>,
     T: 'static,
     B: Stream<Item = P::ResponseBody, Error = P::Error>,
@@ -120,9 +257,25 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
                 request: PipelineMessage<Self::Out, Body<Self::BodyOut, Self::Error>, Self::Error>)
                 -> io::Result<()>
     {
-        if let Ok(request) = request {
-            let response = self.service.call(request);
-            self.in_flight.push_back(InFlight::Active(response));
+        if let Ok(mut request) = request {
+            if let Some(ref info) = self.info {
+                if let Some(extensions) = P::request_extensions(request.get_mut()) {
+                    extensions.insert(info.clone());
+                }
+            }
+
+            if streaming::deadline_expired(P::request_deadline(request.get_ref())) {
+                // Nobody is waiting on the response anymore, so skip the
+                // service. A response slot is still needed to keep the
+                // pipeline in order.
+                trace!("   --> request deadline expired; not dispatching");
+                let error = streaming::deadline_error().into();
+                self.in_flight.push_back(InFlight::Done(Err(error)));
+            } else {
+                let deadline = P::request_deadline(request.get_ref());
+                let response = self.service.call(request);
+                self.in_flight.push_back(InFlight::Active(response, deadline));
+            }
         }
 
         // TODO: Should the error be handled differently?
@@ -149,20 +302,42 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
     fn has_in_flight(&self) -> bool {
         !self.in_flight.is_empty()
     }
+
+    fn poll_timeout(&self) -> Option<Instant> {
+        // Wake up once the earliest in-flight deadline passes
+        self.in_flight.iter().filter_map(InFlight::deadline).min()
+    }
 }
 
-impl<F: Future> InFlight<F> {
+impl<F: Future> InFlight<F> where F::Error: From<io::Error> {
     fn poll(&mut self) {
         let res = match *self {
-            InFlight::Active(ref mut f) => {
+            InFlight::Active(ref mut f, deadline) => {
                 match f.poll() {
                     Ok(Async::Ready(e)) => Ok(e),
                     Err(e) => Err(e),
-                    Ok(Async::NotReady) => return,
+                    Ok(Async::NotReady) => {
+                        if !streaming::deadline_expired(deadline) {
+                            return;
+                        }
+
+                        // Nobody will read the response, so stop working on
+                        // it: the future is dropped, and the error written in
+                        // its place.
+                        trace!("   --> request deadline expired; dropping response future");
+                        Err(streaming::deadline_error().into())
+                    }
                 }
             }
             _ => return,
         };
         *self = InFlight::Done(res);
     }
+
+    fn deadline(&self) -> Option<Instant> {
+        match *self {
+            InFlight::Active(_, deadline) => deadline,
+            InFlight::Done(_) => None,
+        }
+    }
 }
@@ -1,16 +1,26 @@
+use std::cell::{Cell, RefCell};
 use std::io;
-use std::sync::Arc;
-use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::marker::PhantomData;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use BindClient;
-use tokio_core::reactor::Handle;
-use tokio_core::net::{TcpStream, TcpStreamNew};
-use futures::{Future, Poll, Async};
+use proxy::{self, Proxy, Target, Tunnel};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_core::net::TcpStream;
+use futures::{future, Future, Poll, Async};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_service::Service;
+#[cfg(target_os = "linux")]
+use libc;
+use net2;
 
 // TODO: add configuration, e.g.:
 // - connection timeout
-// - multiple addresses
 // - request timeout
 
 // TODO: consider global event loop handle, so that providing one in the builder
@@ -26,6 +36,8 @@ use futures::{Future, Poll, Async};
 pub struct TcpClient<Kind, P> {
     _kind: PhantomData<Kind>,
     proto: Arc<P>,
+    proxy: Option<Proxy>,
+    fast_open: bool,
 }
 
 /// A future for establishing a client connection.
@@ -34,10 +46,145 @@ pub struct TcpClient<Kind, P> {
 pub struct Connect<Kind, P> {
     _kind: PhantomData<Kind>,
     proto: Arc<P>,
-    socket: TcpStreamNew,
+    socket: Tunnel,
     handle: Handle,
 }
 
+/// A named endpoint, resolved to socket addresses when connecting.
+///
+/// The resolved addresses are cached and shared between clones of the
+/// endpoint. They are resolved again once the refresh interval elapses, or
+/// when none of them can be connected to. Successive connections rotate
+/// through the addresses, each one starting with the address following the
+/// one the previous connection started with, and falling back to the next
+/// addresses on failure.
+///
+/// When the host name resolves to both IPv6 and IPv4 addresses, connection
+/// attempts alternate between the two families and are staggered, as
+/// described in RFC 8305 ("Happy Eyeballs"): a new attempt is started when
+/// the previous one fails or has not succeeded within the attempt delay,
+/// without cancelling the attempts in progress. The first connection
+/// established is used.
+#[derive(Clone)]
+pub struct Endpoint {
+    inner: Arc<Mutex<EndpointInner>>,
+}
+
+struct EndpointInner {
+    host: String,
+    refresh_interval: Duration,
+    attempt_delay: Duration,
+    addrs: Vec<SocketAddr>,
+    resolved_at: Option<Instant>,
+    next: usize,
+}
+
+/// A future for establishing a client connection to an `Endpoint`.
+///
+/// Yields a service for interacting with the server.
+pub struct ConnectEndpoint<Kind, P> {
+    _kind: PhantomData<Kind>,
+    proto: Arc<P>,
+    endpoint: Endpoint,
+    handle: Handle,
+    fast_open: bool,
+    state: State,
+    // Remaining addresses to try, in reverse order
+    addrs: Vec<SocketAddr>,
+    // Connection attempts in progress
+    attempts: Vec<Tunnel>,
+    attempt_delay: Duration,
+    // Fires when the next attempt should be started
+    delay: Option<Timeout>,
+    resolved: bool,
+    error: Option<io::Error>,
+}
+
+/// A client establishing a new connection for every request.
+///
+/// This is meant for oneshot protocols, whose servers close the connection
+/// after a single exchange. Each call connects to the server, sends the
+/// request on the fresh connection and closes it once the response was
+/// received. See `TcpClient::oneshot`.
+pub struct Oneshot<Kind, P> {
+    inner: Rc<OneshotInner<Kind, P>>,
+}
+
+struct OneshotInner<Kind, P> {
+    _kind: PhantomData<Kind>,
+    proto: Arc<P>,
+    proxy: Option<Proxy>,
+    fast_open: bool,
+    addr: SocketAddr,
+    handle: Handle,
+    // Bounds the number of connections open at once
+    max: Cell<usize>,
+    active: Cell<usize>,
+    // Calls waiting for a connection to close
+    waiters: RefCell<Vec<Task>>,
+}
+
+/// A client keeping connections to a server open between requests.
+///
+/// Each call is sent on an idle connection of the pool, or on a new one if
+/// none is idle, which returns to the pool once the response was received.
+/// A connection whose call failed, or whose response future was dropped, is
+/// closed instead. See `TcpClient::pool`.
+pub struct Pool<Kind, P> where P: BindClient<Kind, TcpStream> {
+    inner: Rc<PoolInner<Kind, P>>,
+}
+
+struct PoolInner<Kind, P> where P: BindClient<Kind, TcpStream> {
+    _kind: PhantomData<Kind>,
+    proto: Arc<P>,
+    proxy: Option<Proxy>,
+    fast_open: bool,
+    addr: SocketAddr,
+    handle: Handle,
+    // The most recently used connection last
+    idle: RefCell<Vec<P::BindClient>>,
+    min_idle: Cell<usize>,
+    // Connections being established in the background, or waiting to be
+    // retried
+    warming: Cell<usize>,
+}
+
+/// Response future of `Pool`
+pub struct PoolResponse<Kind, P> where P: BindClient<Kind, TcpStream> {
+    inner: Rc<PoolInner<Kind, P>>,
+    state: PoolState<Kind, P>,
+}
+
+enum PoolState<Kind, P> where P: BindClient<Kind, TcpStream> {
+    Connecting(Connect<Kind, P>, Option<P::ServiceRequest>),
+    // The client returns to the pool once the response is received
+    Calling(Option<P::BindClient>, <P::BindClient as Service>::Future),
+    Done,
+}
+
+/// Response future of `Oneshot`
+pub struct OneshotResponse<Kind, P> where P: BindClient<Kind, TcpStream> {
+    inner: Rc<OneshotInner<Kind, P>>,
+    state: OneshotState<Kind, P>,
+    // True while the call counts against the connection limit
+    acquired: bool,
+}
+
+enum OneshotState<Kind, P> where P: BindClient<Kind, TcpStream> {
+    Waiting(Option<P::ServiceRequest>),
+    Connecting(Connect<Kind, P>, Option<P::ServiceRequest>),
+    // The client is kept until the response is received
+    Calling(P::BindClient, <P::BindClient as Service>::Future),
+    Done,
+}
+
+enum State {
+    Resolving(oneshot::Receiver<io::Result<Vec<SocketAddr>>>),
+    Connecting,
+    Tunneling(Tunnel),
+    Done,
+}
+
 impl<Kind, P> Future for Connect<Kind, P> where P: BindClient<Kind, TcpStream> {
     type Item = P::BindClient;
     type Error = io::Error;
@@ -56,10 +203,31 @@ impl<Kind, P> TcpClient<Kind, P> where P: BindClient<Kind, TcpStream> {
     pub fn new(protocol: P) -> TcpClient<Kind, P> {
         TcpClient {
             _kind: PhantomData,
-            proto: Arc::new(protocol)
+            proto: Arc::new(protocol),
+            proxy: None,
+            fast_open: false,
         }
     }
 
+    /// Tunnel the connections through the given proxy.
+    ///
+    /// See `Proxy` for details.
+    pub fn proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+
+    /// Use TCP Fast Open for the connections to the server (Linux only).
+    ///
+    /// The connection is then only established once the first request is
+    /// written, which is sent along with the SYN when the client holds a
+    /// Fast Open cookie of the server, saving a round trip. Otherwise, or if
+    /// the kernel does not support it, connections are established as usual.
+    /// This pays off for short lived connections, e.g. oneshot ones; see
+    /// `TcpServer::fast_open`. Connections to a proxy do not use Fast Open.
+    pub fn fast_open(&mut self, enabled: bool) {
+        self.fast_open = enabled;
+    }
+
     /// Establish a connection to the given address.
     ///
     /// # Return value
@@ -68,11 +236,713 @@ impl<Kind, P> TcpClient<Kind, P> where P: BindClient<Kind, TcpStream> {
     /// future completes, it yields an instance of `Service` for interacting
     /// with the server.
     pub fn connect(&self, addr: &SocketAddr, handle: &Handle) -> Connect<Kind, P> {
-        Connect {
+        connect(&self.proto, &self.proxy, self.fast_open, addr, handle)
+    }
+
+    /// Returns a client connecting to the given address anew for every
+    /// request.
+    ///
+    /// Each connection is closed once the response to its request was
+    /// received, see `Oneshot`. The number of connections open at once is not
+    /// bounded by default, see `Oneshot::max_concurrent`.
+    pub fn oneshot(&self, addr: &SocketAddr, handle: &Handle) -> Oneshot<Kind, P> {
+        Oneshot {
+            inner: Rc::new(OneshotInner {
+                _kind: PhantomData,
+                proto: self.proto.clone(),
+                proxy: self.proxy.clone(),
+                fast_open: self.fast_open,
+                addr: *addr,
+                handle: handle.clone(),
+                max: Cell::new(usize::max_value()),
+                active: Cell::new(0),
+                waiters: RefCell::new(vec![]),
+            }),
+        }
+    }
+
+    /// Returns a client reusing connections to the given address across
+    /// requests.
+    ///
+    /// No connection is established until the first call, see
+    /// `Pool::min_idle` to establish some up front.
+    pub fn pool(&self, addr: &SocketAddr, handle: &Handle) -> Pool<Kind, P> {
+        Pool {
+            inner: Rc::new(PoolInner {
+                _kind: PhantomData,
+                proto: self.proto.clone(),
+                proxy: self.proxy.clone(),
+                fast_open: self.fast_open,
+                addr: *addr,
+                handle: handle.clone(),
+                idle: RefCell::new(vec![]),
+                min_idle: Cell::new(0),
+                warming: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Establish a connection to the given endpoint.
+    ///
+    /// The endpoint is resolved first if its addresses are not known yet or
+    /// are stale. Each address is then tried in turn until a connection is
+    /// established. When a proxy is configured, the host name is passed to
+    /// the proxy instead.
+    ///
+    /// # Return value
+    ///
+    /// Returns a future for the establishment of the connection. When the
+    /// future completes, it yields an instance of `Service` for interacting
+    /// with the server.
+    pub fn connect_endpoint(&self, endpoint: &Endpoint, handle: &Handle) -> ConnectEndpoint<Kind, P> {
+        let mut connect = ConnectEndpoint {
             _kind: PhantomData,
             proto: self.proto.clone(),
-            socket: TcpStream::connect(addr, handle),
+            endpoint: endpoint.clone(),
             handle: handle.clone(),
+            fast_open: self.fast_open,
+            state: State::Done,
+            addrs: vec![],
+            attempts: vec![],
+            attempt_delay: endpoint.inner.lock().unwrap().attempt_delay,
+            delay: None,
+            resolved: false,
+            error: None,
+        };
+
+        if let Some(ref proxy) = self.proxy {
+            let target = Target::Host(endpoint.host());
+            connect.state = State::Tunneling(proxy::tunnel(proxy, target, handle));
+            return connect;
+        }
+
+        connect.state = match endpoint.addrs() {
+            Some(addrs) => {
+                connect.addrs = addrs;
+                State::Connecting
+            }
+            None => connect.resolve(),
+        };
+
+        connect
+    }
+}
+
+fn connect<Kind, P>(proto: &Arc<P>,
+                    proxy: &Option<Proxy>,
+                    fast_open: bool,
+                    addr: &SocketAddr,
+                    handle: &Handle) -> Connect<Kind, P> {
+    Connect {
+        _kind: PhantomData,
+        proto: proto.clone(),
+        socket: match *proxy {
+            Some(ref proxy) => proxy::tunnel(proxy, Target::Addr(*addr), handle),
+            None => tcp_connect(addr, fast_open, handle),
+        },
+        handle: handle.clone(),
+    }
+}
+
+/// Returns a future connecting to `addr`, with TCP Fast Open if asked to
+fn tcp_connect(addr: &SocketAddr, fast_open: bool, handle: &Handle) -> Tunnel {
+    if !fast_open {
+        return Box::new(TcpStream::connect(addr, handle));
+    }
+
+    let builder = match *addr {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => net2::TcpBuilder::new_v6(),
+    };
+
+    let socket = builder.and_then(|builder| {
+        if let Err(e) = set_fast_open_connect(&builder) {
+            debug!("failed to enable TCP Fast Open; err={}", e);
+        }
+
+        builder.to_tcp_stream()
+    });
+
+    match socket {
+        Ok(socket) => TcpStream::connect_stream(socket, addr, handle),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(tcp: &net2::TcpBuilder) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(tcp.as_raw_fd(),
+                         libc::IPPROTO_TCP,
+                         libc::TCP_FASTOPEN_CONNECT,
+                         &enabled as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open_connect(_tcp: &net2::TcpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "TCP Fast Open not supported"))
+}
+
+impl<Kind, P> Oneshot<Kind, P> where P: BindClient<Kind, TcpStream> {
+    /// Set the maximum number of connections open at once. Calls made beyond
+    /// it wait for a connection to close before connecting.
+    pub fn max_concurrent(&mut self, max: usize) {
+        assert!(max > 0);
+        self.inner.max.set(max);
+    }
+
+    /// Returns the number of connections currently open or being opened
+    pub fn active(&self) -> usize {
+        self.inner.active.get()
+    }
+}
+
+impl<Kind, P> Clone for Oneshot<Kind, P> {
+    fn clone(&self) -> Oneshot<Kind, P> {
+        Oneshot { inner: self.inner.clone() }
+    }
+}
+
+impl<Kind, P> Service for Oneshot<Kind, P>
+    where P: BindClient<Kind, TcpStream>,
+          P::ServiceError: From<io::Error>,
+{
+    type Request = P::ServiceRequest;
+    type Response = P::ServiceResponse;
+    type Error = P::ServiceError;
+    type Future = OneshotResponse<Kind, P>;
+
+    fn call(&self, req: P::ServiceRequest) -> Self::Future {
+        OneshotResponse {
+            inner: self.inner.clone(),
+            state: OneshotState::Waiting(Some(req)),
+            acquired: false,
         }
     }
 }
+
+impl<Kind, P> OneshotResponse<Kind, P>
+    where P: BindClient<Kind, TcpStream>,
+          P::ServiceError: From<io::Error>,
+{
+    fn poll_state(&mut self) -> Poll<P::ServiceResponse, P::ServiceError> {
+        loop {
+            let next = match self.state {
+                OneshotState::Waiting(ref mut req) => {
+                    let inner = &self.inner;
+
+                    if inner.active.get() >= inner.max.get() {
+                        trace!("oneshot connection limit reached; waiting");
+                        inner.waiters.borrow_mut().push(task::park());
+                        return Ok(Async::NotReady);
+                    }
+
+                    inner.active.set(inner.active.get() + 1);
+                    self.acquired = true;
+
+                    let connect = connect(&inner.proto, &inner.proxy, inner.fast_open,
+                                          &inner.addr, &inner.handle);
+                    OneshotState::Connecting(connect, req.take())
+                }
+                OneshotState::Connecting(ref mut connect, ref mut req) => {
+                    let client = try_ready!(connect.poll());
+                    let response = client.call(req.take().expect("polled after completion"));
+                    OneshotState::Calling(client, response)
+                }
+                OneshotState::Calling(_, ref mut response) => {
+                    return response.poll();
+                }
+                OneshotState::Done => panic!("polled after completion"),
+            };
+
+            self.state = next;
+        }
+    }
+}
+
+impl<Kind, P> OneshotResponse<Kind, P> where P: BindClient<Kind, TcpStream> {
+    // Let a waiting call connect once the connection is closed
+    fn release(&mut self) {
+        if !self.acquired {
+            return;
+        }
+
+        self.acquired = false;
+        self.inner.active.set(self.inner.active.get() - 1);
+
+        // Waiters may have been dropped since, so all of them are woken up
+        for task in self.inner.waiters.borrow_mut().drain(..) {
+            task.unpark();
+        }
+    }
+}
+
+impl<Kind, P> Future for OneshotResponse<Kind, P>
+    where P: BindClient<Kind, TcpStream>,
+          P::ServiceError: From<io::Error>,
+{
+    type Item = P::ServiceResponse;
+    type Error = P::ServiceError;
+
+    fn poll(&mut self) -> Poll<P::ServiceResponse, P::ServiceError> {
+        let res = self.poll_state();
+
+        match res {
+            Ok(Async::NotReady) => {}
+            _ => {
+                // Dropping the client closes the connection
+                self.state = OneshotState::Done;
+                self.release();
+            }
+        }
+
+        res
+    }
+}
+
+impl<Kind, P> Drop for OneshotResponse<Kind, P> where P: BindClient<Kind, TcpStream> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Delay before connecting again after failing to establish an idle
+/// connection
+const POOL_RETRY_DELAY_MS: u64 = 1000;
+
+impl<Kind: 'static, P> Pool<Kind, P> where P: BindClient<Kind, TcpStream> {
+    /// Keep at least `n` idle connections open, establishing them right away.
+    ///
+    /// Connections taken by calls, or closed after failing, are replaced in
+    /// the background, so that bursts of requests do not wait for
+    /// connections to be established. A connection closed by the server
+    /// while idle is only noticed once a call fails on it. Failing to
+    /// connect is retried after a second. Defaults to 0.
+    pub fn min_idle(&mut self, n: usize) {
+        self.inner.min_idle.set(n);
+        refill(&self.inner);
+    }
+
+    /// Returns the number of idle connections
+    pub fn idle(&self) -> usize {
+        self.inner.idle.borrow().len()
+    }
+}
+
+impl<Kind, P> Clone for Pool<Kind, P> where P: BindClient<Kind, TcpStream> {
+    fn clone(&self) -> Pool<Kind, P> {
+        Pool { inner: self.inner.clone() }
+    }
+}
+
+impl<Kind: 'static, P> Service for Pool<Kind, P>
+    where P: BindClient<Kind, TcpStream>,
+          P::ServiceError: From<io::Error>,
+{
+    type Request = P::ServiceRequest;
+    type Response = P::ServiceResponse;
+    type Error = P::ServiceError;
+    type Future = PoolResponse<Kind, P>;
+
+    fn call(&self, req: P::ServiceRequest) -> Self::Future {
+        let inner = &self.inner;
+        let idle = inner.idle.borrow_mut().pop();
+
+        let state = match idle {
+            Some(client) => {
+                trace!("pool; reusing idle connection");
+                let response = client.call(req);
+                PoolState::Calling(Some(client), response)
+            }
+            None => {
+                trace!("pool; no idle connection, connecting");
+                let connect = connect(&inner.proto, &inner.proxy, inner.fast_open,
+                                      &inner.addr, &inner.handle);
+                PoolState::Connecting(connect, Some(req))
+            }
+        };
+
+        refill(inner);
+
+        PoolResponse {
+            inner: inner.clone(),
+            state: state,
+        }
+    }
+}
+
+/// Establish connections in the background until the pool has its minimum
+/// number of idle ones
+fn refill<Kind: 'static, P>(inner: &Rc<PoolInner<Kind, P>>)
+    where P: BindClient<Kind, TcpStream>
+{
+    while inner.idle.borrow().len() + inner.warming.get() < inner.min_idle.get() {
+        inner.warming.set(inner.warming.get() + 1);
+
+        // The pool may be dropped meanwhile, its connections with it
+        let pool = Rc::downgrade(inner);
+        let connect = connect(&inner.proto, &inner.proxy, inner.fast_open,
+                              &inner.addr, &inner.handle);
+
+        inner.handle.spawn(connect.then(move |res| {
+            let inner = match pool.upgrade() {
+                Some(inner) => inner,
+                None => return Ok(()),
+            };
+
+            match res {
+                Ok(client) => {
+                    trace!("pool; idle connection established");
+                    inner.warming.set(inner.warming.get() - 1);
+                    inner.idle.borrow_mut().push(client);
+                }
+                Err(e) => {
+                    warn!("pool; failed to establish idle connection; err={}", e);
+                    retry(&inner, Rc::downgrade(&inner));
+                }
+            }
+
+            Ok(())
+        }));
+    }
+}
+
+/// Give up a warming slot once the retry delay elapsed, refilling the pool
+fn retry<Kind: 'static, P>(inner: &PoolInner<Kind, P>, pool: Weak<PoolInner<Kind, P>>)
+    where P: BindClient<Kind, TcpStream>
+{
+    let delay = Duration::from_millis(POOL_RETRY_DELAY_MS);
+    let timeout = Timeout::new(delay, &inner.handle)
+        .map(|timeout| Box::new(timeout) as Box<dyn Future<Item = (), Error = io::Error>>)
+        .unwrap_or_else(|e| Box::new(future::err(e)));
+
+    inner.handle.spawn(timeout.then(move |_| {
+        if let Some(inner) = pool.upgrade() {
+            inner.warming.set(inner.warming.get() - 1);
+            refill(&inner);
+        }
+
+        Ok(())
+    }));
+}
+
+impl<Kind: 'static, P> Future for PoolResponse<Kind, P>
+    where P: BindClient<Kind, TcpStream>,
+          P::ServiceError: From<io::Error>,
+{
+    type Item = P::ServiceResponse;
+    type Error = P::ServiceError;
+
+    fn poll(&mut self) -> Poll<P::ServiceResponse, P::ServiceError> {
+        loop {
+            let next = match self.state {
+                PoolState::Connecting(ref mut connect, ref mut req) => {
+                    let client = try_ready!(connect.poll());
+                    let response = client.call(req.take().expect("polled after completion"));
+                    PoolState::Calling(Some(client), response)
+                }
+                PoolState::Calling(ref mut client, ref mut response) => {
+                    let res = response.poll();
+
+                    match res {
+                        Ok(Async::NotReady) => return res,
+                        Ok(Async::Ready(_)) => {
+                            let client = client.take().expect("polled after completion");
+                            self.inner.idle.borrow_mut().push(client);
+                        }
+                        Err(_) => {
+                            trace!("pool; call failed, closing its connection");
+                            drop(client.take());
+                            refill(&self.inner);
+                        }
+                    }
+
+                    self.state = PoolState::Done;
+                    return res;
+                }
+                PoolState::Done => panic!("polled after completion"),
+            };
+
+            self.state = next;
+        }
+    }
+}
+
+impl Endpoint {
+    /// Create an endpoint for the given host name and port, e.g.
+    /// `"example.com:80"`.
+    ///
+    /// Any value accepted by `ToSocketAddrs` for strings may be used,
+    /// including IP addresses.
+    pub fn new<S: Into<String>>(host: S) -> Endpoint {
+        Endpoint {
+            inner: Arc::new(Mutex::new(EndpointInner {
+                host: host.into(),
+                refresh_interval: Duration::from_secs(30),
+                attempt_delay: Duration::from_millis(250),
+                addrs: vec![],
+                resolved_at: None,
+                next: 0,
+            })),
+        }
+    }
+
+    /// Set how long resolved addresses are used before resolving the host
+    /// name again.
+    ///
+    /// The default is 30 seconds.
+    pub fn refresh_interval(&mut self, interval: Duration) {
+        self.inner.lock().unwrap().refresh_interval = interval;
+    }
+
+    /// Set how long a connection attempt may be in progress before the next
+    /// address is tried concurrently.
+    ///
+    /// The default is 250 milliseconds.
+    pub fn attempt_delay(&mut self, delay: Duration) {
+        self.inner.lock().unwrap().attempt_delay = delay;
+    }
+
+    /// Returns the host name of the endpoint
+    pub fn host(&self) -> String {
+        self.inner.lock().unwrap().host.clone()
+    }
+
+    /// Forget the resolved addresses, so that the host name is resolved again
+    /// on the next connection.
+    pub fn invalidate(&self) {
+        self.inner.lock().unwrap().resolved_at = None;
+    }
+
+    /// Returns the cached addresses to try, rotated for the next connection
+    /// and in reverse order, or `None` if they are stale.
+    fn addrs(&self) -> Option<Vec<SocketAddr>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let fresh = match inner.resolved_at {
+            Some(at) => at.elapsed() < inner.refresh_interval,
+            None => false,
+        };
+
+        if !fresh || inner.addrs.is_empty() {
+            return None;
+        }
+
+        let start = inner.next % inner.addrs.len();
+        inner.next = start + 1;
+
+        let mut addrs = inner.addrs[start..].to_vec();
+        addrs.extend_from_slice(&inner.addrs[..start]);
+
+        let mut addrs = interleave(addrs);
+        addrs.reverse();
+
+        Some(addrs)
+    }
+
+    fn update(&self, addrs: Vec<SocketAddr>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.addrs = addrs;
+        inner.resolved_at = Some(Instant::now());
+    }
+}
+
+impl<Kind, P> ConnectEndpoint<Kind, P> {
+    /// Resolve the host name on a separate thread, as `ToSocketAddrs` blocks.
+    fn resolve(&mut self) -> State {
+        let (tx, rx) = oneshot::channel();
+        let host = self.endpoint.host();
+
+        self.resolved = true;
+
+        trace!("resolving endpoint; host={}", host);
+
+        let spawned = thread::Builder::new().name("tokio-proto-resolve".to_string()).spawn(move || {
+            let addrs = host.to_socket_addrs().map(|addrs| addrs.collect());
+            tx.complete(addrs);
+        });
+
+        if let Err(e) = spawned {
+            self.error = Some(e);
+            return State::Done;
+        }
+
+        State::Resolving(rx)
+    }
+
+    /// Drive the connection attempts, starting new ones as needed. Returns
+    /// `None` once all addresses failed.
+    fn poll_attempts(&mut self) -> Poll<Option<TcpStream>, io::Error> {
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(socket)) => return Ok(Async::Ready(Some(socket))),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        debug!("failed to connect to endpoint; err={}", e);
+                        drop(self.attempts.remove(i));
+                        self.error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+
+            // Start the next attempt right away if the previous one failed,
+            // otherwise once the attempt delay elapsed
+            let start_next = failed || self.attempts.is_empty() || match self.delay {
+                Some(ref mut delay) => try!(delay.poll()).is_ready(),
+                None => false,
+            };
+
+            if !start_next {
+                return Ok(Async::NotReady);
+            }
+
+            match self.addrs.pop() {
+                Some(addr) => {
+                    trace!("connecting to endpoint; addr={}", addr);
+                    self.attempts.push(tcp_connect(&addr, self.fast_open, &self.handle));
+                    self.delay = Some(try!(Timeout::new(self.attempt_delay, &self.handle)));
+                }
+                None => {
+                    self.delay = None;
+
+                    if self.attempts.is_empty() {
+                        return Ok(Async::Ready(None));
+                    }
+
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+impl<Kind, P> Future for ConnectEndpoint<Kind, P> where P: BindClient<Kind, TcpStream> {
+    type Item = P::BindClient;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<P::BindClient, io::Error> {
+        loop {
+            let resolved = match self.state {
+                State::Resolving(ref mut rx) => {
+                    match rx.poll() {
+                        Ok(Async::Ready(resolved)) => resolved,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "resolver thread panicked")),
+                    }
+                }
+                State::Connecting => {
+                    match try!(self.poll_attempts()) {
+                        Async::Ready(Some(socket)) => {
+                            return Ok(Async::Ready(self.proto.bind_client(&self.handle, socket)));
+                        }
+                        Async::Ready(None) => {
+                            // Resolve the host name again when the cached
+                            // addresses are exhausted
+                            self.endpoint.invalidate();
+
+                            self.state = if self.resolved {
+                                State::Done
+                            } else {
+                                self.resolve()
+                            };
+
+                            continue;
+                        }
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                }
+                State::Tunneling(ref mut tunnel) => {
+                    let socket = try_ready!(tunnel.poll());
+                    return Ok(Async::Ready(self.proto.bind_client(&self.handle, socket)));
+                }
+                State::Done => {
+                    let e = self.error.take().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "endpoint resolved to no addresses")
+                    });
+
+                    return Err(e);
+                }
+            };
+
+            // Resolution completed
+            match resolved {
+                Ok(addrs) => {
+                    self.endpoint.update(addrs);
+                    self.addrs = self.endpoint.addrs().unwrap_or(vec![]);
+                    self.state = State::Connecting;
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    self.state = State::Done;
+                }
+            }
+        }
+    }
+}
+
+/// Order the addresses so that the families alternate, starting with the
+/// family of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+
+    first.reverse();
+    second.reverse();
+
+    let mut ret = Vec::with_capacity(first.len() + second.len());
+
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return ret,
+            (a, b) => {
+                ret.extend(a);
+                ret.extend(b);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::interleave;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v6_first = addrs(&["[::1]:80", "[::2]:80", "[::3]:80", "1.0.0.1:80", "1.0.0.2:80"]);
+        assert_eq!(addrs(&["[::1]:80", "1.0.0.1:80", "[::2]:80", "1.0.0.2:80", "[::3]:80"]),
+                   interleave(v6_first));
+
+        let v4_first = addrs(&["1.0.0.1:80", "[::1]:80", "1.0.0.2:80"]);
+        assert_eq!(addrs(&["1.0.0.1:80", "[::1]:80", "1.0.0.2:80"]),
+                   interleave(v4_first));
+
+        assert_eq!(addrs(&[]), interleave(addrs(&[])));
+    }
+}
@@ -1,19 +1,37 @@
+use std::any::Any;
+use std::collections::HashMap;
+#[cfg(all(unix, feature = "systemd"))]
+use std::env;
 use std::io;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
-use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::net::{self, SocketAddr, Shutdown};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
+#[cfg(all(unix, feature = "systemd"))]
+use std::os::unix::io::{FromRawFd, RawFd};
 
 use BindServer;
+use incoming::WrapService;
+use futures::{Poll, Async};
 use futures::stream::Stream;
-use futures::future::{Then, Future};
+use futures::future::{self, Then, Future, Either, FutureResult};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+#[cfg(any(target_os = "linux", all(unix, feature = "systemd")))]
+use libc;
 use net2;
 use tokio_core::net::{TcpStream, TcpListener};
-use tokio_core::reactor::{Core, Handle};
+use tokio_core::reactor::{Core, Handle, Remote, Timeout};
 use tokio_service::{NewService, Service};
+use util::extensions::ConnectionId;
+use util::load_shed;
 
 // TODO: Add more options, e.g.:
-// - max concurrent requests
 // - request timeout
 // - read timeout
 // - write timeout
@@ -36,7 +54,88 @@ pub struct TcpServer<Kind, P> {
     _kind: PhantomData<Kind>,
     proto: Arc<P>,
     threads: usize,
+    thread_name: String,
+    on_thread_start: Option<Arc<dyn Fn() + Send + Sync>>,
+    on_thread_panic: Option<Arc<dyn Fn(Box<dyn Any + Send>) + Send + Sync>>,
+    cpus: Vec<usize>,
+    fast_open: Option<u32>,
     addr: SocketAddr,
+    // Sockets bound by someone else, e.g. systemd, served instead of `addr`
+    listeners: Vec<net::TcpListener>,
+    drain: Option<Drain>,
+    drain_timeout: Option<Duration>,
+    on_accept_error: AcceptErrorPolicy,
+    executor: Option<Arc<dyn ConnectionExecutor>>,
+    max_in_flight: Option<usize>,
+}
+
+/// Future returned by `TcpServer::serve_future`, completing once the server
+/// is shut down
+pub struct Serve {
+    inner: Box<dyn Future<Item = (), Error = io::Error>>,
+}
+
+/// Chooses the event loop each connection accepted by a `TcpServer` runs on.
+///
+/// By default, connections run on the event loop of the thread accepting
+/// them. Spawning them elsewhere keeps CPU-heavy protocols from delaying
+/// the accept loop. `Remote` runs every connection on a single event loop,
+/// and `ReactorPool` spreads them over a pool of threads.
+pub trait ConnectionExecutor: Send + Sync + 'static {
+    /// Returns the event loop the next connection is spawned on
+    fn remote(&self) -> Remote;
+}
+
+/// A pool of threads, each running an event loop, which connections are
+/// spawned on in turn.
+///
+/// The threads exit once the pool is dropped.
+pub struct ReactorPool {
+    remotes: Vec<Remote>,
+    next: AtomicUsize,
+    _shutdown: Vec<oneshot::Sender<()>>,
+}
+
+/// What a `TcpServer` does when accepting a connection fails.
+///
+/// Errors specific to the connection being accepted, e.g. the peer
+/// resetting it before it could be accepted, are skipped without applying
+/// the policy. The policy applies to the other errors, such as running out of
+/// file descriptors (`EMFILE` / `ENFILE`), which would otherwise make the
+/// accept loop spin.
+#[derive(Clone)]
+pub enum AcceptErrorPolicy {
+    /// Stop accepting connections for the given duration, then retry
+    Delay(Duration),
+
+    /// Call the closure with the error. Accepting connections is retried
+    /// after the returned duration, or the server fails if it returns `None`.
+    Callback(Arc<dyn Fn(&io::Error) -> Option<Duration> + Send + Sync>),
+
+    /// Fail the server, which makes `serve` panic
+    Fatal,
+}
+
+/// A handle for gracefully shutting down a `TcpServer`.
+///
+/// Once `start` is called, the server stops accepting connections and waits
+/// for the open connections to be closed, after which `serve` returns. If a
+/// drain timeout is configured on the server, connections still open once it
+/// elapses are forcefully closed, and the requests that were in flight on
+/// them are accounted for as dropped.
+///
+/// The handle can be cloned and triggered from any thread.
+#[derive(Clone)]
+pub struct Drain {
+    inner: Arc<DrainInner>,
+}
+
+struct DrainInner {
+    started: AtomicBool,
+    // One per event loop waiting for the drain to start
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+    aborted_connections: AtomicUsize,
+    dropped_requests: AtomicUsize,
 }
 
 impl<Kind, P> TcpServer<Kind, P> where
@@ -60,16 +159,98 @@ impl<Kind, P> TcpServer<Kind, P> where
             _kind: PhantomData,
             proto: Arc::new(protocol),
             threads: 1,
+            thread_name: "worker".to_string(),
+            on_thread_start: None,
+            on_thread_panic: None,
+            cpus: Vec::new(),
+            fast_open: None,
             addr: addr,
+            listeners: Vec::new(),
+            drain: None,
+            drain_timeout: None,
+            on_accept_error: AcceptErrorPolicy::default(),
+            executor: None,
+            max_in_flight: None,
         }
     }
 
+    /// Starts building a server for the given protocol, serving on the
+    /// sockets passed by systemd socket activation.
+    ///
+    /// The sockets are taken from the `LISTEN_FDS` and `LISTEN_PID`
+    /// environment variables, which are then removed so child processes do
+    /// not pick the sockets up as well. All of them are served, and shared
+    /// by the threads of the server. Since systemd keeps the sockets open
+    /// while the server restarts, connections made in the meantime wait in
+    /// the backlog instead of being refused.
+    ///
+    /// Fails if the process was not socket activated, or if one of the
+    /// sockets is not a TCP listener.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn from_systemd(protocol: P) -> io::Result<TcpServer<Kind, P>> {
+        TcpServer::from_listeners(protocol, try!(listen_fds(SD_LISTEN_FDS_START)))
+    }
+
+    /// Starts building a server for the given protocol, serving on sockets
+    /// that are already listening, e.g. received from the process being
+    /// replaced, see `util::handoff`.
+    ///
+    /// All of them are served, and shared by the threads of the server.
+    /// Fails if no socket is given.
+    pub fn from_listeners(protocol: P, listeners: Vec<net::TcpListener>)
+                          -> io::Result<TcpServer<Kind, P>> {
+        let addr = match listeners.first() {
+            Some(listener) => try!(listener.local_addr()),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "no listener to serve on"))
+            }
+        };
+
+        let mut server = TcpServer::new(protocol, addr);
+        server.listeners = listeners;
+        Ok(server)
+    }
+
     /// Set the address for the server.
+    ///
+    /// The address is ignored when serving on sockets that are already
+    /// listening, e.g. the ones passed by systemd.
     pub fn addr(&mut self, addr: SocketAddr) {
         self.addr = addr;
     }
 
+    /// Bind the address of the server right away, instead of once serving.
+    ///
+    /// The listening socket is then shared by the threads of the server,
+    /// instead of each thread binding its own, and is returned by
+    /// `listeners`. Does nothing if the server already has sockets to serve
+    /// on.
+    pub fn bind(&mut self) -> io::Result<()> {
+        if self.listeners.is_empty() {
+            let listener = try!(bind_listener(&self.addr, 1, self.fast_open));
+            self.addr = try!(listener.local_addr());
+            self.listeners.push(listener);
+        }
+
+        Ok(())
+    }
+
+    /// Returns handles to the listening sockets of the server, e.g. to hand
+    /// them off to the process replacing this one, see `util::handoff`.
+    ///
+    /// Only sockets the server was built with, or bound with `bind`, are
+    /// returned; those bound by the threads once serving are not.
+    pub fn listeners(&self) -> io::Result<Vec<net::TcpListener>> {
+        self.listeners.iter().map(|l| l.try_clone()).collect()
+    }
+
     /// Set the number of threads running simultaneous event loops (Unix only).
+    ///
+    /// Each thread binds its own listener with `SO_REUSEPORT`, so that the
+    /// kernel shards incoming connections across the threads, rather than
+    /// having them compete for a shared socket. Sockets the server was built
+    /// with, or bound with `bind`, are shared by the threads instead.
     pub fn threads(&mut self, threads: usize) {
         assert!(threads > 0);
         if cfg!(unix) {
@@ -77,15 +258,122 @@ impl<Kind, P> TcpServer<Kind, P> where
         }
     }
 
+    /// Set the prefix of the names of the worker threads, which are suffixed
+    /// with their index, e.g. `worker0`, `worker1` and so on.
+    ///
+    /// Worker threads are the ones spawned by the server on top of the
+    /// calling thread, see `threads`. Defaults to `worker`.
+    pub fn thread_name(&mut self, prefix: &str) {
+        self.thread_name = prefix.to_string();
+    }
+
+    /// Pin the threads serving connections to the given CPUs (Linux only).
+    ///
+    /// Worker thread `i` is pinned to `cpus[i % cpus.len()]`, and the calling
+    /// thread counts as the last one. With one thread per CPU, each event
+    /// loop, and the connections it accepts, stays on its own core instead
+    /// of bouncing packets between cores. The calling thread stays pinned
+    /// once the server shuts down. Failing to pin a thread is logged, and the
+    /// thread serves connections regardless.
+    pub fn cpu_affinity(&mut self, cpus: &[usize]) {
+        assert!(!cpus.is_empty());
+        if cfg!(target_os = "linux") {
+            self.cpus = cpus.to_vec();
+        }
+    }
+
+    /// Set a closure run on every thread serving connections, before its
+    /// event loop starts, e.g. to set up thread-local state.
+    ///
+    /// This includes the calling thread, not only the worker threads.
+    pub fn on_thread_start<F>(&mut self, f: F)
+        where F: Fn() + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(f));
+    }
+
+    /// Set a closure called with the payload of a panic of a worker thread,
+    /// on that thread, once it unwound.
+    ///
+    /// The thread stops serving connections, while the other threads keep
+    /// running. By default, the panic is propagated to the calling thread
+    /// once the server shuts down. Panics of the calling thread itself are
+    /// left alone.
+    pub fn on_thread_panic<F>(&mut self, f: F)
+        where F: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.on_thread_panic = Some(Arc::new(f));
+    }
+
+    /// Enable TCP Fast Open on the listening sockets, accepting up to `queue`
+    /// pending Fast Open handshakes (Linux only).
+    ///
+    /// Clients that connected before, and got a cookie from the server, then
+    /// send their first request along with the SYN, saving a round trip per
+    /// connection; see `TcpClient::fast_open`. This pays off for short lived
+    /// connections, e.g. oneshot ones. The sockets passed by systemd are left
+    /// alone, Fast Open is configured in their socket unit instead.
+    pub fn fast_open(&mut self, queue: u32) {
+        assert!(queue > 0);
+        self.fast_open = Some(queue);
+    }
+
+    /// Shut down the server gracefully once the given handle is triggered.
+    ///
+    /// See `Drain` for details.
+    pub fn drain(&mut self, drain: Drain) {
+        self.drain = Some(drain);
+    }
+
+    /// Set the maximum time spent waiting for connections to close once the
+    /// server is draining.
+    ///
+    /// Connections still open after the timeout are forcefully closed. By
+    /// default, the server waits for all connections to close.
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = Some(timeout);
+    }
+
+    /// Set what to do when accepting a connection fails.
+    ///
+    /// Defaults to `AcceptErrorPolicy::Delay` of one second.
+    pub fn on_accept_error(&mut self, policy: AcceptErrorPolicy) {
+        self.on_accept_error = policy;
+    }
+
+    /// Set the executor spawning the connections accepted by the server.
+    ///
+    /// The handle given to the closure of `with_handle` is still the one of
+    /// the accepting event loop. See `ConnectionExecutor` for details.
+    pub fn executor<E: ConnectionExecutor>(&mut self, executor: E) {
+        self.executor = Some(Arc::new(executor));
+    }
+
+    /// Set the maximum number of requests in flight on the server, across
+    /// all of its connections and threads.
+    ///
+    /// Once the limit is reached, new requests are not dispatched to the
+    /// service, which keeps the latency of the accepted ones bounded under
+    /// overload. They are answered right away with the protocol's busy
+    /// response instead, see `pipeline::ServerProto::busy_response`, or fail
+    /// with a `load_shed::Overloaded` error if it has none. By default, the
+    /// number of requests in flight is not limited.
+    pub fn max_in_flight(&mut self, max: usize) {
+        assert!(max > 0);
+        self.max_in_flight = Some(max);
+    }
+
     /// Start up the server, providing the given service on it.
     ///
     /// This method will block the current thread until the server is shut down.
     pub fn serve<S>(&self, new_service: S) where
+        Kind: 'static,
         S: NewService + Send + Sync + 'static,
         S::Instance: 'static,
         P::ServiceError: 'static,
         P::ServiceResponse: 'static,
         P::ServiceRequest: 'static,
+        P::ServiceError: From<io::Error>,
         S::Request: From<P::ServiceRequest>,
         S::Response: Into<P::ServiceResponse>,
         S::Error: Into<P::ServiceError>,
@@ -94,6 +382,48 @@ impl<Kind, P> TcpServer<Kind, P> where
         self.with_handle(move |_| new_service.clone())
     }
 
+    /// Returns a future serving the given service on the event loop of
+    /// `handle`, instead of blocking the current thread.
+    ///
+    /// The future completes once the server is shut down, see `Drain`, and
+    /// fails if accepting connections does, see `AcceptErrorPolicy::Fatal`.
+    /// It can be composed with other futures, e.g. selected with a shutdown
+    /// signal, and several servers can run on the same event loop. Dropping
+    /// it stops accepting connections; the connections already accepted are
+    /// still served.
+    ///
+    /// Connections are accepted on the event loop of `handle` only, the
+    /// number of threads is ignored. An executor can still run them
+    /// elsewhere, see `executor`.
+    pub fn serve_future<S>(&self, handle: &Handle, new_service: S) -> Serve where
+        Kind: 'static,
+        S: NewService + Send + Sync + 'static,
+        S::Instance: 'static,
+        P::ServiceError: 'static,
+        P::ServiceResponse: 'static,
+        P::ServiceRequest: 'static,
+        P::ServiceError: From<io::Error>,
+        S::Request: From<P::ServiceRequest>,
+        S::Response: Into<P::ServiceResponse>,
+        S::Error: Into<P::ServiceError>,
+    {
+        let listeners = self.listeners.iter()
+            .map(|l| l.try_clone())
+            .collect::<io::Result<Vec<_>>>();
+
+        let server = listeners.and_then(|listeners| {
+            serve_on(self.proto.clone(), self.settings(1), listeners, handle,
+                     Arc::new(new_service))
+        });
+
+        Serve {
+            inner: match server {
+                Ok(server) => server,
+                Err(e) => Box::new(future::err(e)),
+            },
+        }
+    }
+
     /// Start up the server, providing the given service on it, and providing
     /// access to the event loop handle.
     ///
@@ -103,67 +433,519 @@ impl<Kind, P> TcpServer<Kind, P> where
     ///
     /// This method will block the current thread until the server is shut down.
     pub fn with_handle<F, S>(&self, new_service: F) where
+        Kind: 'static,
         F: Fn(&Handle) -> S + Send + Sync + 'static,
         S: NewService + Send + Sync + 'static,
         S::Instance: 'static,
         P::ServiceError: 'static,
         P::ServiceResponse: 'static,
         P::ServiceRequest: 'static,
+        P::ServiceError: From<io::Error>,
         S::Request: From<P::ServiceRequest>,
         S::Response: Into<P::ServiceResponse>,
         S::Error: Into<P::ServiceError>,
     {
         let proto = self.proto.clone();
         let new_service = Arc::new(new_service);
-        let addr = self.addr;
-        let workers = self.threads;
+        let settings = self.settings(self.threads);
+
+        let listeners = |listeners: &[net::TcpListener]| {
+            listeners.iter().map(|l| l.try_clone()).collect::<io::Result<Vec<_>>>()
+        };
 
         let threads = (0..self.threads - 1).map(|i| {
+            let listeners = listeners(&self.listeners);
             let proto = proto.clone();
             let new_service = new_service.clone();
+            let settings = settings.clone();
+            let on_thread_start = self.on_thread_start.clone();
+            let cpu = self.cpu(i);
+            let on_thread_panic = self.on_thread_panic.clone();
 
-            thread::Builder::new().name(format!("worker{}", i)).spawn(move || {
-                serve(proto, addr, workers, &*new_service)
+            let name = format!("{}{}", self.thread_name, i);
+            thread::Builder::new().name(name).spawn(move || {
+                let work = move || {
+                    if let Some(cpu) = cpu {
+                        pin_thread(cpu);
+                    }
+
+                    if let Some(ref on_thread_start) = on_thread_start {
+                        on_thread_start();
+                    }
+
+                    serve(proto, settings, listeners, &*new_service)
+                };
+
+                match on_thread_panic {
+                    Some(on_thread_panic) => {
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(work)) {
+                            on_thread_panic(payload);
+                        }
+                    }
+                    None => work(),
+                }
             }).unwrap()
         }).collect::<Vec<_>>();
 
-        serve(proto, addr, workers, &*new_service);
+        if let Some(cpu) = self.cpu(self.threads - 1) {
+            pin_thread(cpu);
+        }
+
+        if let Some(ref on_thread_start) = self.on_thread_start {
+            on_thread_start();
+        }
+
+        serve(proto, settings, listeners(&self.listeners), &*new_service);
 
         for thread in threads {
             thread.join().unwrap();
         }
     }
+
+    // The settings of the event loops serving connections, each of the
+    // `workers` threads binding its own listener
+    fn settings(&self, workers: usize) -> Settings {
+        Settings {
+            addr: self.addr,
+            workers: workers,
+            fast_open: self.fast_open,
+            connections: Arc::new(AtomicUsize::new(0)),
+            drain: self.drain.clone(),
+            drain_timeout: self.drain_timeout,
+            on_accept_error: self.on_accept_error.clone(),
+            executor: self.executor.clone(),
+            limit: Arc::new(Limit {
+                max: self.max_in_flight,
+                in_flight: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    // The CPU the `i`th thread serving connections is pinned to, if any
+    fn cpu(&self, i: usize) -> Option<usize> {
+        if self.cpus.is_empty() {
+            None
+        } else {
+            Some(self.cpus[i % self.cpus.len()])
+        }
+    }
+}
+
+impl Future for Serve {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll()
+    }
+}
+
+impl ConnectionExecutor for Remote {
+    fn remote(&self) -> Remote {
+        self.clone()
+    }
+}
+
+impl ReactorPool {
+    /// Start a pool of `threads` event loops
+    pub fn new(threads: usize) -> io::Result<ReactorPool> {
+        assert!(threads > 0);
+
+        let mut remotes = Vec::with_capacity(threads);
+        let mut shutdown = Vec::with_capacity(threads);
+
+        for i in 0..threads {
+            let (remote_tx, remote_rx) = ::std::sync::mpsc::channel();
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+            try!(thread::Builder::new().name(format!("tokio-proto-pool{}", i)).spawn(move || {
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(e) => {
+                        drop(remote_tx.send(Err(e)));
+                        return;
+                    }
+                };
+
+                drop(remote_tx.send(Ok(core.remote())));
+
+                // Runs until the pool is dropped, which cancels the shutdown
+                // receiver; either outcome ends the thread
+                let _ = core.run(shutdown_rx);
+            }));
+
+            let remote = try!(remote_rx.recv().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "event loop thread panicked"))
+            }));
+
+            remotes.push(remote);
+            shutdown.push(shutdown_tx);
+        }
+
+        Ok(ReactorPool {
+            remotes: remotes,
+            next: AtomicUsize::new(0),
+            _shutdown: shutdown,
+        })
+    }
+}
+
+impl ConnectionExecutor for ReactorPool {
+    fn remote(&self) -> Remote {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.remotes[i % self.remotes.len()].clone()
+    }
+}
+
+impl Default for AcceptErrorPolicy {
+    fn default() -> AcceptErrorPolicy {
+        AcceptErrorPolicy::Delay(Duration::from_secs(1))
+    }
+}
+
+/// Accepts connections, applying the accept error policy
+struct Incoming {
+    listeners: Vec<TcpListener>,
+    // The listener accepted from first, so that all of them are served
+    next: usize,
+    policy: AcceptErrorPolicy,
+    handle: Handle,
+    backoff: Option<Timeout>,
+}
+
+impl Incoming {
+    // Accept a connection from the first listener having one pending
+    fn accept(&mut self) -> io::Result<Option<(net::TcpStream, SocketAddr)>> {
+        for _ in 0..self.listeners.len() {
+            let i = self.next;
+            self.next = (i + 1) % self.listeners.len();
+
+            match self.listeners[i].accept_std() {
+                Ok(accepted) => return Ok(Some(accepted)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Stream for Incoming {
+    type Item = (net::TcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            if let Some(mut backoff) = self.backoff.take() {
+                if !try!(backoff.poll()).is_ready() {
+                    self.backoff = Some(backoff);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            let e = match self.accept() {
+                Ok(Some(accepted)) => return Ok(Async::Ready(Some(accepted))),
+                Ok(None) => return Ok(Async::NotReady),
+                Err(e) => e,
+            };
+
+            match e.kind() {
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::Interrupted => {
+                    debug!("failed to accept connection; err={}", e);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let delay = match self.policy {
+                AcceptErrorPolicy::Delay(delay) => Some(delay),
+                AcceptErrorPolicy::Callback(ref f) => f(&e),
+                AcceptErrorPolicy::Fatal => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    warn!("failed to accept connection; retrying in {:?}; err={}", delay, e);
+                    self.backoff = Some(try!(Timeout::new(delay, &self.handle)));
+                }
+                None => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drain {
+    /// Return a new handle, which has not been triggered yet
+    pub fn new() -> Drain {
+        Drain {
+            inner: Arc::new(DrainInner {
+                started: AtomicBool::new(false),
+                waiters: Mutex::new(Vec::new()),
+                aborted_connections: AtomicUsize::new(0),
+                dropped_requests: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Start draining the servers using this handle
+    pub fn start(&self) {
+        self.inner.started.store(true, Ordering::SeqCst);
+
+        let waiters = ::std::mem::replace(&mut *self.inner.waiters.lock().unwrap(), Vec::new());
+
+        for waiter in waiters {
+            waiter.complete(());
+        }
+    }
+
+    /// Returns true once draining has started
+    pub fn is_started(&self) -> bool {
+        self.inner.started.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of connections which were forcefully closed because
+    /// they were still open when the drain timeout elapsed
+    pub fn aborted_connections(&self) -> usize {
+        self.inner.aborted_connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests that were in flight on the forcefully
+    /// closed connections
+    pub fn dropped_requests(&self) -> usize {
+        self.inner.dropped_requests.load(Ordering::SeqCst)
+    }
+
+    /// Returns a future completing once draining starts
+    fn wait(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.inner.waiters.lock().unwrap();
+
+        if self.is_started() {
+            tx.complete(());
+        } else {
+            waiters.push(tx);
+        }
+
+        rx
+    }
+}
+
+impl Default for Drain {
+    fn default() -> Drain {
+        Drain::new()
+    }
+}
+
+/// The connections open on a single event loop
+struct Connections {
+    next_id: usize,
+    open: HashMap<usize, OpenConnection>,
+    // Notified when the last connection is closed
+    idle_task: Option<Task>,
+}
+
+struct OpenConnection {
+    // A second handle to the socket, used to close it when the drain timeout
+    // elapses. Only set when a timeout is configured.
+    socket: Option<net::TcpStream>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Removes the connection from the open connections when the service bound to
+/// it is dropped
+struct ConnectionGuard {
+    id: usize,
+    connections: Arc<Mutex<Connections>>,
+}
+
+/// A response future, counted as in flight until it completes
+struct InFlight<F> {
+    inner: F,
+    in_flight: Arc<AtomicUsize>,
+    limit: Arc<Limit>,
+}
+
+/// The settings of a server, shared by the event loops accepting its
+/// connections
+#[derive(Clone)]
+struct Settings {
+    addr: SocketAddr,
+    workers: usize,
+    fast_open: Option<u32>,
+    connections: Arc<AtomicUsize>,
+    drain: Option<Drain>,
+    drain_timeout: Option<Duration>,
+    on_accept_error: AcceptErrorPolicy,
+    executor: Option<Arc<dyn ConnectionExecutor>>,
+    limit: Arc<Limit>,
+}
+
+/// The requests in flight on the server, across all event loops
+struct Limit {
+    max: Option<usize>,
+    in_flight: AtomicUsize,
 }
 
-fn serve<P, Kind, F, S>(binder: Arc<P>, addr: SocketAddr, workers: usize, new_service: &F)
-    where P: BindServer<Kind, TcpStream>,
+/// Completes once all connections are closed
+struct Idle {
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl Connections {
+    fn new() -> Connections {
+        Connections {
+            next_id: 0,
+            open: HashMap::new(),
+            idle_task: None,
+        }
+    }
+
+    fn insert(&mut self, conn: OpenConnection) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, conn);
+        id
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = lock(&self.connections);
+        connections.open.remove(&self.id);
+
+        if connections.open.is_empty() {
+            if let Some(task) = connections.idle_task.take() {
+                task.unpark();
+            }
+        }
+    }
+}
+
+impl<F: Future> Future for InFlight<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<F> Drop for InFlight<F> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.limit.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limit {
+    // Counts a new request as in flight, unless the limit is reached
+    fn acquire(&self) -> bool {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        match self.max {
+            Some(max) if in_flight >= max => {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Future for Idle {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut connections = lock(&self.connections);
+
+        if connections.open.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        connections.idle_task = Some(task::park());
+        Ok(Async::NotReady)
+    }
+}
+
+fn serve<P, Kind, F, S>(binder: Arc<P>,
+                        settings: Settings,
+                        listeners: io::Result<Vec<net::TcpListener>>,
+                        new_service: &F)
+    where P: BindServer<Kind, TcpStream> + Send + Sync + 'static,
+          Kind: 'static,
           F: Fn(&Handle) -> S,
-          S: NewService + Send + Sync,
+          S: NewService + Send + Sync + 'static,
           S::Instance: 'static,
           P::ServiceError: 'static,
           P::ServiceResponse: 'static,
           P::ServiceRequest: 'static,
+          P::ServiceError: From<io::Error>,
           S::Request: From<P::ServiceRequest>,
           S::Response: Into<P::ServiceResponse>,
           S::Error: Into<P::ServiceError>,
 {
-    struct WrapService<S, Request, Response, Error> {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let new_service = Arc::new(new_service(&handle));
+
+    let server = listeners.and_then(|listeners| {
+        serve_on(binder, settings, listeners, &handle, new_service)
+    });
+
+    if let Err(e) = server.and_then(|server| core.run(server)) {
+        panic!("server failed; err={:?}", e);
+    }
+}
+
+/// Returns a future accepting connections on the event loop of `handle`
+/// until the server is shut down
+fn serve_on<P, Kind, S>(binder: Arc<P>,
+                        settings: Settings,
+                        listeners: Vec<net::TcpListener>,
+                        handle: &Handle,
+                        new_service: Arc<S>)
+                        -> io::Result<Box<dyn Future<Item = (), Error = io::Error>>>
+    where P: BindServer<Kind, TcpStream> + Send + Sync + 'static,
+          Kind: 'static,
+          S: NewService + Send + Sync + 'static,
+          S::Instance: 'static,
+          P::ServiceError: 'static,
+          P::ServiceResponse: 'static,
+          P::ServiceRequest: 'static,
+          P::ServiceError: From<io::Error>,
+          S::Request: From<P::ServiceRequest>,
+          S::Response: Into<P::ServiceResponse>,
+          S::Error: Into<P::ServiceError>,
+{
+    /// Sheds the requests over the limit of the server, and tracks the ones
+    /// in flight on the connection
+    struct LimitService<S, Request, Response, Error> {
         inner: S,
+        busy: fn(&Request) -> Option<Response>,
+        in_flight: Arc<AtomicUsize>,
+        limit: Arc<Limit>,
+        _guard: ConnectionGuard,
         _marker: PhantomData<fn() -> (Request, Response, Error)>,
     }
 
-    impl<S, Request, Response, Error> Service for WrapService<S, Request, Response, Error>
+    impl<S, Request, Response, Error> Service for LimitService<S, Request, Response, Error>
         where S: Service,
               S::Request: From<Request>,
               S::Response: Into<Response>,
               S::Error: Into<Error>,
+              Error: From<io::Error>,
     {
         type Request = Request;
         type Response = Response;
         type Error = Error;
-        type Future = Then<S::Future,
-                           Result<Response, Error>,
-                           fn(Result<S::Response, S::Error>) -> Result<Response, Error>>;
+        type Future = Either<InFlight<Then<S::Future,
+                                           Result<Response, Error>,
+                                           fn(Result<S::Response, S::Error>) -> Result<Response, Error>>>,
+                             FutureResult<Response, Error>>;
 
         fn call(&self, req: Request) -> Self::Future {
             fn change_types<A, B, C, D>(r: Result<A, B>) -> Result<C, D>
@@ -176,34 +958,248 @@ fn serve<P, Kind, F, S>(binder: Arc<P>, addr: SocketAddr, workers: usize, new_se
                 }
             }
 
-            self.inner.call(S::Request::from(req)).then(change_types)
+            if !self.limit.acquire() {
+                trace!("server busy; shedding request");
+
+                let res = match (self.busy)(&req) {
+                    Some(response) => Ok(response),
+                    None => Err(load_shed::overloaded().into()),
+                };
+
+                return Either::B(future::result(res));
+            }
+
+            self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+            Either::A(InFlight {
+                inner: self.inner.call(S::Request::from(req)).then(change_types),
+                in_flight: self.in_flight.clone(),
+                limit: self.limit.clone(),
+            })
         }
     }
 
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let new_service = new_service(&handle);
-    let listener = listener(&addr, workers, &handle).unwrap();
+    /// An accepted connection, not bound yet
+    struct Accepted {
+        socket: net::TcpStream,
+        peer_addr: SocketAddr,
+        connection_id: ConnectionId,
+        in_flight: Arc<AtomicUsize>,
+        limit: Arc<Limit>,
+        guard: ConnectionGuard,
+    }
+
+    fn bind<P, Kind, S>(binder: &P, handle: &Handle, new_service: &S, conn: Accepted)
+                        -> io::Result<()>
+        where P: BindServer<Kind, TcpStream>,
+              S: NewService,
+              S::Instance: 'static,
+              P::ServiceError: 'static,
+              P::ServiceResponse: 'static,
+              P::ServiceRequest: 'static,
+              P::ServiceError: From<io::Error>,
+              S::Request: From<P::ServiceRequest>,
+              S::Response: Into<P::ServiceResponse>,
+              S::Error: Into<P::ServiceError>,
+    {
+        let socket = try!(TcpStream::from_stream(conn.socket, handle));
 
-    let server = listener.incoming().for_each(move |(socket, _)| {
         // Create the service
         let service = try!(new_service.new_service());
 
         // Bind it!
-        binder.bind_server(&handle, socket, WrapService {
+        let service = LimitService {
             inner: service,
+            busy: P::busy_response,
+            in_flight: conn.in_flight,
+            limit: conn.limit,
+            _guard: conn.guard,
             _marker: PhantomData,
+        };
+
+        binder.bind_server(handle, socket, WrapService::new(service,
+                                                            P::request_extensions,
+                                                            conn.connection_id,
+                                                            conn.peer_addr));
+
+        Ok(())
+    }
+
+    let Settings {
+        addr,
+        workers,
+        fast_open,
+        connections,
+        drain,
+        drain_timeout,
+        on_accept_error,
+        executor,
+        limit,
+    } = settings;
+
+    let listeners = if listeners.is_empty() {
+        vec![try!(listener(&addr, workers, fast_open, handle))]
+    } else {
+        try!(listeners.into_iter().map(|l| {
+            let addr = try!(l.local_addr());
+            TcpListener::from_listener(l, &addr, handle)
+        }).collect::<io::Result<_>>())
+    };
+    let open = Arc::new(Mutex::new(Connections::new()));
+    let track_sockets = drain.is_some() && drain_timeout.is_some();
+
+    let incoming = Incoming {
+        listeners: listeners,
+        next: 0,
+        policy: on_accept_error,
+        handle: handle.clone(),
+        backoff: None,
+    };
+
+    let inner_handle = handle.clone();
+    let inner_open = open.clone();
+
+    let server = incoming.for_each(move |(socket, peer_addr)| {
+        let tracked = if track_sockets {
+            Some(try!(socket.try_clone()))
+        } else {
+            None
+        };
+
+        let connection_id = ConnectionId(connections.fetch_add(1, Ordering::Relaxed));
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let id = lock(&inner_open).insert(OpenConnection {
+            socket: tracked,
+            in_flight: in_flight.clone(),
+        });
+
+        let conn = Accepted {
+            socket: socket,
+            peer_addr: peer_addr,
+            connection_id: connection_id,
+            in_flight: in_flight,
+            limit: limit.clone(),
+            guard: ConnectionGuard {
+                id: id,
+                connections: inner_open.clone(),
+            },
+        };
+
+        let executor = match executor {
+            Some(ref executor) => executor,
+            None => return bind::<P, Kind, S>(&*binder, &inner_handle, &*new_service, conn),
+        };
+
+        let binder = binder.clone();
+        let new_service = new_service.clone();
+
+        executor.remote().spawn(move |handle| {
+            if let Err(e) = bind::<P, Kind, S>(&*binder, handle, &*new_service, conn) {
+                error!("failed to bind connection; err={}", e);
+            }
+
+            Ok(())
         });
 
         Ok(())
     });
 
-    core.run(server).unwrap();
+    let drain = match drain {
+        Some(drain) => drain,
+        None => return Ok(Box::new(server)),
+    };
+
+    // Serve until draining starts, then stop accepting connections by
+    // dropping the listener
+    let draining = server.select2(drain.wait()).then(|res| {
+        match res {
+            Ok(Either::A(..)) => Ok(false),
+            Ok(Either::B((_, server))) => {
+                drop(server);
+                Ok(true)
+            }
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((_, server))) => {
+                drop(server);
+                Ok(true)
+            }
+        }
+    });
+
+    let handle = handle.clone();
+
+    let drained = draining.and_then(move |draining| -> Box<dyn Future<Item = (), Error = io::Error>> {
+        if !draining {
+            return Box::new(future::ok(()));
+        }
+
+        debug!("draining server; open-connections={}", lock(&open).open.len());
+
+        let idle = Idle { connections: open.clone() };
+
+        let idle: Box<dyn Future<Item = bool, Error = io::Error>> = match drain_timeout {
+            Some(timeout) => {
+                let timeout = match Timeout::new(timeout, &handle) {
+                    Ok(timeout) => timeout,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+
+                Box::new(idle.select2(timeout).then(|res| {
+                    match res {
+                        Ok(Either::A(..)) => Ok(true),
+                        Ok(Either::B(..)) => Ok(false),
+                        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+                    }
+                }))
+            }
+            None => Box::new(idle.map(|()| true)),
+        };
+
+        Box::new(idle.map(move |drained| {
+            if drained {
+                return;
+            }
+
+            // The timeout elapsed, close the lingering connections
+            for conn in lock(&open).open.values() {
+                if let Some(ref socket) = conn.socket {
+                    if let Err(e) = socket.shutdown(Shutdown::Both) {
+                        debug!("failed to shut down connection; err={}", e);
+                    }
+                }
+
+                drain.inner.aborted_connections.fetch_add(1, Ordering::SeqCst);
+                drain.inner.dropped_requests.fetch_add(conn.in_flight.load(Ordering::SeqCst),
+                                                       Ordering::SeqCst);
+            }
+
+            debug!("drain timeout elapsed; aborted-connections={}", lock(&open).open.len());
+        }))
+    });
+
+    Ok(Box::new(drained))
+}
+
+fn lock<'a>(connections: &'a Mutex<Connections>) -> MutexGuard<'a, Connections> {
+    match connections.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
 }
 
 fn listener(addr: &SocketAddr,
             workers: usize,
+            fast_open: Option<u32>,
             handle: &Handle) -> io::Result<TcpListener> {
+    bind_listener(addr, workers, fast_open).and_then(|l| {
+        TcpListener::from_listener(l, addr, handle)
+    })
+}
+
+fn bind_listener(addr: &SocketAddr,
+                 workers: usize,
+                 fast_open: Option<u32>) -> io::Result<net::TcpListener> {
     let listener = match *addr {
         SocketAddr::V4(_) => try!(net2::TcpBuilder::new_v4()),
         SocketAddr::V6(_) => try!(net2::TcpBuilder::new_v6()),
@@ -211,9 +1207,135 @@ fn listener(addr: &SocketAddr,
     try!(configure_tcp(workers, &listener));
     try!(listener.reuse_address(true));
     try!(listener.bind(addr));
-    listener.listen(1024).and_then(|l| {
-        TcpListener::from_listener(l, addr, handle)
-    })
+    if let Some(queue) = fast_open {
+        try!(set_fast_open(&listener, queue));
+    }
+    listener.listen(1024)
+}
+
+/// The first file descriptor passed by systemd
+#[cfg(all(unix, feature = "systemd"))]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the sockets passed by systemd, numbered from `start`
+#[cfg(all(unix, feature = "systemd"))]
+fn listen_fds(start: RawFd) -> io::Result<Vec<net::TcpListener>> {
+    fn not_activated() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "no sockets passed by systemd")
+    }
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok());
+
+    // The sockets are meant for this process only
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if pid != Some(::std::process::id()) {
+        return Err(not_activated());
+    }
+
+    let fds = match fds {
+        Some(fds) if fds > 0 => fds,
+        _ => return Err(not_activated()),
+    };
+
+    (start..start + fds).map(|fd| {
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if try!(getsockopt_int(fd, libc::SO_TYPE)) != libc::SOCK_STREAM ||
+           try!(getsockopt_int(fd, libc::SO_ACCEPTCONN)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "passed socket is not a listening stream socket"));
+        }
+
+        let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+
+        // Fails for stream sockets other than TCP ones, e.g. Unix ones
+        try!(listener.local_addr());
+        Ok(listener)
+    }).collect()
+}
+
+#[cfg(all(unix, feature = "systemd"))]
+fn getsockopt_int(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         opt,
+                         &mut value as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+fn pin_thread(cpu: usize) {
+    match set_affinity(cpu) {
+        Ok(()) => trace!("pinned thread; cpu={}", cpu),
+        Err(e) => warn!("failed to pin thread; cpu={}; err={}", cpu, e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpu: usize) -> io::Result<()> {
+    // `CPU_SET` does not check its index
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index out of range"));
+    }
+
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpu: usize) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(tcp: &net2::TcpBuilder, queue: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let queue = queue as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(tcp.as_raw_fd(),
+                         libc::IPPROTO_TCP,
+                         libc::TCP_FASTOPEN,
+                         &queue as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_tcp: &net2::TcpBuilder, _queue: u32) -> io::Result<()> {
+    Ok(())
 }
 
 #[cfg(unix)]
@@ -231,3 +1353,103 @@ fn configure_tcp(workers: usize, tcp: &net2::TcpBuilder) -> io::Result<()> {
 fn configure_tcp(_workers: usize, _tcp: &net2::TcpBuilder) -> io::Result<()> {
     Ok(())
 }
+
+#[cfg(all(test, unix, feature = "systemd"))]
+mod test {
+    use std::env;
+    use std::net;
+    use std::os::unix::io::IntoRawFd;
+    use std::process;
+
+    use net2;
+
+    use super::listen_fds;
+
+    #[test]
+    fn test_listen_fds() {
+        let a = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = a.local_addr().unwrap();
+
+        // Passed sockets are numbered consecutively
+        let fd = a.into_raw_fd();
+        env::set_var("LISTEN_PID", process::id().to_string());
+        env::set_var("LISTEN_FDS", "1");
+
+        let listeners = listen_fds(fd).unwrap();
+        assert_eq!(1, listeners.len());
+        assert_eq!(addr, listeners[0].local_addr().unwrap());
+
+        // The variables are consumed
+        assert!(env::var("LISTEN_FDS").is_err());
+        assert!(listen_fds(fd).is_err());
+
+        // Sockets passed to another process are ignored
+        env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "1");
+        assert_eq!(::std::io::ErrorKind::NotFound, listen_fds(fd).unwrap_err().kind());
+
+        // Only listening TCP sockets are served
+        let udp = net::UdpSocket::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let tcp = net2::TcpBuilder::new_v4().unwrap();
+        tcp.bind("127.0.0.1:0").unwrap();
+        let unlistened = tcp.to_tcp_stream().unwrap().into_raw_fd();
+
+        for &fd in &[udp, unlistened] {
+            env::set_var("LISTEN_PID", process::id().to_string());
+            env::set_var("LISTEN_FDS", "1");
+            assert_eq!(::std::io::ErrorKind::InvalidInput, listen_fds(fd).unwrap_err().kind());
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod affinity_test {
+    use std::io;
+    use std::mem;
+    use std::thread;
+
+    use libc;
+
+    use super::set_affinity;
+
+    #[test]
+    fn test_set_affinity() {
+        thread::spawn(|| {
+            // Pin to the last CPU the thread may run on, which is not
+            // necessarily CPU 0 in a container
+            let cpu = unsafe {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                assert_eq!(0, libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(),
+                                                      &mut set));
+                (0..libc::CPU_SETSIZE as usize).filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                    .last()
+                    .unwrap()
+            };
+
+            set_affinity(cpu).unwrap();
+            assert_eq!(cpu as libc::c_int, unsafe { libc::sched_getcpu() });
+
+            let err = set_affinity(libc::CPU_SETSIZE as usize).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }).join().unwrap();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod listener_test {
+    use super::bind_listener;
+
+    #[test]
+    fn test_worker_listeners_share_the_port() {
+        // Each worker binds its own listener, the kernel sharding connections
+        // between them
+        let a = bind_listener(&"127.0.0.1:0".parse().unwrap(), 2, None).unwrap();
+        let addr = a.local_addr().unwrap();
+        let b = bind_listener(&addr, 2, None).unwrap();
+        assert_eq!(addr, b.local_addr().unwrap());
+
+        // A single thread does not let others bind the port
+        let c = bind_listener(&"127.0.0.1:0".parse().unwrap(), 1, None).unwrap();
+        assert!(bind_listener(&c.local_addr().unwrap(), 1, None).is_err());
+    }
+}
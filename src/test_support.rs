@@ -0,0 +1,30 @@
+//! Fixtures shared by the unit tests
+
+use std::io;
+
+use tokio_core::io::{Codec, EasyBuf};
+
+/// Newline terminated lines
+#[derive(Clone)]
+pub struct Lines;
+
+impl Codec for Lines {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Vec<u8>>> {
+        match buf.as_slice().iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let line = buf.drain_to(i + 1);
+                Ok(Some(line.as_slice()[..i].to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(&msg);
+        buf.push(b'\n');
+        Ok(())
+    }
+}
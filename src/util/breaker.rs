@@ -0,0 +1,391 @@
+//! Circuit breaker for client services
+//!
+//! `CircuitBreaker` wraps the client service of a single endpoint and tracks
+//! the outcome of its most recent requests. Once the rate of failed requests
+//! reaches a threshold, the breaker opens: requests fail right away, without
+//! being sent, until the endpoint had some time to recover. The breaker then
+//! lets a single request through as a probe, and closes again if it succeeds.
+//!
+//! ```ignore
+//! let mut config = BreakerConfig::new();
+//! config.failure_rate(0.25);
+//! config.open_for(Duration::from_secs(10));
+//!
+//! let client = CircuitBreaker::new(client, config);
+//! ```
+//!
+//! Code spreading requests over several endpoints can skip those with an open
+//! breaker via `CircuitBreaker::state`. Requests failed by an open breaker are
+//! told apart from failures of the endpoint with `is_breaker_open`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll, Async};
+use tokio_service::Service;
+
+/// Configuration of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    failure_rate: f64,
+    window: usize,
+    open_for: Duration,
+}
+
+/// The state of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are sent
+    Closed,
+    /// Requests fail without being sent
+    Open,
+    /// A single request is let through to probe the endpoint
+    HalfOpen,
+}
+
+/// The error a request is failed with when the breaker is open
+///
+/// It is wrapped in an `io::Error` of kind `Other`; see `is_breaker_open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerOpen;
+
+/// A service failing fast while its endpoint keeps failing
+pub struct CircuitBreaker<S> {
+    inner: S,
+    state: Rc<RefCell<State>>,
+}
+
+/// Response future of `CircuitBreaker`
+pub struct Tracked<F> {
+    inner: Option<F>,
+    // Failed without being sent, as the breaker was open
+    rejected: bool,
+    probe: bool,
+    state: Rc<RefCell<State>>,
+}
+
+struct State {
+    config: BreakerConfig,
+    // Outcomes of the most recent requests, true for failures
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    status: Status,
+}
+
+enum Status {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+impl fmt::Display for BreakerOpen {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("circuit breaker open")
+    }
+}
+
+impl Error for BreakerOpen {}
+
+/// Returns an `io::Error` wrapping `BreakerOpen`
+pub fn breaker_open() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, BreakerOpen)
+}
+
+/// Returns true if the error was caused by an open breaker
+pub fn is_breaker_open(error: &io::Error) -> bool {
+    error.get_ref().map(|e| e.is::<BreakerOpen>()).unwrap_or(false)
+}
+
+impl BreakerConfig {
+    /// Returns the default configuration: the breaker opens once half of
+    /// the last 20 requests failed, for 5 seconds.
+    pub fn new() -> BreakerConfig {
+        BreakerConfig {
+            failure_rate: 0.5,
+            window: 20,
+            open_for: Duration::from_secs(5),
+        }
+    }
+
+    /// Set the rate of failed requests, between 0 and 1, opening the breaker
+    pub fn failure_rate(&mut self, rate: f64) {
+        self.failure_rate = rate;
+    }
+
+    /// Set the number of most recent requests the failure rate is computed
+    /// over. The breaker does not open before that many requests completed.
+    pub fn window(&mut self, requests: usize) {
+        assert!(requests > 0);
+        self.window = requests;
+    }
+
+    /// Set how long the breaker stays open before probing the endpoint
+    pub fn open_for(&mut self, duration: Duration) {
+        self.open_for = duration;
+    }
+}
+
+impl Default for BreakerConfig {
+    fn default() -> BreakerConfig {
+        BreakerConfig::new()
+    }
+}
+
+impl<S> CircuitBreaker<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    /// Wrap the client service `inner`
+    pub fn new(inner: S, config: BreakerConfig) -> CircuitBreaker<S> {
+        CircuitBreaker {
+            inner: inner,
+            state: Rc::new(RefCell::new(State {
+                config: config,
+                outcomes: VecDeque::with_capacity(config.window),
+                failures: 0,
+                status: Status::Closed,
+            })),
+        }
+    }
+
+    /// Returns the current state of the breaker
+    pub fn state(&self) -> BreakerState {
+        let mut state = self.state.borrow_mut();
+        state.expire();
+
+        match state.status {
+            Status::Closed => BreakerState::Closed,
+            Status::Open { .. } => BreakerState::Open,
+            Status::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Service for CircuitBreaker<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Tracked<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let probe = {
+            let mut state = self.state.borrow_mut();
+            state.expire();
+
+            match state.status {
+                Status::Closed => false,
+                Status::HalfOpen { ref mut probing } if !*probing => {
+                    *probing = true;
+                    true
+                }
+                _ => {
+                    trace!("circuit breaker open; failing request");
+
+                    return Tracked {
+                        inner: None,
+                        rejected: true,
+                        probe: false,
+                        state: self.state.clone(),
+                    };
+                }
+            }
+        };
+
+        Tracked {
+            inner: Some(self.inner.call(req)),
+            rejected: false,
+            probe: probe,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl State {
+    // Move from open to half-open once the open duration elapsed
+    fn expire(&mut self) {
+        let expired = match self.status {
+            Status::Open { until } => until <= Instant::now(),
+            _ => false,
+        };
+
+        if expired {
+            debug!("circuit breaker half-open; probing");
+            self.status = Status::HalfOpen { probing: false };
+        }
+    }
+
+    fn record(&mut self, failed: bool, probe: bool) {
+        if probe {
+            if failed {
+                self.open();
+            } else {
+                debug!("circuit breaker closed");
+                self.status = Status::Closed;
+                self.outcomes.clear();
+                self.failures = 0;
+            }
+            return;
+        }
+
+        // Outcomes of requests sent before the breaker opened do not count
+        if let Status::Closed = self.status {
+            if self.outcomes.len() == self.config.window {
+                if self.outcomes.pop_front() == Some(true) {
+                    self.failures -= 1;
+                }
+            }
+
+            self.outcomes.push_back(failed);
+
+            if failed {
+                self.failures += 1;
+            }
+
+            let rate = self.failures as f64 / self.outcomes.len() as f64;
+
+            if self.outcomes.len() == self.config.window && rate >= self.config.failure_rate {
+                self.open();
+            }
+        }
+    }
+
+    fn open(&mut self) {
+        debug!("circuit breaker open; failures={}/{}", self.failures, self.outcomes.len());
+        self.status = Status::Open { until: Instant::now() + self.config.open_for };
+    }
+}
+
+impl<F> Future for Tracked<F>
+    where F: Future,
+          F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        if self.rejected {
+            self.rejected = false;
+            return Err(breaker_open().into());
+        }
+
+        let res = self.inner.as_mut().expect("cannot poll Tracked twice").poll();
+
+        match res {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(_)) => self.state.borrow_mut().record(false, self.probe),
+            Err(_) => self.state.borrow_mut().record(true, self.probe),
+        }
+
+        self.inner = None;
+        self.probe = false;
+        res
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        // A dropped probe lets another request probe the endpoint
+        if self.probe {
+            if let Status::HalfOpen { ref mut probing } = self.state.borrow_mut().status {
+                *probing = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use super::{CircuitBreaker, BreakerConfig, BreakerState, is_breaker_open};
+
+    /// Fails odd requests
+    struct Odd;
+
+    impl Service for Odd {
+        type Request = u32;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            if req % 2 == 1 {
+                future::err(io::Error::new(io::ErrorKind::ConnectionReset, "odd"))
+            } else {
+                future::ok(req)
+            }
+        }
+    }
+
+    fn breaker() -> CircuitBreaker<Odd> {
+        let mut config = BreakerConfig::new();
+        config.window(4);
+        config.open_for(Duration::from_millis(50));
+        CircuitBreaker::new(Odd, config)
+    }
+
+    #[test]
+    fn test_opens_at_failure_rate() {
+        let service = breaker();
+
+        for &req in &[0, 2, 1, 4] {
+            drop(service.call(req).wait());
+        }
+        assert_eq!(BreakerState::Closed, service.state());
+
+        // Two failures out of the last four requests
+        drop(service.call(3).wait());
+        assert_eq!(BreakerState::Open, service.state());
+
+        let e = service.call(2).wait().unwrap_err();
+        assert!(is_breaker_open(&e));
+        assert!(!is_breaker_open(&io::Error::new(io::ErrorKind::Other, "other")));
+    }
+
+    #[test]
+    fn test_probe_closes_or_reopens() {
+        let service = breaker();
+
+        for &req in &[1, 3, 5, 7] {
+            drop(service.call(req).wait());
+        }
+        assert_eq!(BreakerState::Open, service.state());
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(BreakerState::HalfOpen, service.state());
+
+        // A single probe is let through
+        let probe = service.call(1);
+        assert!(is_breaker_open(&service.call(2).wait().unwrap_err()));
+
+        // The failure of the endpoint is not mistaken for the open breaker
+        assert!(!is_breaker_open(&probe.wait().unwrap_err()));
+        assert_eq!(BreakerState::Open, service.state());
+
+        thread::sleep(Duration::from_millis(60));
+
+        // A dropped probe does not count
+        drop(service.call(1));
+        assert_eq!(2, service.call(2).wait().unwrap());
+        assert_eq!(BreakerState::Closed, service.state());
+        assert_eq!(4, service.call(4).wait().unwrap());
+    }
+}
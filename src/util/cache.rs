@@ -0,0 +1,426 @@
+//! Response caching for servers and clients
+//!
+//! `Cache` wraps a service and computes a key for every request. Responses are stored under their key, and later requests with
+//! the same key are answered from the cache without calling the service,
+//! until the entry expires:
+//!
+//! ```ignore
+//! let mut cache = Cache::new(new_service, |req: &Lookup| Some(req.key.clone()));
+//! cache.ttl(Duration::from_secs(30));
+//! cache.max_entries(10_000);
+//!
+//! server.serve(cache);
+//! ```
+//!
+//! A `Cache` wrapping a `NewService` is itself a `NewService`, whose
+//! instances all share the same entries, so that a response computed for one
+//! connection is served to the others as well, on every thread of the
+//! server.
+//!
+//! On the client side, `Cache` wraps a bound client, e.g. to keep widely
+//! shared lookups from hitting the network on every call. Clones of a
+//! `Cache` share its entries, and `share` wraps another client with them,
+//! e.g. that of another thread, so that every task of a process benefits
+//! from the responses cached by the others:
+//!
+//! ```ignore
+//! let cache = Cache::new((), |req: &Lookup| Some(req.key.clone()));
+//!
+//! // On every worker thread, with a client of its own
+//! let client = cache.share(client);
+//!
+//! // Once the data changes upstream
+//! cache.invalidate(&key);
+//! ```
+//!
+//! Requests for which the key function returns `None`, e.g. those that are
+//! not idempotent, are always passed to the service, and errors are never
+//! cached. Responses are cloned out of the cache, which limits caching to
+//! protocols whose responses do not stream a body, i.e. simple protocols.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll, Async};
+use tokio_service::{Service, NewService};
+
+/// A service answering requests from a cache of the responses of another
+pub struct Cache<S, K, R, F> {
+    inner: S,
+    key: Arc<F>,
+    store: Arc<Mutex<Store<K, R>>>,
+}
+
+/// Response future of `Cache`
+pub struct Cached<F: Future, K> {
+    state: State<F, K>,
+    store: Arc<Mutex<Store<K, F::Item>>>,
+}
+
+enum State<F: Future, K> {
+    Hit(Option<F::Item>),
+    // The key to store the response under, if any
+    Miss(F, Option<K>),
+}
+
+struct Store<K, R> {
+    ttl: Duration,
+    max: usize,
+    entries: HashMap<K, Entry<R>>,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry<R> {
+    response: R,
+    expires: Instant,
+}
+
+impl<S, K, R, F> Cache<S, K, R, F>
+    where K: Hash + Eq,
+{
+    /// Wrap `inner`, caching the responses to the requests for which `key`
+    /// returns a value.
+    ///
+    /// Entries expire after a minute by default, and their number is not
+    /// bounded.
+    pub fn new(inner: S, key: F) -> Cache<S, K, R, F> {
+        Cache {
+            inner: inner,
+            key: Arc::new(key),
+            store: Arc::new(Mutex::new(Store {
+                ttl: Duration::from_secs(60),
+                max: usize::max_value(),
+                entries: HashMap::new(),
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// Set how long responses are served from the cache. Defaults to a
+    /// minute.
+    pub fn ttl(&mut self, ttl: Duration) {
+        self.store.lock().unwrap().ttl = ttl;
+    }
+
+    /// Set the maximum number of responses cached. Once reached, expired
+    /// entries are evicted first, then those closest to expiring. Defaults
+    /// to no limit.
+    pub fn max_entries(&mut self, max: usize) {
+        self.store.lock().unwrap().max = max;
+    }
+
+    /// Remove the response cached under `key`, if any
+    pub fn invalidate(&self, key: &K) {
+        self.store.lock().unwrap().entries.remove(key);
+    }
+
+    /// Remove every cached response
+    pub fn clear(&self) {
+        self.store.lock().unwrap().entries.clear();
+    }
+
+    /// Wrap `inner` with the same key function and entries as this cache,
+    /// including its TTL and bounds, which are shared from then on
+    pub fn share<T>(&self, inner: T) -> Cache<T, K, R, F> {
+        Cache {
+            inner: inner,
+            key: self.key.clone(),
+            store: self.store.clone(),
+        }
+    }
+
+    /// Returns the number of responses cached, including expired ones not
+    /// evicted yet
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap().entries.len()
+    }
+
+    /// Returns the number of requests answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.store.lock().unwrap().hits
+    }
+
+    /// Returns the number of keyed requests passed to the service
+    pub fn misses(&self) -> u64 {
+        self.store.lock().unwrap().misses
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Clone, K, R, F> Clone for Cache<S, K, R, F> {
+    fn clone(&self) -> Cache<S, K, R, F> {
+        Cache {
+            inner: self.inner.clone(),
+            key: self.key.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, R: Clone> Store<K, R> {
+    fn get(&mut self, key: &K) -> Option<R> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.expires <= Instant::now(),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+
+        if expired {
+            self.entries.remove(key);
+            self.misses += 1;
+            return None;
+        }
+
+        self.hits += 1;
+        self.entries.get(key).map(|entry| entry.response.clone())
+    }
+
+    fn insert(&mut self, key: K, response: R) {
+        if self.max == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+
+        if self.entries.len() >= self.max && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+
+        while self.entries.len() >= self.max && !self.entries.contains_key(&key) {
+            trace!("evicting cached response; entries={}", self.entries.len());
+            let oldest = self.entries.iter()
+                .min_by_key(|&(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(oldest) => drop(self.entries.remove(&oldest)),
+                None => break,
+            }
+        }
+
+        self.entries.insert(key, Entry {
+            response: response,
+            expires: now + self.ttl,
+        });
+    }
+}
+
+impl<S, K, R, F> Service for Cache<S, K, R, F>
+    where S: Service<Response = R>,
+          R: Clone,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> Option<K>,
+{
+    type Request = S::Request;
+    type Response = R;
+    type Error = S::Error;
+    type Future = Cached<S::Future, K>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let key = (self.key)(&req);
+
+        if let Some(ref key) = key {
+            if let Some(response) = self.store.lock().unwrap().get(key) {
+                trace!("serving cached response");
+
+                return Cached {
+                    state: State::Hit(Some(response)),
+                    store: self.store.clone(),
+                };
+            }
+        }
+
+        Cached {
+            state: State::Miss(self.inner.call(req), key),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<S, K, R, F> NewService for Cache<S, K, R, F>
+    where S: NewService<Response = R>,
+          R: Clone,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> Option<K>,
+{
+    type Request = S::Request;
+    type Response = R;
+    type Error = S::Error;
+    type Instance = Cache<S::Instance, K, R, F>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        Ok(self.share(try!(self.inner.new_service())))
+    }
+}
+
+impl<F, K> Future for Cached<F, K>
+    where F: Future,
+          F::Item: Clone,
+          K: Hash + Eq + Clone,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        match self.state {
+            State::Hit(ref mut response) => {
+                Ok(Async::Ready(response.take().expect("cannot poll Cached twice")))
+            }
+            State::Miss(ref mut inner, ref mut key) => {
+                let response = try_ready!(inner.poll());
+
+                if let Some(key) = key.take() {
+                    self.store.lock().unwrap().insert(key, response.clone());
+                }
+
+                Ok(Async::Ready(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+    use tokio_service::{Service, NewService};
+
+    use super::Cache;
+
+    /// Answers `n` with `n` and the number of calls so far, failing on 0
+    #[derive(Clone)]
+    struct Counter {
+        calls: Rc<Cell<u32>>,
+    }
+
+    impl Service for Counter {
+        type Request = u32;
+        type Response = (u32, u32);
+        type Error = io::Error;
+        type Future = future::FutureResult<(u32, u32), io::Error>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            self.calls.set(self.calls.get() + 1);
+
+            if req == 0 {
+                return future::err(io::Error::new(io::ErrorKind::Other, "zero"));
+            }
+
+            future::ok((req, self.calls.get()))
+        }
+    }
+
+    fn counter() -> Counter {
+        Counter { calls: Rc::new(Cell::new(0)) }
+    }
+
+    fn keyed(req: &u32) -> Option<u32> {
+        if *req == 1 { None } else { Some(*req) }
+    }
+
+    #[test]
+    fn test_hits_served_without_calling_service() {
+        let inner = counter();
+        let service = Cache::new(inner.clone(), keyed);
+
+        assert_eq!((2, 1), service.call(2).wait().unwrap());
+        assert_eq!((2, 1), service.call(2).wait().unwrap());
+        assert_eq!((3, 2), service.call(3).wait().unwrap());
+        assert_eq!(2, inner.calls.get());
+        assert_eq!((1, 2), (service.hits(), service.misses()));
+
+        // Unkeyed requests and errors are not cached
+        assert_eq!((1, 3), service.call(1).wait().unwrap());
+        assert_eq!((1, 4), service.call(1).wait().unwrap());
+        assert!(service.call(0).wait().is_err());
+        assert!(service.call(0).wait().is_err());
+        assert_eq!(6, inner.calls.get());
+        assert_eq!(2, service.len());
+
+        service.invalidate(&2);
+        assert_eq!((2, 7), service.call(2).wait().unwrap());
+    }
+
+    #[test]
+    fn test_entries_expire_and_are_bounded() {
+        let inner = counter();
+        let mut service = Cache::new(inner.clone(), keyed);
+        service.ttl(Duration::from_millis(20));
+        service.max_entries(2);
+
+        service.call(2).wait().unwrap();
+        service.call(3).wait().unwrap();
+        service.call(4).wait().unwrap();
+        assert_eq!(2, service.len());
+
+        // The entry closest to expiring was evicted
+        assert_eq!((2, 4), service.call(2).wait().unwrap());
+        assert_eq!((4, 3), service.call(4).wait().unwrap());
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!((4, 5), service.call(4).wait().unwrap());
+    }
+
+    #[test]
+    fn test_instances_share_entries() {
+        struct NewCounter(Counter);
+
+        impl NewService for NewCounter {
+            type Request = u32;
+            type Response = (u32, u32);
+            type Error = io::Error;
+            type Instance = Counter;
+
+            fn new_service(&self) -> io::Result<Counter> {
+                Ok(self.0.clone())
+            }
+        }
+
+        let inner = counter();
+        let cache = Cache::new(NewCounter(inner.clone()), keyed);
+        let a = cache.new_service().unwrap();
+        let b = cache.new_service().unwrap();
+
+        assert_eq!((2, 1), a.call(2).wait().unwrap());
+        assert_eq!((2, 1), b.call(2).wait().unwrap());
+        assert_eq!(1, inner.calls.get());
+        assert_eq!(1, cache.hits());
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        let cache = Cache::new((), keyed);
+
+        let handle = cache.share(());
+        thread::spawn(move || {
+            let service = handle.share(counter());
+            assert_eq!((2, 1), service.call(2).wait().unwrap());
+        }).join().unwrap();
+
+        // Served from the entry cached by the other thread
+        let inner = counter();
+        let service = cache.share(inner.clone());
+        assert_eq!((2, 1), service.call(2).wait().unwrap());
+        assert_eq!(0, inner.calls.get());
+
+        cache.clear();
+        assert_eq!((2, 1), service.call(2).wait().unwrap());
+        assert_eq!(1, inner.calls.get());
+    }
+}
@@ -0,0 +1,97 @@
+//! In-process channel transports
+//!
+//! A `Channel` is a transport backed by a pair of `futures::sync::mpsc`
+//! channels, which lets two components of the same process speak a protocol
+//! without serializing messages or opening sockets. The two halves may live
+//! on different threads and event loops.
+//!
+//! Since a channel already carries the protocol's frames, it takes the place
+//! of the I/O object: implement the protocol over `Channel` and return the
+//! channel itself from `bind_transport`.
+//!
+//! ```ignore
+//! impl ServerProto<Channel<u64, u64>> for IntProto {
+//!     type Request = u64;
+//!     type Response = u64;
+//!     type Transport = Channel<u64, u64>;
+//!     type BindTransport = Result<Self::Transport, io::Error>;
+//!
+//!     fn bind_transport(&self, io: Channel<u64, u64>) -> Self::BindTransport {
+//!         Ok(io)
+//!     }
+//! }
+//!
+//! let (client_end, server_end) = channel::pair(16);
+//! IntProto.bind_server(&handle, server_end, service);
+//! let client = IntProto.bind_client(&handle, client_end);
+//! ```
+
+use std::io;
+
+use futures::{Stream, Sink, Poll, Async, StartSend};
+use futures::sync::mpsc;
+use streaming::{pipeline, multiplex};
+
+/// One end of an in-process transport
+///
+/// Reads items of type `In` sent by the other end, and sends items of type
+/// `Out` to it.
+pub struct Channel<In, Out> {
+    tx: mpsc::Sender<Out>,
+    rx: mpsc::Receiver<In>,
+}
+
+/// Create both ends of an in-process transport.
+///
+/// Each direction buffers up to `buffer` items before exerting backpressure
+/// on the sender.
+pub fn pair<A, B>(buffer: usize) -> (Channel<A, B>, Channel<B, A>) {
+    let (tx_a, rx_a) = mpsc::channel(buffer);
+    let (tx_b, rx_b) = mpsc::channel(buffer);
+
+    let a = Channel {
+        tx: tx_b,
+        rx: rx_a,
+    };
+
+    let b = Channel {
+        tx: tx_a,
+        rx: rx_b,
+    };
+
+    (a, b)
+}
+
+fn peer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "channel peer dropped")
+}
+
+impl<In, Out> Stream for Channel<In, Out> {
+    type Item = In;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<In>, io::Error> {
+        // Dropping the other end is the equivalent of the socket being closed
+        match self.rx.poll() {
+            Ok(v) => Ok(v),
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<In, Out> Sink for Channel<In, Out> {
+    type SinkItem = Out;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Out) -> StartSend<Out, io::Error> {
+        self.tx.start_send(item).map_err(|_| peer_gone())
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.tx.poll_complete().map_err(|_| peer_gone())
+    }
+}
+
+impl<In: 'static, Out: 'static> pipeline::Transport for Channel<In, Out> {}
+
+impl<In: 'static, Out: 'static, ReadBody> multiplex::Transport<ReadBody> for Channel<In, Out> {}
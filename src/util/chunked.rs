@@ -0,0 +1,265 @@
+//! Splitting of oversized messages into continuation frames
+//!
+//! `Chunked` wraps a `Codec` and splits the encoded form of every message
+//! into frames of at most `max_frame` bytes, which are reassembled and handed
+//! to the wrapped codec on the other side. A protocol limited to small frames
+//! can carry larger messages this way, without the protocol noticing:
+//!
+//! ```ignore
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     Ok(io.framed(Chunked::new(MyCodec, 64 * 1024)))
+//! }
+//! ```
+//!
+//! Each frame starts with a 4 byte big-endian header. Its high bit is set
+//! when more frames of the same message follow, and the other bits hold the
+//! length of the payload following the header. Both ends of a connection
+//! have to use `Chunked`, with the same `max_frame`.
+//!
+//! The frames of a message are written back to back, so messages are not
+//! interleaved, and multiplexed protocols keep working as before.
+
+use std::io;
+use std::mem;
+
+use tokio_core::io::{Codec, EasyBuf};
+
+/// Length of the header of a frame
+const HEADER_LEN: usize = 4;
+
+/// Set in the header of all the frames of a message but the last one
+const MORE: u32 = 1 << 31;
+
+/// A `Codec` splitting messages into frames, see the module docs
+pub struct Chunked<C> {
+    inner: C,
+    max_frame: usize,
+    max_message: usize,
+    // The payloads of the frames received so far for the current message
+    partial: Vec<u8>,
+    // The encoded form of the message being written
+    encoded: Vec<u8>,
+}
+
+impl<C: Codec> Chunked<C> {
+    /// Wrap `inner`, writing frames of at most `max_frame` bytes, header
+    /// included
+    pub fn new(inner: C, max_frame: usize) -> Chunked<C> {
+        assert!(max_frame > HEADER_LEN, "frames need room for a payload");
+        assert!(max_frame - HEADER_LEN < MORE as usize, "max frame size too large");
+
+        Chunked {
+            inner: inner,
+            max_frame: max_frame,
+            max_message: usize::max_value(),
+            partial: Vec::new(),
+            encoded: Vec::new(),
+        }
+    }
+
+    /// Set the maximum size of a reassembled message. A larger message is an
+    /// error. By default, the size of messages is not limited.
+    pub fn max_message(&mut self, size: usize) {
+        self.max_message = size;
+    }
+
+    /// Returns a reference to the wrapped codec
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    // Hand a reassembled message to the wrapped codec
+    fn decode_message(&mut self, mut message: EasyBuf) -> io::Result<C::In> {
+        match try!(self.inner.decode(&mut message)) {
+            Some(msg) if message.len() == 0 => Ok(msg),
+            Some(_) => Err(invalid_data("trailing bytes after chunked message")),
+            None => Err(invalid_data("incomplete chunked message")),
+        }
+    }
+}
+
+impl<C: Codec> Codec for Chunked<C> {
+    type In = C::In;
+    type Out = C::Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<C::In>> {
+        loop {
+            if buf.len() < HEADER_LEN {
+                return Ok(None);
+            }
+
+            let header = buf.as_slice()[..HEADER_LEN].iter()
+                .fold(0u32, |header, &b| header << 8 | b as u32);
+
+            let more = header & MORE != 0;
+            let len = (header & !MORE) as usize;
+
+            if len > self.max_frame - HEADER_LEN {
+                return Err(invalid_data("frame larger than the max frame size"));
+            }
+
+            if self.partial.len() + len > self.max_message {
+                return Err(invalid_data("chunked message larger than the max message size"));
+            }
+
+            if buf.len() < HEADER_LEN + len {
+                return Ok(None);
+            }
+
+            buf.drain_to(HEADER_LEN);
+            let payload = buf.drain_to(len);
+
+            if more {
+                self.partial.extend_from_slice(payload.as_slice());
+                continue;
+            }
+
+            // Messages fitting in a single frame are not copied
+            let message = if self.partial.is_empty() {
+                payload
+            } else {
+                self.partial.extend_from_slice(payload.as_slice());
+                EasyBuf::from(mem::replace(&mut self.partial, Vec::new()))
+            };
+
+            return self.decode_message(message).map(Some);
+        }
+    }
+
+    fn encode(&mut self, msg: C::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.encoded.clear();
+        try!(self.inner.encode(msg, &mut self.encoded));
+
+        let max = self.max_frame - HEADER_LEN;
+        let mut remaining = &self.encoded[..];
+
+        loop {
+            let len = ::std::cmp::min(max, remaining.len());
+            let (payload, rest) = remaining.split_at(len);
+
+            let header = len as u32 | if rest.is_empty() { 0 } else { MORE };
+            buf.extend_from_slice(&[(header >> 24) as u8,
+                                    (header >> 16) as u8,
+                                    (header >> 8) as u8,
+                                    header as u8]);
+            buf.extend_from_slice(payload);
+
+            if rest.is_empty() {
+                break;
+            }
+
+            remaining = rest;
+        }
+
+        // Do not pin the memory of an oversized message
+        if self.encoded.capacity() > self.max_frame {
+            self.encoded = Vec::new();
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use super::Chunked;
+
+    /// Messages are prefixed with their length on one byte
+    struct Short;
+
+    impl Codec for Short {
+        type In = Vec<u8>;
+        type Out = Vec<u8>;
+
+        fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Vec<u8>>> {
+            let len = match buf.as_slice().first() {
+                Some(&len) => len as usize,
+                None => return Ok(None),
+            };
+
+            if buf.len() < 1 + len {
+                return Ok(None);
+            }
+
+            buf.drain_to(1);
+            Ok(Some(buf.drain_to(len).as_slice().to_vec()))
+        }
+
+        fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+            buf.push(msg.len() as u8);
+            buf.extend_from_slice(&msg);
+            Ok(())
+        }
+    }
+
+    fn encode(codec: &mut Chunked<Short>, msg: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        codec.encode(msg.to_vec(), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_message_split_into_frames() {
+        let mut codec = Chunked::new(Short, 8);
+
+        // The encoded message is 11 bytes long, in frames of 4 bytes payload
+        let buf = encode(&mut codec, b"abcdefghij");
+        assert_eq!(&b"\x80\0\0\x04\x0aabc\x80\0\0\x04defg\0\0\0\x03hij"[..], &buf[..]);
+
+        // Frames are reassembled as they arrive
+        let mut rd = EasyBuf::new();
+        for (i, &b) in buf.iter().enumerate() {
+            rd.get_mut().push(b);
+            let decoded = codec.decode(&mut rd).unwrap();
+
+            if i + 1 < buf.len() {
+                assert_eq!(None, decoded);
+            } else {
+                assert_eq!(Some(b"abcdefghij".to_vec()), decoded);
+            }
+        }
+        assert_eq!(0, rd.len());
+    }
+
+    #[test]
+    fn test_small_messages_fit_in_one_frame() {
+        let mut codec = Chunked::new(Short, 8);
+
+        let mut buf = encode(&mut codec, b"ab");
+        buf.extend(encode(&mut codec, b""));
+        assert_eq!(&b"\0\0\0\x03\x02ab\0\0\0\x01\x00"[..], &buf[..]);
+
+        let mut rd = EasyBuf::from(buf);
+        assert_eq!(Some(b"ab".to_vec()), codec.decode(&mut rd).unwrap());
+        assert_eq!(Some(vec![]), codec.decode(&mut rd).unwrap());
+        assert_eq!(None, codec.decode(&mut rd).unwrap());
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let mut codec = Chunked::new(Short, 8);
+        codec.max_message(6);
+
+        let buf = encode(&mut codec, b"abcdefghij");
+        let err = codec.decode(&mut EasyBuf::from(buf)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A frame above the max frame size is rejected before it is buffered
+        let mut codec = Chunked::new(Short, 8);
+        let err = codec.decode(&mut EasyBuf::from(b"\0\0\0\x05".to_vec())).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
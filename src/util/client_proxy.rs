@@ -6,6 +6,15 @@
 //!
 //! However, some protocols require implementing the dispatch layer directly,
 //! in which case using client channel is helpful.
+//!
+//! A dispatcher reading from a `Receiver` supports `ClientProxy::close` as
+//! long as it returns once the receiver is done, after the requests it
+//! yielded got their responses, and fails the connection when the receiver
+//! yields an error.
+//!
+//! Messages the dispatcher reads without a matching request, e.g. events
+//! pushed by the server, are handed to `Receiver::notify`, and yielded by the
+//! `Notifications` stream of the client, if one was requested.
 
 // Allow warnings in order to prevent the compiler from outputting an error
 // that seems to be fixed on nightly.
@@ -16,22 +25,66 @@ use tokio_service::Service;
 use futures::{Future, Async, Poll, Stream, AsyncSink, Sink};
 use futures::sync::mpsc;
 use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
 use std::io;
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 /// Client `Service` for pipeline or multiplex protocols
+///
+/// Requests are queued to the task dispatching them over a thread-safe
+/// channel, so the handle is `Send` and `Sync` as long as the request and
+/// response types are `Send`: the threads of a pool can share a single
+/// connection by cloning the handle, or by calling it through an `Arc`. The
+/// connection itself is still driven by the event loop it was bound on, and
+/// the response futures are notified from there.
 pub struct ClientProxy<R, S, E> {
-    tx: RefCell<mpsc::UnboundedSender<io::Result<Envelope<R, S, E>>>>,
+    tx: mpsc::UnboundedSender<io::Result<Envelope<R, S, E>>>,
+    shutdown: Arc<Mutex<Shutdown>>,
+    notifications: Arc<Mutex<Option<mpsc::UnboundedReceiver<Result<S, E>>>>>,
 }
 
 impl<R, S, E> Clone for ClientProxy<R, S, E> {
     fn clone(&self) -> Self {
         ClientProxy {
-            tx: RefCell::new(self.tx.borrow().clone()),
+            tx: self.tx.clone(),
+            shutdown: self.shutdown.clone(),
+            notifications: self.notifications.clone(),
         }
     }
 }
 
+/// Stream of the messages received without a matching request, see
+/// `ClientProxy::notifications`
+///
+/// The stream ends once the connection is closed.
+pub struct Notifications<S, E> {
+    rx: mpsc::UnboundedReceiver<Result<S, E>>,
+}
+
+/// Future returned from `ClientProxy::close`, completing once the connection
+/// is closed
+pub struct Close {
+    shutdown: Arc<Mutex<Shutdown>>,
+    timeout: Option<Timeout>,
+    error: Option<io::Error>,
+}
+
+/// Shared between the client handles and the receiver
+struct Shutdown {
+    // No new requests are accepted
+    closing: bool,
+    // The close timeout elapsed, the connection is failed
+    aborted: bool,
+    // The receiver is dropped
+    closed: bool,
+    // The task polling the receiver, notified when closing or aborting
+    dispatch: Option<Task>,
+    // The tasks waiting for the connection to close
+    waiters: Vec<Task>,
+}
+
 /// Response future returned from a client
 pub struct Response<T, E> {
     inner: oneshot::Receiver<Result<T, E>>,
@@ -45,20 +98,99 @@ type Envelope<R, S, E> = (R, oneshot::Sender<Result<S, E>>);
 pub type Pair<R, S, E> = (ClientProxy<R, S, E>, Receiver<R, S, E>);
 
 /// Receive requests submitted to the client
-pub type Receiver<R, S, E> = mpsc::UnboundedReceiver<io::Result<Envelope<R, S, E>>>;
+///
+/// Once the client is closed, the receiver yields the requests submitted
+/// before, then completes. If the close timeout elapses first, it yields an
+/// error instead.
+pub struct Receiver<R, S, E> {
+    rx: mpsc::UnboundedReceiver<io::Result<Envelope<R, S, E>>>,
+    shutdown: Arc<Mutex<Shutdown>>,
+    closing: bool,
+    notify: mpsc::UnboundedSender<Result<S, E>>,
+    // Still holds the receiving end until the client asks for notifications
+    notifications: Arc<Mutex<Option<mpsc::UnboundedReceiver<Result<S, E>>>>>,
+}
 
 /// Return a client handle and a handle used to receive requests on
 pub fn pair<R, S, E>() -> Pair<R, S, E> {
     // Create a stream
     let (tx, rx) = mpsc::unbounded();
 
+    let shutdown = Arc::new(Mutex::new(Shutdown {
+        closing: false,
+        aborted: false,
+        closed: false,
+        dispatch: None,
+        waiters: vec![],
+    }));
+
+    let (notify, notifications) = mpsc::unbounded();
+    let notifications = Arc::new(Mutex::new(Some(notifications)));
+
     // Use the sender handle to create a `Client` handle
-    let client = ClientProxy { tx: RefCell::new(tx) };
+    let client = ClientProxy {
+        tx: tx,
+        shutdown: shutdown.clone(),
+        notifications: notifications.clone(),
+    };
+
+    let rx = Receiver {
+        rx: rx,
+        shutdown: shutdown,
+        closing: false,
+        notify: notify,
+        notifications: notifications,
+    };
 
     // Return the pair
     (client, rx)
 }
 
+impl<R, S, E> ClientProxy<R, S, E> {
+    /// Close the connection gracefully.
+    ///
+    /// New calls, on this handle and its clones, fail with a `BrokenPipe`
+    /// error. The requests submitted before are still written, and the
+    /// connection is shut down once they all got their response.
+    pub fn close(&self) -> Close {
+        lock(&self.shutdown).close();
+
+        Close {
+            shutdown: self.shutdown.clone(),
+            timeout: None,
+            error: None,
+        }
+    }
+
+    /// Close the connection gracefully, waiting at most `timeout` for the
+    /// outstanding responses.
+    ///
+    /// Once the timeout elapses, the connection is closed right away, the
+    /// outstanding requests fail with a `BrokenPipe` error and the returned
+    /// future with a `TimedOut` error. See `close`.
+    pub fn close_timeout(&self, timeout: Duration, handle: &Handle) -> Close {
+        let mut close = self.close();
+
+        match Timeout::new(timeout, handle) {
+            Ok(timeout) => close.timeout = Some(timeout),
+            Err(e) => close.error = Some(e),
+        }
+
+        close
+    }
+
+    /// Returns the stream of messages the dispatcher received without a
+    /// matching request, e.g. events pushed by the server.
+    ///
+    /// Only the first call, on this handle or its clones, returns the stream.
+    /// Until then, such messages are dropped. See
+    /// `streaming::multiplex::ClientDispatch` for the messages multiplexed
+    /// clients deliver.
+    pub fn notifications(&self) -> Option<Notifications<S, E>> {
+        lock(&self.notifications).take().map(|rx| Notifications { rx: rx })
+    }
+}
+
 impl<R, S, E: From<io::Error>> Service for ClientProxy<R, S, E> {
     type Request = R;
     type Response = S;
@@ -68,14 +200,18 @@ impl<R, S, E: From<io::Error>> Service for ClientProxy<R, S, E> {
     fn call(&self, request: R) -> Self::Future {
         let (tx, rx) = oneshot::channel();
 
+        // Dropping `tx` fails the call with a BrokenPipe, see below
+        if lock(&self.shutdown).closing {
+            return Response { inner: rx };
+        }
+
         // If send returns an Err, its because the other side has been dropped.
         // By ignoring it, we are just dropping the `tx`, which will mean the
         // rx will return Canceled when polled. In turn, that is translated
         // into a BrokenPipe, which conveys the proper error.
         // NOTE: If Service changes to have some sort of `try_call`, it'd
         // probably be more appropriate to return the Request.
-        let _ = mpsc::UnboundedSender::send(&mut self.tx.borrow_mut(),
-                                            Ok((request, tx)));
+        let _ = self.tx.unbounded_send(Ok((request, tx)));
 
         Response { inner: rx }
     }
@@ -99,3 +235,126 @@ impl<T, E> Future for Response<T, E>
         }
     }
 }
+
+impl<R, S, E> Receiver<R, S, E> {
+    /// Hand a message received without a matching request to the
+    /// `Notifications` stream of the client.
+    ///
+    /// Returns false if the client did not ask for notifications, or
+    /// dropped the stream since.
+    pub fn notify(&self, message: Result<S, E>) -> bool {
+        if lock(&self.notifications).is_some() {
+            return false;
+        }
+
+        self.notify.unbounded_send(message).is_ok()
+    }
+}
+
+impl<S, E> Stream for Notifications<S, E> {
+    type Item = S;
+    type Error = E;
+
+    fn poll(&mut self) -> Poll<Option<S>, E> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Some(Ok(message)))) => Ok(Async::Ready(Some(message))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => unreachable!(),
+        }
+    }
+}
+
+impl<R, S, E> Stream for Receiver<R, S, E> {
+    type Item = io::Result<Envelope<R, S, E>>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        {
+            let mut shutdown = lock(&self.shutdown);
+
+            if shutdown.aborted {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "client close timed out");
+                return Ok(Async::Ready(Some(Err(e))));
+            }
+
+            if shutdown.closing && !self.closing {
+                // The requests already sent are still yielded
+                self.rx.close();
+                self.closing = true;
+            }
+
+            shutdown.dispatch = Some(task::park());
+        }
+
+        self.rx.poll()
+    }
+}
+
+impl<R, S, E> Drop for Receiver<R, S, E> {
+    fn drop(&mut self) {
+        let mut shutdown = lock(&self.shutdown);
+        shutdown.closed = true;
+
+        for task in shutdown.waiters.drain(..) {
+            task.unpark();
+        }
+    }
+}
+
+impl Future for Close {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut shutdown = lock(&self.shutdown);
+
+        if shutdown.closed {
+            return Ok(Async::Ready(()));
+        }
+
+        if let Some(e) = self.error.take() {
+            shutdown.abort();
+            return Err(e);
+        }
+
+        let expired = match self.timeout {
+            Some(ref mut timeout) => try!(timeout.poll()).is_ready(),
+            None => false,
+        };
+
+        if expired {
+            shutdown.abort();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "client close timed out"));
+        }
+
+        if !shutdown.waiters.iter().any(|task| task.will_notify_current()) {
+            shutdown.waiters.push(task::park());
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Shutdown {
+    fn close(&mut self) {
+        self.closing = true;
+
+        if let Some(task) = self.dispatch.take() {
+            task.unpark();
+        }
+    }
+
+    fn abort(&mut self) {
+        self.aborted = true;
+        self.close();
+    }
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
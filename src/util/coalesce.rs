@@ -0,0 +1,303 @@
+//! Coalescing of identical in-flight requests
+//!
+//! `Coalesce` wraps a client service and computes a key for every request.
+//! While a request is in flight, further requests with the same key are not
+//! sent; they wait for the response to the first one instead, and all of
+//! them get a copy of it. This keeps a burst of duplicate lookups, e.g.
+//! during a cache stampede on a hot key, from turning into as many network
+//! calls:
+//!
+//! ```ignore
+//! let client = Coalesce::new(client, |req: &Lookup| Some(req.key.clone()));
+//! ```
+//!
+//! Requests for which the key function returns `None`, e.g. those that are
+//! not idempotent, are always sent. An error is handed to every waiter, with
+//! the same `ErrorKind` and message.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::rc::Rc;
+
+use futures::{Future, Poll, Async};
+use futures::future::Shared;
+use tokio_service::Service;
+
+/// A service sending a single request for identical in-flight requests
+pub struct Coalesce<S: Service, K, F> {
+    inner: S,
+    key: F,
+    in_flight: Rc<RefCell<InFlight<K, S::Response>>>,
+}
+
+/// Response future of `Coalesce`
+pub struct Coalesced<K: Hash + Eq, R> {
+    shared: Shared<Call<R>>,
+    // The key and ID of the entry this future waits on, if any
+    entry: Option<(K, usize)>,
+    in_flight: Rc<RefCell<InFlight<K, R>>>,
+}
+
+type Call<R> = Box<dyn Future<Item = R, Error = io::Error>>;
+
+struct InFlight<K, R> {
+    next_id: usize,
+    calls: HashMap<K, Entry<R>>,
+}
+
+struct Entry<R> {
+    id: usize,
+    shared: Shared<Call<R>>,
+    waiters: usize,
+}
+
+impl<S, K, F> Coalesce<S, K, F>
+    where S: Service<Error = io::Error>,
+          S::Response: Clone,
+          S::Future: 'static,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> Option<K>,
+{
+    /// Wrap `inner`, coalescing the requests for which `key` returns the
+    /// same value
+    pub fn new(inner: S, key: F) -> Coalesce<S, K, F> {
+        Coalesce {
+            inner: inner,
+            key: key,
+            in_flight: Rc::new(RefCell::new(InFlight {
+                next_id: 0,
+                calls: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Returns the number of distinct requests currently in flight through
+    /// the coalescer, not counting those without a key
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.borrow().calls.len()
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, K, F> Service for Coalesce<S, K, F>
+    where S: Service<Error = io::Error>,
+          S::Response: Clone,
+          S::Future: 'static,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> Option<K>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = io::Error;
+    type Future = Coalesced<K, S::Response>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let key = match (self.key)(&req) {
+            Some(key) => key,
+            None => {
+                let call: Call<S::Response> = Box::new(self.inner.call(req));
+
+                return Coalesced {
+                    shared: call.shared(),
+                    entry: None,
+                    in_flight: self.in_flight.clone(),
+                };
+            }
+        };
+
+        let mut in_flight = self.in_flight.borrow_mut();
+        let in_flight = &mut *in_flight;
+
+        if let Some(entry) = in_flight.calls.get_mut(&key) {
+            trace!("coalescing request; waiters={}", entry.waiters + 1);
+            entry.waiters += 1;
+
+            return Coalesced {
+                shared: entry.shared.clone(),
+                entry: Some((key, entry.id)),
+                in_flight: self.in_flight.clone(),
+            };
+        }
+
+        let id = in_flight.next_id;
+        in_flight.next_id = in_flight.next_id.wrapping_add(1);
+
+        let call: Call<S::Response> = Box::new(self.inner.call(req));
+        let shared = call.shared();
+
+        in_flight.calls.insert(key.clone(), Entry {
+            id: id,
+            shared: shared.clone(),
+            waiters: 1,
+        });
+
+        Coalesced {
+            shared: shared,
+            entry: Some((key, id)),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, R: Clone> Future for Coalesced<K, R> {
+    type Item = R;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<R, io::Error> {
+        let res = match self.shared.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(response)) => Ok(Async::Ready((*response).clone())),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        };
+
+        // Requests made from now on are sent again
+        if let Some((key, id)) = self.entry.take() {
+            let mut in_flight = self.in_flight.borrow_mut();
+
+            if in_flight.calls.get(&key).map(|entry| entry.id) == Some(id) {
+                in_flight.calls.remove(&key);
+            }
+        }
+
+        res
+    }
+}
+
+impl<K: Hash + Eq, R> Drop for Coalesced<K, R> {
+    fn drop(&mut self) {
+        let (key, id) = match self.entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let mut in_flight = self.in_flight.borrow_mut();
+
+        let done = match in_flight.calls.get_mut(&key) {
+            Some(entry) if entry.id == id => {
+                entry.waiters -= 1;
+                entry.waiters == 0
+            }
+            _ => false,
+        };
+
+        // Nobody is waiting for the response anymore, cancel the request
+        if done {
+            in_flight.calls.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::rc::Rc;
+
+    use futures::Future;
+    use futures::sync::oneshot;
+    use tokio_service::Service;
+
+    use super::Coalesce;
+
+    /// Responds to `(key, n)` once the test completes the response
+    #[derive(Clone)]
+    struct Pending {
+        calls: Rc<Cell<usize>>,
+        responses: Rc<RefCell<Vec<oneshot::Sender<Result<u32, io::Error>>>>>,
+    }
+
+    impl Service for Pending {
+        type Request = (u32, u32);
+        type Response = u32;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = u32, Error = io::Error>>;
+
+        fn call(&self, _req: (u32, u32)) -> Self::Future {
+            self.calls.set(self.calls.get() + 1);
+
+            let (tx, rx) = oneshot::channel();
+            self.responses.borrow_mut().push(tx);
+            Box::new(rx.then(|res| res.unwrap()))
+        }
+    }
+
+    fn pending() -> Pending {
+        Pending {
+            calls: Rc::new(Cell::new(0)),
+            responses: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    #[test]
+    fn test_coalesces_identical_requests() {
+        let inner = pending();
+        let service = Coalesce::new(inner.clone(), |req: &(u32, u32)| Some(req.0));
+
+        let a = service.call((1, 10));
+        let b = service.call((1, 20));
+        let c = service.call((2, 30));
+        assert_eq!(2, inner.calls.get());
+        assert_eq!(2, service.in_flight());
+
+        let mut responses = inner.responses.borrow_mut().drain(..).collect::<Vec<_>>();
+        responses.remove(0).complete(Ok(10));
+        responses.remove(0).complete(Ok(30));
+
+        assert_eq!(10, a.wait().unwrap());
+        assert_eq!(10, b.wait().unwrap());
+        assert_eq!(30, c.wait().unwrap());
+        assert_eq!(0, service.in_flight());
+
+        // Once completed, the request is sent again
+        drop(service.call((1, 40)));
+        assert_eq!(3, inner.calls.get());
+    }
+
+    #[test]
+    fn test_errors_and_unkeyed_requests() {
+        let inner = pending();
+        let service = Coalesce::new(inner.clone(), |req: &(u32, u32)| {
+            if req.0 == 0 { None } else { Some(req.0) }
+        });
+
+        let a = service.call((1, 10));
+        let b = service.call((1, 10));
+        drop(service.call((0, 10)));
+        drop(service.call((0, 10)));
+        assert_eq!(3, inner.calls.get());
+
+        let tx = inner.responses.borrow_mut().remove(0);
+        tx.complete(Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+
+        for f in vec![a, b] {
+            let e = f.wait().unwrap_err();
+            assert_eq!(io::ErrorKind::TimedOut, e.kind());
+            assert_eq!("timed out", e.to_string());
+        }
+    }
+
+    #[test]
+    fn test_dropping_all_waiters_cancels() {
+        let inner = pending();
+        let service = Coalesce::new(inner.clone(), |req: &(u32, u32)| Some(req.0));
+
+        let a = service.call((1, 10));
+        let b = service.call((1, 10));
+
+        drop(b);
+        assert_eq!(1, service.in_flight());
+
+        drop(a);
+        assert_eq!(0, service.in_flight());
+
+        // The next request is sent again
+        drop(service.call((1, 10)));
+        assert_eq!(2, inner.calls.get());
+    }
+}
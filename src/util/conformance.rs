@@ -0,0 +1,325 @@
+//! Round-trip conformance checks for codecs
+//!
+//! The functions of this module write frames through the transport of one
+//! side of a protocol, as built by its `bind_transport`, and read them back
+//! through the transport of the other side. The bytes in between are handed
+//! to the reading transport in chunks of arbitrary sizes, each of them
+//! followed by a spurious `WouldBlock`, so that decoders are exercised with
+//! frames split at every boundary, and encoders with partial writes:
+//!
+//! ```ignore
+//! #[test]
+//! fn test_round_trip() {
+//!     conformance::pipeline_requests(&LineProto, vec!["hello".into()], &[1, 3]).unwrap();
+//! }
+//! ```
+//!
+//! The check succeeds if the frames read are the frames written, with no
+//! bytes left over. The frames and chunk sizes are plain arguments, so the
+//! checks make properties for `quickcheck` or `proptest`:
+//!
+//! ```ignore
+//! quickcheck! {
+//!     fn prop_round_trip(lines: Vec<String>, splits: Vec<usize>) -> bool {
+//!         conformance::pipeline_requests(&LineProto, lines, &splits).is_ok()
+//!     }
+//! }
+//! ```
+//!
+//! Chunk sizes are used in turn, starting over once all were used, and sizes
+//! of zero are skipped. Without sizes, everything is read at once.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use futures::{stream, task, Future, Stream, Sink, IntoFuture};
+use tokio_core::io::Io;
+use multiplex::{self, RequestId};
+use pipeline;
+
+/// An in-memory I/O object reading and writing in chunks of given sizes
+///
+/// Protocols are checked over this I/O object, their `ServerProto` and
+/// `ClientProto` implementations must support it.
+pub struct SplitIo {
+    input: Vec<u8>,
+    read: usize,
+    output: Rc<RefCell<Vec<u8>>>,
+    splits: Vec<usize>,
+    next_read: usize,
+    next_write: usize,
+    // Reads and writes alternate between a chunk and a `WouldBlock`
+    blocked_read: bool,
+    blocked_write: bool,
+}
+
+impl SplitIo {
+    /// Returns an I/O object reading `input`, and chunking reads and writes
+    /// at the given sizes
+    pub fn new(input: Vec<u8>, splits: &[usize]) -> SplitIo {
+        SplitIo {
+            input: input,
+            read: 0,
+            output: Rc::new(RefCell::new(Vec::new())),
+            splits: splits.iter().cloned().filter(|&n| n > 0).collect(),
+            next_read: 0,
+            next_write: 0,
+            blocked_read: false,
+            blocked_write: false,
+        }
+    }
+
+    /// Returns the bytes written so far
+    pub fn written(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    fn chunk(&self, next: &mut usize, len: usize) -> usize {
+        if self.splits.is_empty() {
+            return len;
+        }
+
+        let n = self.splits[*next % self.splits.len()];
+        *next += 1;
+        cmp::min(n, len)
+    }
+}
+
+impl fmt::Debug for SplitIo {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SplitIo")
+            .field("remaining", &(self.input.len() - self.read))
+            .field("written", &self.output.borrow().len())
+            .field("splits", &self.splits)
+            .finish()
+    }
+}
+
+fn would_block() -> io::Error {
+    // The object is ready again right away
+    task::park().unpark();
+    io::Error::new(io::ErrorKind::WouldBlock, "split")
+}
+
+impl Read for SplitIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.input.len() - self.read;
+        if remaining == 0 {
+            return Ok(0);
+        }
+
+        if self.blocked_read {
+            self.blocked_read = false;
+            return Err(would_block());
+        }
+
+        let mut next = self.next_read;
+        let n = self.chunk(&mut next, cmp::min(remaining, buf.len()));
+        self.next_read = next;
+
+        buf[..n].copy_from_slice(&self.input[self.read..self.read + n]);
+        self.read += n;
+        self.blocked_read = true;
+        Ok(n)
+    }
+}
+
+impl Write for SplitIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.blocked_write {
+            self.blocked_write = false;
+            return Err(would_block());
+        }
+
+        let mut next = self.next_write;
+        let n = self.chunk(&mut next, buf.len());
+        self.next_write = next;
+
+        self.output.borrow_mut().extend_from_slice(&buf[..n]);
+        self.blocked_write = true;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Io for SplitIo {}
+
+/// Check that `requests` round-trip from the client transport of a
+/// pipelined protocol to its server transport
+pub fn pipeline_requests<P, T>(proto: &P, requests: Vec<T>, splits: &[usize]) -> io::Result<()>
+    where P: pipeline::ClientProto<SplitIo, Request = T>,
+          P: pipeline::ServerProto<SplitIo, Request = T>,
+          T: Clone + PartialEq + fmt::Debug + 'static,
+{
+    round_trip(|io| pipeline::ClientProto::bind_transport(proto, io),
+               |io| pipeline::ServerProto::bind_transport(proto, io),
+               requests,
+               splits)
+}
+
+/// Check that `responses` round-trip from the server transport of a
+/// pipelined protocol to its client transport
+pub fn pipeline_responses<P, T>(proto: &P, responses: Vec<T>, splits: &[usize]) -> io::Result<()>
+    where P: pipeline::ServerProto<SplitIo, Response = T>,
+          P: pipeline::ClientProto<SplitIo, Response = T>,
+          T: Clone + PartialEq + fmt::Debug + 'static,
+{
+    round_trip(|io| pipeline::ServerProto::bind_transport(proto, io),
+               |io| pipeline::ClientProto::bind_transport(proto, io),
+               responses,
+               splits)
+}
+
+/// Check that `requests`, tagged with their request IDs, round-trip from the
+/// client transport of a multiplexed protocol to its server transport
+pub fn multiplex_requests<P, T>(proto: &P, requests: Vec<(RequestId, T)>, splits: &[usize])
+                                -> io::Result<()>
+    where P: multiplex::ClientProto<SplitIo, Request = T>,
+          P: multiplex::ServerProto<SplitIo, Request = T>,
+          T: Clone + PartialEq + fmt::Debug + 'static,
+{
+    round_trip(|io| multiplex::ClientProto::bind_transport(proto, io),
+               |io| multiplex::ServerProto::bind_transport(proto, io),
+               requests,
+               splits)
+}
+
+/// Check that `responses`, tagged with their request IDs, round-trip from
+/// the server transport of a multiplexed protocol to its client transport
+pub fn multiplex_responses<P, T>(proto: &P, responses: Vec<(RequestId, T)>, splits: &[usize])
+                                 -> io::Result<()>
+    where P: multiplex::ServerProto<SplitIo, Response = T>,
+          P: multiplex::ClientProto<SplitIo, Response = T>,
+          T: Clone + PartialEq + fmt::Debug + 'static,
+{
+    round_trip(|io| multiplex::ServerProto::bind_transport(proto, io),
+               |io| multiplex::ClientProto::bind_transport(proto, io),
+               responses,
+               splits)
+}
+
+fn round_trip<FW, W, FR, R, T>(bind_writer: FW, bind_reader: FR, frames: Vec<T>, splits: &[usize])
+                               -> io::Result<()>
+    where FW: FnOnce(SplitIo) -> W,
+          W: IntoFuture<Error = io::Error>,
+          W::Item: Sink<SinkItem = T, SinkError = io::Error>,
+          FR: FnOnce(SplitIo) -> R,
+          R: IntoFuture<Error = io::Error>,
+          R::Item: Stream<Item = T, Error = io::Error>,
+          T: Clone + PartialEq + fmt::Debug,
+{
+    let io = SplitIo::new(vec![], splits);
+    let output = io.output.clone();
+
+    let writer = try!(bind_writer(io).into_future().wait());
+    let frames_written = frames.clone().into_iter().map(Ok::<T, io::Error>);
+    let writer = try!(writer.send_all(stream::iter(frames_written)).wait());
+    drop(writer);
+
+    let bytes = output.borrow().clone();
+    trace!("decoding frames; bytes={}", bytes.len());
+
+    let reader = try!(bind_reader(SplitIo::new(bytes, splits)).into_future().wait());
+    let read = try!(reader.collect().wait());
+
+    if read != frames {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("frames did not round-trip; written={:?} read={:?} \
+                                           splits={:?}", frames, read, splits)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use tokio_core::io::{Io, Codec, EasyBuf, Framed};
+    use pipeline;
+    use util::echo::{EchoProto, MultiplexEchoProto};
+    use super::{pipeline_requests, pipeline_responses, multiplex_requests,
+                multiplex_responses, SplitIo};
+
+    #[test]
+    fn test_echo_protocols_conform() {
+        let payloads = vec![vec![], b"hello".to_vec(), vec![7; 300]];
+        let tagged = payloads.iter().cloned().enumerate().map(|(i, p)| (i as u64, p))
+            .collect::<Vec<_>>();
+
+        for splits in vec![vec![], vec![1], vec![2, 0, 5], vec![4096]] {
+            pipeline_requests(&EchoProto, payloads.clone(), &splits).unwrap();
+            pipeline_responses(&EchoProto, payloads.clone(), &splits).unwrap();
+            multiplex_requests(&MultiplexEchoProto, tagged.clone(), &splits).unwrap();
+            multiplex_responses(&MultiplexEchoProto, tagged.clone(), &splits).unwrap();
+        }
+    }
+
+    /// Lines, assuming a whole line is read at once
+    struct Naive;
+
+    impl Codec for Naive {
+        type In = String;
+        type Out = String;
+
+        fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<String>> {
+            if buf.len() == 0 {
+                return Ok(None);
+            }
+
+            let len = buf.len();
+            let line = buf.drain_to(len);
+            Ok(Some(String::from_utf8_lossy(line.as_slice()).trim_end().to_string()))
+        }
+
+        fn encode(&mut self, line: String, buf: &mut Vec<u8>) -> io::Result<()> {
+            buf.extend_from_slice(line.as_bytes());
+            buf.push(b'\n');
+            Ok(())
+        }
+    }
+
+    struct NaiveProto;
+
+    impl pipeline::ClientProto<SplitIo> for NaiveProto {
+        type Request = String;
+        type Response = String;
+        type Transport = Framed<SplitIo, Naive>;
+        type BindTransport = Result<Self::Transport, io::Error>;
+
+        fn bind_transport(&self, io: SplitIo) -> Self::BindTransport {
+            Ok(io.framed(Naive))
+        }
+    }
+
+    impl pipeline::ServerProto<SplitIo> for NaiveProto {
+        type Request = String;
+        type Response = String;
+        type Transport = Framed<SplitIo, Naive>;
+        type BindTransport = Result<Self::Transport, io::Error>;
+
+        fn bind_transport(&self, io: SplitIo) -> Self::BindTransport {
+            Ok(io.framed(Naive))
+        }
+    }
+
+    #[test]
+    fn test_partial_reads_caught() {
+        let lines = vec!["hello".to_string(), "world".to_string()];
+
+        pipeline_requests(&NaiveProto, lines.clone(), &[6]).unwrap();
+
+        let e = pipeline_requests(&NaiveProto, lines, &[3]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
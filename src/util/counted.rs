@@ -0,0 +1,326 @@
+//! Byte and frame counting transports
+//!
+//! `Counted` wraps either an I/O object, counting the bytes read and
+//! written, or a transport, counting the frames received and sent. Both
+//! layers can share the same `Counters`, so that a protocol gets traffic
+//! statistics without a custom transport:
+//!
+//! ```ignore
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     let counters = Counters::new();
+//!     self.registry.insert(counters.clone());
+//!
+//!     let io = Counted::new(io, counters.clone());
+//!     Ok(Counted::new(io.framed(LineCodec), counters))
+//! }
+//! ```
+//!
+//! `Counters` are cheap to clone and can be read from any thread while the
+//! connection is open, e.g. after inserting them in the connection's
+//! `Session` or in a map keyed by `ConnectionId`.
+//!
+//! `Counters` are also a `TransportLayer`, wrapping transports in a
+//! `Counted` one when stacked with other layers, see `util::layer`.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use futures::{Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use tokio_core::io::Io;
+use streaming::{pipeline, multiplex};
+use streaming::multiplex::RequestId;
+use util::layer::TransportLayer;
+
+/// Traffic counters of a connection
+///
+/// Clones share the same counters.
+#[derive(Clone)]
+pub struct Counters {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    bytes_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+    frames_read: AtomicUsize,
+    frames_written: AtomicUsize,
+}
+
+/// An I/O object or transport updating a set of `Counters`
+pub struct Counted<T> {
+    inner: T,
+    counters: Counters,
+}
+
+impl Counters {
+    /// Return new counters, all set to zero
+    pub fn new() -> Counters {
+        Counters {
+            inner: Arc::new(Inner {
+                bytes_read: AtomicUsize::new(0),
+                bytes_written: AtomicUsize::new(0),
+                frames_read: AtomicUsize::new(0),
+                frames_written: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the number of bytes read from the I/O object
+    pub fn bytes_read(&self) -> usize {
+        self.inner.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes written to the I/O object
+    pub fn bytes_written(&self) -> usize {
+        self.inner.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames received from the transport
+    pub fn frames_read(&self) -> usize {
+        self.inner.frames_read.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of frames sent to the transport
+    pub fn frames_written(&self) -> usize {
+        self.inner.frames_written.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters::new()
+    }
+}
+
+impl fmt::Debug for Counters {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Counters")
+            .field("bytes_read", &self.bytes_read())
+            .field("bytes_written", &self.bytes_written())
+            .field("frames_read", &self.frames_read())
+            .field("frames_written", &self.frames_written())
+            .finish()
+    }
+}
+
+impl<T> Counted<T> {
+    /// Wrap `inner`, updating `counters`
+    pub fn new(inner: T, counters: Counters) -> Counted<T> {
+        Counted {
+            inner: inner,
+            counters: counters,
+        }
+    }
+
+    /// Returns the counters updated by this object
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Returns a reference to the wrapped object
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped object
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consume the wrapper, returning the wrapped object
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> TransportLayer<T> for Counters {
+    type Transport = Counted<T>;
+
+    fn layer(&self, transport: T) -> Counted<T> {
+        Counted::new(transport, self.clone())
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.counters.inner.bytes_read.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.counters.inner.bytes_written.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Io> Io for Counted<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.inner.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
+
+impl<T: Stream> Stream for Counted<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
+        let frame = try_ready!(self.inner.poll());
+
+        if frame.is_some() {
+            self.counters.inner.frames_read.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(Async::Ready(frame))
+    }
+}
+
+impl<T: Sink> Sink for Counted<T> {
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem) -> StartSend<T::SinkItem, T::SinkError> {
+        let res = try!(self.inner.start_send(item));
+
+        if let AsyncSink::Ready = res {
+            self.counters.inner.frames_written.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+impl<T: pipeline::Transport> pipeline::Transport for Counted<T> {
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self) -> io::Result<()> {
+        self.inner.cancel()
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+impl<T: multiplex::Transport<ReadBody>, ReadBody> multiplex::Transport<ReadBody> for Counted<T> {
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.cancel(request_id)
+    }
+
+    fn abort_body(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.abort_body(request_id)
+    }
+
+    fn poll_write_body(&mut self, id: RequestId) -> Async<()> {
+        self.inner.poll_write_body(id)
+    }
+
+    fn dispatching_body(&mut self, id: RequestId, body: &ReadBody) {
+        self.inner.dispatching_body(id, body)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use futures::{Async, Stream, Sink};
+    use tokio_core::io::Io;
+
+    use test_support::Lines;
+    use super::{Counted, Counters};
+
+    struct Mock {
+        input: Vec<u8>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+
+            let n = ::std::cmp::min(dst.len(), self.input.len());
+            dst[..n].copy_from_slice(&self.input[..n]);
+            drop(self.input.drain(..n));
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(src);
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    #[test]
+    fn test_counts_bytes_and_frames() {
+        let counters = Counters::new();
+        let io = Mock {
+            input: b"one\ntwo\npartial".to_vec(),
+            written: vec![],
+        };
+
+        let io = Counted::new(io, counters.clone());
+        let mut transport = Counted::new(io.framed(Lines), counters.clone());
+
+        assert_eq!(Async::Ready(Some(b"one".to_vec())), transport.poll().unwrap());
+        assert_eq!(Async::Ready(Some(b"two".to_vec())), transport.poll().unwrap());
+        assert_eq!(Async::NotReady, transport.poll().unwrap());
+
+        assert!(transport.start_send(b"hello".to_vec()).unwrap().is_ready());
+        assert_eq!(Async::Ready(()), transport.poll_complete().unwrap());
+
+        assert_eq!(15, counters.bytes_read());
+        assert_eq!(6, counters.bytes_written());
+        assert_eq!(2, counters.frames_read());
+        assert_eq!(1, counters.frames_written());
+
+        let io = transport.into_inner().into_inner();
+        assert_eq!(b"hello\n", &io.get_ref().written[..]);
+    }
+}
@@ -0,0 +1,366 @@
+//! A ready-made echo protocol, for smoke tests and benchmarks
+//!
+//! `EchoProto` and `MultiplexEchoProto` are pipelined and multiplexed
+//! protocols exchanging opaque byte payloads, with length-prefixed frames,
+//! and `Echo` is a service answering every request with its payload. Together
+//! with `flood`, they verify that a deployment, e.g. a proxy or a TLS
+//! transport, carries requests end to end, and measure the overhead of the
+//! dispatchers without writing a throwaway codec:
+//!
+//! ```ignore
+//! let client = core.run(TcpClient::new(EchoProto).connect(&addr, &handle)).unwrap();
+//! let report = core.run(flood(client, vec![0; 64], 100_000, 32)).unwrap();
+//!
+//! println!("{} req/s; p50={:?} p99={:?}", report.requests_per_sec(),
+//!          report.quantile(0.5), report.quantile(0.99));
+//! ```
+//!
+//! On the wire, pipelined frames are a big-endian `u32` payload length
+//! followed by the payload. Multiplexed frames start with the big-endian
+//! `u64` request ID.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Poll, Async};
+use tokio_core::io::{Io, Codec, EasyBuf, Framed};
+use tokio_service::Service;
+use multiplex::{self, RequestId};
+use pipeline;
+
+/// The pipelined echo protocol, for both clients and servers
+#[derive(Debug, Clone, Copy)]
+pub struct EchoProto;
+
+/// The multiplexed echo protocol, for both clients and servers
+#[derive(Debug, Clone, Copy)]
+pub struct MultiplexEchoProto;
+
+/// The codec of `EchoProto`
+#[derive(Debug, Clone, Copy)]
+pub struct EchoCodec;
+
+/// The codec of `MultiplexEchoProto`
+#[derive(Debug, Clone, Copy)]
+pub struct MultiplexEchoCodec;
+
+/// A service answering every request with its payload
+#[derive(Debug, Clone, Copy)]
+pub struct Echo;
+
+/// Future returned by `flood`, resolving with the `FloodReport`
+pub struct Flood<S: Service> {
+    service: S,
+    request: S::Request,
+    remaining: usize,
+    concurrency: usize,
+    in_flight: Vec<(Instant, S::Future)>,
+    latencies: Vec<Duration>,
+    errors: usize,
+    start: Instant,
+}
+
+/// The latencies measured by `flood`
+#[derive(Debug, Clone)]
+pub struct FloodReport {
+    // Sorted, of the successful requests only
+    latencies: Vec<Duration>,
+    errors: usize,
+    elapsed: Duration,
+}
+
+const LEN: usize = 4;
+const ID: usize = 8;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf.iter().fold(0, |n, &b| n << 8 | b as u32)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+fn write_u32(n: u32, into: &mut Vec<u8>) {
+    into.extend((0..4).rev().map(|i| (n >> (i * 8)) as u8));
+}
+
+fn write_u64(n: u64, into: &mut Vec<u8>) {
+    into.extend((0..8).rev().map(|i| (n >> (i * 8)) as u8));
+}
+
+// Decodes a length-prefixed payload following a header of `header` bytes,
+// returning the header along with it
+fn decode_payload(buf: &mut EasyBuf, header: usize) -> Option<(EasyBuf, Vec<u8>)> {
+    if buf.len() < header + LEN {
+        return None;
+    }
+
+    let len = read_u32(&buf.as_slice()[header..header + LEN]) as usize;
+    if buf.len() < header + LEN + len {
+        return None;
+    }
+
+    let head = buf.drain_to(header);
+    buf.drain_to(LEN);
+    let payload = buf.drain_to(len).as_slice().to_vec();
+    Some((head, payload))
+}
+
+fn encode_payload(payload: &[u8], into: &mut Vec<u8>) -> io::Result<()> {
+    if payload.len() > u32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "echo payload too large"));
+    }
+
+    write_u32(payload.len() as u32, into);
+    into.extend_from_slice(payload);
+    Ok(())
+}
+
+impl Codec for EchoCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Vec<u8>>> {
+        Ok(decode_payload(buf, 0).map(|(_, payload)| payload))
+    }
+
+    fn encode(&mut self, payload: Vec<u8>, into: &mut Vec<u8>) -> io::Result<()> {
+        encode_payload(&payload, into)
+    }
+}
+
+impl Codec for MultiplexEchoCodec {
+    type In = (RequestId, Vec<u8>);
+    type Out = (RequestId, Vec<u8>);
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<(RequestId, Vec<u8>)>> {
+        Ok(decode_payload(buf, ID).map(|(id, payload)| (read_u64(id.as_slice()), payload)))
+    }
+
+    fn encode(&mut self, (id, payload): (RequestId, Vec<u8>), into: &mut Vec<u8>)
+              -> io::Result<()> {
+        write_u64(id, into);
+        encode_payload(&payload, into)
+    }
+}
+
+impl<T: Io + 'static> pipeline::ServerProto<T> for EchoProto {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<T, EchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(EchoCodec))
+    }
+}
+
+impl<T: Io + 'static> pipeline::ClientProto<T> for EchoProto {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<T, EchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(EchoCodec))
+    }
+}
+
+impl<T: Io + 'static> multiplex::ServerProto<T> for MultiplexEchoProto {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<T, MultiplexEchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(MultiplexEchoCodec))
+    }
+}
+
+impl<T: Io + 'static> multiplex::ClientProto<T> for MultiplexEchoProto {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<T, MultiplexEchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(MultiplexEchoCodec))
+    }
+}
+
+impl Service for Echo {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Error = io::Error;
+    type Future = future::FutureResult<Vec<u8>, io::Error>;
+
+    fn call(&self, payload: Vec<u8>) -> Self::Future {
+        future::ok(payload)
+    }
+}
+
+/// Returns a future calling `service` with `requests` copies of `request`,
+/// keeping up to `concurrency` of them in flight, and measuring the latency
+/// of each one
+///
+/// Failed requests are counted, but do not stop the flood.
+pub fn flood<S>(service: S, request: S::Request, requests: usize, concurrency: usize)
+                -> Flood<S>
+    where S: Service,
+          S::Request: Clone,
+{
+    assert!(concurrency > 0);
+
+    Flood {
+        service: service,
+        request: request,
+        remaining: requests,
+        concurrency: concurrency,
+        in_flight: Vec::with_capacity(concurrency),
+        latencies: Vec::with_capacity(requests),
+        errors: 0,
+        start: Instant::now(),
+    }
+}
+
+impl<S> Future for Flood<S>
+    where S: Service,
+          S::Request: Clone,
+{
+    type Item = FloodReport;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<FloodReport, ()> {
+        loop {
+            while self.remaining > 0 && self.in_flight.len() < self.concurrency {
+                self.remaining -= 1;
+                let response = self.service.call(self.request.clone());
+                self.in_flight.push((Instant::now(), response));
+            }
+
+            let mut completed = false;
+            let mut i = 0;
+
+            while i < self.in_flight.len() {
+                let res = match self.in_flight[i].1.poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
+                    }
+                    Ok(Async::Ready(_)) => true,
+                    Err(_) => false,
+                };
+
+                let (start, _) = self.in_flight.swap_remove(i);
+                completed = true;
+
+                if res {
+                    self.latencies.push(start.elapsed());
+                } else {
+                    self.errors += 1;
+                }
+            }
+
+            if self.in_flight.is_empty() && self.remaining == 0 {
+                break;
+            }
+
+            if !completed {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let mut latencies = ::std::mem::replace(&mut self.latencies, Vec::new());
+        latencies.sort();
+
+        Ok(Async::Ready(FloodReport {
+            latencies: latencies,
+            errors: self.errors,
+            elapsed: self.start.elapsed(),
+        }))
+    }
+}
+
+impl FloodReport {
+    /// Returns the number of successful requests
+    pub fn count(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Returns the number of failed requests
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    /// Returns how long the whole flood took
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the number of successful requests per second
+    pub fn requests_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 +
+            self.elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.count() as f64 / secs
+        }
+    }
+
+    /// Returns the latency below which the given quantile of the successful
+    /// requests fall, e.g. `0.99` for the 99th percentile
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::from_secs(0);
+        }
+
+        let quantile = quantile.max(0.0).min(1.0);
+        let i = (quantile * (self.latencies.len() - 1) as f64).round() as usize;
+        self.latencies[i]
+    }
+
+    /// Returns the latency of every successful request, sorted
+    pub fn latencies(&self) -> &[Duration] {
+        &self.latencies
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::Future;
+    use tokio_core::io::{Codec, EasyBuf};
+
+    use super::{flood, Echo, EchoCodec, MultiplexEchoCodec};
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let mut out = vec![];
+        EchoCodec.encode(b"hello".to_vec(), &mut out).unwrap();
+        MultiplexEchoCodec.encode((7, b"world".to_vec()), &mut out).unwrap();
+
+        let mut buf = EasyBuf::from(out);
+        assert_eq!(Some(b"hello".to_vec()), EchoCodec.decode(&mut buf).unwrap());
+
+        // Partial frames are left in the buffer
+        let mut partial = EasyBuf::from(buf.as_slice()[..10].to_vec());
+        assert_eq!(None, MultiplexEchoCodec.decode(&mut partial).unwrap());
+        assert_eq!(10, partial.len());
+
+        assert_eq!(Some((7, b"world".to_vec())), MultiplexEchoCodec.decode(&mut buf).unwrap());
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn test_flood_reports_latencies() {
+        let report = flood(Echo, vec![1, 2, 3], 10, 3).wait().unwrap();
+
+        assert_eq!(10, report.count());
+        assert_eq!(0, report.errors());
+        assert!(report.quantile(0.5) <= report.quantile(1.0));
+        assert!(report.quantile(1.0) <= report.elapsed() + Duration::from_millis(1));
+    }
+}
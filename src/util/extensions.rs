@@ -0,0 +1,194 @@
+//! Per-request extensions
+//!
+//! An `Extensions` map holds arbitrary values keyed by their type. Protocols
+//! can carry one in their request type and expose it through the
+//! `request_extensions` hook of the server protocol traits, which lets the
+//! server and any service middleware attach metadata to a request that is not
+//! part of the wire format.
+//!
+//! `TcpServer` pre-populates the map of every request with the `ConnectionId`
+//! and `PeerAddr` of the connection it arrived on, as well as a handle to the
+//! connection's `Session`. The dispatchers insert the `ConnectionInfo`
+//! reported by the transport, if the protocol implements the
+//! `transport_info` hook.
+//!
+//! On Linux, `PeerCred::from_socket` reads the credentials of the process on
+//! the other end of a Unix socket, which a server accepting Unix-socket
+//! connections can insert alongside the other connection metadata.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::{io, mem};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use libc;
+
+/// A type map of request extensions
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+/// Identifies the connection a request was received on
+///
+/// Connection IDs are unique for the lifetime of a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub usize);
+
+/// The address of the remote peer a request was received from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// The credentials of the process connected to a Unix socket, as reported
+/// by `SO_PEERCRED`
+///
+/// The credentials are those of the peer at the time it connected, so they
+/// can be used to authorize local callers without exchanging a password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerCred {
+    /// User ID of the peer process
+    pub uid: u32,
+    /// Group ID of the peer process
+    pub gid: u32,
+    /// Process ID of the peer process
+    pub pid: i32,
+}
+
+impl Extensions {
+    /// Return an empty extensions map
+    pub fn new() -> Extensions {
+        Extensions { map: HashMap::new() }
+    }
+
+    /// Insert a value into the map.
+    ///
+    /// If a value of this type already existed, it is returned.
+    pub fn insert<T: Any + Send>(&mut self, val: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    /// Returns a reference to the value of type `T`, if one was inserted
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref())
+    }
+
+    /// Returns a mutable reference to the value of type `T`, if one was
+    /// inserted
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_mut())
+    }
+
+    /// Remove the value of type `T` from the map and return it
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>())
+            .and_then(|val| val.downcast().ok().map(|val| *val))
+    }
+
+    /// Returns true if the map holds no values
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all values from the map
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl PeerCred {
+    /// Returns the credentials of the peer of a connected Unix socket
+    pub fn from_socket<T: AsRawFd>(socket: &T) -> io::Result<PeerCred> {
+        let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(socket.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_PEERCRED,
+                             &mut cred as *mut libc::ucred as *mut libc::c_void,
+                             &mut len)
+        };
+
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PeerCred {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: cred.pid,
+        })
+    }
+}
+
+impl Default for Extensions {
+    fn default() -> Extensions {
+        Extensions::new()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Extensions {{ len: {} }}", self.map.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Extensions, ConnectionId};
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut ext = Extensions::new();
+        assert!(ext.is_empty());
+
+        assert_eq!(None, ext.insert(ConnectionId(1)));
+        assert_eq!(None, ext.insert(5u32));
+
+        assert_eq!(Some(&ConnectionId(1)), ext.get());
+        assert_eq!(Some(&5u32), ext.get());
+        assert_eq!(None, ext.get::<u64>());
+
+        *ext.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(Some(6u32), ext.remove());
+        assert_eq!(None, ext.remove::<u32>());
+    }
+
+    #[test]
+    fn test_insert_replaces() {
+        let mut ext = Extensions::new();
+
+        assert_eq!(None, ext.insert(ConnectionId(1)));
+        assert_eq!(Some(ConnectionId(1)), ext.insert(ConnectionId(2)));
+        assert_eq!(Some(&ConnectionId(2)), ext.get());
+
+        ext.clear();
+        assert!(ext.is_empty());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_peer_cred() {
+        use std::os::unix::net::UnixStream;
+        use std::process;
+        use libc;
+        use super::PeerCred;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let cred = PeerCred::from_socket(&a).unwrap();
+
+        assert_eq!(process::id() as i32, cred.pid);
+        assert_eq!(unsafe { libc::getuid() }, cred.uid);
+        assert_eq!(unsafe { libc::getgid() }, cred.gid);
+        assert_eq!(cred, PeerCred::from_socket(&b).unwrap());
+    }
+}
@@ -0,0 +1,452 @@
+//! Fault injection, for resilience testing
+//!
+//! `Faulty` wraps either an I/O object or a transport, and misbehaves as
+//! configured by a set of `Faults`: it delays reads, drops frames, writes only
+//! part of the buffers it is given, spuriously reports that it would block,
+//! or fails part way through the connection. This lets tests check that
+//! dispatchers, and the retry or timeout layers built on top of them,
+//! behave under partial failure:
+//!
+//! ```ignore
+//! let mut faults = Faults::new();
+//! faults.would_block(0.3);
+//! faults.truncate_writes(7);
+//! faults.fail_after(64 * 1024);
+//!
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     Ok(Faulty::new(io, self.faults.clone()).framed(LineCodec))
+//! }
+//! ```
+//!
+//! Faults are drawn from a pseudo-random generator, seeded with `Faults::seed`
+//! so that failing runs can be reproduced. `Faults` are also a
+//! `TransportLayer`, see `util::layer`.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use futures::{task, Future, Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use tokio_core::io::Io;
+use tokio_core::reactor::{Handle, Timeout};
+use streaming::{pipeline, multiplex};
+use streaming::multiplex::RequestId;
+use util::layer::TransportLayer;
+
+/// The faults injected by `Faulty`
+///
+/// None are injected by default.
+#[derive(Clone)]
+pub struct Faults {
+    latency: Option<(Duration, Handle)>,
+    drop_frames: f64,
+    would_block: f64,
+    max_write: Option<usize>,
+    fail_after: Option<usize>,
+    seed: [u32; 4],
+}
+
+/// An I/O object or transport injecting `Faults`
+pub struct Faulty<T> {
+    inner: T,
+    faults: Faults,
+    rng: XorShiftRng,
+    delay: Option<Timeout>,
+    // Bytes, or frames, read and written so far
+    count: usize,
+}
+
+impl Faults {
+    /// Returns a configuration injecting no faults
+    pub fn new() -> Faults {
+        Faults {
+            latency: None,
+            drop_frames: 0.0,
+            would_block: 0.0,
+            max_write: None,
+            fail_after: None,
+            seed: [0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb],
+        }
+    }
+
+    /// Delay every read by `delay`, using timeouts on the event loop of
+    /// `handle`
+    pub fn latency(&mut self, delay: Duration, handle: &Handle) {
+        self.latency = Some((delay, handle.clone()));
+    }
+
+    /// Silently drop frames read from or written to a transport with the
+    /// given probability, between 0 and 1
+    pub fn drop_frames(&mut self, probability: f64) {
+        self.drop_frames = probability;
+    }
+
+    /// Report that reads and writes would block with the given probability,
+    /// between 0 and 1, even though the wrapped object is ready.
+    ///
+    /// The current task is notified, as if the object became ready right
+    /// away.
+    pub fn would_block(&mut self, probability: f64) {
+        self.would_block = probability;
+    }
+
+    /// Write at most `max` bytes to an I/O object at once, so that writes
+    /// are only partially done
+    pub fn truncate_writes(&mut self, max: usize) {
+        assert!(max > 0);
+        self.max_write = Some(max);
+    }
+
+    /// Fail with `ConnectionReset` once `n` bytes, for I/O objects, or
+    /// frames, for transports, were read and written in total
+    pub fn fail_after(&mut self, n: usize) {
+        self.fail_after = Some(n);
+    }
+
+    /// Seed the generator deciding which operations fail
+    pub fn seed(&mut self, seed: u64) {
+        // The generator must not be seeded with only zeroes
+        self.seed = [seed as u32 | 1, (seed >> 32) as u32, 0x9783_0e05, 0x113b_a7bb];
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Faults {
+        Faults::new()
+    }
+}
+
+impl fmt::Debug for Faults {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Faults")
+            .field("latency", &self.latency.as_ref().map(|&(delay, _)| delay))
+            .field("drop_frames", &self.drop_frames)
+            .field("would_block", &self.would_block)
+            .field("max_write", &self.max_write)
+            .field("fail_after", &self.fail_after)
+            .finish()
+    }
+}
+
+impl<T> TransportLayer<T> for Faults {
+    type Transport = Faulty<T>;
+
+    fn layer(&self, transport: T) -> Faulty<T> {
+        Faulty::new(transport, self.clone())
+    }
+}
+
+impl<T> Faulty<T> {
+    /// Wrap `inner`, injecting `faults`
+    pub fn new(inner: T, faults: Faults) -> Faulty<T> {
+        Faulty {
+            inner: inner,
+            rng: XorShiftRng::from_seed(faults.seed),
+            faults: faults,
+            delay: None,
+            count: 0,
+        }
+    }
+
+    /// Returns a reference to the wrapped object
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped object
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consume the wrapper, returning the wrapped object
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        match self.faults.fail_after {
+            Some(n) if self.count >= n => {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "injected fault"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen::<f64>() < probability
+    }
+
+    fn spurious_block(&mut self) -> bool {
+        let would_block = self.faults.would_block;
+
+        if self.chance(would_block) {
+            trace!("injecting spurious block");
+            task::park().unpark();
+            return true;
+        }
+
+        false
+    }
+
+    // Waits for the read latency, if any, arming the delay for the next read
+    fn poll_delay(&mut self) -> Poll<(), io::Error> {
+        let handle = match self.faults.latency {
+            Some((delay, ref handle)) if self.delay.is_none() => {
+                Some((Instant::now() + delay, handle.clone()))
+            }
+            _ => None,
+        };
+
+        if let Some((at, handle)) = handle {
+            self.delay = Some(try!(Timeout::new_at(at, &handle)));
+        }
+
+        match self.delay {
+            Some(ref mut delay) => delay.poll(),
+            None => Ok(Async::Ready(())),
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "injected fault")
+}
+
+impl<T: Read> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.check_failed());
+
+        if !try!(self.poll_delay()).is_ready() || self.spurious_block() {
+            return Err(would_block());
+        }
+
+        let n = try!(self.inner.read(buf));
+        if n > 0 {
+            self.delay = None;
+            self.count += n;
+        }
+
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Faulty<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.check_failed());
+
+        if self.spurious_block() {
+            return Err(would_block());
+        }
+
+        let len = match self.faults.max_write {
+            Some(max) => cmp::min(max, buf.len()),
+            None => buf.len(),
+        };
+
+        let n = try!(self.inner.write(&buf[..len]));
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.check_failed());
+        self.inner.flush()
+    }
+}
+
+impl<T: Io> Io for Faulty<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.inner.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
+
+impl<T> Stream for Faulty<T>
+    where T: Stream<Error = io::Error>,
+{
+    type Item = T::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, io::Error> {
+        loop {
+            try!(self.check_failed());
+            try_ready!(self.poll_delay());
+
+            if self.spurious_block() {
+                return Ok(Async::NotReady);
+            }
+
+            let frame = match try_ready!(self.inner.poll()) {
+                Some(frame) => frame,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            self.delay = None;
+            self.count += 1;
+
+            let drop_frames = self.faults.drop_frames;
+            if self.chance(drop_frames) {
+                trace!("dropping frame read");
+                continue;
+            }
+
+            return Ok(Async::Ready(Some(frame)));
+        }
+    }
+}
+
+impl<T> Sink for Faulty<T>
+    where T: Sink<SinkError = io::Error>,
+{
+    type SinkItem = T::SinkItem;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, frame: T::SinkItem) -> StartSend<T::SinkItem, io::Error> {
+        try!(self.check_failed());
+
+        if self.spurious_block() {
+            return Ok(AsyncSink::NotReady(frame));
+        }
+
+        let drop_frames = self.faults.drop_frames;
+        if self.chance(drop_frames) {
+            trace!("dropping frame written");
+            self.count += 1;
+            return Ok(AsyncSink::Ready);
+        }
+
+        let res = try!(self.inner.start_send(frame));
+        if res.is_ready() {
+            self.count += 1;
+        }
+
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try!(self.check_failed());
+        self.inner.poll_complete()
+    }
+}
+
+impl<T: pipeline::Transport> pipeline::Transport for Faulty<T> {
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self) -> io::Result<()> {
+        self.inner.cancel()
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+impl<T: multiplex::Transport<ReadBody>, ReadBody> multiplex::Transport<ReadBody> for Faulty<T> {
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.cancel(request_id)
+    }
+
+    fn abort_body(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.abort_body(request_id)
+    }
+
+    fn poll_write_body(&mut self, id: RequestId) -> Async<()> {
+        self.inner.poll_write_body(id)
+    }
+
+    fn dispatching_body(&mut self, id: RequestId, body: &ReadBody) {
+        self.inner.dispatching_body(id, body)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::time::{Duration, Instant};
+
+    use futures::{stream, Future, Stream, Sink};
+    use tokio_core::reactor::Core;
+
+    use util::channel;
+    use super::{Faults, Faulty};
+
+    #[test]
+    fn test_fails_after_frames() {
+        let mut faults = Faults::new();
+        faults.fail_after(2);
+
+        let (a, b) = channel::pair::<u32, u32>(8);
+        let b = Faulty::new(b, faults);
+
+        let _a = a.send_all(stream::iter((0..4).map(Ok::<_, io::Error>))).wait().unwrap();
+
+        let res = b.collect().wait();
+        assert_eq!(io::ErrorKind::ConnectionReset, res.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_drops_frames_and_blocks() {
+        let mut faults = Faults::new();
+        faults.drop_frames(0.5);
+        faults.would_block(0.5);
+        faults.seed(7);
+
+        let (a, b) = channel::pair::<u32, u32>(128);
+        let b = Faulty::new(b, faults);
+
+        let a = a.send_all(stream::iter((0..100).map(Ok::<_, io::Error>))).wait().unwrap().0;
+        drop(a);
+
+        let frames = b.collect().wait().unwrap();
+        assert!(frames.len() > 20 && frames.len() < 80, "frames={}", frames.len());
+
+        // Frames that went through kept their order
+        assert!(frames.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_truncates_writes_and_delays_reads() {
+        let mut core = Core::new().unwrap();
+
+        let mut faults = Faults::new();
+        faults.truncate_writes(3);
+        faults.latency(Duration::from_millis(20), &core.handle());
+
+        let mut io = Faulty::new(vec![], faults.clone());
+        assert_eq!(3, io.write(b"hello").unwrap());
+        assert_eq!(2, io.write(b"lo").unwrap());
+        assert_eq!(b"hello", &io.get_ref()[..]);
+
+        let (a, b) = channel::pair::<u32, u32>(8);
+        let b = Faulty::new(b, faults);
+        let _a = a.send(1).wait().unwrap();
+
+        let start = Instant::now();
+        let (frame, _) = core.run(b.into_future().map_err(|(e, _)| e)).unwrap();
+        assert_eq!(Some(1), frame);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,445 @@
+//! A framed transport with configurable buffer sizes
+//!
+//! `Framed` is the equivalent of `tokio_core::io::Framed`: it turns an I/O
+//! object into a `Stream` and `Sink` of frames using a `Codec`. Unlike the
+//! former, which allocates 8KB read and write buffers for every connection,
+//! the sizes of its buffers are set by a `BufferSizes`:
+//!
+//! - the read buffer starts at `read_initial` bytes and grows as needed to
+//!   hold a partially received frame, up to `read_max` bytes. A frame that
+//!   does not fit is an error.
+//! - the write buffer starts at `write_initial` bytes. Once it holds
+//!   `write_max` bytes, the transport flushes it before accepting more
+//!   frames.
+//!
+//! Buffers that grew past their initial size are released once drained, so
+//! that a burst of large frames does not pin memory for the lifetime of the
+//! connection.
+
+use std::cmp;
+use std::io;
+
+use futures::{Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use tokio_core::io::{Io, Codec, EasyBuf};
+use streaming::{pipeline, multiplex};
+
+/// Read and write buffer sizes of a `Framed` transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSizes {
+    read_initial: usize,
+    read_max: usize,
+    write_initial: usize,
+    write_max: usize,
+}
+
+/// A `Stream` and `Sink` of frames over an I/O object, see the module docs
+pub struct Framed<T, C> {
+    upstream: T,
+    codec: C,
+    sizes: BufferSizes,
+    eof: bool,
+    is_readable: bool,
+    rd: EasyBuf,
+    // Capacity of `rd` as of the last read, which `EasyBuf` does not expose
+    rd_capacity: usize,
+    wr: Vec<u8>,
+}
+
+impl BufferSizes {
+    /// Return the default sizes, which match those of
+    /// `tokio_core::io::Framed`: 8KB initial buffers, a write buffer flushed
+    /// once it holds 8KB, and no limit on the read buffer.
+    pub fn new() -> BufferSizes {
+        BufferSizes {
+            read_initial: 8 * 1024,
+            read_max: usize::max_value(),
+            write_initial: 8 * 1024,
+            write_max: 8 * 1024,
+        }
+    }
+
+    /// Set the initial capacity of the read buffer
+    pub fn read_initial(&mut self, size: usize) {
+        self.read_initial = size;
+    }
+
+    /// Set the maximum size of the read buffer, which bounds the size of a
+    /// frame
+    pub fn read_max(&mut self, size: usize) {
+        assert!(size > 0);
+        self.read_max = size;
+    }
+
+    /// Set the initial capacity of the write buffer
+    pub fn write_initial(&mut self, size: usize) {
+        self.write_initial = size;
+    }
+
+    /// Set the number of buffered bytes above which frames are rejected until
+    /// the write buffer is flushed
+    pub fn write_max(&mut self, size: usize) {
+        self.write_max = size;
+    }
+}
+
+impl Default for BufferSizes {
+    fn default() -> BufferSizes {
+        BufferSizes::new()
+    }
+}
+
+impl<T: Io, C: Codec> Framed<T, C> {
+    /// Frame `io` with `codec`, using buffers of the given sizes
+    pub fn new(io: T, codec: C, sizes: BufferSizes) -> Framed<T, C> {
+        Framed {
+            upstream: io,
+            codec: codec,
+            sizes: sizes,
+            eof: false,
+            is_readable: false,
+            rd: EasyBuf::with_capacity(sizes.read_initial),
+            rd_capacity: sizes.read_initial,
+            wr: Vec::with_capacity(sizes.write_initial),
+        }
+    }
+}
+
+impl<T, C> Framed<T, C> {
+    /// Returns a reference to the underlying I/O object
+    pub fn get_ref(&self) -> &T {
+        &self.upstream
+    }
+
+    /// Returns a mutable reference to the underlying I/O object
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.upstream
+    }
+
+    /// Returns a reference to the codec
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Consumes the `Framed`, returning the underlying I/O object.
+    ///
+    /// Buffered data is lost.
+    pub fn into_inner(self) -> T {
+        self.upstream
+    }
+
+    /// Consumes the `Framed`, returning the bytes read but not decoded yet
+    /// along with the underlying I/O object.
+    ///
+    /// Frames written but not flushed are lost.
+    pub fn into_parts(self) -> (Vec<u8>, T) {
+        (self.rd.as_slice().to_vec(), self.upstream)
+    }
+}
+
+impl<T: Io, C: Codec> Framed<T, C> {
+    /// Read more data into the read buffer, returning the number of bytes
+    /// read
+    fn fill(&mut self) -> io::Result<usize> {
+        if self.rd.len() == 0 && self.rd.get_mut().capacity() > self.sizes.read_initial {
+            self.rd = EasyBuf::with_capacity(self.sizes.read_initial);
+        }
+
+        let max = self.sizes.read_max;
+        let mut buf = self.rd.get_mut();
+        let len = buf.len();
+
+        if len >= max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "frame exceeds the maximum read buffer size"));
+        }
+
+        if buf.capacity() == len {
+            let additional = cmp::max(len, cmp::max(self.sizes.read_initial, 1));
+            buf.reserve(cmp::min(additional, max - len));
+        }
+
+        let end = cmp::min(buf.capacity(), max);
+        buf.resize(end, 0);
+
+        let ret = self.upstream.read(&mut buf[len..]);
+        let n = match ret {
+            Ok(n) => n,
+            Err(_) => 0,
+        };
+
+        buf.truncate(len + n);
+        self.rd_capacity = buf.capacity();
+        ret
+    }
+}
+
+impl<T: Io, C: Codec> Stream for Framed<T, C> {
+    type Item = C::In;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<C::In>, io::Error> {
+        loop {
+            if self.is_readable {
+                if self.eof {
+                    if self.rd.len() == 0 {
+                        return Ok(None.into());
+                    }
+
+                    let frame = try!(self.codec.decode_eof(&mut self.rd));
+                    return Ok(Async::Ready(Some(frame)));
+                }
+
+                if let Some(frame) = try!(self.codec.decode(&mut self.rd)) {
+                    return Ok(Async::Ready(Some(frame)));
+                }
+
+                self.is_readable = false;
+            }
+
+            match self.fill() {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+            }
+
+            self.is_readable = true;
+        }
+    }
+}
+
+impl<T: Io, C: Codec> Sink for Framed<T, C> {
+    type SinkItem = C::Out;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: C::Out) -> StartSend<C::Out, io::Error> {
+        if self.wr.len() >= self.sizes.write_max {
+            try!(self.poll_complete());
+
+            if self.wr.len() >= self.sizes.write_max {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        try!(self.codec.encode(item, &mut self.wr));
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        while !self.wr.is_empty() {
+            let n = match self.upstream.write(&self.wr) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+            };
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write frame to transport"));
+            }
+
+            drop(self.wr.drain(..n));
+        }
+
+        if self.wr.capacity() > self.sizes.write_initial {
+            self.wr = Vec::with_capacity(self.sizes.write_initial);
+        }
+
+        match self.upstream.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.poll_complete()
+    }
+}
+
+impl<T: Io + 'static, C: Codec + 'static> pipeline::Transport for Framed<T, C> {
+    fn memory_used(&self) -> usize {
+        self.rd_capacity + self.wr.capacity()
+    }
+}
+
+impl<T: Io + 'static, C: Codec + 'static, ReadBody> multiplex::Transport<ReadBody> for Framed<T, C> {
+    fn memory_used(&self) -> usize {
+        self.rd_capacity + self.wr.capacity()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use futures::{Async, Stream, Sink, AsyncSink};
+    use tokio_core::io::{Io, Codec, EasyBuf};
+
+    use super::{Framed, BufferSizes};
+
+    /// Reads from a script of chunks, where an empty chunk stands for
+    /// `WouldBlock`, and records writes
+    struct Mock {
+        chunks: Vec<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+
+            if self.chunks[0].is_empty() {
+                self.chunks.remove(0);
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+
+            let n = ::std::cmp::min(dst.len(), self.chunks[0].len());
+            dst[..n].copy_from_slice(&self.chunks[0][..n]);
+            drop(self.chunks[0].drain(..n));
+
+            if self.chunks[0].is_empty() {
+                self.chunks.remove(0);
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(src);
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    /// Fixed size frames of 4 bytes
+    struct Fixed;
+
+    impl Codec for Fixed {
+        type In = Vec<u8>;
+        type Out = Vec<u8>;
+
+        fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Vec<u8>>> {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+
+            Ok(Some(buf.drain_to(4).as_slice().to_vec()))
+        }
+
+        fn encode(&mut self, msg: Vec<u8>, buf: &mut Vec<u8>) -> io::Result<()> {
+            buf.extend_from_slice(&msg);
+            Ok(())
+        }
+    }
+
+    fn mock(chunks: Vec<&[u8]>) -> Mock {
+        Mock {
+            chunks: chunks.into_iter().map(|c| c.to_vec()).collect(),
+            written: vec![],
+        }
+    }
+
+    fn small() -> BufferSizes {
+        let mut sizes = BufferSizes::new();
+        sizes.read_initial(2);
+        sizes.read_max(6);
+        sizes.write_initial(4);
+        sizes.write_max(8);
+        sizes
+    }
+
+    #[test]
+    fn test_read_frames_with_small_buffers() {
+        let io = mock(vec![b"abc", b"", b"defghi", b"jkl"]);
+        let mut framed = Framed::new(io, Fixed, small());
+
+        assert_eq!(Async::NotReady, framed.poll().unwrap());
+        assert_eq!(Async::Ready(Some(b"abcd".to_vec())), framed.poll().unwrap());
+        assert_eq!(Async::Ready(Some(b"efgh".to_vec())), framed.poll().unwrap());
+        assert_eq!(Async::Ready(Some(b"ijkl".to_vec())), framed.poll().unwrap());
+        assert_eq!(Async::Ready(None), framed.poll().unwrap());
+    }
+
+    #[test]
+    fn test_memory_used() {
+        use streaming::pipeline::Transport;
+
+        let io = mock(vec![b"abcdef", b""]);
+        let mut framed = Framed::new(io, Fixed, small());
+        assert_eq!(6, framed.memory_used());
+
+        // The read buffer grew to hold the partial frame
+        assert_eq!(Async::Ready(Some(b"abcd".to_vec())), framed.poll().unwrap());
+        assert!(framed.memory_used() > 6);
+    }
+
+    #[test]
+    fn test_frame_larger_than_read_max() {
+        let mut sizes = small();
+        sizes.read_max(3);
+
+        let io = mock(vec![b"abcd"]);
+        let mut framed = Framed::new(io, Fixed, sizes);
+
+        let err = framed.poll().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_write_backpressure() {
+        struct Blocked;
+
+        impl Read for Blocked {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+            }
+        }
+
+        impl Write for Blocked {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl Io for Blocked {}
+
+        let mut framed = Framed::new(Blocked, Fixed, small());
+
+        assert_eq!(AsyncSink::Ready, framed.start_send(b"abcd".to_vec()).unwrap());
+        assert_eq!(AsyncSink::Ready, framed.start_send(b"efgh".to_vec()).unwrap());
+        assert_eq!(AsyncSink::NotReady(b"ijkl".to_vec()),
+                   framed.start_send(b"ijkl".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_write_buffer_released_after_flush() {
+        let mut framed = Framed::new(mock(vec![]), Fixed, small());
+
+        for _ in 0..2 {
+            assert_eq!(AsyncSink::Ready, framed.start_send(b"abcd".to_vec()).unwrap());
+        }
+
+        assert!(framed.wr.capacity() > 4);
+        assert_eq!(Async::Ready(()), framed.poll_complete().unwrap());
+        assert_eq!(4, framed.wr.capacity());
+        assert_eq!(b"abcdabcd", &framed.get_ref().written[..]);
+    }
+}
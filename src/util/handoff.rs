@@ -0,0 +1,199 @@
+//! Listener handoff between processes (Unix only)
+//!
+//! To restart a server without refusing connections, the running process
+//! passes its listening sockets to the new one over a Unix socket, as
+//! `SCM_RIGHTS` ancillary data, before draining. Both processes then share
+//! the same sockets, so connections queued in the backlog in the meantime
+//! are accepted by whichever accepts them first, and none is refused:
+//!
+//! ```ignore
+//! // The running process bound its sockets up front, see `TcpServer::bind`,
+//! // and hands them off when asked to
+//! let (socket, _) = control.accept()?;
+//! handoff::send_listeners(&socket, &server.listeners()?)?;
+//! drain.start();
+//!
+//! // The new process serves them
+//! let socket = UnixStream::connect(path)?;
+//! let listeners = handoff::recv_listeners(&socket)?;
+//! TcpServer::from_listeners(proto, listeners)?.serve(new_service);
+//! ```
+//!
+//! The old process stops accepting connections once draining, and finishes
+//! serving the ones it accepted, see `Drain`, while the new process accepts
+//! everything else.
+
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use libc;
+
+/// The maximum number of listeners passed at once
+pub const MAX_LISTENERS: usize = 32;
+
+/// Send the given listeners over `socket`, to be received with
+/// `recv_listeners` by the process at the other end.
+///
+/// The listeners keep working in this process, which closes its own copies
+/// once done with them.
+pub fn send_listeners(socket: &UnixStream, listeners: &[TcpListener]) -> io::Result<()> {
+    if listeners.is_empty() || listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "invalid number of listeners to send"));
+    }
+
+    let fds = listeners.iter().map(|l| l.as_raw_fd()).collect::<Vec<_>>();
+    let len = fds.len() * mem::size_of::<RawFd>();
+
+    // Ancillary data cannot be sent on its own, so one byte goes along with
+    // it: the number of listeners
+    let mut data = [fds.len() as u8];
+    let mut control = control_buf();
+
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        if libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    trace!("sent listeners; count={}", fds.len());
+    Ok(())
+}
+
+/// Receive the listeners sent over `socket` with `send_listeners`.
+///
+/// Blocks until they are received, unless the socket is non-blocking.
+pub fn recv_listeners(socket: &UnixStream) -> io::Result<Vec<TcpListener>> {
+    let mut data = [0u8];
+    let mut control = control_buf();
+    let mut listeners = Vec::new();
+
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (control.len() * mem::size_of::<u64>()) as _;
+
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Own the received descriptors first, so that they are closed on
+        // error
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let fds = libc::CMSG_DATA(cmsg) as *const RawFd;
+
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(fds.offset(i as isize));
+                    listeners.push(TcpListener::from_raw_fd(fd));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "socket closed before receiving listeners"));
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 || listeners.len() != data[0] as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "failed to receive all listeners"));
+        }
+    }
+
+    for listener in &listeners {
+        try!(set_cloexec(listener.as_raw_fd()));
+
+        // Fails for sockets other than TCP ones
+        try!(listener.local_addr());
+    }
+
+    trace!("received listeners; count={}", listeners.len());
+    Ok(listeners)
+}
+
+// A buffer for the ancillary data, aligned as `cmsghdr`
+fn control_buf() -> Vec<u64> {
+    let space = unsafe { libc::CMSG_SPACE((MAX_LISTENERS * mem::size_of::<RawFd>()) as u32) };
+    vec![0; (space as usize + 7) / 8]
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+
+    use super::{send_listeners, recv_listeners};
+
+    #[test]
+    fn test_listeners_handed_off() {
+        let a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let b = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![a.local_addr().unwrap(), b.local_addr().unwrap()];
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        send_listeners(&tx, &[a, b]).unwrap();
+
+        // The listeners outlive the copies of the sender
+        let listeners = recv_listeners(&rx).unwrap();
+        assert_eq!(addrs, listeners.iter().map(|l| l.local_addr().unwrap()).collect::<Vec<_>>());
+
+        let mut client = TcpStream::connect(&addrs[1]).unwrap();
+        let (mut server, _) = listeners[1].accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+    }
+
+    #[test]
+    fn test_closed_socket() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        drop(tx);
+
+        let e = recv_listeners(&rx).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+    }
+}
@@ -0,0 +1,195 @@
+//! Version negotiation before framing a connection
+//!
+//! Protocols negotiating a version when a connection opens usually speak a
+//! different wire format during the negotiation than afterwards. `handshake`
+//! frames the connection with a codec dedicated to the negotiation and hands
+//! the framed transport to a future exchanging the negotiation frames. Once
+//! the future resolves to the selected version, the raw I/O object is
+//! returned, ready to be framed with the codec that version calls for:
+//!
+//! ```ignore
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     let negotiation = handshake(io, HelloCodec, |transport| {
+//!         transport.into_future()
+//!             .map_err(|(e, _)| e)
+//!             .and_then(|(hello, transport)| {
+//!                 let version = select_version(hello);
+//!                 transport.send(Hello::Accept(version)).map(move |t| (t, version))
+//!             })
+//!     });
+//!
+//!     Box::new(negotiation.map(|(version, io)| {
+//!         let codec = match version {
+//!             1 => Negotiated::First(CodecV1),
+//!             _ => Negotiated::Second(CodecV2),
+//!         };
+//!         io.framed(codec)
+//!     }))
+//! }
+//! ```
+//!
+//! Bytes the peer sent past the negotiation frames are not lost: the
+//! returned I/O object is a `Peeked`, replaying them before reading from the
+//! connection again. Frames written during the negotiation have to be
+//! flushed before the future resolves, which `Sink::send` takes care of.
+//!
+//! `Negotiated` is a codec delegating to either of two codecs, so that the
+//! connection has a single transport type whatever the selected version.
+
+use std::io;
+
+use futures::{Future, IntoFuture, Poll, Async};
+use tokio_core::io::{Io, Codec, EasyBuf};
+
+use Peeked;
+use util::framed::{Framed, BufferSizes};
+
+/// The maximum size of a negotiation frame
+const MAX_HANDSHAKE_FRAME: usize = 64 * 1024;
+
+/// A codec delegating to one of two codecs, see the module docs
+pub enum Negotiated<A, B> {
+    /// Frame the connection with the first codec
+    First(A),
+    /// Frame the connection with the second codec
+    Second(B),
+}
+
+/// Future returned by `handshake`
+pub struct Handshake<F> {
+    inner: F,
+}
+
+/// Frame `io` with `codec` and negotiate the version of the connection with
+/// the future returned by `negotiate`.
+///
+/// The returned future resolves to the negotiated version along with the
+/// I/O object, see the module docs.
+pub fn handshake<T, C, F, R, V>(io: T, codec: C, negotiate: F) -> Handshake<R::Future>
+    where T: Io,
+          C: Codec,
+          F: FnOnce(Framed<T, C>) -> R,
+          R: IntoFuture<Item = (Framed<T, C>, V), Error = io::Error>,
+{
+    let mut sizes = BufferSizes::new();
+    sizes.read_max(MAX_HANDSHAKE_FRAME);
+
+    Handshake {
+        inner: negotiate(Framed::new(io, codec, sizes)).into_future(),
+    }
+}
+
+impl<F, T, C, V> Future for Handshake<F>
+    where F: Future<Item = (Framed<T, C>, V), Error = io::Error>,
+{
+    type Item = (V, Peeked<T>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let (transport, version) = try_ready!(self.inner.poll());
+        let (buf, io) = transport.into_parts();
+
+        trace!("handshake done; buffered={}", buf.len());
+
+        Ok(Async::Ready((version, Peeked::new(buf, io))))
+    }
+}
+
+impl<A, B> Codec for Negotiated<A, B>
+    where A: Codec,
+          B: Codec<In = A::In, Out = A::Out>,
+{
+    type In = A::In;
+    type Out = A::Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<A::In>> {
+        match *self {
+            Negotiated::First(ref mut codec) => codec.decode(buf),
+            Negotiated::Second(ref mut codec) => codec.decode(buf),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut EasyBuf) -> io::Result<A::In> {
+        match *self {
+            Negotiated::First(ref mut codec) => codec.decode_eof(buf),
+            Negotiated::Second(ref mut codec) => codec.decode_eof(buf),
+        }
+    }
+
+    fn encode(&mut self, msg: A::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        match *self {
+            Negotiated::First(ref mut codec) => codec.encode(msg, buf),
+            Negotiated::Second(ref mut codec) => codec.encode(msg, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use futures::{Future, Stream, Sink};
+    use tokio_core::io::Io;
+
+    use test_support::Lines;
+    use super::{handshake, Negotiated};
+
+    /// Reads from a fixed buffer and records writes
+    struct Mock {
+        rd: io::Cursor<Vec<u8>>,
+        wr: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            self.rd.read(dst)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            self.wr.borrow_mut().extend_from_slice(src);
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    #[test]
+    fn test_handshake_then_framed_with_selected_codec() {
+        let wr = Rc::new(RefCell::new(vec![]));
+        let io = Mock {
+            rd: io::Cursor::new(b"hello 2\nping\n".to_vec()),
+            wr: wr.clone(),
+        };
+
+        let negotiation = handshake(io, Lines, |transport| {
+            transport.into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(hello, transport)| {
+                    let version = if hello == Some(b"hello 2".to_vec()) { 2 } else { 1 };
+                    let accept = format!("accept {}", version).into_bytes();
+                    transport.send(accept).map(move |t| (t, version))
+                })
+        });
+
+        let (version, io) = negotiation.wait().unwrap();
+        assert_eq!(2, version);
+        assert_eq!(&b"accept 2\n"[..], &wr.borrow()[..]);
+
+        let codec = match version {
+            1 => Negotiated::First(Lines),
+            _ => Negotiated::Second(Lines),
+        };
+
+        // The bytes sent past the handshake are framed with the new codec
+        let frames = io.framed(codec).collect().wait().unwrap();
+        assert_eq!(vec![b"ping".to_vec()], frames);
+    }
+}
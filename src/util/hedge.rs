@@ -0,0 +1,291 @@
+//! Hedged requests
+//!
+//! `Hedge` spreads requests over several backends. Each request is first
+//! sent to one backend; if no response arrives within the latency budget, a
+//! copy, the hedge, is sent to the next backend. Whichever response arrives
+//! first is returned and the other response future is dropped:
+//!
+//! ```ignore
+//! let client = Hedge::new(vec![primary, secondary], Duration::from_millis(20), &handle);
+//! ```
+//!
+//! Dropping the response future of a multiplexed client cancels the request,
+//! which lets the transport tell the server to stop working on it; see
+//! `multiplex::Transport::cancel`.
+//!
+//! A failed request is hedged right away. The request fails only if both
+//! backends fail, with the last error.
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Future, Poll, Async};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+/// A service sending a hedge to a second backend when the first one is slow
+pub struct Hedge<S> {
+    backends: Rc<Vec<S>>,
+    delay: Duration,
+    handle: Handle,
+    next: Cell<usize>,
+    hedged: Rc<Cell<usize>>,
+}
+
+/// Response future of `Hedge`
+pub struct Hedged<S: Service> {
+    backends: Rc<Vec<S>>,
+    // The request and the backend to send the hedge to, until it is sent
+    pending: Option<(S::Request, usize)>,
+    timeout: Option<Timeout>,
+    first: Option<S::Future>,
+    second: Option<S::Future>,
+    hedged: Rc<Cell<usize>>,
+}
+
+impl<S> Hedge<S>
+    where S: Service,
+          S::Request: Clone,
+          S::Error: From<io::Error>,
+{
+    /// Spread requests over `backends`, sending a hedge when no response
+    /// arrived after `delay`
+    ///
+    /// Requests are sent to the backends in turn. Panics if fewer than two
+    /// backends are given.
+    pub fn new(backends: Vec<S>, delay: Duration, handle: &Handle) -> Hedge<S> {
+        assert!(backends.len() >= 2, "hedging needs at least two backends");
+
+        Hedge {
+            backends: Rc::new(backends),
+            delay: delay,
+            handle: handle.clone(),
+            next: Cell::new(0),
+            hedged: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns the number of hedges sent so far
+    pub fn hedged(&self) -> usize {
+        self.hedged.get()
+    }
+}
+
+impl<S> Service for Hedge<S>
+    where S: Service,
+          S::Request: Clone,
+          S::Error: From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Hedged<S>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let first = self.next.get();
+        self.next.set((first + 1) % self.backends.len());
+
+        let second = (first + 1) % self.backends.len();
+
+        // Without a timer, hedge right away rather than never
+        let timeout = match Timeout::new(self.delay, &self.handle) {
+            Ok(timeout) => Some(timeout),
+            Err(e) => {
+                debug!("failed to create hedge timeout; err={}", e);
+                None
+            }
+        };
+
+        Hedged {
+            first: Some(self.backends[first].call(req.clone())),
+            second: None,
+            pending: Some((req, second)),
+            timeout: timeout,
+            backends: self.backends.clone(),
+            hedged: self.hedged.clone(),
+        }
+    }
+}
+
+impl<S> Hedged<S>
+    where S: Service,
+{
+    fn send_hedge(&mut self) {
+        self.timeout = None;
+
+        if let Some((req, backend)) = self.pending.take() {
+            trace!("sending hedge; backend={}", backend);
+            self.hedged.set(self.hedged.get() + 1);
+            self.second = Some(self.backends[backend].call(req));
+        }
+    }
+}
+
+impl<S> Future for Hedged<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Item = S::Response;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<S::Response, S::Error> {
+        let expired = match self.timeout {
+            Some(ref mut timeout) => try!(timeout.poll()).is_ready(),
+            None => self.pending.is_some(),
+        };
+
+        if expired {
+            self.send_hedge();
+        }
+
+        loop {
+            let mut error = None;
+
+            for slot in &mut [&mut self.first, &mut self.second] {
+                let res = match **slot {
+                    Some(ref mut f) => f.poll(),
+                    None => continue,
+                };
+
+                match res {
+                    // The other response future is dropped along with `self`
+                    Ok(Async::Ready(response)) => return Ok(Async::Ready(response)),
+                    Ok(Async::NotReady) => {}
+                    Err(e) => {
+                        **slot = None;
+                        error = Some(e);
+                    }
+                }
+            }
+
+            match error {
+                Some(e) => {
+                    if self.first.is_some() || self.second.is_some() {
+                        continue;
+                    }
+
+                    if self.pending.is_none() {
+                        return Err(e);
+                    }
+
+                    // Hedge right away, and poll the hedge
+                    self.send_hedge();
+                }
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+    use futures::sync::oneshot;
+    use tokio_core::reactor::{Core, Handle, Timeout};
+    use tokio_service::Service;
+
+    use super::Hedge;
+
+    /// Responds after `delay`, with an error if `fail` is set
+    #[derive(Clone)]
+    struct Backend {
+        name: &'static str,
+        delay: Duration,
+        fail: bool,
+        handle: Handle,
+        calls: Rc<RefCell<Vec<oneshot::Receiver<()>>>>,
+    }
+
+    impl Service for Backend {
+        type Request = u32;
+        type Response = &'static str;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = &'static str, Error = io::Error>>;
+
+        fn call(&self, _req: u32) -> Self::Future {
+            // Completes with an error once the response future is dropped
+            let (tx, rx) = oneshot::channel::<()>();
+            self.calls.borrow_mut().push(rx);
+
+            let name = self.name;
+            let fail = self.fail;
+            let response = Timeout::new(self.delay, &self.handle).unwrap().and_then(move |_| {
+                drop(tx);
+
+                if fail {
+                    Err(io::Error::new(io::ErrorKind::Other, name))
+                } else {
+                    Ok(name)
+                }
+            });
+
+            Box::new(response)
+        }
+    }
+
+    fn pair(core: &Core, specs: &[(&'static str, u64, bool)])
+            -> (Vec<Backend>, Rc<RefCell<Vec<oneshot::Receiver<()>>>>) {
+        let calls = Rc::new(RefCell::new(vec![]));
+
+        let backends = specs.iter().map(|&(name, delay, fail)| {
+            Backend {
+                name: name,
+                delay: Duration::from_millis(delay),
+                fail: fail,
+                handle: core.handle(),
+                calls: calls.clone(),
+            }
+        }).collect();
+
+        (backends, calls)
+    }
+
+    #[test]
+    fn test_fast_response_is_not_hedged() {
+        let mut core = Core::new().unwrap();
+        let (backends, calls) = pair(&core, &[("a", 0, false), ("b", 0, false)]);
+        let service = Hedge::new(backends, Duration::from_millis(200), &core.handle());
+
+        assert_eq!("a", core.run(service.call(1)).unwrap());
+        assert_eq!("b", core.run(service.call(2)).unwrap());
+        assert_eq!(0, service.hedged());
+        assert_eq!(2, calls.borrow().len());
+    }
+
+    #[test]
+    fn test_slow_response_is_hedged() {
+        let mut core = Core::new().unwrap();
+        let (backends, calls) = pair(&core, &[("slow", 10_000, false), ("fast", 0, false)]);
+        let service = Hedge::new(backends, Duration::from_millis(20), &core.handle());
+
+        assert_eq!("fast", core.run(service.call(1)).unwrap());
+        assert_eq!(1, service.hedged());
+
+        // The slow call was dropped
+        let slow = calls.borrow_mut().remove(0);
+        assert!(core.run(slow).is_err());
+    }
+
+    #[test]
+    fn test_failure_is_hedged_right_away() {
+        let mut core = Core::new().unwrap();
+        let (backends, _calls) = pair(&core, &[("a", 0, true), ("b", 0, false)]);
+        let service = Hedge::new(backends, Duration::from_millis(10_000), &core.handle());
+
+        assert_eq!("b", core.run(service.call(1)).unwrap());
+        assert_eq!(1, service.hedged());
+
+        let (backends, _calls) = pair(&core, &[("a", 0, true), ("b", 0, true)]);
+        let service = Hedge::new(backends, Duration::from_millis(10_000), &core.handle());
+
+        let call = service.call(1).then(|res| future::ok::<_, ()>(res));
+        assert_eq!("b", core.run(call).unwrap().unwrap_err().to_string());
+    }
+}
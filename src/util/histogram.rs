@@ -0,0 +1,348 @@
+//! Request latency histograms
+//!
+//! `RecordLatency` wraps a service and records how long each response takes
+//! to complete into a `Recorder`, which keeps one `Histogram` per key. The key
+//! is computed from the request by a classifier closure, e.g. the request
+//! type, or the `ConnectionId` that `TcpServer` stores in the request
+//! extensions. `Recorder::snapshot` returns a copy of the histograms, from
+//! which percentiles are read:
+//!
+//! ```ignore
+//! let recorder = Recorder::new();
+//! let service = RecordLatency::new(service, recorder.clone(), |req: &Request| req.kind());
+//!
+//! for (kind, histogram) in recorder.snapshot() {
+//!     println!("{:?}: p50={}us p99={}us p999={}us", kind,
+//!              histogram.value_at_quantile(0.5),
+//!              histogram.value_at_quantile(0.99),
+//!              histogram.value_at_quantile(0.999));
+//! }
+//! ```
+//!
+//! Histograms are HDR histograms, from the `hdrhistogram` crate. They keep
+//! two significant digits, i.e. values are tracked with a relative error
+//! below 1%, and grow to cover the largest recorded value, up to the whole
+//! `u64` range. Latencies are recorded in microseconds.
+//!
+//! This module is only available with the `histogram` feature.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll};
+use hdrhistogram;
+use tokio_service::Service;
+
+/// Significant digits kept by the histograms
+const SIGNIFICANT_DIGITS: u8 = 2;
+
+/// A histogram of `u64` values
+#[derive(Clone)]
+pub struct Histogram {
+    inner: hdrhistogram::Histogram<u64>,
+}
+
+/// Records latency histograms, keyed by `K`
+///
+/// Clones share the same histograms.
+pub struct Recorder<K> {
+    inner: Arc<Mutex<HashMap<K, Histogram>>>,
+}
+
+/// A service recording the latency of each request of the inner service
+pub struct RecordLatency<S, K, F> {
+    inner: S,
+    recorder: Recorder<K>,
+    classify: F,
+}
+
+/// Response future of `RecordLatency`
+pub struct Timed<F, K> {
+    inner: F,
+    key: Option<K>,
+    start: Instant,
+    recorder: Recorder<K>,
+}
+
+impl Histogram {
+    /// Return an empty histogram
+    pub fn new() -> Histogram {
+        Histogram {
+            inner: hdrhistogram::Histogram::new(SIGNIFICANT_DIGITS)
+                .expect("valid significant digits"),
+        }
+    }
+
+    /// Record a value
+    pub fn record(&mut self, value: u64) {
+        // The histogram resizes to cover the value, which only fails for
+        // values beyond what it can track
+        if self.inner.record(value).is_err() {
+            self.inner.saturating_record(value);
+        }
+    }
+
+    /// Record a duration, in microseconds
+    pub fn record_duration(&mut self, duration: Duration) {
+        let micros = duration.as_secs()
+            .saturating_mul(1_000_000)
+            .saturating_add(duration.subsec_nanos() as u64 / 1_000);
+
+        self.record(micros);
+    }
+
+    /// Returns the number of recorded values
+    pub fn count(&self) -> u64 {
+        self.inner.len()
+    }
+
+    /// Returns the smallest recorded value, or 0 if the histogram is empty
+    pub fn min(&self) -> u64 {
+        self.inner.min()
+    }
+
+    /// Returns the largest recorded value, or 0 if the histogram is empty
+    pub fn max(&self) -> u64 {
+        self.inner.max()
+    }
+
+    /// Returns the mean of the recorded values, or 0 if the histogram is empty
+    pub fn mean(&self) -> f64 {
+        self.inner.mean()
+    }
+
+    /// Returns the value below which the given fraction of the recorded
+    /// values fall, e.g. `0.99` for the 99th percentile.
+    ///
+    /// The returned value is the largest value equivalent to the matching
+    /// one, within the precision of the histogram.
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        self.inner.value_at_quantile(quantile.max(0.0).min(1.0))
+    }
+
+    /// Add the values recorded by `other` to this histogram
+    pub fn merge(&mut self, other: &Histogram) {
+        self.inner.add(&other.inner).expect("histogram resizes to fit");
+    }
+
+    /// Remove all recorded values
+    pub fn clear(&mut self) {
+        self.inner.reset();
+    }
+
+    /// Returns the underlying HDR histogram, e.g. to serialize it
+    pub fn get_ref(&self) -> &hdrhistogram::Histogram<u64> {
+        &self.inner
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> Recorder<K> {
+    /// Return a recorder without any histograms
+    pub fn new() -> Recorder<K> {
+        Recorder { inner: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Record a latency in the histogram for `key`
+    pub fn record(&self, key: K, latency: Duration) {
+        self.inner.lock().unwrap()
+            .entry(key)
+            .or_insert_with(Histogram::new)
+            .record_duration(latency);
+    }
+
+    /// Returns a copy of the histograms recorded so far
+    pub fn snapshot(&self) -> HashMap<K, Histogram> {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// Returns a copy of the histogram for `key`, if any latency was recorded
+    /// for it
+    pub fn get(&self, key: &K) -> Option<Histogram> {
+        self.inner.lock().unwrap().get(key).cloned()
+    }
+
+    /// Remove all histograms
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Recorder<K> {
+    fn default() -> Recorder<K> {
+        Recorder::new()
+    }
+}
+
+impl<K> Clone for Recorder<K> {
+    fn clone(&self) -> Recorder<K> {
+        Recorder { inner: self.inner.clone() }
+    }
+}
+
+impl<S, K, F> RecordLatency<S, K, F>
+    where S: Service,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> K,
+{
+    /// Wrap `inner`, recording the latency of each request in the histogram
+    /// of the key returned by `classify`
+    pub fn new(inner: S, recorder: Recorder<K>, classify: F) -> RecordLatency<S, K, F> {
+        RecordLatency {
+            inner: inner,
+            recorder: recorder,
+            classify: classify,
+        }
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, K, F> Service for RecordLatency<S, K, F>
+    where S: Service,
+          K: Hash + Eq + Clone,
+          F: Fn(&S::Request) -> K,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Timed<S::Future, K>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let key = (self.classify)(&req);
+
+        Timed {
+            start: Instant::now(),
+            inner: self.inner.call(req),
+            key: Some(key),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+impl<F, K> Future for Timed<F, K>
+    where F: Future,
+          K: Hash + Eq + Clone,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.inner.poll();
+
+        // Failed responses are recorded as well
+        if res.as_ref().map(|res| res.is_ready()).unwrap_or(true) {
+            if let Some(key) = self.key.take() {
+                self.recorder.record(key, self.start.elapsed());
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use super::*;
+
+    #[test]
+    fn test_exact_small_values() {
+        let mut h = Histogram::new();
+
+        for v in 0..128 {
+            h.record(v);
+        }
+
+        assert_eq!(128, h.count());
+        assert_eq!(0, h.min());
+        assert_eq!(127, h.max());
+        assert_eq!(63, h.value_at_quantile(0.5));
+        assert_eq!(127, h.value_at_quantile(1.0));
+    }
+
+    #[test]
+    fn test_relative_error() {
+        for &v in &[1_000, 12_345, 1_000_000, 987_654_321, u64::max_value() / 2] {
+            let mut h = Histogram::new();
+            h.record(v);
+            h.record(0);
+
+            let p = h.value_at_quantile(1.0);
+            assert!(p >= v, "v={} p={}", v, p);
+            assert!((p - v) as f64 <= v as f64 * 0.01, "v={} p={}", v, p);
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut h = Histogram::new();
+
+        for v in 1..10_001 {
+            h.record(v);
+        }
+
+        let p99 = h.value_at_quantile(0.99);
+        assert!(p99 >= 9_900 && p99 <= 9_900 + 9_900 / 100, "p99={}", p99);
+        assert!((h.mean() - 5000.5).abs() < 50.0, "mean={}", h.mean());
+
+        let mut other = Histogram::new();
+        other.record(50_000);
+        h.merge(&other);
+
+        assert_eq!(10_001, h.count());
+        assert!(h.max() >= 50_000 && h.max() <= 50_500, "max={}", h.max());
+
+        h.clear();
+        assert_eq!(0, h.count());
+    }
+
+    #[test]
+    fn test_record_latency() {
+        struct Echo;
+
+        impl Service for Echo {
+            type Request = u32;
+            type Response = u32;
+            type Error = io::Error;
+            type Future = future::FutureResult<u32, io::Error>;
+
+            fn call(&self, req: u32) -> Self::Future {
+                if req == 0 {
+                    future::err(io::Error::new(io::ErrorKind::Other, "zero"))
+                } else {
+                    future::ok(req)
+                }
+            }
+        }
+
+        let recorder = Recorder::new();
+        let service = RecordLatency::new(Echo, recorder.clone(), |req: &u32| req % 2 == 0);
+
+        for req in 0..5 {
+            drop(service.call(req).wait());
+        }
+
+        let snapshot = recorder.snapshot();
+        assert_eq!(3, snapshot[&true].count());
+        assert_eq!(2, snapshot[&false].count());
+        assert!(snapshot[&true].max() < 1_000_000);
+
+        recorder.clear();
+        assert!(recorder.get(&true).is_none());
+    }
+}
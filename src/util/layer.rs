@@ -0,0 +1,369 @@
+//! Composable transport middleware
+//!
+//! A `TransportLayer` wraps a transport in another one, e.g. to compress,
+//! checksum, throttle, instrument or encrypt its frames. Layers stack, the
+//! first one wrapping the transport built by the codec and every following
+//! one wrapping the previous, so that a protocol assembles its transport
+//! declaratively in `bind_transport`:
+//!
+//! ```ignore
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     let layers = MapFrames::new(Checksum)
+//!         .stack(MapFrames::new(Compress::new(self.level)))
+//!         .stack(self.counters.clone());
+//!
+//!     Ok(layers.layer(io.framed(BytesCodec)))
+//! }
+//! ```
+//!
+//! Any two layers are stacked with `Stack::new`, and `MapFrames` and
+//! `Stack` have a `stack` method to chain further ones.
+//!
+//! Most wrappers only transform frames one at a time. Those implement
+//! `FrameMap`, and are turned into a layer with `MapFrames`, whose transport
+//! takes care of the `Stream` and `Sink` plumbing: keeping frames in order
+//! while the wrapped transport is not ready, propagating errors and
+//! forwarding the `pipeline::Transport` and `multiplex::Transport` hooks.
+//! Wrappers that need more control, e.g. to delay frames, implement
+//! `TransportLayer` with a transport of their own.
+
+use std::io;
+use std::time::Instant;
+
+use futures::{Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use streaming::{pipeline, multiplex};
+use streaming::multiplex::RequestId;
+
+/// Builds a transport wrapping another one
+pub trait TransportLayer<T> {
+    /// The wrapping transport
+    type Transport;
+
+    /// Wrap `transport`, using `self` for any configuration
+    fn layer(&self, transport: T) -> Self::Transport;
+}
+
+/// Two layers applied one on top of the other
+#[derive(Debug, Clone)]
+pub struct Stack<A, B> {
+    inner: A,
+    outer: B,
+}
+
+impl<A, B> Stack<A, B> {
+    /// Returns a layer applying `inner`, then `outer` on top of it
+    pub fn new(inner: A, outer: B) -> Stack<A, B> {
+        Stack {
+            inner: inner,
+            outer: outer,
+        }
+    }
+
+    /// Returns a layer applying this stack, then `outer` on top of it
+    pub fn stack<L>(self, outer: L) -> Stack<Stack<A, B>, L> {
+        Stack::new(self, outer)
+    }
+}
+
+impl<T, A, B> TransportLayer<T> for Stack<A, B>
+    where A: TransportLayer<T>,
+          B: TransportLayer<A::Transport>,
+{
+    type Transport = B::Transport;
+
+    fn layer(&self, transport: T) -> B::Transport {
+        self.outer.layer(self.inner.layer(transport))
+    }
+}
+
+/// Transforms the frames of a transport one at a time
+///
+/// Every connection gets its own clone of the value given to `MapFrames`,
+/// so that implementations may keep per-connection state, e.g. a
+/// compression dictionary.
+pub trait FrameMap {
+    /// Frames read from the wrapped transport
+    type ReadIn;
+
+    /// Frames yielded to the dispatcher
+    type ReadOut;
+
+    /// Frames written by the dispatcher
+    type WriteIn;
+
+    /// Frames written to the wrapped transport
+    type WriteOut;
+
+    /// Transform a frame read from the wrapped transport. An error fails the
+    /// transport as if the wrapped one had.
+    fn map_read(&mut self, frame: Self::ReadIn) -> io::Result<Self::ReadOut>;
+
+    /// Transform a frame before writing it to the wrapped transport. An
+    /// error fails the write.
+    fn map_write(&mut self, frame: Self::WriteIn) -> io::Result<Self::WriteOut>;
+}
+
+/// A layer transforming frames with a `FrameMap`
+#[derive(Debug, Clone)]
+pub struct MapFrames<M> {
+    map: M,
+}
+
+/// The transport of `MapFrames`
+pub struct Mapped<T, M: FrameMap> {
+    inner: T,
+    map: M,
+    // A frame accepted and transformed, but not accepted by the wrapped
+    // transport yet
+    buffered: Option<M::WriteOut>,
+}
+
+impl<M> MapFrames<M> {
+    /// Returns a layer transforming frames with a clone of `map` per
+    /// transport
+    pub fn new(map: M) -> MapFrames<M> {
+        MapFrames { map: map }
+    }
+
+    /// Returns a layer applying this one, then `outer` on top of it
+    pub fn stack<L>(self, outer: L) -> Stack<MapFrames<M>, L> {
+        Stack::new(self, outer)
+    }
+}
+
+impl<T, M: FrameMap + Clone> TransportLayer<T> for MapFrames<M> {
+    type Transport = Mapped<T, M>;
+
+    fn layer(&self, transport: T) -> Mapped<T, M> {
+        Mapped {
+            inner: transport,
+            map: self.map.clone(),
+            buffered: None,
+        }
+    }
+}
+
+impl<T, M: FrameMap> Mapped<T, M> {
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns a reference to the frame map
+    pub fn map_ref(&self) -> &M {
+        &self.map
+    }
+}
+
+impl<T, M> Mapped<T, M>
+    where T: Sink<SinkItem = M::WriteOut, SinkError = io::Error>,
+          M: FrameMap,
+{
+    fn poll_buffered(&mut self) -> Poll<(), io::Error> {
+        if let Some(frame) = self.buffered.take() {
+            if let AsyncSink::NotReady(frame) = try!(self.inner.start_send(frame)) {
+                self.buffered = Some(frame);
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T, M> Stream for Mapped<T, M>
+    where T: Stream<Item = M::ReadIn, Error = io::Error>,
+          M: FrameMap,
+{
+    type Item = M::ReadOut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<M::ReadOut>, io::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(frame) => Ok(Async::Ready(Some(try!(self.map.map_read(frame))))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T, M> Sink for Mapped<T, M>
+    where T: Sink<SinkItem = M::WriteOut, SinkError = io::Error>,
+          M: FrameMap,
+{
+    type SinkItem = M::WriteIn;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, frame: M::WriteIn) -> StartSend<M::WriteIn, io::Error> {
+        // The transformed frame cannot be handed back, so it is only
+        // transformed once there is room for it
+        if !try!(self.poll_buffered()).is_ready() {
+            return Ok(AsyncSink::NotReady(frame));
+        }
+
+        let frame = try!(self.map.map_write(frame));
+
+        if let AsyncSink::NotReady(frame) = try!(self.inner.start_send(frame)) {
+            self.buffered = Some(frame);
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_buffered());
+        self.inner.poll_complete()
+    }
+}
+
+impl<T, M> pipeline::Transport for Mapped<T, M>
+    where T: pipeline::Transport<Item = M::ReadIn, SinkItem = M::WriteOut>,
+          M: FrameMap + 'static,
+{
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self) -> io::Result<()> {
+        self.inner.cancel()
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+impl<T, M, ReadBody> multiplex::Transport<ReadBody> for Mapped<T, M>
+    where T: multiplex::Transport<ReadBody, Item = M::ReadIn, SinkItem = M::WriteOut>,
+          M: FrameMap + 'static,
+{
+    fn tick(&mut self) {
+        self.inner.tick()
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.inner.poll_timeout()
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.cancel(request_id)
+    }
+
+    fn abort_body(&mut self, request_id: RequestId) -> io::Result<()> {
+        self.inner.abort_body(request_id)
+    }
+
+    fn poll_write_body(&mut self, id: RequestId) -> Async<()> {
+        self.inner.poll_write_body(id)
+    }
+
+    fn dispatching_body(&mut self, id: RequestId, body: &ReadBody) {
+        self.inner.dispatching_body(id, body)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.memory_used()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use futures::{stream, Future, Stream, Sink};
+
+    use util::channel;
+    use util::counted::Counters;
+    use super::{TransportLayer, FrameMap, MapFrames};
+
+    /// Appends the sum of the bytes of every frame, checking it on read
+    #[derive(Clone)]
+    struct Checksum;
+
+    impl FrameMap for Checksum {
+        type ReadIn = Vec<u8>;
+        type ReadOut = Vec<u8>;
+        type WriteIn = Vec<u8>;
+        type WriteOut = Vec<u8>;
+
+        fn map_read(&mut self, mut frame: Vec<u8>) -> io::Result<Vec<u8>> {
+            match frame.pop() {
+                Some(sum) if sum == checksum(&frame) => Ok(frame),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad checksum")),
+            }
+        }
+
+        fn map_write(&mut self, mut frame: Vec<u8>) -> io::Result<Vec<u8>> {
+            let sum = checksum(&frame);
+            frame.push(sum);
+            Ok(frame)
+        }
+    }
+
+    /// Numbers frames, as strings
+    #[derive(Clone)]
+    struct Numbered(usize);
+
+    impl FrameMap for Numbered {
+        type ReadIn = Vec<u8>;
+        type ReadOut = String;
+        type WriteIn = String;
+        type WriteOut = Vec<u8>;
+
+        fn map_read(&mut self, frame: Vec<u8>) -> io::Result<String> {
+            String::from_utf8(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        fn map_write(&mut self, frame: String) -> io::Result<Vec<u8>> {
+            self.0 += 1;
+            Ok(format!("{}:{}", self.0, frame).into_bytes())
+        }
+    }
+
+    fn checksum(frame: &[u8]) -> u8 {
+        frame.iter().fold(0, |sum: u8, &b| sum.wrapping_add(b))
+    }
+
+    #[test]
+    fn test_stacked_layers() {
+        let counters = Counters::new();
+        let layers = MapFrames::new(Checksum)
+            .stack(MapFrames::new(Numbered(0)))
+            .stack(counters.clone());
+
+        let (a, b) = channel::pair::<Vec<u8>, Vec<u8>>(8);
+        let a = layers.layer(a);
+        let b = layers.layer(b);
+
+        let frames = vec!["one", "two", "three"].into_iter().map(|s| Ok::<_, io::Error>(String::from(s)));
+        let _a = a.send_all(stream::iter(frames)).wait().unwrap();
+
+        let frames = b.take(3).collect().wait().unwrap();
+        assert_eq!(vec!["1:one", "2:two", "3:three"], frames);
+
+        assert_eq!(3, counters.frames_written());
+        assert_eq!(3, counters.frames_read());
+    }
+
+    #[test]
+    fn test_map_errors_fail_the_transport() {
+        let (a, b) = channel::pair::<Vec<u8>, Vec<u8>>(1);
+        let b = MapFrames::new(Checksum).layer(b);
+
+        let _a = a.send(vec![1, 2, 4]).wait().unwrap();
+
+        let e = match b.into_future().wait() {
+            Err((e, _)) => e,
+            Ok(_) => panic!("expected the checksum to fail"),
+        };
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
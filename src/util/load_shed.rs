@@ -0,0 +1,196 @@
+//! Load shedding
+//!
+//! `LoadShed` wraps the client service of a connection and bounds the number
+//! of calls queued or in flight on it. Calls made beyond the limit are not
+//! buffered; they fail right away with an `Overloaded` error, which keeps the
+//! latency of the accepted calls in check when the connection falls behind:
+//!
+//! ```ignore
+//! let client = LoadShed::new(client, 100);
+//!
+//! match client.call(req).wait() {
+//!     Err(ref e) if load_shed::is_overloaded(e) => retry_elsewhere(),
+//!     res => res,
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use futures::{Future, Poll, Async};
+use tokio_service::Service;
+
+/// The error a call is failed with when it is shed
+///
+/// It is wrapped in an `io::Error` of kind `Other`; see `is_overloaded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded;
+
+/// A service failing calls beyond a number of outstanding ones
+pub struct LoadShed<S> {
+    inner: S,
+    max: usize,
+    pending: Rc<Cell<usize>>,
+}
+
+/// Response future of `LoadShed`
+pub struct Shed<F> {
+    inner: Option<F>,
+    pending: Rc<Cell<usize>>,
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("too many pending requests")
+    }
+}
+
+impl Error for Overloaded {}
+
+/// Returns an `io::Error` wrapping `Overloaded`
+pub fn overloaded() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, Overloaded)
+}
+
+/// Returns true if the error was caused by a shed call
+pub fn is_overloaded(error: &io::Error) -> bool {
+    error.get_ref().map(|e| e.is::<Overloaded>()).unwrap_or(false)
+}
+
+impl<S> LoadShed<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    /// Wrap `inner`, allowing at most `max` calls to be outstanding
+    pub fn new(inner: S, max: usize) -> LoadShed<S> {
+        LoadShed {
+            inner: inner,
+            max: max,
+            pending: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns the number of calls currently queued or in flight
+    pub fn pending(&self) -> usize {
+        self.pending.get()
+    }
+
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Service for LoadShed<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Shed<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        if self.pending.get() >= self.max {
+            trace!("shedding request; pending={}", self.pending.get());
+
+            return Shed {
+                inner: None,
+                pending: self.pending.clone(),
+            };
+        }
+
+        self.pending.set(self.pending.get() + 1);
+
+        Shed {
+            inner: Some(self.inner.call(req)),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<F> Future for Shed<F>
+    where F: Future,
+          F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = match self.inner {
+            Some(ref mut f) => f.poll(),
+            None => return Err(overloaded().into()),
+        };
+
+        match res {
+            Ok(Async::NotReady) => {}
+            _ => {
+                self.inner = None;
+                self.pending.set(self.pending.get() - 1);
+            }
+        }
+
+        res
+    }
+}
+
+impl<F> Drop for Shed<F> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            self.pending.set(self.pending.get() - 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use futures::Future;
+    use futures::sync::oneshot;
+    use tokio_service::Service;
+
+    use super::{LoadShed, is_overloaded};
+
+    struct Pending;
+
+    impl Service for Pending {
+        type Request = oneshot::Receiver<u32>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = u32, Error = io::Error>>;
+
+        fn call(&self, rx: oneshot::Receiver<u32>) -> Self::Future {
+            Box::new(rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "canceled")))
+        }
+    }
+
+    #[test]
+    fn test_sheds_beyond_max() {
+        let service = LoadShed::new(Pending, 2);
+
+        let (tx1, rx1) = oneshot::channel();
+        let (_tx2, rx2) = oneshot::channel();
+        let (_tx3, rx3) = oneshot::channel();
+
+        let a = service.call(rx1);
+        let b = service.call(rx2);
+        assert_eq!(2, service.pending());
+
+        let e = service.call(rx3).wait().unwrap_err();
+        assert!(is_overloaded(&e));
+        assert!(!is_overloaded(&io::Error::new(io::ErrorKind::Other, "other")));
+
+        tx1.complete(1);
+        assert_eq!(1, a.wait().unwrap());
+        drop(b);
+        assert_eq!(0, service.pending());
+
+        let (tx, rx) = oneshot::channel();
+        tx.complete(2);
+        assert_eq!(2, service.call(rx).wait().unwrap());
+    }
+}
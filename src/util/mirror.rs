@@ -0,0 +1,198 @@
+//! Request mirroring
+//!
+//! `Mirror` wraps the service of a server and forwards a copy of every
+//! request to a shadow service, e.g. a client bound to a staging backend,
+//! while responses are only ever served by the primary service. This lets a
+//! new implementation be validated against live traffic without affecting
+//! it:
+//!
+//! ```ignore
+//! let service = Mirror::new(primary, staging_client, &handle);
+//! ```
+//!
+//! Shadow calls are spawned on the event loop and their responses, or
+//! errors, are discarded. They do not delay the primary response. To keep a
+//! slow shadow from piling up work, see `Mirror::max_pending`.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use futures::{Future, Poll};
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+/// A service forwarding a copy of each request to a shadow service
+pub struct Mirror<S, M> {
+    primary: S,
+    shadow: Rc<M>,
+    handle: Handle,
+    max: usize,
+    pending: Rc<Cell<usize>>,
+    skipped: Cell<usize>,
+}
+
+/// Response future of `Mirror`, resolving with the primary response
+pub struct Mirrored<F> {
+    inner: F,
+}
+
+impl<S, M> Mirror<S, M>
+    where S: Service,
+          S::Request: Clone,
+          M: Service<Request = S::Request> + 'static,
+          M::Future: 'static,
+{
+    /// Serve requests with `primary`, mirroring them to `shadow` on the event
+    /// loop of `handle`
+    pub fn new(primary: S, shadow: M, handle: &Handle) -> Mirror<S, M> {
+        Mirror {
+            primary: primary,
+            shadow: Rc::new(shadow),
+            handle: handle.clone(),
+            max: usize::max_value(),
+            pending: Rc::new(Cell::new(0)),
+            skipped: Cell::new(0),
+        }
+    }
+
+    /// Set the maximum number of shadow calls in flight. Requests arriving
+    /// beyond it are not mirrored. Defaults to no limit.
+    pub fn max_pending(&mut self, max: usize) {
+        self.max = max;
+    }
+
+    /// Returns the number of shadow calls currently in flight
+    pub fn pending(&self) -> usize {
+        self.pending.get()
+    }
+
+    /// Returns the number of requests that were not mirrored because of
+    /// `max_pending`
+    pub fn skipped(&self) -> usize {
+        self.skipped.get()
+    }
+
+    /// Returns a reference to the primary service
+    pub fn get_ref(&self) -> &S {
+        &self.primary
+    }
+
+    fn mirror(&self, req: S::Request) {
+        if self.pending.get() >= self.max {
+            trace!("not mirroring request; pending={}", self.pending.get());
+            self.skipped.set(self.skipped.get() + 1);
+            return;
+        }
+
+        self.pending.set(self.pending.get() + 1);
+
+        let pending = self.pending.clone();
+        let shadow = self.shadow.call(req).then(move |res| {
+            if res.is_err() {
+                trace!("shadow call failed");
+            }
+
+            pending.set(pending.get() - 1);
+            Ok::<(), ()>(())
+        });
+
+        self.handle.spawn(shadow);
+    }
+}
+
+impl<S, M> Service for Mirror<S, M>
+    where S: Service,
+          S::Request: Clone,
+          M: Service<Request = S::Request> + 'static,
+          M::Future: 'static,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Mirrored<S::Future>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        self.mirror(req.clone());
+
+        Mirrored { inner: self.primary.call(req) }
+    }
+}
+
+impl<F: Future> Future for Mirrored<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use futures::{future, Future};
+    use futures::sync::oneshot;
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    use super::Mirror;
+
+    struct Double;
+
+    impl Service for Double {
+        type Request = u32;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            future::ok(req * 2)
+        }
+    }
+
+    /// Records the requests it gets, answering once `release` completes
+    struct Shadow {
+        seen: Rc<RefCell<Vec<u32>>>,
+        release: future::Shared<oneshot::Receiver<()>>,
+    }
+
+    impl Service for Shadow {
+        type Request = u32;
+        type Response = String;
+        type Error = ();
+        type Future = Box<dyn Future<Item = String, Error = ()>>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            self.seen.borrow_mut().push(req);
+            Box::new(self.release.clone().then(|_| Err(())))
+        }
+    }
+
+    #[test]
+    fn test_requests_mirrored_to_shadow() {
+        let mut core = Core::new().unwrap();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let (tx, rx) = oneshot::channel();
+
+        let shadow = Shadow { seen: seen.clone(), release: rx.shared() };
+        let mut service = Mirror::new(Double, shadow, &core.handle());
+        service.max_pending(2);
+
+        // Served by the primary, regardless of the shadow
+        assert_eq!(2, core.run(service.call(1)).unwrap());
+        assert_eq!(4, core.run(service.call(2)).unwrap());
+        assert_eq!(6, core.run(service.call(3)).unwrap());
+
+        assert_eq!(vec![1, 2], *seen.borrow());
+        assert_eq!(2, service.pending());
+        assert_eq!(1, service.skipped());
+
+        // Failed shadow calls are discarded
+        tx.complete(());
+        core.turn(None);
+        assert_eq!(0, service.pending());
+    }
+}
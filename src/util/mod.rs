@@ -1,3 +1,30 @@
 //! Utilities for building protocols
 
+pub mod breaker;
+pub mod cache;
+pub mod channel;
+pub mod chunked;
 pub mod client_proxy;
+pub mod coalesce;
+pub mod conformance;
+pub mod counted;
+pub mod echo;
+pub mod extensions;
+pub mod fault;
+pub mod framed;
+#[cfg(unix)]
+pub mod handoff;
+pub mod handshake;
+pub mod hedge;
+#[cfg(feature = "histogram")]
+pub mod histogram;
+pub mod layer;
+pub mod load_shed;
+pub mod mirror;
+pub mod session;
+pub mod sni;
+pub mod spawn_pool;
+pub mod transport_info;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod unix;
+pub mod upgrade;
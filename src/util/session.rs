@@ -0,0 +1,75 @@
+//! Per-connection session state
+//!
+//! Stateful protocols (authenticated sessions, prepared statements, ...) need
+//! somewhere to keep data that outlives a single request but is scoped to a
+//! connection. A `Session` is created when a connection is bound and a handle
+//! to it is attached to every request dispatched on that connection.
+//!
+//! `TcpServer` inserts the connection's `Session` into the `Extensions` map of
+//! every request; see the `request_extensions` hook of the server protocol
+//! traits.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use util::extensions::Extensions;
+
+/// A handle to the state of a single connection
+///
+/// Cloning the handle is cheap; all clones refer to the same state, which is
+/// dropped once the connection and all of its requests are gone.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Mutex<Extensions>>,
+}
+
+impl Session {
+    /// Return a new, empty session
+    pub fn new() -> Session {
+        Session { inner: Arc::new(Mutex::new(Extensions::new())) }
+    }
+
+    /// Lock the session, giving access to its state
+    ///
+    /// The state is a typed map; see `Extensions` for the available
+    /// operations.
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a, Extensions> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns true if both handles refer to the same session
+    pub fn is_same(&self, other: &Session) -> bool {
+        &*self.inner as *const _ == &*other.inner as *const _
+    }
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Session {{ .. }}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Session;
+
+    #[test]
+    fn test_clones_share_state() {
+        let session = Session::new();
+        let other = session.clone();
+
+        session.lock().insert(42u32);
+        assert_eq!(Some(&42u32), other.lock().get());
+        assert!(session.is_same(&other));
+        assert!(!session.is_same(&Session::new()));
+    }
+}
@@ -0,0 +1,166 @@
+//! Routing TLS connections by server name
+//!
+//! A client opening a TLS connection sends the name of the server it wants
+//! to talk to (SNI) as part of the handshake, which lets one listener host
+//! several virtual endpoints. `SniRoutes` maps those names to the
+//! `NewService` serving each one, along with, optionally, the certificate to
+//! present for it:
+//!
+//! ```ignore
+//! let mut routes = SniRoutes::new();
+//! routes.route_with_certificate("api.example.com", api, api_cert);
+//! routes.route_with_certificate("*.example.com", sites, wildcard_cert);
+//! routes.fallback(not_found);
+//! let routes = Arc::new(routes);
+//!
+//! // The TLS acceptor picks the certificate during the handshake
+//! let resolver = routes.clone();
+//! acceptor.set_sni_callback(move |name| resolver.certificate(name).cloned());
+//!
+//! let incoming = listener.incoming().and_then(move |(socket, addr)| {
+//!     acceptor.accept(socket).map(move |socket| (socket, addr))
+//! });
+//! core.run(serve_sni(MyProto, incoming, routes, &handle))?;
+//! ```
+//!
+//! `serve_sni` binds each connection with the service routed to the server
+//! name reported by its I/O object, see `TransportInfo::tls`. Connections
+//! matching no route, and no fallback, are closed.
+//!
+//! Names are matched case insensitively. A name starting with `*.` matches
+//! any name with exactly one more label, e.g. `*.example.com` matches
+//! `www.example.com` but neither `example.com` nor `a.b.example.com`; exact
+//! names take precedence. All routes share the type of their `NewService`,
+//! which may be boxed for services of different types.
+
+use std::collections::HashMap;
+
+/// Services and certificates by TLS server name
+#[derive(Debug)]
+pub struct SniRoutes<S, C = ()> {
+    exact: HashMap<String, Route<S, C>>,
+    // By the domain following the wildcard label
+    wildcard: HashMap<String, Route<S, C>>,
+    fallback: Option<S>,
+}
+
+#[derive(Debug)]
+struct Route<S, C> {
+    new_service: S,
+    certificate: Option<C>,
+}
+
+impl<S, C> SniRoutes<S, C> {
+    /// Returns a table without any route
+    pub fn new() -> SniRoutes<S, C> {
+        SniRoutes {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// Serve connections to `name` with `new_service`, replacing any
+    /// previous route for the name
+    pub fn route(&mut self, name: &str, new_service: S) {
+        self.insert(name, Route { new_service: new_service, certificate: None });
+    }
+
+    /// Serve connections to `name` with `new_service`, presenting
+    /// `certificate`
+    pub fn route_with_certificate(&mut self, name: &str, new_service: S, certificate: C) {
+        self.insert(name, Route { new_service: new_service, certificate: Some(certificate) });
+    }
+
+    /// Serve connections without a server name, or to a name matching no
+    /// route, with `new_service`. Without a fallback, those are closed.
+    pub fn fallback(&mut self, new_service: S) {
+        self.fallback = Some(new_service);
+    }
+
+    /// Returns the service routed to `server_name`, or the fallback
+    pub fn select(&self, server_name: Option<&str>) -> Option<&S> {
+        server_name.and_then(|name| self.find(name))
+            .map(|route| &route.new_service)
+            .or(self.fallback.as_ref())
+    }
+
+    /// Returns the certificate to present for `server_name`, if its route
+    /// has one, e.g. for the SNI callback of a TLS acceptor
+    pub fn certificate(&self, server_name: &str) -> Option<&C> {
+        self.find(server_name).and_then(|route| route.certificate.as_ref())
+    }
+
+    /// Returns true if there is no route, nor fallback
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.fallback.is_none()
+    }
+
+    fn insert(&mut self, name: &str, route: Route<S, C>) {
+        let name = name.to_lowercase();
+
+        if name.starts_with("*.") {
+            self.wildcard.insert(name[2..].to_string(), route);
+        } else {
+            self.exact.insert(name, route);
+        }
+    }
+
+    fn find(&self, server_name: &str) -> Option<&Route<S, C>> {
+        // Names may be sent fully qualified
+        let name = server_name.trim_end_matches('.').to_lowercase();
+
+        if let Some(route) = self.exact.get(&name) {
+            return Some(route);
+        }
+
+        match name.find('.') {
+            Some(i) if i > 0 => self.wildcard.get(&name[i + 1..]),
+            _ => None,
+        }
+    }
+}
+
+impl<S, C> Default for SniRoutes<S, C> {
+    fn default() -> SniRoutes<S, C> {
+        SniRoutes::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SniRoutes;
+
+    #[test]
+    fn test_names_matched() {
+        let mut routes = SniRoutes::new();
+        routes.route_with_certificate("api.example.com", "api", "api-cert");
+        routes.route_with_certificate("*.example.com", "sites", "wildcard-cert");
+        routes.route("Static.Example.com", "static");
+
+        assert_eq!(Some(&"api"), routes.select(Some("API.example.com")));
+        assert_eq!(Some(&"api"), routes.select(Some("api.example.com.")));
+        assert_eq!(Some(&"sites"), routes.select(Some("www.example.com")));
+        assert_eq!(Some(&"static"), routes.select(Some("static.example.com")));
+        assert_eq!(None, routes.select(Some("example.com")));
+        assert_eq!(None, routes.select(Some("a.b.example.com")));
+        assert_eq!(None, routes.select(None));
+
+        assert_eq!(Some(&"api-cert"), routes.certificate("api.example.com"));
+        assert_eq!(Some(&"wildcard-cert"), routes.certificate("www.example.com"));
+        assert_eq!(None, routes.certificate("static.example.com"));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let mut routes: SniRoutes<_> = SniRoutes::new();
+        assert!(routes.is_empty());
+
+        routes.route("api.example.com", "api");
+        routes.fallback("default");
+
+        assert_eq!(Some(&"api"), routes.select(Some("api.example.com")));
+        assert_eq!(Some(&"default"), routes.select(Some("other.example.com")));
+        assert_eq!(Some(&"default"), routes.select(None));
+    }
+}
@@ -0,0 +1,206 @@
+//! Offloading CPU-bound services to a thread pool
+//!
+//! A connection is dispatched on the thread of its event loop, which also
+//! runs every call of its service. A handler spending milliseconds of CPU on
+//! a request, e.g. to compress, encrypt or plan a query, stalls every other
+//! connection of that event loop meanwhile. `SpawnPool` runs the calls of a
+//! service on threads of its own instead, and returns a future resolving
+//! once the call is done:
+//!
+//! ```ignore
+//! let pool = SpawnPool::new(Planner::new(catalog), 4)?;
+//!
+//! server.serve(move || Ok(pool.clone()));
+//! ```
+//!
+//! Clones of a `SpawnPool` share its threads and service, which is why the
+//! service must be `Sync`. The future returned by the service is waited on
+//! by the pool thread, so it should not depend on an event loop, e.g. to
+//! talk to another server. Pool threads exit once every clone is dropped.
+//!
+//! A call panicking fails with an error, leaving the pool thread to run the
+//! following calls.
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use futures::{Future, Poll, Async};
+use futures::sync::oneshot;
+use tokio_service::Service;
+
+/// A service running the calls of another on a thread pool
+pub struct SpawnPool<S> {
+    inner: Arc<S>,
+    jobs: Arc<Mutex<mpsc::Sender<Job>>>,
+}
+
+/// Response future of `SpawnPool`
+pub struct Spawned<R, E> {
+    rx: oneshot::Receiver<Result<R, E>>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+impl<S> SpawnPool<S>
+    where S: Service + Send + Sync + 'static,
+          S::Request: Send,
+          S::Response: Send,
+          S::Error: Send + From<io::Error>,
+{
+    /// Run the calls of `inner` on a pool of `threads` threads
+    pub fn new(inner: S, threads: usize) -> io::Result<SpawnPool<S>> {
+        assert!(threads > 0);
+
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..threads {
+            let rx = rx.clone();
+
+            try!(thread::Builder::new().name(format!("tokio-proto-spawn{}", i)).spawn(move || {
+                loop {
+                    // The lock is only held while waiting for a job
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+
+                    job();
+                }
+            }));
+        }
+
+        Ok(SpawnPool {
+            inner: Arc::new(inner),
+            jobs: Arc::new(Mutex::new(tx)),
+        })
+    }
+}
+
+impl<S> SpawnPool<S> {
+    /// Returns a reference to the wrapped service
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Clone for SpawnPool<S> {
+    fn clone(&self) -> SpawnPool<S> {
+        SpawnPool {
+            inner: self.inner.clone(),
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<S> Service for SpawnPool<S>
+    where S: Service + Send + Sync + 'static,
+          S::Request: Send,
+          S::Response: Send,
+          S::Error: Send + From<io::Error>,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Spawned<S::Response, S::Error>;
+
+    fn call(&self, req: S::Request) -> Self::Future {
+        let (tx, rx) = oneshot::channel();
+        let inner = self.inner.clone();
+
+        let job: Job = Box::new(move || {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| inner.call(req).wait()));
+
+            match res {
+                Ok(res) => tx.complete(res),
+                Err(_) => {
+                    warn!("service panicked on a pool thread");
+                    drop(tx);
+                }
+            }
+        });
+
+        // If the pool threads are gone, the job and its sender are dropped,
+        // which fails the call, see below
+        drop(self.jobs.lock().unwrap().send(job));
+
+        Spawned { rx: rx }
+    }
+}
+
+impl<R, E> Future for Spawned<R, E>
+    where E: From<io::Error>,
+{
+    type Item = R;
+    type Error = E;
+
+    fn poll(&mut self) -> Poll<R, E> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Ok(response))) => Ok(Async::Ready(response)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "service call panicked").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+    use tokio_service::Service;
+
+    use super::SpawnPool;
+
+    /// Answers with the name of the thread serving the request, panicking on
+    /// 0 and failing on 1
+    struct Where;
+
+    impl Service for Where {
+        type Request = u32;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            match req {
+                0 => panic!("zero"),
+                1 => future::err(io::Error::new(io::ErrorKind::InvalidInput, "one")),
+                n => {
+                    thread::sleep(Duration::from_millis(n as u64));
+                    future::ok(thread::current().name().unwrap_or("").to_string())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_calls_run_on_pool_threads() {
+        let pool = SpawnPool::new(Where, 2).unwrap();
+
+        let a = pool.call(50);
+        let b = pool.clone().call(50);
+        let a = a.wait().unwrap();
+        let b = b.wait().unwrap();
+
+        assert!(a.starts_with("tokio-proto-spawn"), "ran on {:?}", a);
+        assert!(b.starts_with("tokio-proto-spawn"), "ran on {:?}", b);
+        assert!(a != b, "calls did not run concurrently");
+    }
+
+    #[test]
+    fn test_errors_and_panics_fail_the_call() {
+        let pool = SpawnPool::new(Where, 1).unwrap();
+
+        assert_eq!(io::ErrorKind::InvalidInput, pool.call(1).wait().unwrap_err().kind());
+        assert_eq!(io::ErrorKind::Other, pool.call(0).wait().unwrap_err().kind());
+
+        // The thread survived the panic
+        assert!(pool.call(2).wait().is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+//! Connection details exposed to services
+//!
+//! Services sometimes need more than what a request carries about the
+//! connection it arrived on, e.g. the identity in the client certificate of a
+//! TLS connection, to authorize operations. I/O objects and transports report
+//! those details by implementing `TransportInfo`, which server protocols
+//! return from their `transport_info` hook:
+//!
+//! ```ignore
+//! fn transport_info(transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+//!     Some(transport.get_ref())
+//! }
+//! ```
+//!
+//! Once the transport is bound, the dispatcher takes a `ConnectionInfo`
+//! snapshot of the details and inserts it in the extensions of every request
+//! read from the connection, see `request_extensions`.
+
+use std::net::SocketAddr;
+
+use tokio_core::io::Framed;
+use tokio_core::net::TcpStream;
+
+use Peeked;
+use util::framed;
+
+/// Details about the connection a transport runs on
+///
+/// All methods have default implementations, reporting nothing.
+pub trait TransportInfo {
+    /// Returns the address of the remote peer
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns the local address of the connection
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns the details of the TLS session, for encrypted connections
+    fn tls(&self) -> Option<TlsInfo> {
+        None
+    }
+
+    /// Returns the version of the protocol negotiated with the peer, if any
+    fn protocol_version(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Details about a TLS session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The identity presented in the peer's certificate, e.g. its subject
+    pub peer_identity: Option<String>,
+    /// The name of the negotiated cipher suite
+    pub cipher_suite: Option<String>,
+    /// The server name requested by the client (SNI)
+    pub server_name: Option<String>,
+}
+
+/// A snapshot of the `TransportInfo` of a connection, inserted in the
+/// extensions of its requests
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// See `TransportInfo::peer_addr`
+    pub peer_addr: Option<SocketAddr>,
+    /// See `TransportInfo::local_addr`
+    pub local_addr: Option<SocketAddr>,
+    /// See `TransportInfo::tls`
+    pub tls: Option<TlsInfo>,
+    /// See `TransportInfo::protocol_version`
+    pub protocol_version: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// Take a snapshot of the details reported by `info`
+    pub fn new(info: &dyn TransportInfo) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: info.peer_addr(),
+            local_addr: info.local_addr(),
+            tls: info.tls(),
+            protocol_version: info.protocol_version(),
+        }
+    }
+}
+
+impl TransportInfo for ConnectionInfo {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    fn tls(&self) -> Option<TlsInfo> {
+        self.tls.clone()
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        self.protocol_version.clone()
+    }
+}
+
+impl TransportInfo for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl<T: TransportInfo> TransportInfo for Peeked<T> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn tls(&self) -> Option<TlsInfo> {
+        self.get_ref().tls()
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        self.get_ref().protocol_version()
+    }
+}
+
+impl<T: TransportInfo, C> TransportInfo for Framed<T, C> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn tls(&self) -> Option<TlsInfo> {
+        self.get_ref().tls()
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        self.get_ref().protocol_version()
+    }
+}
+
+impl<T: TransportInfo, C> TransportInfo for framed::Framed<T, C> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn tls(&self) -> Option<TlsInfo> {
+        self.get_ref().tls()
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        self.get_ref().protocol_version()
+    }
+}
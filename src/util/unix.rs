@@ -0,0 +1,138 @@
+//! Abstract namespace Unix sockets (Linux only)
+//!
+//! The name of a Unix socket bound in the abstract namespace starts with a
+//! NUL byte, and is not a path on the filesystem. The name goes away once
+//! the last socket bound to it is closed, so there is no stale socket file
+//! to clean up when a server restarts, and containers sharing a network
+//! namespace can reach each other without sharing a volume:
+//!
+//! ```ignore
+//! let listener = unix::bind_abstract(b"my-service")?;
+//! let socket = unix::connect_abstract(b"my-service")?;
+//! ```
+//!
+//! Names are given without the leading NUL byte. The sockets returned are
+//! regular, blocking, standard library sockets; switch them to non-blocking
+//! mode before registering them with an event loop.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, AsRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use libc;
+
+/// The backlog of listeners bound by `bind_abstract`
+const BACKLOG: libc::c_int = 1024;
+
+/// Bind a listener to the given name in the abstract namespace.
+pub fn bind_abstract(name: &[u8]) -> io::Result<UnixListener> {
+    let (addr, len) = try!(address(name));
+
+    // Owning the file descriptor right away closes it on error
+    let listener = unsafe { UnixListener::from_raw_fd(try!(socket())) };
+
+    unsafe {
+        let ret = libc::bind(listener.as_raw_fd(),
+                             &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                             len);
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if libc::listen(listener.as_raw_fd(), BACKLOG) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(listener)
+}
+
+/// Connect to the listener bound to the given name in the abstract
+/// namespace.
+pub fn connect_abstract(name: &[u8]) -> io::Result<UnixStream> {
+    let (addr, len) = try!(address(name));
+
+    let socket = unsafe { UnixStream::from_raw_fd(try!(socket())) };
+
+    let ret = unsafe {
+        libc::connect(socket.as_raw_fd(),
+                      &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                      len)
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+fn socket() -> io::Result<libc::c_int> {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+fn address(name: &[u8]) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // The first byte of the path stays NUL
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "abstract socket name is too long"));
+    }
+
+    for (dst, &src) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dst = src as libc::c_char;
+    }
+
+    // The address length tells where the name ends, it is not NUL-terminated
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+    Ok((addr, len as libc::socklen_t))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::process;
+
+    use super::{bind_abstract, connect_abstract};
+
+    #[test]
+    fn test_bind_and_connect() {
+        let name = format!("tokio-proto-test-{}", process::id());
+        let listener = bind_abstract(name.as_bytes()).unwrap();
+
+        // The name is taken
+        let e = bind_abstract(name.as_bytes()).unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+
+        let mut client = connect_abstract(name.as_bytes()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+
+        // The name is released along with the listener
+        drop(listener);
+        bind_abstract(name.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let e = bind_abstract(&[b'a'; 200]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+
+        let e = connect_abstract(b"tokio-proto-test-unbound").unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionRefused, e.kind());
+    }
+}
@@ -0,0 +1,365 @@
+//! Mid-connection transport upgrades
+//!
+//! Some protocols start out in plaintext and switch to an encrypted stream
+//! after a negotiation exchange (`STARTTLS` in SMTP, IMAP, LDAP, ...). An
+//! `Upgradable` transport frames the plaintext connection with a codec until
+//! a frame marked by the protocol's `Upgrade` implementation has been sent or
+//! received. At that point it stops reading frames, flushes any pending
+//! writes and hands the raw I/O object to the upgrade future. Once the
+//! future completes, framing resumes with the same codec on the wrapped
+//! stream.
+//!
+//! While the upgrade is in progress, the transport is neither readable nor
+//! writable, which pauses the dispatcher driving it. If the upgrade fails,
+//! the error is returned by the transport and the connection is closed.
+//!
+//! ```ignore
+//! impl<T: Io + 'static> Upgrade<T, LineCodec> for StartTls {
+//!     type Io = TlsStream<T>;
+//!     type Future = AcceptAsync<T>;
+//!
+//!     fn upgrade_after_send(&mut self, frame: &String) -> bool {
+//!         frame == "220 Ready to start TLS"
+//!     }
+//!
+//!     fn upgrade(&mut self, io: T) -> Self::Future {
+//!         self.acceptor.accept_async(io)
+//!     }
+//! }
+//!
+//! fn bind_transport(&self, io: T) -> Self::BindTransport {
+//!     Ok(Upgradable::new(io, LineCodec, StartTls { acceptor: .. }))
+//! }
+//! ```
+//!
+//! Bytes buffered by the plaintext framing but not yet decoded when the
+//! upgrade starts are discarded, as a peer is not allowed to send anything
+//! past the upgrade point before the negotiation is done.
+
+use std::io;
+use std::mem;
+
+use futures::{Future, Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use tokio_core::io::{Io, Codec, Framed};
+use streaming::{pipeline, multiplex};
+
+/// Describes when and how a transport is upgraded
+///
+/// The `T` parameter is the plaintext I/O object and `C` is the codec used to
+/// frame the connection both before and after the upgrade.
+pub trait Upgrade<T, C: Codec>: 'static {
+    /// The upgraded I/O object
+    type Io: Io;
+
+    /// A future performing the upgrade, e.g. a TLS handshake
+    type Future: Future<Item = Self::Io, Error = io::Error>;
+
+    /// Returns true if the upgrade starts once the given outbound frame has
+    /// been flushed.
+    ///
+    /// This is how a server starts the upgrade, after acknowledging the
+    /// peer's request for it. By default, no outbound frame starts an
+    /// upgrade.
+    fn upgrade_after_send(&mut self, _frame: &C::Out) -> bool {
+        false
+    }
+
+    /// Returns true if the upgrade starts after the given inbound frame.
+    ///
+    /// This is how a client starts the upgrade, once the server acknowledged
+    /// its request for it. By default, no inbound frame starts an upgrade.
+    fn upgrade_after_recv(&mut self, _frame: &C::In) -> bool {
+        false
+    }
+
+    /// Upgrade the raw I/O object
+    fn upgrade(&mut self, io: T) -> Self::Future;
+}
+
+/// A framed transport that can be upgraded once during its lifetime
+///
+/// See the module documentation for details.
+pub struct Upgradable<T, C, U>
+    where T: Io,
+          C: Codec,
+          U: Upgrade<T, C>,
+{
+    state: State<T, C, U>,
+    // Used for framing the upgraded stream
+    codec: Option<C>,
+    upgrade: U,
+    // Set once a frame starting the upgrade was sent or received
+    triggered: bool,
+}
+
+enum State<T, C, U>
+    where T: Io,
+          C: Codec,
+          U: Upgrade<T, C>,
+{
+    Plain(Framed<T, C>),
+    Upgrading(U::Future),
+    Upgraded(Framed<U::Io, C>),
+    Empty,
+}
+
+impl<T, C, U> Upgradable<T, C, U>
+    where T: Io,
+          C: Codec + Clone,
+          U: Upgrade<T, C>,
+{
+    /// Frame `io` with `codec`, upgrading it as directed by `upgrade`
+    pub fn new(io: T, codec: C, upgrade: U) -> Upgradable<T, C, U> {
+        Upgradable {
+            state: State::Plain(io.framed(codec.clone())),
+            codec: Some(codec),
+            upgrade: upgrade,
+            triggered: false,
+        }
+    }
+}
+
+impl<T, C, U> Upgradable<T, C, U>
+    where T: Io,
+          C: Codec,
+          U: Upgrade<T, C>,
+{
+    /// Returns true once the transport has been upgraded
+    pub fn is_upgraded(&self) -> bool {
+        match self.state {
+            State::Upgraded(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Drive a triggered upgrade to completion.
+    ///
+    /// Returns `Ready` when no upgrade is in progress.
+    fn poll_upgrade(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let upgraded = match self.state {
+                State::Plain(ref mut framed) => {
+                    if !self.triggered {
+                        return Ok(Async::Ready(()));
+                    }
+
+                    // Flush everything written in plaintext before handing
+                    // off the I/O object
+                    try_ready!(framed.poll_complete());
+                    None
+                }
+                State::Upgrading(ref mut upgrade) => {
+                    Some(try_ready!(upgrade.poll()))
+                }
+                State::Upgraded(_) => return Ok(Async::Ready(())),
+                State::Empty => panic!("invalid internal state"),
+            };
+
+            match upgraded {
+                Some(io) => {
+                    trace!("transport upgraded");
+                    let codec = self.codec.take().expect("upgraded twice");
+                    self.state = State::Upgraded(io.framed(codec));
+                }
+                None => {
+                    trace!("upgrading transport");
+                    let io = match mem::replace(&mut self.state, State::Empty) {
+                        State::Plain(framed) => framed.into_inner(),
+                        _ => unreachable!(),
+                    };
+                    self.state = State::Upgrading(self.upgrade.upgrade(io));
+                }
+            }
+        }
+    }
+}
+
+impl<T, C, U> Stream for Upgradable<T, C, U>
+    where T: Io,
+          C: Codec,
+          U: Upgrade<T, C>,
+{
+    type Item = C::In;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<C::In>, io::Error> {
+        try_ready!(self.poll_upgrade());
+
+        match self.state {
+            State::Plain(ref mut framed) => {
+                let frame = try_ready!(framed.poll());
+
+                if let Some(ref frame) = frame {
+                    if self.upgrade.upgrade_after_recv(frame) {
+                        self.triggered = true;
+                    }
+                }
+
+                Ok(Async::Ready(frame))
+            }
+            State::Upgraded(ref mut framed) => framed.poll(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T, C, U> Sink for Upgradable<T, C, U>
+    where T: Io,
+          C: Codec,
+          U: Upgrade<T, C>,
+{
+    type SinkItem = C::Out;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: C::Out) -> StartSend<C::Out, io::Error> {
+        if !try!(self.poll_upgrade()).is_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        match self.state {
+            State::Plain(ref mut framed) => {
+                let trigger = self.upgrade.upgrade_after_send(&item);
+                let res = try!(framed.start_send(item));
+
+                if trigger && res.is_ready() {
+                    self.triggered = true;
+                }
+
+                Ok(res)
+            }
+            State::Upgraded(ref mut framed) => framed.start_send(item),
+            _ => unreachable!(),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_upgrade());
+
+        match self.state {
+            State::Plain(ref mut framed) => framed.poll_complete(),
+            State::Upgraded(ref mut framed) => framed.poll_complete(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T, C, U> pipeline::Transport for Upgradable<T, C, U>
+    where T: Io + 'static,
+          C: Codec + 'static,
+          U: Upgrade<T, C>,
+{
+}
+
+impl<T, C, U, ReadBody> multiplex::Transport<ReadBody> for Upgradable<T, C, U>
+    where T: Io + 'static,
+          C: Codec + 'static,
+          U: Upgrade<T, C>,
+{
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    use futures::{Future, Stream, Sink, Async};
+    use futures::future::{self, FutureResult};
+    use tokio_core::io::Io;
+
+    use test_support::Lines;
+    use super::{Upgrade, Upgradable};
+
+    // Reads chunks from a script, an empty chunk standing for `WouldBlock`,
+    // and records writes
+    struct Mock {
+        rd: Vec<Vec<u8>>,
+        wr: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            if self.rd.is_empty() {
+                return Ok(0);
+            }
+
+            let chunk = self.rd.remove(0);
+            if chunk.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"));
+            }
+
+            assert!(chunk.len() <= dst.len());
+            dst[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            self.wr.borrow_mut().extend_from_slice(src);
+            Ok(src.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Mock {}
+
+    // Uppercases everything written, standing in for encryption
+    struct Shout(Mock);
+
+    impl Read for Shout {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            self.0.read(dst)
+        }
+    }
+
+    impl Write for Shout {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            let upper: Vec<u8> = src.iter().map(|b| b.to_ascii_uppercase()).collect();
+            self.0.write(&upper)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Io for Shout {}
+
+    struct StartShouting;
+
+    impl Upgrade<Mock, Lines> for StartShouting {
+        type Io = Shout;
+        type Future = FutureResult<Shout, io::Error>;
+
+        fn upgrade_after_send(&mut self, frame: &Vec<u8>) -> bool {
+            &frame[..] == b"go ahead"
+        }
+
+        fn upgrade(&mut self, io: Mock) -> Self::Future {
+            future::ok(Shout(io))
+        }
+    }
+
+    #[test]
+    fn test_upgrade_after_send() {
+        let wr = Rc::new(RefCell::new(vec![]));
+        let rd = vec![b"starttls\n".to_vec(), vec![], b"hello\n".to_vec()];
+        let io = Mock { rd: rd, wr: wr.clone() };
+        let transport = Upgradable::new(io, Lines, StartShouting);
+
+        let (line, transport) = transport.into_future().wait().ok().unwrap();
+        assert_eq!(Some(b"starttls".to_vec()), line);
+        assert!(!transport.is_upgraded());
+
+        let transport = transport.send(b"go ahead".to_vec()).wait().unwrap();
+        let mut transport = transport.send(b"hello".to_vec()).wait().unwrap();
+        assert!(transport.is_upgraded());
+
+        assert_eq!(Async::Ready(Some(b"hello".to_vec())), transport.poll().unwrap());
+        assert_eq!(&b"go ahead\nHELLO\n"[..], &wr.borrow()[..]);
+    }
+}
@@ -4,13 +4,19 @@ extern crate tokio_proto;
 extern crate tokio_service;
 
 use std::str;
-use std::io::{self, ErrorKind, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use futures::{Future};
+use futures::{future, Future};
 use tokio_core::io::{Io, Codec, Framed, EasyBuf};
 use tokio_core::reactor::Core;
 use tokio_proto::pipeline::ClientProto;
-use tokio_proto::TcpClient;
+use tokio_proto::{TcpClient, Endpoint, Proxy};
+use tokio_service::Service;
 
 // First, we implement a *codec*, which provides a way of encoding and
 // decoding messages for the protocol. See the documentation for `Codec` in
@@ -87,3 +93,315 @@ fn test_clone() {
         is_clone(&service);
     }
 }
+
+/// Serves a single connection, answering each number incremented by one
+fn incr_server() -> u16 {
+    incr_server_with(|_| ())
+}
+
+/// Like `incr_server`, running the given handshake on the connection first
+fn incr_server_with<F>(handshake: F) -> u16
+    where F: FnOnce(&mut BufReader<net::TcpStream>) + Send + 'static
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(socket);
+
+        handshake(&mut reader);
+
+        let mut writer = reader.get_ref().try_clone().unwrap();
+
+        for line in reader.lines() {
+            let n: u64 = line.unwrap().parse().unwrap();
+            writeln!(writer, "{}", n + 1).unwrap();
+        }
+    });
+
+    port
+}
+
+/// Answers a single number per connection, then waits for the client to close
+/// it. Returns the port
+/// along with the number of connections accepted so far and the maximum
+/// number served at once.
+fn oneshot_server() -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+    let current = Arc::new(AtomicUsize::new(0));
+
+    let (accepted2, max2) = (accepted.clone(), max.clone());
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = socket.unwrap();
+            accepted2.fetch_add(1, Ordering::SeqCst);
+
+            let (max, current) = (max2.clone(), current.clone());
+
+            thread::spawn(move || {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+
+                let mut reader = BufReader::new(socket);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let n: u64 = line.trim().parse().unwrap();
+
+                thread::sleep(Duration::from_millis(20));
+
+                current.fetch_sub(1, Ordering::SeqCst);
+                writeln!(reader.get_mut(), "{}", n + 1).unwrap();
+
+                // The client closes the connection once answered
+                assert_eq!(0, reader.read_to_string(&mut line).unwrap());
+            });
+        }
+    });
+
+    (port, accepted, max)
+}
+
+/// Answers numbers incremented by one on every connection, breaking the
+/// connection instead on 0. Returns the port along with the number of
+/// connections accepted so far.
+fn pool_server() -> (u16, Arc<AtomicUsize>) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted2 = accepted.clone();
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = socket.unwrap();
+            accepted2.fetch_add(1, Ordering::SeqCst);
+
+            thread::spawn(move || {
+                let mut writer = socket.try_clone().unwrap();
+
+                for line in BufReader::new(socket).lines() {
+                    let n: u64 = line.unwrap().parse().unwrap();
+                    if n == 0 {
+                        writeln!(writer, "not a number").unwrap();
+                        return;
+                    }
+                    writeln!(writer, "{}", n + 1).unwrap();
+                }
+            });
+        }
+    });
+
+    (port, accepted)
+}
+
+fn read_n(reader: &mut BufReader<net::TcpStream>, n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    reader.read_exact(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn test_connect_endpoint() {
+    let port = incr_server();
+
+    let mut core = Core::new().unwrap();
+    let endpoint = Endpoint::new(format!("127.0.0.1:{}", port));
+    let connect = TcpClient::new(IntProto).connect_endpoint(&endpoint, &core.handle());
+    let client = core.run(connect).unwrap();
+
+    assert_eq!(8, core.run(client.call(7)).unwrap());
+}
+
+#[test]
+fn test_connect_endpoint_failure() {
+    let port = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let mut core = Core::new().unwrap();
+    let endpoint = Endpoint::new(format!("127.0.0.1:{}", port));
+    let connect = TcpClient::new(IntProto).connect_endpoint(&endpoint, &core.handle());
+
+    assert!(core.run(connect).is_err());
+
+    // The endpoint is resolved again and connects once the server is up
+    let listener = net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let connect = TcpClient::new(IntProto).connect_endpoint(&endpoint, &core.handle());
+    let _client = core.run(connect).unwrap();
+    listener.accept().unwrap();
+}
+
+#[test]
+fn test_connect_endpoint_unresolvable() {
+    let mut core = Core::new().unwrap();
+    let endpoint = Endpoint::new("not a host name");
+    let connect = TcpClient::new(IntProto).connect_endpoint(&endpoint, &core.handle());
+
+    assert!(core.run(connect).is_err());
+}
+
+#[test]
+fn test_oneshot_connection_per_request() {
+    let (port, accepted, max) = oneshot_server();
+
+    let mut core = Core::new().unwrap();
+    let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let mut client = TcpClient::new(IntProto).oneshot(&addr, &core.handle());
+    client.max_concurrent(2);
+
+    let calls = (1..5).map(|n| client.call(n)).collect::<Vec<_>>();
+    assert_eq!(vec![2, 3, 4, 5], core.run(future::join_all(calls)).unwrap());
+
+    assert_eq!(4, accepted.load(Ordering::SeqCst));
+    assert_eq!(2, max.load(Ordering::SeqCst));
+    assert_eq!(0, client.active());
+}
+
+#[test]
+fn test_pool_min_idle() {
+    let (port, accepted) = pool_server();
+
+    let mut core = Core::new().unwrap();
+    let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let mut client = TcpClient::new(IntProto).pool(&addr, &core.handle());
+    client.min_idle(2);
+
+    let wait_for = |core: &mut Core, idle: usize, connections: usize| {
+        while client.idle() < idle || accepted.load(Ordering::SeqCst) < connections {
+            core.turn(Some(Duration::from_millis(10)));
+        }
+    };
+
+    // The connections are established up front
+    wait_for(&mut core, 2, 2);
+
+    // A connection failing is closed, and replaced in the background
+    assert!(core.run(client.call(0)).is_err());
+    wait_for(&mut core, 2, 3);
+
+    // A connection taken by a call is replaced too, and returns to the pool
+    // once answered
+    assert_eq!(6, core.run(client.call(5)).unwrap());
+    wait_for(&mut core, 3, 4);
+
+    // The idle connections are reused from then on
+    for n in 1..4 {
+        assert_eq!(n + 1, core.run(client.call(n)).unwrap());
+    }
+    assert_eq!(4, accepted.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_socks5_proxy() {
+    let port = incr_server_with(|proxy| {
+        assert_eq!(vec![5, 1, 0], read_n(proxy, 3));
+        proxy.get_mut().write_all(&[5, 0]).unwrap();
+
+        assert_eq!(vec![5, 1, 0, 1, 10, 0, 0, 1, 0, 80], read_n(proxy, 10));
+        proxy.get_mut().write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut client = TcpClient::new(IntProto);
+    client.proxy(Proxy::socks5(format!("127.0.0.1:{}", port).parse().unwrap()));
+
+    let connect = client.connect(&"10.0.0.1:80".parse().unwrap(), &core.handle());
+    let client = core.run(connect).unwrap();
+
+    assert_eq!(8, core.run(client.call(7)).unwrap());
+}
+
+#[test]
+fn test_socks5_proxy_endpoint_auth() {
+    let port = incr_server_with(|proxy| {
+        assert_eq!(vec![5, 2, 0, 2], read_n(proxy, 4));
+        proxy.get_mut().write_all(&[5, 2]).unwrap();
+
+        assert_eq!(b"\x01\x04user\x06secret".to_vec(), read_n(proxy, 13));
+        proxy.get_mut().write_all(&[1, 0]).unwrap();
+
+        assert_eq!(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb".to_vec(), read_n(proxy, 18));
+        proxy.get_mut().write_all(&[5, 0, 0, 3, 4, b'h', b'o', b's', b't', 0, 0]).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut client = TcpClient::new(IntProto);
+    let proxy_addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    client.proxy(Proxy::socks5_auth(proxy_addr, "user", "secret"));
+
+    let endpoint = Endpoint::new("example.com:443");
+    let client = core.run(client.connect_endpoint(&endpoint, &core.handle())).unwrap();
+
+    assert_eq!(8, core.run(client.call(7)).unwrap());
+}
+
+#[test]
+fn test_socks5_proxy_refused() {
+    let port = incr_server_with(|proxy| {
+        read_n(proxy, 3);
+        proxy.get_mut().write_all(&[5, 0]).unwrap();
+
+        read_n(proxy, 10);
+        proxy.get_mut().write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut client = TcpClient::new(IntProto);
+    client.proxy(Proxy::socks5(format!("127.0.0.1:{}", port).parse().unwrap()));
+
+    let connect = client.connect(&"10.0.0.1:80".parse().unwrap(), &core.handle());
+    assert!(core.run(connect).is_err());
+}
+
+#[test]
+fn test_http_connect_proxy() {
+    let port = incr_server_with(|proxy| {
+        let mut head = String::new();
+
+        while !head.ends_with("\r\n\r\n") {
+            proxy.read_line(&mut head).unwrap();
+        }
+
+        assert_eq!("CONNECT example.com:443 HTTP/1.1\r\n\
+                    Host: example.com:443\r\n\
+                    Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n", head);
+
+        proxy.get_mut().write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut client = TcpClient::new(IntProto);
+    let mut proxy = Proxy::http(format!("127.0.0.1:{}", port).parse().unwrap());
+    proxy.header("Proxy-Authorization", "Basic dXNlcjpzZWNyZXQ=");
+    client.proxy(proxy);
+
+    let endpoint = Endpoint::new("example.com:443");
+    let client = core.run(client.connect_endpoint(&endpoint, &core.handle())).unwrap();
+
+    assert_eq!(8, core.run(client.call(7)).unwrap());
+}
+
+#[test]
+fn test_http_connect_proxy_refused() {
+    let port = incr_server_with(|proxy| {
+        let mut head = String::new();
+
+        while !head.ends_with("\r\n\r\n") {
+            proxy.read_line(&mut head).unwrap();
+        }
+
+        proxy.get_mut().write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let mut client = TcpClient::new(IntProto);
+    client.proxy(Proxy::http(format!("127.0.0.1:{}", port).parse().unwrap()));
+
+    let connect = client.connect(&"10.0.0.1:80".parse().unwrap(), &core.handle());
+    let err = core.run(connect).err().unwrap();
+    assert!(err.to_string().contains("403"));
+}
@@ -0,0 +1,65 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+use std::str;
+
+use self::futures::future;
+use self::tokio_core::io::{Io, Codec, Framed, EasyBuf};
+use self::tokio_proto::pipeline::ServerProto;
+use self::tokio_service::Service;
+
+/// Newline terminated lines
+pub struct LineCodec;
+
+impl Codec for LineCodec {
+    type In = String;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<String>> {
+        match buf.as_slice().iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let line = buf.drain_to(i);
+                buf.drain_to(1);
+                Ok(Some(str::from_utf8(line.as_slice()).unwrap().to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(msg.as_bytes());
+        buf.push(b'\n');
+        Ok(())
+    }
+}
+
+/// Pipelined lines, see `LineCodec`
+pub struct LineProto;
+
+impl<T: Io + 'static> ServerProto<T> for LineProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+}
+
+/// Echoes lines
+pub struct Echo;
+
+impl Service for Echo {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = future::FutureResult<String, io::Error>;
+
+    fn call(&self, req: String) -> Self::Future {
+        future::ok(req)
+    }
+}
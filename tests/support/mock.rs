@@ -8,6 +8,9 @@ use std::any::Any;
 use std::thread;
 use std::cell::RefCell;
 use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use self::futures::stream::Wait;
 use self::futures::sync::mpsc;
@@ -16,16 +19,41 @@ use self::futures::{Future, Stream, Sink, Poll, StartSend, Async};
 use self::tokio_core::io::Io;
 use self::tokio_core::reactor::Core;
 use self::tokio_proto::streaming::multiplex;
+use self::tokio_proto::streaming::multiplex::RequestIds;
+use self::tokio_proto::streaming::multiplex::advanced::ExchangeLimits;
 use self::tokio_proto::streaming::pipeline;
-use self::tokio_proto::streaming::{Message, Body};
-use self::tokio_proto::util::client_proxy::Response;
+use self::tokio_proto::streaming::{Message, Body, MemoryBudget};
+use self::tokio_proto::util::client_proxy::ClientProxy;
 use self::tokio_proto::{BindClient, BindServer};
 use self::tokio_service::Service;
 
 struct MockProtocol<T>(RefCell<Option<MockTransport<T>>>);
 
+/// Mock requests with this value carry a deadline that has already passed.
+pub const EXPIRED: &'static str = "expired";
+
+/// Mock requests with this value carry a deadline passing shortly after they
+/// are read.
+pub const SOON: &'static str = "soon";
+
+pub trait MockRequest {
+    fn deadline(&self) -> Option<Instant>;
+}
+
+impl MockRequest for &'static str {
+    fn deadline(&self) -> Option<Instant> {
+        if *self == EXPIRED {
+            Some(Instant::now())
+        } else if *self == SOON {
+            Some(Instant::now() + Duration::from_millis(20))
+        } else {
+            None
+        }
+    }
+}
+
 impl<T, U, I> pipeline::ClientProto<I> for MockProtocol<pipeline::Frame<T, U, io::Error>>
-    where T: 'static,
+    where T: MockRequest + 'static,
           U: 'static,
           I: Io + 'static,
 {
@@ -41,10 +69,14 @@ impl<T, U, I> pipeline::ClientProto<I> for MockProtocol<pipeline::Frame<T, U, io
                       -> Result<MockTransport<pipeline::Frame<T, U, io::Error>>, io::Error> {
         Ok(self.0.borrow_mut().take().unwrap())
     }
+
+    fn request_deadline(request: &T) -> Option<Instant> {
+        request.deadline()
+    }
 }
 
 impl<T, U, I> multiplex::ClientProto<I> for MockProtocol<multiplex::Frame<T, U, io::Error>>
-    where T: 'static,
+    where T: MockRequest + 'static,
           U: 'static,
           I: Io + 'static,
 {
@@ -60,10 +92,14 @@ impl<T, U, I> multiplex::ClientProto<I> for MockProtocol<multiplex::Frame<T, U,
                       -> Result<MockTransport<multiplex::Frame<T, U, io::Error>>, io::Error> {
         Ok(self.0.borrow_mut().take().unwrap())
     }
+
+    fn request_deadline(request: &T) -> Option<Instant> {
+        request.deadline()
+    }
 }
 
 impl<T, U, I> pipeline::ServerProto<I> for MockProtocol<pipeline::Frame<T, U, io::Error>>
-    where T: 'static,
+    where T: MockRequest + 'static,
           U: 'static,
           I: Io + 'static,
 {
@@ -79,10 +115,14 @@ impl<T, U, I> pipeline::ServerProto<I> for MockProtocol<pipeline::Frame<T, U, io
                       -> Result<MockTransport<pipeline::Frame<T, U, io::Error>>, io::Error> {
         Ok(self.0.borrow_mut().take().unwrap())
     }
+
+    fn request_deadline(request: &T) -> Option<Instant> {
+        request.deadline()
+    }
 }
 
 impl<T, U, I> multiplex::ServerProto<I> for MockProtocol<multiplex::Frame<T, U, io::Error>>
-    where T: 'static,
+    where T: MockRequest + 'static,
           U: 'static,
           I: Io + 'static,
 {
@@ -98,11 +138,42 @@ impl<T, U, I> multiplex::ServerProto<I> for MockProtocol<multiplex::Frame<T, U,
                       -> Result<MockTransport<multiplex::Frame<T, U, io::Error>>, io::Error> {
         Ok(self.0.borrow_mut().take().unwrap())
     }
+
+    fn request_deadline(request: &T) -> Option<Instant> {
+        request.deadline()
+    }
 }
 
 struct MockTransport<T> {
     tx: mpsc::Sender<T>,
     rx: mpsc::UnboundedReceiver<io::Result<T>>,
+    tick: Arc<Mutex<MockTick>>,
+    canceled: Arc<Mutex<Vec<u64>>>,
+    aborted: Arc<Mutex<Vec<u64>>>,
+    memory: Arc<AtomicUsize>,
+}
+
+// The tick requested by the test, and whether the transport was ticked once
+// it was due
+#[derive(Default)]
+struct MockTick {
+    at: Option<Instant>,
+    fired: bool,
+}
+
+impl<T> MockTransport<T> {
+    fn tick(&mut self) {
+        let mut tick = self.tick.lock().unwrap();
+
+        if tick.at.map(|at| at <= Instant::now()).unwrap_or(false) {
+            tick.at = None;
+            tick.fired = true;
+        }
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        self.tick.lock().unwrap().at
+    }
 }
 
 impl<T: 'static> Stream for MockTransport<T> {
@@ -132,8 +203,43 @@ impl<T: 'static> Sink for MockTransport<T> {
     }
 }
 
-impl<T: 'static> pipeline::Transport for MockTransport<T> {}
-impl<B, T: 'static> multiplex::Transport<B> for MockTransport<T> {}
+impl<T: 'static> pipeline::Transport for MockTransport<T> {
+    fn tick(&mut self) {
+        MockTransport::tick(self)
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        MockTransport::poll_timeout(self)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.memory.load(Ordering::SeqCst)
+    }
+}
+
+impl<B, T: 'static> multiplex::Transport<B> for MockTransport<T> {
+    fn tick(&mut self) {
+        MockTransport::tick(self)
+    }
+
+    fn poll_timeout(&mut self) -> Option<Instant> {
+        MockTransport::poll_timeout(self)
+    }
+
+    fn cancel(&mut self, request_id: u64) -> io::Result<()> {
+        self.canceled.lock().unwrap().push(request_id);
+        Ok(())
+    }
+
+    fn abort_body(&mut self, request_id: u64) -> io::Result<()> {
+        self.aborted.lock().unwrap().push(request_id);
+        Ok(())
+    }
+
+    fn memory_used(&self) -> usize {
+        self.memory.load(Ordering::SeqCst)
+    }
+}
 
 struct MockIo;
 
@@ -157,9 +263,17 @@ impl Io for MockIo {}
 
 pub type MockBodyStream = Box<Stream<Item = u32, Error = io::Error> + Send>;
 
+pub type MockClient = ClientProxy<Message<&'static str, MockBodyStream>,
+                                  Message<&'static str, Body<u32, io::Error>>,
+                                  io::Error>;
+
 pub struct MockTransportCtl<T> {
     tx: Option<mpsc::UnboundedSender<io::Result<T>>>,
     rx: Wait<mpsc::Receiver<T>>,
+    tick: Arc<Mutex<MockTick>>,
+    canceled: Arc<Mutex<Vec<u64>>>,
+    aborted: Arc<Mutex<Vec<u64>>>,
+    memory: Arc<AtomicUsize>,
 }
 
 impl<T> MockTransportCtl<T> {
@@ -177,6 +291,38 @@ impl<T> MockTransportCtl<T> {
         self.rx.next().unwrap().expect("cannot error")
     }
 
+    /// Have the transport request a tick at the given instant. Takes effect
+    /// the next time the dispatcher runs.
+    pub fn tick_at(&mut self, at: Instant) {
+        *self.tick.lock().unwrap() = MockTick { at: Some(at), fired: false };
+    }
+
+    /// Returns true if the transport was ticked after the requested instant
+    pub fn ticked(&self) -> bool {
+        self.tick.lock().unwrap().fired
+    }
+
+    /// Returns the request IDs the transport was told to cancel so far
+    pub fn canceled(&self) -> Vec<u64> {
+        self.canceled.lock().unwrap().clone()
+    }
+
+    /// Returns the request IDs whose body the transport was told to abort
+    /// so far
+    pub fn aborted(&self) -> Vec<u64> {
+        self.aborted.lock().unwrap().clone()
+    }
+
+    /// Set the number of bytes the transport reports as used
+    pub fn set_memory_used(&self, bytes: usize) {
+        self.memory.store(bytes, Ordering::SeqCst);
+    }
+
+    /// Returns true once the transport was dropped without writing more
+    pub fn next_write_closed(&mut self) -> bool {
+        self.rx.next().is_none()
+    }
+
     pub fn allow_and_assert_drop(&mut self) {
         drop(self.tx.take());
         assert!(self.rx.next().is_none());
@@ -186,13 +332,25 @@ impl<T> MockTransportCtl<T> {
 fn transport<T>() -> (MockTransportCtl<T>, MockProtocol<T>) {
     let (tx1, rx1) = mpsc::channel(1);
     let (tx2, rx2) = mpsc::unbounded();
+    let tick = Arc::new(Mutex::new(MockTick::default()));
+    let canceled = Arc::new(Mutex::new(Vec::new()));
+    let aborted = Arc::new(Mutex::new(Vec::new()));
+    let memory = Arc::new(AtomicUsize::new(0));
     let ctl = MockTransportCtl {
         tx: Some(tx2),
         rx: rx1.wait(),
+        tick: tick.clone(),
+        canceled: canceled.clone(),
+        aborted: aborted.clone(),
+        memory: memory.clone(),
     };
     let transport = MockTransport {
         tx: tx1,
         rx: rx2,
+        tick: tick,
+        canceled: canceled,
+        aborted: aborted,
+        memory: memory,
     };
     (ctl, MockProtocol(RefCell::new(Some(transport))))
 }
@@ -211,11 +369,7 @@ impl Drop for CompleteOnDrop {
 
 pub fn pipeline_client()
     -> (MockTransportCtl<pipeline::Frame<&'static str, u32, io::Error>>,
-        Box<Service<Request = Message<&'static str, MockBodyStream>,
-                    Response = Message<&'static str, Body<u32, io::Error>>,
-                    Error = io::Error,
-                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
-                                              io::Error>>>,
+        MockClient,
         Box<Any>)
 {
     drop(env_logger::init());
@@ -239,7 +393,7 @@ pub fn pipeline_client()
         thread: Some(t),
         tx: Some(finished_tx),
     };
-    return (ctl, Box::new(service), Box::new(srv));
+    return (ctl, service, Box::new(srv));
 }
 
 pub fn pipeline_server<S>(s: S)
@@ -268,13 +422,52 @@ pub fn pipeline_server<S>(s: S)
     return (ctl, Box::new(srv));
 }
 
+/// Like `pipeline_server`, but builds the dispatcher directly and drives it
+/// without an event loop
+pub fn pipeline_server_dispatch<S>(s: S)
+    -> (MockTransportCtl<pipeline::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    pipeline_server_with_budget(s, None)
+}
+
+/// Like `pipeline_server_dispatch`, with the given memory budget
+pub fn pipeline_server_with_budget<S>(s: S, budget: Option<MemoryBudget>)
+    -> (MockTransportCtl<pipeline::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    use self::tokio_proto::streaming::pipeline::advanced::{Pipeline, ServerDispatch};
+
+    drop(env_logger::init());
+
+    let (ctl, proto) = transport();
+
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let t = thread::spawn(move || {
+        let transport = pipeline::ServerProto::<MockIo>::bind_transport(&proto, MockIo).unwrap();
+        let dispatch: ServerDispatch<_, MockIo, _> = ServerDispatch::new(&proto, transport, s);
+
+        let mut pipeline = Pipeline::new(dispatch);
+        if let Some(budget) = budget {
+            pipeline.memory_budget(budget);
+        }
+        drop(pipeline.select2(finished_rx).wait());
+    });
+
+    let srv = CompleteOnDrop {
+        thread: Some(t),
+        tx: Some(finished_tx),
+    };
+    return (ctl, Box::new(srv));
+}
+
 pub fn multiplex_client()
     -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
-        Box<Service<Request = Message<&'static str, MockBodyStream>,
-                    Response = Message<&'static str, Body<u32, io::Error>>,
-                    Error = io::Error,
-                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
-                                              io::Error>>>,
+        MockClient,
         Box<Any>)
 {
     drop(env_logger::init());
@@ -298,7 +491,37 @@ pub fn multiplex_client()
         thread: Some(t),
         tx: Some(finished_tx),
     };
-    return (ctl, Box::new(service), Box::new(srv));
+    return (ctl, service, Box::new(srv));
+}
+
+/// Like `multiplex_client`, but builds the dispatcher directly, tagging
+/// requests with the given IDs, and drives it without an event loop
+pub fn multiplex_client_with_ids(ids: RequestIds)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        MockClient,
+        Box<Any>)
+{
+    use self::tokio_proto::streaming::multiplex::advanced::{Multiplex, ClientDispatch};
+
+    drop(env_logger::init());
+
+    let (ctl, proto) = transport();
+
+    let transport = multiplex::ClientProto::<MockIo>::bind_transport(&proto, MockIo).unwrap();
+    let (service, mut dispatch): (MockClient, ClientDispatch<_, MockIo, _>) =
+        ClientDispatch::new(&proto, transport);
+    dispatch.request_ids(ids);
+
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let t = thread::spawn(move || {
+        drop(Multiplex::new(dispatch).select2(finished_rx).wait());
+    });
+
+    let srv = CompleteOnDrop {
+        thread: Some(t),
+        tx: Some(finished_tx),
+    };
+    return (ctl, service, Box::new(srv));
 }
 
 pub fn multiplex_server<S>(s: S)
@@ -326,3 +549,44 @@ pub fn multiplex_server<S>(s: S)
     };
     return (ctl, Box::new(srv));
 }
+
+/// Like `multiplex_server`, but builds the dispatcher directly and drives it
+/// without an event loop
+pub fn multiplex_server_dispatch<S>(s: S)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    multiplex_server_with_limits(s, ExchangeLimits::new())
+}
+
+/// Like `multiplex_server_dispatch`, bounding the number of exchanges
+pub fn multiplex_server_with_limits<S>(s: S, limits: ExchangeLimits)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    use self::tokio_proto::streaming::multiplex::advanced::{Multiplex, ServerDispatch};
+
+    drop(env_logger::init());
+
+    let (ctl, proto) = transport();
+
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let t = thread::spawn(move || {
+        let transport = multiplex::ServerProto::<MockIo>::bind_transport(&proto, MockIo).unwrap();
+        let dispatch: ServerDispatch<_, MockIo, _> = ServerDispatch::new(&proto, transport, s);
+
+        let mut multiplex = Multiplex::new(dispatch);
+        multiplex.exchange_limits(limits);
+        drop(multiplex.select2(finished_rx).wait());
+    });
+
+    let srv = CompleteOnDrop {
+        thread: Some(t),
+        tx: Some(finished_tx),
+    };
+    return (ctl, Box::new(srv));
+}
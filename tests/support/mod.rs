@@ -1,4 +1,5 @@
 #![allow(dead_code)]
 
+pub mod line;
 pub mod mock;
 pub mod service;
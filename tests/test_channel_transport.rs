@@ -0,0 +1,100 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+use std::thread;
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+use tokio_proto::{BindClient, BindServer};
+use tokio_proto::pipeline::{ClientProto, ServerProto};
+use tokio_proto::util::channel::{self, Channel};
+use tokio_service::Service;
+
+struct IntProto;
+
+impl ServerProto<Channel<u64, u64>> for IntProto {
+    type Request = u64;
+    type Response = u64;
+    type Transport = Channel<u64, u64>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: Channel<u64, u64>) -> Self::BindTransport {
+        Ok(io)
+    }
+}
+
+impl ClientProto<Channel<u64, u64>> for IntProto {
+    type Request = u64;
+    type Response = u64;
+    type Transport = Channel<u64, u64>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: Channel<u64, u64>) -> Self::BindTransport {
+        Ok(io)
+    }
+}
+
+struct Incr;
+
+impl Service for Incr {
+    type Request = u64;
+    type Response = u64;
+    type Error = io::Error;
+    type Future = future::FutureResult<u64, io::Error>;
+
+    fn call(&self, req: u64) -> Self::Future {
+        future::ok(req + 1)
+    }
+}
+
+#[test]
+fn test_same_event_loop() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (client_end, server_end) = channel::pair(1);
+    BindServer::bind_server(&IntProto, &handle, server_end, Incr);
+    let client = BindClient::bind_client(&IntProto, &handle, client_end);
+
+    let resps = core.run(client.call(1).join(client.call(2))).unwrap();
+    assert_eq!((2, 3), resps);
+}
+
+#[test]
+fn test_across_threads() {
+    let (client_end, server_end) = channel::pair(1);
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+
+    let t = thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        BindServer::bind_server(&IntProto, &handle, server_end, Incr);
+        drop(core.run(done_rx));
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let client = BindClient::bind_client(&IntProto, &handle, client_end);
+
+    assert_eq!(42, core.run(client.call(41)).unwrap());
+
+    done_tx.complete(());
+    t.join().unwrap();
+}
+
+#[test]
+fn test_peer_dropped() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (client_end, server_end) = channel::pair(1);
+    drop(server_end);
+
+    let client = BindClient::bind_client(&IntProto, &handle, client_end);
+    assert!(core.run(client.call(1)).is_err());
+}
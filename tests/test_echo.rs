@@ -0,0 +1,144 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::net;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::{BindServer, Drain, TcpClient, TcpServer};
+use tokio_proto::util::echo::{flood, Echo, EchoProto, MultiplexEchoProto};
+use tokio_service::Service;
+
+#[test]
+fn test_pipeline_echo_flood() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = handle.clone();
+    handle.spawn(listener.incoming().for_each(move |(socket, _)| {
+        BindServer::bind_server(&EchoProto, &server_handle, socket, Echo);
+        Ok(())
+    }).map_err(|e| panic!("accept failed; err={}", e)));
+
+    let client = core.run(TcpClient::new(EchoProto).connect(&addr, &handle)).unwrap();
+    assert_eq!(b"ping".to_vec(), core.run(client.call(b"ping".to_vec())).unwrap());
+
+    let report = core.run(flood(client, vec![0; 64], 100, 8)).unwrap();
+    assert_eq!(100, report.count());
+    assert_eq!(0, report.errors());
+}
+
+#[test]
+fn test_multiplex_echo_flood() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = handle.clone();
+    handle.spawn(listener.incoming().for_each(move |(socket, _)| {
+        BindServer::bind_server(&MultiplexEchoProto, &server_handle, socket, Echo);
+        Ok(())
+    }).map_err(|e| panic!("accept failed; err={}", e)));
+
+    let client = core.run(TcpClient::new(MultiplexEchoProto).connect(&addr, &handle)).unwrap();
+    let report = core.run(flood(client, b"ping".to_vec(), 100, 8)).unwrap();
+    assert_eq!(100, report.count());
+    assert_eq!(0, report.errors());
+}
+
+#[test]
+fn test_fast_open_oneshot() {
+    let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let drain = Drain::new();
+
+    let server_drain = drain.clone();
+    let server = thread::spawn(move || {
+        let mut server = TcpServer::new(EchoProto, addr);
+        server.fast_open(16);
+        server.drain(server_drain);
+        server.serve(|| Ok(Echo));
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = TcpClient::new(EchoProto);
+    client.fast_open(true);
+    let client = client.oneshot(&addr, &handle);
+
+    // Wait for the server to start, connections before that are refused
+    let mut res = core.run(client.call(b"first".to_vec()));
+    for _ in 0..100 {
+        if res.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        res = core.run(client.call(b"first".to_vec()));
+    }
+    assert_eq!(b"first".to_vec(), res.unwrap());
+
+    // Later connections may carry their request in the SYN
+    assert_eq!(b"second".to_vec(), core.run(client.call(b"second".to_vec())).unwrap());
+
+    // Closes the connections, which the server waits for
+    drop(core);
+    drain.start();
+    server.join().unwrap();
+}
+
+fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+#[test]
+fn test_multiplex_client_shared_across_threads() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = handle.clone();
+    handle.spawn(listener.incoming().for_each(move |(socket, _)| {
+        BindServer::bind_server(&MultiplexEchoProto, &server_handle, socket, Echo);
+        Ok(())
+    }).map_err(|e| panic!("accept failed; err={}", e)));
+
+    let client = core.run(TcpClient::new(MultiplexEchoProto).connect(&addr, &handle)).unwrap();
+    assert_send_sync(&client);
+
+    // Workers share the connection, which the event loop keeps driving
+    let client = Arc::new(client);
+    let (tx, rx) = mpsc::channel();
+    let workers = (0..4).map(|i| {
+        let client = client.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for j in 0..10 {
+                let request = format!("{}-{}", i, j).into_bytes();
+                let response = client.call(request.clone()).wait().unwrap();
+                assert_eq!(request, response);
+            }
+            tx.send(()).unwrap();
+        })
+    }).collect::<Vec<_>>();
+
+    let mut finished = 0;
+    while finished < 4 {
+        core.turn(Some(Duration::from_millis(10)));
+        while rx.try_recv().is_ok() {
+            finished += 1;
+        }
+    }
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+
+use futures::{Future, Stream};
+use tokio_core::io::{Io, Framed};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::{BindServer, TcpClient};
+use tokio_proto::multiplex;
+use tokio_proto::util::echo::{flood, Echo, MultiplexEchoCodec};
+use tokio_proto::util::fault::{Faults, Faulty};
+use tokio_service::Service;
+
+/// The multiplexed echo protocol, over a connection injecting faults
+struct FaultyEcho(Faults);
+
+impl<T: Io + 'static> multiplex::ServerProto<T> for FaultyEcho {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<Faulty<T>, MultiplexEchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Faulty::new(io, self.0.clone()).framed(MultiplexEchoCodec))
+    }
+}
+
+impl<T: Io + 'static> multiplex::ClientProto<T> for FaultyEcho {
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+    type Transport = Framed<Faulty<T>, MultiplexEchoCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(Faulty::new(io, self.0.clone()).framed(MultiplexEchoCodec))
+    }
+}
+
+fn serve(core: &Core, faults: Faults) -> ::std::net::SocketAddr {
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    handle.clone().spawn(listener.incoming().for_each(move |(socket, _)| {
+        BindServer::bind_server(&FaultyEcho(faults.clone()), &handle, socket, Echo);
+        Ok(())
+    }).map_err(|e| panic!("accept failed; err={}", e)));
+
+    addr
+}
+
+#[test]
+fn test_multiplex_survives_short_writes_and_spurious_blocks() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut faults = Faults::new();
+    faults.would_block(0.3);
+    faults.truncate_writes(3);
+    let addr = serve(&core, faults.clone());
+
+    faults.seed(42);
+    let client = core.run(TcpClient::new(FaultyEcho(faults)).connect(&addr, &handle)).unwrap();
+
+    let report = core.run(flood(client, b"resilient".to_vec(), 200, 16)).unwrap();
+    assert_eq!(200, report.count());
+    assert_eq!(0, report.errors());
+}
+
+#[test]
+fn test_multiplex_fails_requests_on_reset() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    // Each exchange is 17 bytes each way, the connection breaks while the
+    // server answers the second one
+    let mut faults = Faults::new();
+    faults.fail_after(40);
+    let addr = serve(&core, faults);
+
+    let client = core.run(TcpClient::new(FaultyEcho(Faults::new())).connect(&addr, &handle))
+        .unwrap();
+
+    assert_eq!(b"first".to_vec(), core.run(client.call(b"first".to_vec())).unwrap());
+
+    // Requests in flight fail, rather than hang, and so do the next ones
+    assert!(core.run(client.call(b"again".to_vec())).is_err());
+    assert!(core.run(client.call(b"third".to_vec())).is_err());
+}
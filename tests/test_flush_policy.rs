@@ -0,0 +1,85 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, Read, Write};
+use std::net;
+use std::thread;
+use std::time::Duration;
+
+use futures::Stream;
+use futures::sync::oneshot;
+use tokio_core::io::{Io, Framed};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_proto::serve_incoming;
+use tokio_proto::pipeline::ServerProto;
+use tokio_proto::streaming::FlushPolicy;
+
+mod support;
+use support::line::{Echo, LineCodec};
+
+/// Flushes responses two by two
+struct BatchedProto;
+
+impl ServerProto<TcpStream> for BatchedProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<TcpStream, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+
+    fn flush_policy(&self) -> Option<FlushPolicy> {
+        Some(FlushPolicy::new(2, Duration::from_secs(60)))
+    }
+}
+
+#[test]
+fn test_responses_flushed_in_batches() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = oneshot::channel();
+
+    let client = thread::spawn(move || {
+        let mut socket = net::TcpStream::connect(&addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        // The first response is held back until a second one is written
+        writeln!(socket, "one").unwrap();
+        let mut buf = [0; 8];
+        assert!(socket.read(&mut buf).is_err());
+
+        writeln!(socket, "two").unwrap();
+        let mut lines = String::new();
+        socket.set_read_timeout(None).unwrap();
+        while lines.len() < 8 {
+            let n = socket.read(&mut buf).unwrap();
+            lines.push_str(str::from_utf8(&buf[..n]).unwrap());
+        }
+        assert_eq!("one\ntwo\n", lines);
+
+        // A lone response is flushed once the connection winds down
+        writeln!(socket, "three").unwrap();
+        socket.shutdown(net::Shutdown::Write).unwrap();
+        let mut rest = String::new();
+        socket.read_to_string(&mut rest).unwrap();
+        assert_eq!("three\n", rest);
+
+        tx.complete(());
+    });
+
+    let incoming = listener.incoming().take(1);
+    core.run(serve_incoming(BatchedProto, incoming, || Ok(Echo), &handle)).unwrap();
+
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}
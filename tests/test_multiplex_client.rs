@@ -11,11 +11,14 @@ extern crate log;
 extern crate env_logger;
 
 use std::io;
+use std::thread;
+use std::time::Duration;
 
 use futures::stream::{Stream};
-use futures::{Future};
+use futures::sync::mpsc;
+use futures::{Future, Sink};
 use tokio_proto::streaming::Message;
-use tokio_proto::streaming::multiplex::{RequestId, Frame};
+use tokio_proto::streaming::multiplex::{RequestId, RequestIds, RequestIdPolicy, Frame};
 use tokio_service::Service;
 
 mod support;
@@ -89,6 +92,354 @@ fn drop_client_while_streaming_body() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_full_duplex_streaming() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let (tx, rx) = mpsc::channel(1);
+    let rx = rx.then(|r| r.unwrap());
+    let pong = service.call(Message::WithBody("ping", Box::new(rx) as mock::MockBodyStream));
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!("ping", wr.unwrap_msg());
+
+    // The response arrives while the request body is still open
+    mock.send(msg_with_body(0, "pong"));
+
+    let mut pong = pong.wait().unwrap();
+    assert_eq!("pong", &pong.get_ref()[..]);
+
+    let mut rx = pong.take_body().unwrap().wait();
+    let mut tx = tx;
+
+    for i in 0..3 {
+        tx = tx.send(Ok(i)).wait().ok().unwrap();
+
+        let wr = mock.next_write();
+        assert_eq!(0, wr.request_id());
+        assert_eq!(Some(i), wr.unwrap_body());
+
+        mock.send(body(0, Some(i + 10)));
+        assert_eq!(i + 10, rx.next().unwrap().unwrap());
+    }
+
+    drop(tx);
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!(None, wr.unwrap_body());
+
+    mock.send(body(0, None));
+    assert!(rx.next().is_none());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_requests_and_bodies_fail_once_transport_ends() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let streaming = service.call(Message::WithoutBody("ping"));
+    let waiting = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    assert_eq!(1, mock.next_write().request_id());
+
+    mock.send(msg_with_body(0, "pong"));
+    let mut pong = streaming.wait().unwrap();
+    let mut rx = pong.take_body().unwrap().wait();
+
+    mock.send(body(0, Some(1)));
+    assert_eq!(1, rx.next().unwrap().unwrap());
+
+    // The peer closes the connection: neither the second response nor the
+    // rest of the first body are coming
+    mock.allow_and_assert_drop();
+    assert_eq!(io::ErrorKind::BrokenPipe, waiting.wait().unwrap_err().kind());
+    assert_eq!(io::ErrorKind::BrokenPipe, rx.next().unwrap().unwrap_err().kind());
+}
+
+#[test]
+fn test_expired_request_not_written() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let expired = service.call(Message::WithoutBody(mock::EXPIRED));
+    assert_eq!(io::ErrorKind::TimedOut, expired.wait().unwrap_err().kind());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!("ping", wr.unwrap_msg());
+
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_dropped_response_cancels_request() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+
+    drop(pong);
+
+    for _ in 0..100 {
+        if !mock.canceled().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(vec![0], mock.canceled());
+
+    // The late response is discarded
+    mock.send(msg(0, "pong"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+
+    let wr = mock.next_write();
+    assert_eq!(1, wr.request_id());
+
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_canceled_request_never_answered_is_forgotten() {
+    let mut ids = RequestIds::new();
+    ids.max(2);
+    ids.max_canceled(1);
+    let (mut mock, service, _other) = mock::multiplex_client_with_ids(ids);
+
+    // The server never answers either canceled request
+    for i in 0..2 {
+        drop(service.call(Message::WithoutBody("ping")));
+        assert_eq!(i, mock.next_write().request_id());
+
+        for _ in 0..100 {
+            if mock.canceled().len() > i as usize {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    assert_eq!(vec![0, 1], mock.canceled());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(2, mock.next_write().request_id());
+    mock.send(msg(2, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    // Past the wrap, the forgotten request ID is reused, but not the one
+    // still expecting a response
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    let pong2 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(2, mock.next_write().request_id());
+
+    // Responses matching no request are dropped rather than failing the
+    // connection
+    mock.send(msg(3, "late"));
+    mock.send(msg(0, "pong"));
+    mock.send(msg(2, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+    assert_eq!("pong", pong2.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_close_waits_for_outstanding_responses() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong1 = service.call(Message::WithoutBody("ping"));
+    let pong2 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    assert_eq!(1, mock.next_write().request_id());
+
+    let close = service.close();
+
+    let refused = service.call(Message::WithoutBody("late"));
+    assert_eq!(io::ErrorKind::BrokenPipe, refused.wait().unwrap_err().kind());
+
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong2.wait().unwrap().into_inner());
+
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong1.wait().unwrap().into_inner());
+
+    close.wait().unwrap();
+    assert!(mock.next_write_closed());
+}
+
+#[test]
+fn test_unsolicited_messages_yielded_as_notifications() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    // Dropped, nobody asked for notifications yet
+    mock.send(solo(100, "ignored"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    let notifications = service.notifications().unwrap();
+    assert!(service.notifications().is_none());
+
+    mock.send(solo(101, "event"));
+
+    let (event, notifications) = notifications.into_future().wait().ok().unwrap();
+    assert_eq!("event", event.unwrap().into_inner());
+
+    // The stream ends with the connection
+    mock.allow_and_assert_drop();
+
+    let (event, _) = notifications.into_future().wait().ok().unwrap();
+    assert!(event.is_none());
+}
+
+#[test]
+fn test_request_ids_wrap_around_skipping_those_in_use() {
+    let mut ids = RequestIds::new();
+    ids.max(2);
+    let (mut mock, service, _other) = mock::multiplex_client_with_ids(ids);
+
+    let pong0 = service.call(Message::WithoutBody("ping"));
+    let pong1 = service.call(Message::WithoutBody("ping"));
+    let pong2 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    assert_eq!(1, mock.next_write().request_id());
+    assert_eq!(2, mock.next_write().request_id());
+
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong1.wait().unwrap().into_inner());
+
+    // 0 is still in flight
+    let pong3 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+
+    // Every ID is in flight
+    let refused = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::Other, refused.wait().unwrap_err().kind());
+
+    mock.send(msg(0, "pong"));
+    mock.send(msg(2, "pong"));
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong0.wait().unwrap().into_inner());
+    assert_eq!("pong", pong2.wait().unwrap().into_inner());
+    assert_eq!("pong", pong3.wait().unwrap().into_inner());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_request_ids_wrap_around_skipping_streaming_bodies() {
+    let mut ids = RequestIds::new();
+    ids.max(1);
+    let (mut mock, service, _other) = mock::multiplex_client_with_ids(ids);
+
+    let pong0 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    mock.send(msg_with_body(0, "pong"));
+
+    // The response head arrived, but its body is still streaming
+    let mut pong0 = pong0.wait().unwrap();
+    let mut rx = pong0.take_body().unwrap().wait();
+
+    let pong1 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong1.wait().unwrap().into_inner());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+
+    mock.send(body(0, Some(7)));
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+    assert_eq!(7, rx.next().unwrap().unwrap());
+
+    // Once the body ends, the ID is free again
+    mock.send(body(0, None));
+    assert!(rx.next().is_none());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_request_ids_exhausted_fail_requests() {
+    let mut ids = RequestIds::new();
+    ids.max(1);
+    ids.policy(RequestIdPolicy::Error);
+    let (mut mock, service, _other) = mock::multiplex_client_with_ids(ids);
+
+    let pong0 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong0.wait().unwrap().into_inner());
+
+    let pong1 = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+
+    // 0 is free, but IDs are never reused
+    let refused = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::Other, refused.wait().unwrap_err().kind());
+
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong1.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_request_ids_exhausted_close_connection() {
+    let mut ids = RequestIds::new();
+    ids.max(0);
+    ids.policy(RequestIdPolicy::Reconnect);
+    let (mut mock, service, _other) = mock::multiplex_client_with_ids(ids);
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    let refused = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::BrokenPipe, refused.wait().unwrap_err().kind());
+
+    // The request in flight completes before the connection closes
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+    assert!(mock.next_write_closed());
+
+    let refused = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::BrokenPipe, refused.wait().unwrap_err().kind());
+}
+
+fn solo(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
+    Frame::Message {
+        id: id,
+        message: msg,
+        body: false,
+        solo: true,
+    }
+}
+
 fn msg(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         id: id,
@@ -0,0 +1,140 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+
+use futures::{future, Sink, Stream};
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_proto::streaming::{Body, Message};
+use tokio_proto::streaming::multiplex::{Frame, Control, ControlHandler, ControlFrames, RequestId,
+                                        ServerProto, StreamingMultiplex};
+use tokio_proto::util::channel::{self, Channel};
+use tokio_service::Service;
+
+type RawFrame = Frame<&'static str, u32, io::Error>;
+type Io = Channel<RawFrame, RawFrame>;
+
+const SETTINGS: u32 = 1;
+const PING: u32 = 2;
+const PONG: u32 = 3;
+
+struct ControlProto;
+
+impl ServerProto<Io> for ControlProto {
+    type Request = &'static str;
+    type RequestBody = u32;
+    type Response = &'static str;
+    type ResponseBody = u32;
+    type Error = io::Error;
+    type Transport = Io;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: Io) -> Self::BindTransport {
+        Ok(io)
+    }
+
+    fn control_handler(&self) -> Option<Box<dyn ControlHandler>> {
+        Some(Box::new(Pings { settings_sent: false }))
+    }
+}
+
+/// Sends its settings once, then answers pings with pongs
+struct Pings {
+    settings_sent: bool,
+}
+
+impl ControlHandler for Pings {
+    fn control(&mut self, id: RequestId, control: Control, out: &mut ControlFrames)
+               -> io::Result<()> {
+        match control.kind() {
+            PING => {
+                out.send(id, Control::new(PONG, control.into_payload()));
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected control frame")),
+        }
+    }
+
+    fn tick(&mut self, out: &mut ControlFrames) -> io::Result<()> {
+        if !self.settings_sent {
+            out.send(0, Control::new(SETTINGS, b"max-frame=16".to_vec()));
+            self.settings_sent = true;
+        }
+        Ok(())
+    }
+}
+
+/// Echoes the request
+struct Echo;
+
+impl Service for Echo {
+    type Request = Message<&'static str, Body<u32, io::Error>>;
+    type Response = Message<&'static str, Body<u32, io::Error>>;
+    type Error = io::Error;
+    type Future = future::FutureResult<Self::Response, io::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        future::ok(Message::WithoutBody(req.into_inner()))
+    }
+}
+
+fn control(frame: &RawFrame) -> (RequestId, u32, Vec<u8>) {
+    match *frame {
+        Frame::Control { id, ref control } => (id, control.kind(), control.payload().to_vec()),
+        ref frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+}
+
+#[test]
+fn test_control_frames_routed_to_handler() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (client, server) = channel::pair(8);
+    BindServer::<StreamingMultiplex<Body<u32, io::Error>>, _>::bind_server(
+        &ControlProto, &handle, server, Echo);
+
+    let (tx, rx) = client.split();
+
+    // The settings are written as soon as the connection runs
+    let (frame, rx) = core.run(rx.into_future()).ok().unwrap();
+    assert_eq!((0, SETTINGS, b"max-frame=16".to_vec()), control(&frame.unwrap()));
+
+    let frames = vec![
+        Frame::Control { id: 0, control: Control::new(PING, b"abc".to_vec()) },
+        Frame::Message { id: 1, message: "hello", body: false, solo: false },
+    ];
+    let _tx = core.run(tx.send_all(futures::stream::iter(frames.into_iter().map(Ok::<_, io::Error>)))).unwrap();
+
+    let frames = core.run(rx.take(2).collect()).unwrap();
+    assert_eq!((0, PONG, b"abc".to_vec()), control(&frames[0]));
+
+    match frames[1] {
+        Frame::Message { id: 1, message: "hello", .. } => {}
+        ref frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+}
+
+#[test]
+fn test_control_handler_errors_fail_the_connection() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (client, server) = channel::pair(8);
+    BindServer::<StreamingMultiplex<Body<u32, io::Error>>, _>::bind_server(
+        &ControlProto, &handle, server, Echo);
+
+    let (tx, rx) = client.split();
+    let (frame, rx) = core.run(rx.into_future()).ok().unwrap();
+    assert_eq!(SETTINGS, control(&frame.unwrap()).1);
+
+    let bogus = Frame::Control { id: 0, control: Control::new(PONG, vec![]) };
+    let _tx = core.run(tx.send(bogus)).unwrap();
+
+    // The connection closes without writing anything else
+    let frames = core.run(rx.collect()).unwrap();
+    assert!(frames.is_empty());
+}
@@ -0,0 +1,136 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+
+use futures::{future, Future, Sink, Stream};
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_proto::streaming::{Body, Headers, Message, WithHeaders};
+use tokio_proto::streaming::multiplex::{Frame, MetadataTransport, ServerProto, StreamingMultiplex};
+use tokio_proto::util::channel::{self, Channel};
+use tokio_service::Service;
+
+type RawFrame = Frame<&'static str, u32, io::Error>;
+type Io = Channel<RawFrame, RawFrame>;
+
+struct MetadataProto;
+
+impl ServerProto<Io> for MetadataProto {
+    type Request = WithHeaders<&'static str>;
+    type RequestBody = u32;
+    type Response = WithHeaders<&'static str>;
+    type ResponseBody = u32;
+    type Error = io::Error;
+    type Transport = MetadataTransport<Io, &'static str, u32, io::Error>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: Io) -> Self::BindTransport {
+        Ok(MetadataTransport::new(io))
+    }
+}
+
+/// Echoes the request along with its headers, tagging requests that had any
+struct EchoHeaders;
+
+impl Service for EchoHeaders {
+    type Request = Message<WithHeaders<&'static str>, Body<u32, io::Error>>;
+    type Response = Message<WithHeaders<&'static str>, Body<u32, io::Error>>;
+    type Error = io::Error;
+    type Future = future::FutureResult<Self::Response, io::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (mut headers, payload) = req.into_inner().into_parts();
+        if !headers.is_empty() {
+            headers.insert("echoed", "yes");
+        }
+
+        future::ok(Message::WithoutBody(WithHeaders::from_parts(headers, payload)))
+    }
+}
+
+fn headers(pairs: &[(&str, &str)]) -> Headers {
+    let mut headers = Headers::new();
+    for &(name, value) in pairs {
+        headers.insert(name, value);
+    }
+    headers
+}
+
+fn message(id: u64, message: &'static str) -> RawFrame {
+    Frame::Message { id: id, message: message, body: false, solo: false }
+}
+
+#[test]
+fn test_metadata_frames_merged_into_message() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (client, server) = channel::pair(8);
+    BindServer::<StreamingMultiplex<Body<u32, io::Error>>, _>::bind_server(
+        &MetadataProto, &handle, server, EchoHeaders);
+
+    let frames = vec![
+        Frame::Metadata { id: 1, headers: headers(&[("trace", "abc"), ("auth", "old")]) },
+        Frame::Metadata { id: 1, headers: headers(&[("auth", "new")]) },
+        message(1, "hello"),
+        message(2, "plain"),
+    ];
+    let (tx, rx) = client.split();
+    let _tx = core.run(tx.send_all(futures::stream::iter(frames.into_iter().map(Ok::<_, io::Error>)))).unwrap();
+
+    let frames = core.run(rx.take(3).collect()).unwrap();
+
+    match frames[0] {
+        Frame::Metadata { id: 1, ref headers } => {
+            assert_eq!(Some(&b"new"[..]), headers.get("auth"));
+            assert_eq!(Some(&b"abc"[..]), headers.get("trace"));
+            assert_eq!(Some(&b"yes"[..]), headers.get("echoed"));
+        }
+        ref frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+
+    // Messages without headers are written without a metadata frame
+    let messages = frames[1..].iter().map(|frame| match *frame {
+        Frame::Message { id, message, .. } => (id, message),
+        ref frame => panic!("unexpected frame; frame={:?}", frame),
+    }).collect::<Vec<_>>();
+    assert_eq!(vec![(1, "hello"), (2, "plain")], messages);
+}
+
+#[test]
+fn test_pending_metadata_bounded() {
+    let (client, server) = channel::pair::<RawFrame, RawFrame>(8);
+    let mut transport = MetadataTransport::new(server);
+    transport.max_pending(1);
+
+    let frames = vec![
+        Frame::Metadata { id: 1, headers: headers(&[("trace", "abc")]) },
+        Frame::Error { id: 1, error: io::Error::new(io::ErrorKind::Other, "nope") },
+        Frame::Metadata { id: 2, headers: headers(&[("trace", "def")]) },
+        message(2, "hello"),
+        Frame::Metadata { id: 3, headers: headers(&[("trace", "ghi")]) },
+        Frame::Metadata { id: 4, headers: headers(&[("trace", "jkl")]) },
+    ];
+    let _client = client.send_all(futures::stream::iter(frames.into_iter().map(Ok::<_, io::Error>)))
+        .wait().unwrap();
+
+    let mut transport = Stream::wait(transport);
+
+    // The headers of the failed request were dropped, making room for others
+    match transport.next().unwrap().unwrap() {
+        Frame::Error { id: 1, .. } => {}
+        frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+    match transport.next().unwrap().unwrap() {
+        Frame::Message { id: 2, message, .. } => {
+            assert_eq!(Some(&b"def"[..]), message.headers().get("trace"));
+        }
+        frame => panic!("unexpected frame; frame={:?}", frame),
+    }
+
+    let e = transport.next().unwrap().unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, e.kind());
+}
@@ -14,14 +14,15 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::cell::RefCell;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::{Future, Stream, Sink};
 use futures::future;
 use futures::sync::oneshot;
 use futures::sync::mpsc;
-use tokio_proto::streaming::{Message, Body};
+use tokio_proto::streaming::{Message, Body, Headers};
 use tokio_proto::streaming::multiplex::{Frame, RequestId};
+use tokio_proto::streaming::multiplex::advanced::ExchangeLimits;
 use rand::Rng;
 
 mod support;
@@ -55,6 +56,23 @@ fn test_immediate_writable_echo() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_dispatcher_driven_without_event_loop() {
+    let service = simple_service(|req| {
+        assert_eq!(req, "hello");
+        future::ok(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server_dispatch(service);
+    mock.send(msg(3, "hello"));
+
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 3);
+    assert_eq!(wr.unwrap_msg(), "goodbye");
+
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 fn test_immediate_writable_delayed_response_echo() {
     let (c, fut) = oneshot::channel();
@@ -220,6 +238,66 @@ fn test_repeatedly_flushes_messages() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_reaching_max_exchanges() {
+    let (mut tx, rx) = mpsc::unbounded();
+    let rx = RefCell::new(rx.wait());
+
+    let c1 = Arc::new(AtomicUsize::new(0));
+    let c2 = c1.clone();
+
+    let service = simple_service(move |_| {
+        c2.fetch_add(1, Ordering::SeqCst);
+        let fut = rx.borrow_mut().next().unwrap().unwrap();
+        let fut: oneshot::Receiver<_> = fut;
+        fut.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+           .and_then(|res| res)
+    });
+
+    let mut limits = ExchangeLimits::new();
+    limits.initial(2);
+    limits.max(2);
+
+    let mut responses = vec![];
+
+    let (mut mock, _other) = mock::multiplex_server_with_limits(service, limits);
+    for i in 0..3 {
+        let (c, resp) = oneshot::channel();
+        mpsc::UnboundedSender::send(&mut tx, resp).unwrap();
+        responses.push((i, c));
+        mock.send(msg(i, "request"));
+    }
+
+    while c1.load(Ordering::SeqCst) < 2 {
+        thread::yield_now();
+    }
+
+    // The third request is not read while two are in progress
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(2, c1.load(Ordering::SeqCst));
+
+    let (i, c) = responses.remove(1);
+    c.complete(Ok(Message::WithoutBody("zomg")));
+
+    let wr = mock.next_write();
+    assert_eq!(i, wr.request_id());
+    assert_eq!("zomg", wr.unwrap_msg());
+
+    while c1.load(Ordering::SeqCst) < 3 {
+        thread::yield_now();
+    }
+
+    for (i, c) in responses.drain(..) {
+        c.complete(Ok(Message::WithoutBody("zomg")));
+
+        let wr = mock.next_write();
+        assert_eq!(i, wr.request_id());
+        assert_eq!("zomg", wr.unwrap_msg());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 fn test_reaching_max_in_flight_requests() {
     let (mut tx, rx) = mpsc::unbounded();
@@ -251,15 +329,18 @@ fn test_reaching_max_in_flight_requests() {
         thread::yield_now();
     }
 
+    // Next request not processed while the 32 are in flight. Once a response
+    // is completed, the dispatcher may write it and process the next request
+    // before the write is read, so the count is only checked before.
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(32, c1.load(Ordering::SeqCst));
+
     // Pick one from the first 32 requests to complete.
     rand::thread_rng().shuffle(&mut responses[0..32]);
     let (i, c) = responses.remove(0);
 
     c.complete(Ok(Message::WithoutBody("zomg")));
 
-    // Next request not yet processed
-    assert_eq!(32, c1.load(Ordering::SeqCst));
-
     // Read the response
     let wr = mock.next_write();
     assert_eq!(i, wr.request_id());
@@ -365,6 +446,42 @@ fn test_basic_streaming_request_body_read_then_respond() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_dropped_request_body_aborted() {
+    let service = simple_service(|mut req: Message<&'static str, Body<u32, io::Error>>| {
+        if req == "upload" {
+            // Rejected without reading the body
+            drop(req.take_body());
+            return future::ok(Message::WithoutBody("rejected"));
+        }
+
+        future::ok(Message::WithoutBody("pong"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg_with_body(2, "upload"));
+    mock.send(Frame::Body { id: 2, chunk: Some(0) });
+
+    let wr = mock.next_write();
+    assert_eq!(2, wr.request_id());
+    assert_eq!("rejected", wr.unwrap_msg());
+
+    // The next chunks are discarded, the transport being told once to stop
+    // the body
+    mock.send(Frame::Body { id: 2, chunk: Some(1) });
+    mock.send(msg(3, "ping"));
+    assert_eq!("pong", mock.next_write().unwrap_msg());
+
+    mock.send(Frame::Body { id: 2, chunk: Some(2) });
+    mock.send(Frame::Body { id: 2, chunk: None });
+    mock.send(msg(4, "ping"));
+    assert_eq!("pong", mock.next_write().unwrap_msg());
+
+    assert_eq!(vec![2], mock.aborted());
+
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 fn test_interleaving_request_body_chunks() {
     let (tx, rx) = mpsc::unbounded();
@@ -425,6 +542,42 @@ fn test_interleaving_request_body_chunks() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_full_duplex_streaming_echo() {
+    let service = simple_service(|mut req: Message<&'static str, Body<u32, io::Error>>| {
+        assert_eq!(req, "echo");
+
+        // Respond right away, streaming the request body back as it arrives
+        let body = req.take_body().unwrap();
+        future::ok(Message::WithBody("echo", Box::new(body) as mock::MockBodyStream))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg_with_body(5, "echo"));
+
+    let wr = mock.next_write();
+    assert_eq!(5, wr.request_id());
+    assert_eq!("echo", wr.unwrap_msg());
+
+    for i in 0..5 {
+        // Each request chunk is echoed before the next one is sent
+        mock.send(Frame::Body { id: 5, chunk: Some(i) });
+
+        let wr = mock.next_write();
+        assert_eq!(5, wr.request_id());
+        assert_eq!(Some(i), wr.unwrap_body());
+    }
+
+    mock.send(Frame::Body { id: 5, chunk: None });
+
+    let wr = mock.next_write();
+    assert_eq!(5, wr.request_id());
+    assert_eq!(None, wr.unwrap_body());
+
+    // Clean shutdown
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 #[ignore]
 fn test_interleaving_response_body_chunks() {
@@ -459,6 +612,23 @@ fn test_read_error_as_first_frame() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_unmerged_metadata_fails_connection() {
+    let service = simple_service(|_| {
+        // Makes the compiler happy
+        if true {
+            panic!("should not be called");
+        }
+
+        future::ok(Message::WithoutBody("nope"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(Frame::Metadata { id: 1, headers: Headers::new() });
+    mock.send(msg(1, "hello"));
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 #[ignore]
 fn test_read_error_during_stream() {
@@ -469,6 +639,76 @@ fn test_read_error_during_stream() {
 fn test_error_handling_before_message_dispatched() {
 }
 
+#[test]
+fn test_expired_request_not_dispatched() {
+    let service = simple_service(|req| {
+        assert_eq!(req, "hello");
+        future::ok(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg(0, mock::EXPIRED));
+
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 0);
+    assert_eq!(io::ErrorKind::TimedOut, wr.unwrap_err().kind());
+
+    mock.send(msg(1, "hello"));
+
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 1);
+    assert_eq!(wr.unwrap_msg(), "goodbye");
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_in_flight_request_expires() {
+    let service = simple_service(|req| {
+        if req == mock::SOON {
+            // Never completes, the deadline passes first
+            future::Either::A(future::empty())
+        } else {
+            future::Either::B(future::ok(Message::WithoutBody("goodbye")))
+        }
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg(0, mock::SOON));
+    mock.send(msg(1, "hello"));
+
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 1);
+    assert_eq!(wr.unwrap_msg(), "goodbye");
+
+    // Expired while in flight
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 0);
+    assert_eq!(io::ErrorKind::TimedOut, wr.unwrap_err().kind());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_transport_ticked_when_requested() {
+    let service = simple_service(|_| {
+        future::ok(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.tick_at(Instant::now() + Duration::from_millis(20));
+
+    // Run the dispatcher once so it picks up the requested tick
+    mock.send(msg(0, "hello"));
+    assert_eq!(mock.next_write().unwrap_msg(), "goodbye");
+    assert!(!mock.ticked());
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(mock.ticked());
+
+    mock.allow_and_assert_drop();
+}
+
 fn msg(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         id: id,
@@ -16,6 +16,7 @@ use std::time::Duration;
 
 use futures::sync::mpsc;
 use futures::{Future, Stream, Sink};
+use tokio_core::reactor::Core;
 use tokio_proto::streaming::Message;
 use tokio_proto::streaming::pipeline::Frame;
 use tokio_service::Service;
@@ -117,6 +118,69 @@ fn test_streaming_client_transport_dropped() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_requests_fail_once_transport_ends() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+
+    // The peer closes the connection without responding
+    mock.allow_and_assert_drop();
+    assert_eq!(io::ErrorKind::BrokenPipe, pong.wait().unwrap_err().kind());
+}
+
+#[test]
+fn test_expired_request_not_written() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    let expired = service.call(Message::WithoutBody(mock::EXPIRED));
+    assert_eq!(io::ErrorKind::TimedOut, expired.wait().unwrap_err().kind());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+    mock.send(msg("pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_close_waits_for_outstanding_responses() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+
+    let close = service.close();
+
+    // New calls fail right away
+    let refused = service.clone().call(Message::WithoutBody("late"));
+    assert_eq!(io::ErrorKind::BrokenPipe, refused.wait().unwrap_err().kind());
+
+    mock.send(msg("pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    // The transport is dropped once the response is read
+    close.wait().unwrap();
+    assert!(mock.next_write_closed());
+}
+
+#[test]
+fn test_close_timeout_aborts_connection() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+
+    let mut core = Core::new().unwrap();
+    let close = service.close_timeout(Duration::from_millis(20), &core.handle());
+    assert_eq!(io::ErrorKind::TimedOut, core.run(close).unwrap_err().kind());
+
+    assert_eq!(io::ErrorKind::BrokenPipe, pong.wait().unwrap_err().kind());
+    assert!(mock.next_write_closed());
+}
+
 fn msg(msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         message: msg,
@@ -0,0 +1,102 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::cell::RefCell;
+use std::io;
+
+use futures::{future, stream, Future, Stream, Sink};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+use tokio_proto::BindServer;
+use tokio_proto::pipeline::{MultiResponseProto, Responses};
+use tokio_proto::util::channel::{self, Channel};
+use tokio_service::Service;
+
+struct CountdownProto;
+
+impl MultiResponseProto<Channel<u64, u64>> for CountdownProto {
+    type Request = u64;
+    type Response = u64;
+    type Transport = Channel<u64, u64>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: Channel<u64, u64>) -> Self::BindTransport {
+        Ok(io)
+    }
+}
+
+/// Answers `n` with `n, n - 1, ..., 1`, followed by a `0` terminator. The
+/// responses to `1` are only produced once `gate` completes.
+struct Countdown {
+    gate: RefCell<Option<oneshot::Receiver<()>>>,
+}
+
+impl Service for Countdown {
+    type Request = u64;
+    type Response = Responses<u64>;
+    type Error = io::Error;
+    type Future = future::FutureResult<Responses<u64>, io::Error>;
+
+    fn call(&self, n: u64) -> Self::Future {
+        let responses = stream::iter((0..n + 1).rev().map(Ok::<u64, io::Error>));
+
+        if n == 1 {
+            let gate = self.gate.borrow_mut().take().unwrap();
+            let gated = gate.map_err(|_| io::Error::new(io::ErrorKind::Other, "canceled"))
+                .map(move |_| responses)
+                .flatten_stream();
+
+            return future::ok(Box::new(gated));
+        }
+
+        if n == 99 {
+            let failing = stream::once(Ok(99)).chain(stream::once(Err(
+                io::Error::new(io::ErrorKind::Other, "failed"))));
+
+            return future::ok(Box::new(failing));
+        }
+
+        future::ok(Box::new(responses))
+    }
+}
+
+#[test]
+fn test_responses_are_streamed_in_order() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (gate_tx, gate_rx) = oneshot::channel();
+    let (client, server) = channel::pair(8);
+    BindServer::bind_server(&CountdownProto, &handle, server, Countdown {
+        gate: RefCell::new(Some(gate_rx)),
+    });
+
+    let (tx, rx) = client.split();
+    let _tx = core.run(tx.send_all(stream::iter(vec![Ok::<u64, io::Error>(1), Ok(3)]))).unwrap().0;
+
+    // The responses to the second request wait for those of the first one
+    gate_tx.complete(());
+
+    let responses = core.run(rx.take(6).collect()).unwrap();
+    assert_eq!(vec![1, 0, 3, 2, 1, 0], responses);
+}
+
+#[test]
+fn test_response_stream_error_closes_connection() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (_gate_tx, gate_rx) = oneshot::channel();
+    let (client, server) = channel::pair(8);
+    BindServer::bind_server(&CountdownProto, &handle, server, Countdown {
+        gate: RefCell::new(Some(gate_rx)),
+    });
+
+    let (tx, rx) = client.split();
+    let _tx = core.run(tx.send(99)).unwrap();
+
+    let responses = core.run(rx.collect()).unwrap();
+    assert_eq!(vec![99], responses);
+}
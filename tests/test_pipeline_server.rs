@@ -12,9 +12,10 @@ extern crate env_logger;
 
 use std::cell::RefCell;
 use std::io;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::stream;
 use futures::sync::mpsc;
@@ -22,7 +23,7 @@ use futures::sync::oneshot;
 use futures::future;
 use futures::{Future, Stream, Sink};
 use tokio_proto::streaming::pipeline::Frame;
-use tokio_proto::streaming::{Message, Body};
+use tokio_proto::streaming::{Message, Body, MemoryBudget};
 
 mod support;
 use support::service::simple_service;
@@ -51,6 +52,20 @@ fn test_immediate_writable_echo() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_dispatcher_driven_without_event_loop() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        future::finished(Message::WithoutBody(*req.get_ref()))
+    });
+
+    let (mut mock, _other) = mock::pipeline_server_dispatch(service);
+    mock.send(msg("hello"));
+    mock.send(msg("world"));
+    assert_eq!(mock.next_write().unwrap_msg(), "hello");
+    assert_eq!(mock.next_write().unwrap_msg(), "world");
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 fn test_immediate_writable_delayed_response_echo() {
     let (c, fut) = oneshot::channel();
@@ -382,6 +397,183 @@ fn test_streaming_response_body() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_response_body_error_written_as_error_frame() {
+    let service = simple_service(move |_| {
+        let body = stream::once(Ok(1u32))
+            .chain(stream::once(Err(io::Error::new(io::ErrorKind::Other, "upstream aborted"))))
+            .boxed();
+        future::finished(Message::WithBody("resp", body))
+    });
+
+    let (mut mock, _other) = mock::pipeline_server(service);
+    mock.send(msg("one"));
+
+    assert_eq!(mock.next_write().unwrap_msg(), "resp");
+    assert_eq!(mock.next_write().unwrap_body(), Some(1));
+    assert_eq!("upstream aborted", mock.next_write().unwrap_err().to_string());
+
+    // The connection is still usable
+    mock.send(msg("two"));
+    assert_eq!(mock.next_write().unwrap_msg(), "resp");
+    assert_eq!(mock.next_write().unwrap_body(), Some(1));
+    mock.next_write().unwrap_err();
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_error_frame_fails_request_body() {
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        let body = req.take_body().unwrap();
+
+        body.fold(0, |n, _| future::ok::<_, io::Error>(n + 1)).then(|res| {
+            match res {
+                Ok(_) => future::finished(Message::WithoutBody("complete")),
+                Err(ref e) if e.to_string() == "upstream aborted" => {
+                    future::finished(Message::WithoutBody("aborted"))
+                }
+                Err(e) => future::failed(e),
+            }
+        })
+    });
+
+    let (mut mock, _other) = mock::pipeline_server(service);
+
+    mock.send(msg_with_body("one"));
+    mock.send(Frame::Body { chunk: Some(1) });
+    mock.send(Frame::Error { error: io::Error::new(io::ErrorKind::Other, "upstream aborted") });
+    assert_eq!(mock.next_write().unwrap_msg(), "aborted");
+
+    // Only the body failed, not the connection
+    mock.send(msg_with_body("two"));
+    mock.send(Frame::Body { chunk: Some(1) });
+    mock.send(Frame::Body { chunk: None });
+    assert_eq!(mock.next_write().unwrap_msg(), "complete");
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_reads_paused_over_memory_budget() {
+    let (tx, rx) = mpsc::unbounded();
+    let tx = RefCell::new(tx);
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let service = simple_service(move |_| {
+        calls2.fetch_add(1, Ordering::SeqCst);
+        let (c, fut) = oneshot::channel();
+        mpsc::UnboundedSender::send(&mut tx.borrow_mut(), c).unwrap();
+        fut.then(|r| r.unwrap())
+    });
+
+    let (mut mock, _other) = mock::pipeline_server_with_budget(service, Some(MemoryBudget::new(100)));
+    let mut rx = rx.wait();
+
+    mock.send(msg("one"));
+    let c1 = rx.next().unwrap().unwrap();
+
+    // The second request is not read while over budget
+    mock.set_memory_used(200);
+    mock.send(msg("two"));
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // Reading resumes once the memory is released
+    mock.set_memory_used(0);
+    c1.complete(Ok(Message::WithoutBody("one")));
+    assert_eq!(mock.next_write().unwrap_msg(), "one");
+
+    let c2 = rx.next().unwrap().unwrap();
+    c2.complete(Ok(Message::WithoutBody("two")));
+    assert_eq!(mock.next_write().unwrap_msg(), "two");
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_closed_over_memory_budget() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        future::finished(Message::WithoutBody(*req.get_ref()))
+    });
+
+    let mut budget = MemoryBudget::new(100);
+    budget.close_when_exceeded();
+
+    let (mut mock, _other) = mock::pipeline_server_with_budget(service, Some(budget));
+
+    mock.send(msg("one"));
+    assert_eq!(mock.next_write().unwrap_msg(), "one");
+
+    mock.set_memory_used(200);
+    mock.send(msg("two"));
+    assert!(mock.next_write_closed());
+}
+
+#[test]
+fn test_expired_request_not_dispatched() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        assert_eq!(req, "hello");
+        future::finished(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::pipeline_server(service);
+    mock.send(msg(mock::EXPIRED));
+    mock.send(msg("hello"));
+
+    let err = mock.next_write().unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert_eq!("goodbye", mock.next_write().unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_in_flight_request_expires() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        if req == mock::SOON {
+            // Never completes, the deadline passes first
+            future::Either::A(future::empty())
+        } else {
+            future::Either::B(future::finished(Message::WithoutBody("goodbye")))
+        }
+    });
+
+    let (mut mock, _other) = mock::pipeline_server(service);
+    mock.send(msg(mock::SOON));
+    mock.send(msg("hello"));
+
+    // The response queued behind the expired one is written once it is
+    // dropped
+    let err = mock.next_write().unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert_eq!("goodbye", mock.next_write().unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_transport_ticked_when_requested() {
+    let service = simple_service(|_| {
+        future::finished(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::pipeline_server(service);
+    mock.tick_at(Instant::now() + Duration::from_millis(20));
+
+    // Run the dispatcher once so it picks up the requested tick
+    mock.send(msg("hello"));
+    assert_eq!("goodbye", mock.next_write().unwrap_msg());
+    assert!(!mock.ticked());
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(mock.ticked());
+
+    mock.allow_and_assert_drop();
+}
+
 fn msg(msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message { message: msg, body: false }
 }
@@ -0,0 +1,178 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+
+use futures::{future, Stream};
+use futures::sync::mpsc;
+use tokio_core::reactor::Core;
+use tokio_proto::{BindClient, BindServer};
+use tokio_proto::pubsub::{self, ClientProto, ServerProto, Messages, Push, Request, SubscriptionId};
+use tokio_proto::util::channel::{self, Channel};
+use tokio_service::Service;
+
+type ServerIo = Channel<(SubscriptionId, Request<String>), (SubscriptionId, Push<u64>)>;
+type ClientIo = Channel<(SubscriptionId, Push<u64>), (SubscriptionId, Request<String>)>;
+
+struct TopicProto;
+
+impl ServerProto<ServerIo> for TopicProto {
+    type Subscribe = String;
+    type Message = u64;
+    type Transport = ServerIo;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: ServerIo) -> Self::BindTransport {
+        Ok(io)
+    }
+}
+
+impl ClientProto<ClientIo> for TopicProto {
+    type Subscribe = String;
+    type Message = u64;
+    type Transport = ClientIo;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: ClientIo) -> Self::BindTransport {
+        Ok(io)
+    }
+}
+
+/// Publishes to each topic whatever the test sends to its feed, publishing 0
+/// fails the subscription. Subscribing to an unknown topic fails.
+struct Topics {
+    feeds: RefCell<HashMap<String, mpsc::UnboundedReceiver<u64>>>,
+}
+
+impl Service for Topics {
+    type Request = String;
+    type Response = Messages<u64>;
+    type Error = io::Error;
+    type Future = future::FutureResult<Messages<u64>, io::Error>;
+
+    fn call(&self, topic: String) -> Self::Future {
+        let feed = match self.feeds.borrow_mut().remove(&topic) {
+            Some(feed) => feed,
+            None => return future::err(io::Error::new(io::ErrorKind::NotFound, "unknown topic")),
+        };
+
+        future::ok(Box::new(feed.map_err(|()| unreachable!()).and_then(|n| {
+            if n == 0 {
+                Err(io::Error::new(io::ErrorKind::Other, "feed failed"))
+            } else {
+                Ok(n)
+            }
+        })))
+    }
+}
+
+fn topics(names: &[&str]) -> (Topics, HashMap<String, mpsc::UnboundedSender<u64>>) {
+    let mut feeds = HashMap::new();
+    let mut senders = HashMap::new();
+
+    for name in names {
+        let (tx, rx) = mpsc::unbounded();
+        feeds.insert(name.to_string(), rx);
+        senders.insert(name.to_string(), tx);
+    }
+
+    (Topics { feeds: RefCell::new(feeds) }, senders)
+}
+
+fn next<S: Stream>(core: &mut Core, stream: S) -> (Option<S::Item>, S)
+    where S::Error: ::std::fmt::Debug,
+{
+    match core.run(stream.into_future()) {
+        Ok(res) => res,
+        Err((e, _)) => panic!("stream failed; err={:?}", e),
+    }
+}
+
+#[test]
+fn test_pushes_routed_to_subscriptions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (service, feeds) = topics(&["a", "b"]);
+    let (client_io, server_io) = channel::pair(8);
+    BindServer::<pubsub::PubSub, _>::bind_server(&TopicProto, &handle, server_io, service);
+    let client = BindClient::<pubsub::PubSub, _>::bind_client(&TopicProto, &handle, client_io);
+
+    let a = core.run(client.call("a".to_string())).unwrap();
+    let b = core.run(client.call("b".to_string())).unwrap();
+
+    feeds["a"].unbounded_send(1).unwrap();
+    feeds["b"].unbounded_send(2).unwrap();
+    feeds["a"].unbounded_send(3).unwrap();
+
+    let (msg, a) = next(&mut core, a);
+    assert_eq!(Some(1), msg);
+    let (msg, b) = next(&mut core, b);
+    assert_eq!(Some(2), msg);
+    let (msg, a) = next(&mut core, a);
+    assert_eq!(Some(3), msg);
+
+    // Ending the feed on the server ends the subscription
+    drop(feeds);
+    assert_eq!(Vec::<u64>::new(), core.run(a.collect()).unwrap());
+    assert_eq!(Vec::<u64>::new(), core.run(b.collect()).unwrap());
+}
+
+#[test]
+fn test_unsubscribe_drops_messages_on_server() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (service, feeds) = topics(&["a", "b"]);
+    let (client_io, server_io) = channel::pair(8);
+    BindServer::<pubsub::PubSub, _>::bind_server(&TopicProto, &handle, server_io, service);
+    let client = BindClient::<pubsub::PubSub, _>::bind_client(&TopicProto, &handle, client_io);
+
+    let a = core.run(client.call("a".to_string())).unwrap();
+    let b = core.run(client.call("b".to_string())).unwrap();
+
+    feeds["a"].unbounded_send(1).unwrap();
+    let (msg, mut a) = next(&mut core, a);
+    assert_eq!(Some(1), msg);
+
+    // The stream ends once the server ended the subscription, dropping the
+    // messages of the topic
+    a.unsubscribe();
+    assert_eq!(Vec::<u64>::new(), core.run(a.collect()).unwrap());
+    assert!(feeds["a"].unbounded_send(2).is_err());
+
+    // Other subscriptions are unaffected
+    feeds["b"].unbounded_send(3).unwrap();
+    let (msg, _b) = next(&mut core, b);
+    assert_eq!(Some(3), msg);
+}
+
+#[test]
+fn test_failures_only_end_their_subscription() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (service, feeds) = topics(&["a", "b"]);
+    let (client_io, server_io) = channel::pair(8);
+    BindServer::<pubsub::PubSub, _>::bind_server(&TopicProto, &handle, server_io, service);
+    let client = BindClient::<pubsub::PubSub, _>::bind_client(&TopicProto, &handle, client_io);
+
+    let a = core.run(client.call("a".to_string())).unwrap();
+    let b = core.run(client.call("b".to_string())).unwrap();
+
+    // A failed service rejects the subscription
+    assert!(core.run(client.call("c".to_string())).is_err());
+
+    // Failed messages end the subscription
+    feeds["a"].unbounded_send(1).unwrap();
+    feeds["a"].unbounded_send(0).unwrap();
+    assert_eq!(vec![1], core.run(a.collect()).unwrap());
+
+    feeds["b"].unbounded_send(2).unwrap();
+    let (msg, _b) = next(&mut core, b);
+    assert_eq!(Some(2), msg);
+}
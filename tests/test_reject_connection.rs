@@ -0,0 +1,83 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net;
+use std::thread;
+
+use futures::Stream;
+use futures::sync::oneshot;
+use tokio_core::io::{Io, Framed};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_proto::serve_incoming;
+use tokio_proto::pipeline::ServerProto;
+
+mod support;
+use support::line::{Echo, LineCodec};
+
+/// Serves a single connection, turning the others away
+struct FullProto {
+    connections: Cell<usize>,
+}
+
+impl ServerProto<TcpStream> for FullProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<TcpStream, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+
+    fn reject_connection(&self, _io: &TcpStream) -> Option<String> {
+        let connections = self.connections.get();
+        self.connections.set(connections + 1);
+
+        if connections >= 1 {
+            Some("server full, retry later".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_rejected_connection_gets_goodbye() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = oneshot::channel();
+
+    let client = thread::spawn(move || {
+        let served = net::TcpStream::connect(&addr).unwrap();
+        writeln!(&served, "hello").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(&served).read_line(&mut line).unwrap();
+        assert_eq!("hello\n", line);
+
+        // The second connection only gets the goodbye, then is closed
+        let mut rejected = net::TcpStream::connect(&addr).unwrap();
+        let mut goodbye = String::new();
+        rejected.read_to_string(&mut goodbye).unwrap();
+        assert_eq!("server full, retry later\n", goodbye);
+
+        tx.complete(());
+    });
+
+    let proto = FullProto { connections: Cell::new(0) };
+    let incoming = listener.incoming().take(2);
+    core.run(serve_incoming(proto, incoming, || Ok(Echo), &handle)).unwrap();
+
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}
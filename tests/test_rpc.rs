@@ -0,0 +1,73 @@
+#![cfg(all(feature = "rpc", feature = "serde_json"))]
+
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate tokio_core;
+#[macro_use]
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+
+use futures::{future, Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_proto::{BindServer, TcpClient};
+use tokio_proto::serde_proto::Json;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    x: i32,
+    y: i32,
+}
+
+rpc_service! {
+    mod geo {
+        /// Translate a point
+        rpc translate(p: Point, dx: i32, dy: i32) -> Point;
+        rpc origin() -> Point;
+        rpc fail() -> ();
+    }
+}
+
+struct Geo;
+
+impl geo::Rpc for Geo {
+    fn translate(&self, p: Point, dx: i32, dy: i32) -> geo::Future<Point> {
+        Box::new(future::ok(Point { x: p.x + dx, y: p.y + dy }))
+    }
+
+    fn origin(&self) -> geo::Future<Point> {
+        Box::new(future::ok(Point { x: 0, y: 0 }))
+    }
+
+    fn fail(&self) -> geo::Future<()> {
+        Box::new(future::err(io::Error::new(io::ErrorKind::Other, "nope")))
+    }
+}
+
+#[test]
+fn test_typed_client_and_server() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_handle = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        geo::proto(Json).bind_server(&server_handle, socket, geo::Server::new(Geo));
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| panic!("{}", e)));
+
+    let client = core.run(TcpClient::new(geo::proto(Json)).connect(&addr, &handle)).unwrap();
+    let client = geo::Client::new(client);
+
+    let p = core.run(client.translate(Point { x: 1, y: 2 }, 10, 20)).unwrap();
+    assert_eq!(Point { x: 11, y: 22 }, p);
+
+    assert_eq!(Point { x: 0, y: 0 }, core.run(client.origin()).unwrap());
+}
@@ -0,0 +1,198 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net;
+use std::sync::Arc;
+use std::thread;
+
+use futures::{future, stream, Async, Stream};
+use futures::sync::oneshot;
+use tokio_core::io::Io;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_proto::{serve_incoming, serve_sni, socket_channel};
+use tokio_proto::util::sni::SniRoutes;
+use tokio_proto::util::transport_info::{TransportInfo, TlsInfo};
+use tokio_service::{Service, NewService};
+
+mod support;
+use support::line::{Echo, LineProto};
+
+/// Sends a line on `n` connections to `addr` and checks it is echoed
+fn spawn_client(addr: net::SocketAddr, n: usize, done: oneshot::Sender<()>)
+                -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let sockets = (0..n).map(|_| net::TcpStream::connect(&addr).unwrap()).collect::<Vec<_>>();
+
+        for (i, socket) in sockets.iter().enumerate() {
+            writeln!(&*socket, "hello {}", i).unwrap();
+
+            let mut line = String::new();
+            BufReader::new(socket).read_line(&mut line).unwrap();
+            assert_eq!(format!("hello {}\n", i), line);
+        }
+
+        done.complete(());
+    })
+}
+
+#[test]
+fn test_serve_incoming_stream() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = oneshot::channel();
+    let client = spawn_client(addr, 2, tx);
+
+    // Any stream of I/O objects can be served, here the first two
+    // connections accepted by the listener
+    let incoming = listener.incoming().take(2);
+    core.run(serve_incoming(LineProto, incoming, || Ok(Echo), &handle)).unwrap();
+
+    // The connections are still served once the stream is exhausted
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}
+
+#[test]
+fn test_serve_sockets_pushed_over_channel() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (sender, sockets) = socket_channel(&handle);
+
+    // Sockets are accepted by another thread, which drops the sender once
+    // done, ending the stream
+    let acceptor = thread::spawn(move || {
+        for socket in listener.incoming().take(2) {
+            sender.send(socket.unwrap()).unwrap();
+        }
+    });
+
+    let (tx, rx) = oneshot::channel();
+    let client = spawn_client(addr, 2, tx);
+
+    core.run(serve_incoming(LineProto, sockets, || Ok(Echo), &handle)).unwrap();
+    acceptor.join().unwrap();
+
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}
+
+/// A socket posing as a TLS connection to the given server name
+struct Named {
+    socket: TcpStream,
+    server_name: &'static str,
+}
+
+impl Read for Named {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Write for Named {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Io for Named {
+    fn poll_read(&mut self) -> Async<()> {
+        self.socket.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.socket.poll_write()
+    }
+}
+
+impl TransportInfo for Named {
+    fn tls(&self) -> Option<TlsInfo> {
+        Some(TlsInfo { server_name: Some(self.server_name.to_string()), ..TlsInfo::default() })
+    }
+}
+
+/// Echoes lines behind a prefix
+struct Prefix(&'static str);
+
+impl Service for Prefix {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = future::FutureResult<String, io::Error>;
+
+    fn call(&self, req: String) -> Self::Future {
+        future::ok(format!("{}: {}", self.0, req))
+    }
+}
+
+impl NewService for Prefix {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Instance = Prefix;
+
+    fn new_service(&self) -> io::Result<Prefix> {
+        Ok(Prefix(self.0))
+    }
+}
+
+#[test]
+fn test_serve_routed_by_server_name() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut routes: SniRoutes<_> = SniRoutes::new();
+    routes.route("a.example.com", Prefix("a"));
+    routes.route("*.example.com", Prefix("any"));
+
+    let (tx, rx) = oneshot::channel();
+    let client = thread::spawn(move || {
+        let sockets = (0..3).map(|_| net::TcpStream::connect(&addr).unwrap()).collect::<Vec<_>>();
+
+        for (socket, expected) in sockets[..2].iter().zip(&["a: hello\n", "any: hello\n"]) {
+            writeln!(&*socket, "hello").unwrap();
+
+            let mut line = String::new();
+            BufReader::new(socket).read_line(&mut line).unwrap();
+            assert_eq!(*expected, line);
+        }
+
+        // Matching no route, the connection was closed
+        let mut line = String::new();
+        let read = BufReader::new(&sockets[2]).read_line(&mut line);
+        assert!(read.map(|n| n == 0).unwrap_or(true), "read {:?}", line);
+
+        tx.complete(());
+    });
+
+    let names = stream::iter(vec!["A.example.com", "www.example.com", "example.org"]
+        .into_iter().map(Ok::<_, io::Error>));
+    let incoming = listener.incoming().zip(names).map(|((socket, addr), name)| {
+        (Named { socket: socket, server_name: name }, addr)
+    });
+
+    core.run(serve_sni(LineProto, incoming, Arc::new(routes), &handle)).unwrap();
+
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}
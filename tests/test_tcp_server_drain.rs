@@ -0,0 +1,313 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_core::reactor::Core;
+use tokio_proto::{TcpServer, Drain, ReactorPool};
+use tokio_service::Service;
+
+mod support;
+use support::line::LineProto;
+
+/// Echoes lines, except for "hang", which never gets a response, and
+/// "thread", which gets the name of the thread running the service
+struct Echo {
+    called: mpsc::Sender<String>,
+}
+
+impl Service for Echo {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        self.called.send(req.clone()).unwrap();
+
+        if req == "hang" {
+            Box::new(future::empty())
+        } else if req == "thread" {
+            Box::new(future::ok(thread::current().name().unwrap_or("").to_string()))
+        } else {
+            Box::new(future::ok(req))
+        }
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn connect(addr: &SocketAddr) -> net::TcpStream {
+    for _ in 0..100 {
+        if let Ok(socket) = net::TcpStream::connect(addr) {
+            return socket;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server never started");
+}
+
+fn spawn_server(addr: SocketAddr, drain: Drain, timeout: Option<Duration>)
+                -> (thread::JoinHandle<()>, mpsc::Receiver<String>) {
+    spawn_server_with(addr, drain, timeout, None)
+}
+
+fn spawn_server_with(addr: SocketAddr,
+                     drain: Drain,
+                     timeout: Option<Duration>,
+                     pool: Option<ReactorPool>)
+                     -> (thread::JoinHandle<()>, mpsc::Receiver<String>) {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let t = thread::spawn(move || {
+        let mut server = TcpServer::new(LineProto, addr);
+        server.drain(drain);
+        if let Some(timeout) = timeout {
+            server.drain_timeout(timeout);
+        }
+        if let Some(pool) = pool {
+            server.executor(pool);
+        }
+        server.serve(move || Ok(Echo { called: tx.lock().unwrap().clone() }));
+    });
+
+    (t, rx)
+}
+
+#[test]
+fn test_drain_waits_for_connections_to_close() {
+    let addr = free_addr();
+    let drain = Drain::new();
+    let (server, called) = spawn_server(addr, drain.clone(), None);
+
+    let mut socket = connect(&addr);
+    socket.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", called.recv().unwrap());
+
+    let mut buf = [0; 6];
+    socket.read_exact(&mut buf).unwrap();
+    assert_eq!(b"hello\n", &buf);
+
+    drain.start();
+
+    // New connections are refused once draining
+    thread::sleep(Duration::from_millis(50));
+    assert!(net::TcpStream::connect(&addr).is_err());
+
+    // The open connection is still served
+    socket.write_all(b"again\n").unwrap();
+    assert_eq!("again", called.recv().unwrap());
+
+    drop(socket);
+    server.join().unwrap();
+
+    assert_eq!(0, drain.aborted_connections());
+    assert_eq!(0, drain.dropped_requests());
+}
+
+#[test]
+fn test_drain_timeout_closes_lingering_connections() {
+    let addr = free_addr();
+    let drain = Drain::new();
+    let (server, called) = spawn_server(addr, drain.clone(), Some(Duration::from_millis(100)));
+
+    let mut socket = connect(&addr);
+    socket.write_all(b"hang\n").unwrap();
+    assert_eq!("hang", called.recv().unwrap());
+
+    let start = Instant::now();
+    drain.start();
+    server.join().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    assert_eq!(1, drain.aborted_connections());
+    assert_eq!(1, drain.dropped_requests());
+
+    // The peer observes the connection being closed
+    let mut buf = Vec::new();
+    assert_eq!(0, socket.read_to_end(&mut buf).unwrap());
+}
+
+#[test]
+fn test_drain_connections_spawned_on_executor() {
+    let addr = free_addr();
+    let drain = Drain::new();
+    let pool = ReactorPool::new(2).unwrap();
+    let (server, called) = spawn_server_with(addr, drain.clone(), None, Some(pool));
+
+    let mut socket = connect(&addr);
+    socket.write_all(b"thread\n").unwrap();
+    assert_eq!("thread", called.recv().unwrap());
+
+    let mut buf = [0; 18];
+    socket.read_exact(&mut buf).unwrap();
+    assert_eq!(b"tokio-proto-pool0\n", &buf);
+
+    // The server waits for connections running on other threads as well
+    drain.start();
+    thread::sleep(Duration::from_millis(50));
+
+    socket.write_all(b"again\n").unwrap();
+    assert_eq!("again", called.recv().unwrap());
+
+    drop(socket);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_serve_future_runs_servers_on_one_event_loop() {
+    let (a, b) = (free_addr(), free_addr());
+    let drain = Drain::new();
+    let (tx, called) = mpsc::channel();
+
+    let server_drain = drain.clone();
+    let server = thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let serve = |addr| {
+            let tx = Mutex::new(tx.clone());
+            let mut server = TcpServer::new(LineProto, addr);
+            server.drain(server_drain.clone());
+            server.serve_future(&handle, move || Ok(Echo { called: tx.lock().unwrap().clone() }))
+        };
+
+        let servers = serve(a).join(serve(b));
+        core.run(servers).unwrap();
+    });
+
+    let mut sockets = vec![connect(&a), connect(&b)];
+    for (socket, line) in sockets.iter_mut().zip(&["first", "second"]) {
+        socket.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        assert_eq!(*line, called.recv().unwrap());
+
+        let mut buf = vec![0; line.len() + 1];
+        socket.read_exact(&mut buf).unwrap();
+        assert_eq!(format!("{}\n", line).as_bytes(), &buf[..]);
+    }
+
+    // Both futures complete once their connections are closed
+    drain.start();
+    drop(sockets);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_worker_threads_configured() {
+    let addr = free_addr();
+    let drain = Drain::new();
+    let (started_tx, started) = mpsc::channel();
+    let (panicked_tx, panicked) = mpsc::channel();
+
+    let server_drain = drain.clone();
+    let server = thread::spawn(move || {
+        let (tx, _called) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let started_tx = Mutex::new(started_tx);
+        let panicked_tx = Mutex::new(panicked_tx);
+
+        let mut server = TcpServer::new(LineProto, addr);
+        server.threads(3);
+        server.thread_name("proto-");
+        server.drain(server_drain);
+        server.on_thread_start(move || {
+            let name = thread::current().name().map(|name| name.to_string());
+            started_tx.lock().unwrap().send(name.clone()).unwrap();
+
+            if name == Some("proto-1".to_string()) {
+                panic!("failed to start");
+            }
+        });
+        server.on_thread_panic(move |payload| {
+            let msg = *payload.downcast::<&'static str>().unwrap();
+            let name = thread::current().name().unwrap().to_string();
+            panicked_tx.lock().unwrap().send((name, msg)).unwrap();
+        });
+        server.serve(move || Ok(Echo { called: tx.lock().unwrap().clone() }));
+    });
+
+    let mut names = started.iter().take(3).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(vec![None, Some("proto-0".to_string()), Some("proto-1".to_string())], names);
+
+    assert_eq!(("proto-1".to_string(), "failed to start"), panicked.recv().unwrap());
+
+    // The other threads keep serving
+    let mut socket = connect(&addr);
+    socket.write_all(b"hello\n").unwrap();
+    let mut buf = [0; 6];
+    socket.read_exact(&mut buf).unwrap();
+    assert_eq!(b"hello\n", &buf);
+
+    drain.start();
+    drop(socket);
+    server.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_listener_handoff() {
+    use std::os::unix::net::UnixStream;
+    use tokio_proto::util::handoff;
+
+    let old_drain = Drain::new();
+    let mut old = TcpServer::new(LineProto, free_addr());
+    old.bind().unwrap();
+    old.drain(old_drain.clone());
+    let addr = old.listeners().unwrap()[0].local_addr().unwrap();
+
+    let (control, peer) = UnixStream::pair().unwrap();
+    handoff::send_listeners(&control, &old.listeners().unwrap()).unwrap();
+
+    let (tx, called) = mpsc::channel();
+    let old_tx = Mutex::new(tx.clone());
+    let old = thread::spawn(move || {
+        old.serve(move || Ok(Echo { called: old_tx.lock().unwrap().clone() }));
+    });
+
+    let mut socket = connect(&addr);
+    socket.write_all(b"first\n").unwrap();
+    assert_eq!("first", called.recv().unwrap());
+
+    // The new server takes over the same socket
+    let new_drain = Drain::new();
+    let mut new = TcpServer::from_listeners(LineProto, handoff::recv_listeners(&peer).unwrap())
+        .unwrap();
+    new.drain(new_drain.clone());
+    let new_tx = Mutex::new(tx);
+    let new = thread::spawn(move || {
+        new.serve(move || Ok(Echo { called: new_tx.lock().unwrap().clone() }));
+    });
+
+    // The old server finishes serving its connection, and no new connection
+    // is refused meanwhile
+    old_drain.start();
+    for _ in 0..10 {
+        let mut socket = net::TcpStream::connect(&addr).unwrap();
+        socket.write_all(b"hello\n").unwrap();
+        let mut buf = [0; 6];
+        socket.read_exact(&mut buf).unwrap();
+        assert_eq!(b"hello\n", &buf);
+    }
+
+    socket.write_all(b"again\n").unwrap();
+    let mut buf = [0; 12];
+    socket.read_exact(&mut buf).unwrap();
+    assert_eq!(b"first\nagain\n", &buf);
+    drop(socket);
+    old.join().unwrap();
+
+    new_drain.start();
+    new.join().unwrap();
+}
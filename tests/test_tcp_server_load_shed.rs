@@ -0,0 +1,174 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use tokio_core::io::{Io, Framed};
+use tokio_proto::{MultiProto, Sniff, TcpServer};
+use tokio_proto::pipeline::ServerProto;
+use tokio_service::Service;
+
+mod support;
+use support::line::{LineCodec, LineProto};
+
+/// Answers requests with "busy" when the server is overloaded
+struct BusyProto;
+
+impl<T: Io + 'static> ServerProto<T> for BusyProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+
+    fn busy_response(request: &String) -> Option<String> {
+        Some(format!("busy {}", request))
+    }
+}
+
+/// Echoes lines once the test releases them
+struct Gated {
+    called: mpsc::Sender<(String, oneshot::Sender<()>)>,
+}
+
+impl Service for Gated {
+    type Request = String;
+    type Response = String;
+    type Error = io::Error;
+    type Future = Box<Future<Item = String, Error = io::Error>>;
+
+    fn call(&self, req: String) -> Self::Future {
+        let (tx, rx) = oneshot::channel();
+        self.called.send((req.clone(), tx)).unwrap();
+
+        Box::new(rx.then(move |_| future::ok(req)))
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn connect(addr: &SocketAddr) -> net::TcpStream {
+    for _ in 0..100 {
+        if let Ok(socket) = net::TcpStream::connect(addr) {
+            return socket;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server never started");
+}
+
+#[test]
+fn test_busy_response_beyond_max_in_flight() {
+    let addr = free_addr();
+    let (tx, called) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    thread::spawn(move || {
+        let mut server = TcpServer::new(BusyProto, addr);
+        server.max_in_flight(1);
+        server.serve(move || Ok(Gated { called: tx.lock().unwrap().clone() }));
+    });
+
+    let mut first = connect(&addr);
+    first.write_all(b"one\n").unwrap();
+    let (req, gate) = called.recv().unwrap();
+    assert_eq!("one", req);
+
+    // The limit applies across connections
+    let mut second = connect(&addr);
+    second.write_all(b"two\n").unwrap();
+
+    let mut lines = BufReader::new(second.try_clone().unwrap()).lines();
+    assert_eq!("busy two", lines.next().unwrap().unwrap());
+
+    gate.complete(());
+    let mut buf = [0; 4];
+    first.read_exact(&mut buf).unwrap();
+    assert_eq!(b"one\n", &buf);
+
+    // Requests are served again once the server caught up
+    second.write_all(b"three\n").unwrap();
+    let (req, gate) = called.recv().unwrap();
+    assert_eq!("three", req);
+    gate.complete(());
+    assert_eq!("three", lines.next().unwrap().unwrap());
+}
+
+#[test]
+fn test_connection_closed_without_busy_response() {
+    let addr = free_addr();
+    let (tx, called) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    thread::spawn(move || {
+        let mut server = TcpServer::new(LineProto, addr);
+        server.max_in_flight(1);
+        server.serve(move || Ok(Gated { called: tx.lock().unwrap().clone() }));
+    });
+
+    let mut first = connect(&addr);
+    first.write_all(b"one\n").unwrap();
+    let (_, gate) = called.recv().unwrap();
+
+    let mut second = connect(&addr);
+    second.write_all(b"two\n").unwrap();
+
+    let mut buf = Vec::new();
+    assert_eq!(0, second.read_to_end(&mut buf).unwrap());
+
+    gate.complete(());
+    let mut buf = [0; 4];
+    first.read_exact(&mut buf).unwrap();
+    assert_eq!(b"one\n", &buf);
+}
+
+#[test]
+fn test_busy_response_of_second_multi_proto() {
+    let addr = free_addr();
+    let (tx, called) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    thread::spawn(move || {
+        // Lines starting with "b" select the protocol answering when busy
+        let proto = MultiProto::new(LineProto, BusyProto, |buf: &[u8]| {
+            match buf.first() {
+                Some(&b'b') => Sniff::Second,
+                Some(_) => Sniff::First,
+                None => Sniff::NeedMore,
+            }
+        });
+
+        let mut server = TcpServer::new(proto, addr);
+        server.max_in_flight(1);
+        server.serve(move || Ok(Gated { called: tx.lock().unwrap().clone() }));
+    });
+
+    let mut first = connect(&addr);
+    first.write_all(b"one\n").unwrap();
+    let (_, gate) = called.recv().unwrap();
+
+    let mut second = connect(&addr);
+    second.write_all(b"b two\n").unwrap();
+
+    let mut lines = BufReader::new(second).lines();
+    assert_eq!("busy b two", lines.next().unwrap().unwrap());
+
+    gate.complete(());
+    let mut buf = [0; 4];
+    first.read_exact(&mut buf).unwrap();
+    assert_eq!(b"one\n", &buf);
+}
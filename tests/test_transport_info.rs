@@ -0,0 +1,117 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net;
+use std::thread;
+
+use futures::{future, Stream};
+use futures::sync::oneshot;
+use tokio_core::io::{Io, Codec, Framed, EasyBuf};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_proto::serve_incoming;
+use tokio_proto::pipeline::ServerProto;
+use tokio_proto::util::extensions::Extensions;
+use tokio_proto::util::transport_info::{TransportInfo, ConnectionInfo};
+use tokio_service::Service;
+
+/// A request, carrying its extensions
+struct Request {
+    extensions: Extensions,
+}
+
+struct LineCodec;
+
+impl Codec for LineCodec {
+    type In = Request;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Request>> {
+        match buf.as_slice().iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                buf.drain_to(i + 1);
+                Ok(Some(Request { extensions: Extensions::new() }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(msg.as_bytes());
+        buf.push(b'\n');
+        Ok(())
+    }
+}
+
+struct InfoProto;
+
+impl ServerProto<TcpStream> for InfoProto {
+    type Request = Request;
+    type Response = String;
+    type Transport = Framed<TcpStream, LineCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+
+    fn request_extensions(request: &mut Request) -> Option<&mut Extensions> {
+        Some(&mut request.extensions)
+    }
+
+    fn transport_info(transport: &Self::Transport) -> Option<&dyn TransportInfo> {
+        Some(transport.get_ref())
+    }
+}
+
+/// Answers with the peer address of the connection
+struct PeerAddr;
+
+impl Service for PeerAddr {
+    type Request = Request;
+    type Response = String;
+    type Error = io::Error;
+    type Future = future::FutureResult<String, io::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let info = req.extensions.get::<ConnectionInfo>().unwrap();
+        assert!(info.tls.is_none());
+        future::ok(info.peer_addr.unwrap().to_string())
+    }
+}
+
+#[test]
+fn test_connection_info_in_request_extensions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = oneshot::channel();
+
+    let client = thread::spawn(move || {
+        let socket = net::TcpStream::connect(&addr).unwrap();
+        let local_addr = socket.local_addr().unwrap();
+
+        for _ in 0..2 {
+            writeln!(&socket, "who am i").unwrap();
+
+            let mut line = String::new();
+            BufReader::new(&socket).read_line(&mut line).unwrap();
+            assert_eq!(format!("{}\n", local_addr), line);
+        }
+
+        tx.complete(());
+    });
+
+    let incoming = listener.incoming().take(1);
+    core.run(serve_incoming(InfoProto, incoming, || Ok(PeerAddr), &handle)).unwrap();
+
+    core.run(rx).unwrap();
+    client.join().unwrap();
+}